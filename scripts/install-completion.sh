@@ -8,7 +8,7 @@ usage() {
 Install tplenv shell completion.
 
 Usage:
-  $0 [--shell bash|zsh]
+  $0 [--shell bash|zsh|fish]
 
 If --shell is omitted, the script tries to detect your current shell.
 USAGE
@@ -31,18 +31,27 @@ if [[ -z "$shell_name" ]]; then
   shell_name="$(basename "${SHELL:-}")"
 fi
 
+# Completion scripts are generated from the CLI definition, so they always match the flags.
+print_completion() {
+  if command -v tplenv >/dev/null 2>&1; then
+    tplenv --print-completion "$1"
+  else
+    (cd "$ROOT_DIR" && cargo run --quiet -- --print-completion "$1")
+  fi
+}
+
 case "$shell_name" in
   bash)
     target_dir="${XDG_DATA_HOME:-$HOME/.local/share}/bash-completion/completions"
     mkdir -p "$target_dir"
-    cp "$ROOT_DIR/completions/tplenv.bash" "$target_dir/tplenv"
+    print_completion bash > "$target_dir/tplenv"
     echo "Installed bash completion: $target_dir/tplenv"
     echo "Open a new shell, or run: source $target_dir/tplenv"
     ;;
   zsh)
     target_dir="$HOME/.zsh/completions"
     mkdir -p "$target_dir"
-    cp "$ROOT_DIR/completions/_tplenv" "$target_dir/_tplenv"
+    print_completion zsh > "$target_dir/_tplenv"
 
     zshrc="$HOME/.zshrc"
     line='fpath=(~/.zsh/completions $fpath)'
@@ -57,9 +66,16 @@ case "$shell_name" in
     echo "Installed zsh completion: $target_dir/_tplenv"
     echo "Open a new shell, or run: fpath=(~/.zsh/completions \$fpath); autoload -Uz compinit && compinit"
     ;;
+  fish)
+    target_dir="${XDG_CONFIG_HOME:-$HOME/.config}/fish/completions"
+    mkdir -p "$target_dir"
+    print_completion fish > "$target_dir/tplenv.fish"
+    echo "Installed fish completion: $target_dir/tplenv.fish"
+    echo "Open a new shell to use it."
+    ;;
   *)
     echo "Unsupported shell: $shell_name" >&2
-    echo "Use --shell bash or --shell zsh" >&2
+    echo "Use --shell bash, --shell zsh or --shell fish" >&2
     exit 1
     ;;
 esac
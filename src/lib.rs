@@ -0,0 +1,11623 @@
+// src/lib.rs
+//! The tplenv rendering engine, used by the `tplenv` binary and available as a library.
+//!
+//! [`render`] fills the placeholders of one template from an env map and a values
+//! document, the way the CLI does for a single `--file`:
+//!
+//! ```
+//! use std::collections::HashMap;
+//!
+//! let values: serde_yaml::Value = serde_yaml::from_str("image:\n  tag: v1\n").unwrap();
+//! let env = HashMap::from([("NAMESPACE".to_string(), "dev".to_string())]);
+//! let out = tplenv::render(
+//!     "namespace: ${NAMESPACE}\ntag: {{ .Values.image.tag }}\n",
+//!     &env,
+//!     &values,
+//!     &tplenv::Options::default(),
+//! )
+//! .unwrap();
+//! assert_eq!(out, "namespace: dev\ntag: v1\n");
+//! ```
+//!
+//! Items hidden from these docs are the parts of the engine the binary drives (its command
+//! line lives in `main.rs`); they are not a stable API.
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use clap::ValueEnum;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
+use sha2::Digest;
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[doc(hidden)]
+pub enum PlaceholderStyle {
+    /// {{VARNAME}}, $VARNAME, ${VARNAME} and {{ .Values.key }}
+    Standard,
+    /// Standard styles plus Perl-style $ENV{VARNAME}
+    PerlEnv,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TemplateFormat {
+    /// YAML templates; multiple files become a `---` document stream
+    Yaml,
+    /// JSON templates with placeholders inside strings; multiple files become a JSON array
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[doc(hidden)]
+pub enum OutputFormat {
+    /// The rendered text as is
+    Yaml,
+    /// Rendered documents converted to pretty-printed JSON
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[doc(hidden)]
+pub enum TraceFormat {
+    Yaml,
+    Json,
+    /// Header plus a GitHub-flavored table of placeholders, sources and values
+    Markdown,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[doc(hidden)]
+pub enum ScanFormat {
+    Text,
+    Json,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[doc(hidden)]
+pub enum KeyCase {
+    /// Keys must match exactly
+    #[value(alias = "none")]
+    Exact,
+    /// Compare keys converted to snake_case (imageTag matches image_tag)
+    Snake,
+    /// Compare keys converted to camelCase (image_tag and app-name match imageTag and appName)
+    Camel,
+    /// Compare keys converted to kebab-case (appName matches app-name)
+    Kebab,
+    /// A dashed path segment also matches its camelCase key (app-name matches appName)
+    KebabToCamel,
+    /// A camelCase path segment also matches its kebab-case key (appName matches app-name)
+    CamelToKebab,
+    /// Compare keys ignoring case, '_' and '-'
+    Insensitive,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[doc(hidden)]
+pub enum OutputEncoding {
+    /// Standard base64 (RFC 4648) with padding
+    Base64,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[doc(hidden)]
+pub enum FoldedPolicy {
+    /// Keep the `>` header and add a blank line per line break, so folding keeps them
+    Preserve,
+    /// Turn the enclosing `>` header into `|`, so all of its lines keep their breaks
+    Literal,
+    /// Leave the scalar as it is (line breaks become spaces) and print a warning
+    Warn,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[doc(hidden)]
+pub enum Charset {
+    /// UTF-8 without a byte order mark
+    Utf8,
+    /// UTF-16 little-endian, written with a byte order mark
+    Utf16le,
+    /// UTF-16 big-endian, written with a byte order mark
+    Utf16be,
+    /// ISO-8859-1, one byte per character (U+0000 to U+00FF only)
+    Latin1,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[doc(hidden)]
+pub enum ConflictPolicy {
+    /// environment.<VAR> from the values file always wins
+    ValuesFile,
+    /// For multi-line values, keep the one with more non-empty lines
+    PreferLonger,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+#[doc(hidden)]
+pub enum EnvSource {
+    /// environment.<VAR> in the values file (after --values-default, --secrets-file etc.)
+    Values,
+    /// The value entered for environment.<VAR> at a --create-values-file prompt
+    Prompt,
+    /// <VAR> in the --env-file dotenv file
+    EnvFile,
+    /// The OS environment variable <VAR>
+    OsEnv,
+    /// The `# @tplenv default: ...` annotation of the placeholder
+    Default,
+}
+
+/// Matches the first word of a file name, after an optional `<NUM>-` prefix:
+/// `10-prod-app.yaml` and `prod.yaml` both select `prod`.
+#[doc(hidden)]
+pub const DEFAULT_VALUES_NAME_REGEX: &str = r"^(?:[0-9]+-)?([A-Za-z0-9_]+)[-.]";
+/// --strict-env-names default: UPPER_CASE names of letters, digits and underscores.
+#[doc(hidden)]
+pub const DEFAULT_ENV_NAME_PATTERN: &str = "[A-Z_][A-Z0-9_]*";
+
+thread_local! {
+    /// Warnings emitted during this run, for --fail-on-warning. Per thread, because a run
+    /// (each --pipeline step, or the --timeout worker) happens on one thread.
+    static WARNINGS: Cell<usize> = const { Cell::new(0) };
+
+    /// The -v level of this run; see `log_at`.
+    static VERBOSITY: Cell<u8> = const { Cell::new(0) };
+}
+
+/// What the run is doing, for the --timeout error.
+static PHASE: Mutex<&str> = Mutex::new("starting");
+
+/// The phase in which --timeout no longer aborts, so outputs are not left half-written.
+#[doc(hidden)]
+pub const PHASE_WRITING: &str = "writing output";
+
+#[doc(hidden)]
+pub fn set_phase(phase: &'static str) {
+    *PHASE.lock().unwrap_or_else(PoisonError::into_inner) = phase;
+}
+
+#[doc(hidden)]
+pub fn current_phase() -> &'static str {
+    *PHASE.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Starts a run (the command line or a --pipeline step) on this thread: sets its -v level
+/// and resets its warning count.
+#[doc(hidden)]
+pub fn begin_run(verbosity: u8) {
+    VERBOSITY.set(verbosity);
+    WARNINGS.set(0);
+}
+
+/// The -v level of the run on this thread.
+#[doc(hidden)]
+pub fn verbosity() -> u8 {
+    VERBOSITY.get()
+}
+
+/// Prints the message to stderr if the run is at least this verbose (-v is 1, -vv 2, ...).
+/// The message is only built when it is printed.
+#[doc(hidden)]
+pub fn log_at(level: u8, message: impl FnOnce() -> String) {
+    if verbosity() >= level {
+        eprintln!("{}", message());
+    }
+}
+
+/// Counts a warning and prints it as `warning: ...` unless `quiet`.
+#[doc(hidden)]
+pub fn warn(quiet: bool, message: &str) {
+    WARNINGS.set(WARNINGS.get() + 1);
+    if !quiet {
+        eprintln!("warning: {message}");
+    }
+}
+
+/// With --fail-on-warning, fails once the run is done if any warning was emitted.
+#[doc(hidden)]
+pub fn check_warnings(fail_on_warning: bool) -> Result<()> {
+    let count = WARNINGS.get();
+    if fail_on_warning && count > 0 {
+        bail!("{count} warning(s) emitted (--fail-on-warning)");
+    }
+    Ok(())
+}
+
+#[doc(hidden)]
+pub struct ResolveOptions<'a> {
+    pub value_file_only: bool,
+    pub verbose: bool,
+    pub quiet: bool,
+    pub conflict_policy: ConflictPolicy,
+    pub resolution_order: &'a [EnvSource],
+    pub key_case: KeyCase,
+    pub strict_unknown_keys: bool,
+    pub tolerant_values: bool,
+    /// Load the values file even if no placeholder needs it (--dump-values).
+    pub always_load: bool,
+    pub annotations: &'a HashMap<String, Annotation>,
+    pub prompted_env_map: &'a HashMap<String, String>,
+    /// --override values, keyed like `sources` (`environment.NAME` or the values path).
+    pub overrides: &'a HashMap<String, String>,
+    /// --deny-os-env-leak: the OS environment is never a source.
+    pub deny_os_env: bool,
+    /// --env-ignore-case: `environment.<VAR>` keys match env placeholder names ignoring case.
+    pub env_ignore_case: bool,
+    /// --env-ignore-case: the OS variable to read for placeholder names not set as written.
+    pub os_env_names: &'a HashMap<String, String>,
+    /// Variables from --env-file.
+    pub env_file: &'a HashMap<String, String>,
+    /// --optional-key: missing `.Values` keys matching these render as empty.
+    pub optional_keys: &'a [Regex],
+    pub values_default: Option<&'a YamlValue>,
+    /// Values document from --values-base64; replaces reading the values file.
+    pub values_inline: Option<&'a YamlValue>,
+    pub values_patch: Option<&'a YamlValue>,
+    pub secrets: Option<&'a YamlValue>,
+    pub select: Option<&'a ValuesSelect>,
+    pub load: &'a ValuesLoadOptions<'a>,
+}
+
+/// Which element of a values file that is a YAML list becomes the `.Values` root.
+#[doc(hidden)]
+pub enum ValuesSelect {
+    /// --select FIELD=VALUE
+    Field(String, String),
+    /// --select-index INDEX
+    Index(usize),
+}
+
+/// Templates that render against one values file, with `{{ if }}` blocks already applied.
+#[doc(hidden)]
+pub struct ValuesGroup {
+    pub values_file: PathBuf,
+    /// Positions of the templates in the input order.
+    pub indices: Vec<usize>,
+    pub templates: Vec<(PathBuf, String)>,
+    pub resolution: Resolution,
+}
+
+/// Placeholder values resolved against one values file.
+#[derive(Default)]
+#[doc(hidden)]
+pub struct Resolution {
+    pub env_map: HashMap<String, String>,
+    pub values_map: HashMap<String, String>,
+    pub sources: HashMap<String, ValueSource>,
+    pub missing_env: Vec<String>,
+    pub missing_values: Vec<String>,
+    /// `(key, message)` for values paths that walk through a non-mapping (--strict-unknown-keys).
+    pub misshapen_values: Vec<(String, String)>,
+    /// The effective values document, for --dump-values.
+    pub values: Option<YamlValue>,
+    /// Keys (values-file notation) whose value is a YAML string in the values document.
+    pub string_values: BTreeSet<String>,
+    /// The values-document node of each key taken from the values or secrets file, for
+    /// --typed-values; keys rewritten by --expand-values are dropped.
+    pub typed_values: HashMap<String, YamlValue>,
+}
+
+impl Resolution {
+    pub fn has_missing(&self) -> bool {
+        !self.missing_env.is_empty()
+            || !self.missing_values.is_empty()
+            || !self.misshapen_values.is_empty()
+    }
+
+    /// Keys whose value came from --secrets-file.
+    fn secret_keys(&self) -> BTreeSet<String> {
+        self.sources
+            .iter()
+            .filter(|(_, source)| **source == ValueSource::SecretsFile)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// All unresolved keys in values-file notation, for the trace.
+    pub fn missing_keys(&self) -> Vec<String> {
+        self.missing_env
+            .iter()
+            .map(|v| env_var_values_path(v))
+            .chain(self.missing_values.iter().cloned())
+            .chain(self.misshapen_values.iter().map(|(key, _)| key.clone()))
+            .collect()
+    }
+}
+
+/// Loads the values file (or the --values-base64 document) after --select; the other layers
+/// are merged by `merge_values_layers`. `None` when no placeholder needs it (and not
+/// --dump-values).
+#[doc(hidden)]
+pub fn load_values_layer(
+    values_file: &Path,
+    env_vars: &BTreeSet<String>,
+    values_paths: &BTreeSet<String>,
+    opts: &ResolveOptions,
+) -> Result<Option<YamlValue>> {
+    // Load values YAML:
+    // - required when .Values placeholders exist
+    // - optional (if exists) for env placeholder precedence via environment.<VAR>
+    // - with --values-default or --secrets-file, the file is optional and merged between them
+    let values_file_optional = opts.values_default.is_some() || opts.secrets.is_some();
+    let values_yaml: Option<YamlValue> = if let Some(inline) = opts.values_inline {
+        Some(inline.clone())
+    } else if !values_paths.is_empty() && values_file_optional {
+        Some(load_values_yaml_if_exists(values_file, opts.load)?)
+    } else if !values_paths.is_empty() {
+        load_values_yaml(values_file, opts.load)?
+    } else if !env_vars.is_empty() && opts.tolerant_values {
+        Some(load_values_yaml_tolerant(values_file, opts.load)?)
+    } else if !env_vars.is_empty() || opts.always_load {
+        Some(load_values_yaml_if_exists(values_file, opts.load)?)
+    } else {
+        None
+    };
+    // A missing values file loads as an empty mapping; there is nothing to select from then.
+    let values_yaml = match (values_yaml, opts.select) {
+        (Some(yaml), Some(select)) if !is_empty_mapping(&yaml) => Some(match select {
+            ValuesSelect::Field(field, value) => select_yaml_element(yaml, field, value)
+                .with_context(|| format!("--select in {}", values_file.display()))?,
+            ValuesSelect::Index(index) => select_yaml_index(yaml, *index)
+                .with_context(|| format!("--select-index in {}", values_file.display()))?,
+        }),
+        (values_yaml, _) => values_yaml,
+    };
+    Ok(values_yaml)
+}
+
+/// Layers --values-default below and --values-patch and --secrets-file over `values_yaml`.
+#[doc(hidden)]
+pub fn merge_values_layers(values_yaml: YamlValue, opts: &ResolveOptions) -> YamlValue {
+    let values_yaml = match opts.values_default {
+        Some(defaults) => merge_yaml(defaults.clone(), values_yaml),
+        None => values_yaml,
+    };
+    let values_yaml = match opts.values_patch {
+        Some(patch) => apply_merge_patch(values_yaml, patch.clone()),
+        None => values_yaml,
+    };
+    match opts.secrets {
+        Some(secrets) => merge_yaml(values_yaml, secrets.clone()),
+        None => values_yaml,
+    }
+}
+
+/// The --explain-value report for `key`: each layer (lowest first) that sets or, for
+/// --values-patch, deletes it, then the final value and the layer it came from. Values from
+/// the layer at `secrets_layer` are shown as `<redacted>`.
+#[doc(hidden)]
+pub fn render_value_explanation(
+    key: &str,
+    layers: &[(String, YamlValue)],
+    effective: &YamlValue,
+    key_case: KeyCase,
+    secrets_layer: Option<usize>,
+) -> Result<String> {
+    let key = key.strip_prefix(".Values.").unwrap_or(key);
+    let show = |value: &YamlValue, secret: bool| -> Result<String> {
+        Ok(if secret {
+            TRACE_REDACTED.to_string()
+        } else {
+            format!("{:?}", yaml_value_to_string(value)?)
+        })
+    };
+    let mut out = format!("explain {}:\n", display_values_key(key));
+    let mut winner = None;
+    for (i, (name, layer)) in layers.iter().enumerate() {
+        let secret = secrets_layer == Some(i);
+        match lookup_yaml_path_cased(layer, key, key_case)? {
+            // In a JSON Merge Patch, null removes the key from the layers below.
+            Some(YamlValue::Null) if name.starts_with("--values-patch") => {
+                out.push_str(&format!("  {name}: deleted\n"));
+                winner = None;
+            }
+            Some(value) => {
+                out.push_str(&format!("  {name}: {}\n", show(value, secret)?));
+                winner = Some((name, secret));
+            }
+            None => {}
+        }
+    }
+    match (lookup_yaml_path_cased(effective, key, key_case)?, winner) {
+        (Some(value), Some((name, secret))) => {
+            out.push_str(&format!(
+                "  final: {} (from {name})\n",
+                show(value, secret)?
+            ));
+        }
+        _ => out.push_str("  final: not set\n"),
+    }
+    Ok(out)
+}
+
+/// The `values` source of --resolution-order: environment.<VAR> from the values file,
+/// subject to --conflict-policy when the OS environment sets the variable too.
+/// The env names and values paths that no --override pins, i.e. the ones that still need
+/// values from a file, a prompt or the environment.
+#[doc(hidden)]
+pub fn without_overrides(
+    env_vars: &BTreeSet<String>,
+    values_paths: &BTreeSet<String>,
+    overrides: &HashMap<String, String>,
+) -> (BTreeSet<String>, BTreeSet<String>) {
+    (
+        env_vars
+            .iter()
+            .filter(|name| !overrides.contains_key(&env_var_values_path(name)))
+            .cloned()
+            .collect(),
+        values_paths
+            .iter()
+            .filter(|path| !overrides.contains_key(*path))
+            .cloned()
+            .collect(),
+    )
+}
+
+/// Reads a dotenv file: `KEY=VALUE` lines with an optional `export ` prefix; blank lines
+/// and `#` comments are skipped. Double-quoted values understand `\n`, `\"` and `\\`,
+/// single-quoted values are taken literally, and unquoted values end at ` #`.
+#[doc(hidden)]
+pub fn load_env_file(path: &Path) -> Result<HashMap<String, String>> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read --env-file {}", path.display()))?;
+    parse_env_file(&text).with_context(|| format!("invalid --env-file {}", path.display()))
+}
+
+fn parse_env_file(text: &str) -> Result<HashMap<String, String>> {
+    let name_re = Regex::new(r"^[A-Za-z_][A-Za-z0-9_]*$").expect("valid regex");
+    let mut vars = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let Some((key, raw)) = line.split_once('=') else {
+            bail!("line {}: expected KEY=VALUE", i + 1);
+        };
+        let key = key.trim();
+        if !name_re.is_match(key) {
+            bail!("line {}: invalid variable name '{key}'", i + 1);
+        }
+        let raw = raw.trim_start();
+        let value = if let Some(rest) = raw.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = rest.chars();
+            let mut closed = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => {
+                        closed = true;
+                        break;
+                    }
+                    '\\' => match chars.next() {
+                        Some('n') => value.push('\n'),
+                        Some(other) => value.push(other),
+                        None => break,
+                    },
+                    c => value.push(c),
+                }
+            }
+            let tail = chars.as_str().trim();
+            if !closed || !(tail.is_empty() || tail.starts_with('#')) {
+                bail!(
+                    "line {}: unterminated or malformed quoted value for {key}",
+                    i + 1
+                );
+            }
+            value
+        } else if let Some(rest) = raw.strip_prefix('\'') {
+            let Some((value, tail)) = rest.split_once('\'') else {
+                bail!("line {}: unterminated quoted value for {key}", i + 1);
+            };
+            let tail = tail.trim();
+            if !(tail.is_empty() || tail.starts_with('#')) {
+                bail!(
+                    "line {}: unterminated or malformed quoted value for {key}",
+                    i + 1
+                );
+            }
+            value.to_string()
+        } else {
+            let value = raw.split_once(" #").map_or(raw, |(value, _)| value);
+            value.trim_end().to_string()
+        };
+        vars.insert(key.to_string(), value);
+    }
+    Ok(vars)
+}
+
+fn resolve_env_from_values(
+    v: &str,
+    yaml: &YamlValue,
+    os_val: Option<&String>,
+    opts: &ResolveOptions,
+) -> Result<Option<(String, ValueSource)>> {
+    let path = env_var_values_path(v);
+    let Some(val) = lookup_env_values_key(yaml, v, opts.key_case, opts.env_ignore_case)? else {
+        return Ok(None);
+    };
+    let values_val = yaml_value_to_string(val)?;
+    if opts.conflict_policy == ConflictPolicy::PreferLonger
+        && let Some(env_val) = os_val
+        && let Some(prefer_env) = prefer_longer_multiline(env_val, &values_val)
+    {
+        let winner = if prefer_env { "env" } else { "values file" };
+        warn(
+            opts.quiet,
+            &format!(
+                "env {v} and values file {path} differ; using {winner} value (more non-empty lines)"
+            ),
+        );
+        return Ok(Some(if prefer_env {
+            (env_val.clone(), ValueSource::OsEnv)
+        } else {
+            (values_val, ValueSource::ValuesFile)
+        }));
+    }
+    if opts.verbose
+        && let Some(env_val) = os_val
+        && env_val != &values_val
+    {
+        warn(
+            false,
+            &format!("env {v} differs from values file {path}; using values file value"),
+        );
+    }
+    Ok(Some((values_val, ValueSource::ValuesFile)))
+}
+
+/// How many rounds of --expand-values substitution run before a value that still contains
+/// placeholders is taken to be part of a cycle.
+const MAX_EXPANSION_PASSES: usize = 10;
+
+/// --expand-values: substitutes placeholders inside resolved values, one level per pass, until
+/// none are left. Keys that only values refer to are resolved like template placeholders; if
+/// any of them is missing, expansion stops so the run reports it.
+#[doc(hidden)]
+pub fn expand_nested_values(
+    mut res: Resolution,
+    values_yaml: Option<YamlValue>,
+    re: &Regex,
+    opts: &ResolveOptions,
+) -> Result<Resolution> {
+    let masked = BTreeSet::new();
+    let plain = RenderOptions {
+        verbose: false,
+        value_file_only: opts.value_file_only,
+        indent: false,
+        block_scalar: false,
+        format: TemplateFormat::Yaml,
+        max_file_size: opts.load.max_file_size,
+        masked: &masked,
+        quoted: None,
+        typed: None,
+        folded_policy: FoldedPolicy::Preserve,
+        strict_multiline: false,
+        on_warning: &|message| warn(true, message),
+        diff: false,
+    };
+    for _ in 0..MAX_EXPANSION_PASSES {
+        let nested = |map: &HashMap<String, String>| -> Vec<String> {
+            map.iter()
+                .filter(|(_, value)| placeholder_captures(re, value).next().is_some())
+                .map(|(key, _)| key.clone())
+                .collect()
+        };
+        let (nested_env, nested_values) = (nested(&res.env_map), nested(&res.values_map));
+        if nested_env.is_empty() && nested_values.is_empty() {
+            return Ok(res);
+        }
+
+        let mut env_vars = BTreeSet::new();
+        let mut values_paths = BTreeSet::new();
+        for value in res.env_map.values().chain(res.values_map.values()) {
+            let (env, paths) = collect_placeholders(value, re);
+            env_vars.extend(env.into_iter().filter(|v| !res.env_map.contains_key(v)));
+            values_paths.extend(
+                paths
+                    .into_iter()
+                    .filter(|p| !res.values_map.contains_key(p)),
+            );
+        }
+        if !env_vars.is_empty() || !values_paths.is_empty() {
+            let inner = resolve_placeholders(values_yaml.clone(), &env_vars, &values_paths, opts)?;
+            res.env_map.extend(inner.env_map);
+            res.values_map.extend(inner.values_map);
+            res.sources.extend(inner.sources);
+            res.missing_env.extend(inner.missing_env);
+            res.missing_values.extend(inner.missing_values);
+            res.misshapen_values.extend(inner.misshapen_values);
+            if res.has_missing() {
+                return Ok(res);
+            }
+        }
+
+        let (env_map, values_map) = (res.env_map.clone(), res.values_map.clone());
+        let expand = |key: &str, value: &str| -> Result<String> {
+            let template = [(PathBuf::from(format!("<{key}>")), value.to_string())];
+            let mut out = render_templates(&template, re, &values_map, &env_map, &plain)
+                .with_context(|| format!("expanding the value of {}", display_values_key(key)))?;
+            Ok(out.pop().map(|(_, text)| text).unwrap_or_default())
+        };
+        for name in nested_env {
+            let value = expand(&env_var_values_path(&name), &res.env_map[&name])?;
+            res.typed_values.remove(&env_var_values_path(&name));
+            res.env_map.insert(name, value);
+        }
+        for path in nested_values {
+            let value = expand(&path, &res.values_map[&path])?;
+            res.typed_values.remove(&path);
+            res.values_map.insert(path, value);
+        }
+    }
+
+    let mut unexpanded: Vec<String> = res
+        .env_map
+        .iter()
+        .filter(|(_, value)| placeholder_captures(re, value).next().is_some())
+        .map(|(name, _)| display_values_key(&env_var_values_path(name)))
+        .chain(
+            res.values_map
+                .iter()
+                .filter(|(_, value)| placeholder_captures(re, value).next().is_some())
+                .map(|(path, _)| display_values_key(path)),
+        )
+        .collect();
+    unexpanded.sort();
+    bail!(
+        "values still contain placeholders after {MAX_EXPANSION_PASSES} expansion passes (a reference cycle?): {}",
+        unexpanded.join(", ")
+    );
+}
+
+#[doc(hidden)]
+pub fn resolve_placeholders(
+    values_yaml: Option<YamlValue>,
+    env_vars: &BTreeSet<String>,
+    values_paths: &BTreeSet<String>,
+    opts: &ResolveOptions,
+) -> Result<Resolution> {
+    // Resolve placeholders
+    let mut res = Resolution::default();
+
+    // --override wins over every source; the loops below only see the other keys.
+    for name in env_vars {
+        let key = env_var_values_path(name);
+        if let Some(value) = opts.overrides.get(&key) {
+            res.env_map.insert(name.clone(), value.clone());
+            res.sources.insert(key, ValueSource::Override);
+        }
+    }
+    for path in values_paths {
+        if let Some(value) = opts.overrides.get(path) {
+            res.values_map.insert(path.clone(), value.clone());
+            res.sources.insert(path.clone(), ValueSource::Override);
+        }
+    }
+    if opts.verbose {
+        let mut keys: Vec<&String> = res.sources.keys().collect();
+        keys.sort();
+        for key in keys {
+            eprintln!(
+                "override {} = {}",
+                display_values_key(key),
+                opts.overrides[key]
+            );
+        }
+    }
+    let (env_vars, values_paths) = &without_overrides(env_vars, values_paths, opts.overrides);
+    if opts.value_file_only {
+        if !env_vars.is_empty() {
+            let yaml = values_yaml
+                .as_ref()
+                .expect("values_yaml must be loaded in --value-file-only mode");
+            let (resolved, missing_paths) =
+                resolve_env_from_values_file(env_vars, yaml, opts.key_case, opts.env_ignore_case)?;
+            if opts.verbose {
+                for (name, val) in &resolved {
+                    if let Some(os) = os_env_var(name, opts.os_env_names) {
+                        let env_val = os.to_string_lossy().to_string();
+                        if env_val != *val {
+                            warn(
+                                false,
+                                &format!(
+                                    "env {name} differs from values file environment.{name}; using values file value"
+                                ),
+                            );
+                        }
+                    }
+                }
+            }
+            for name in resolved.keys() {
+                res.sources
+                    .insert(env_var_values_path(name), ValueSource::ValuesFile);
+            }
+            res.env_map = resolved;
+
+            // Treat missing env substitutions as missing values file keys.
+            for p in missing_paths {
+                if let Some(default) = annotation_default(opts.annotations, &p) {
+                    if let Some(name) = p.strip_prefix("environment.") {
+                        res.env_map.insert(name.to_string(), default);
+                    }
+                    res.sources.insert(p, ValueSource::Annotation);
+                    continue;
+                }
+                res.missing_values.push(p);
+            }
+        }
+    } else {
+        // The OS value only competes with the values file if os-env is consulted at all.
+        let os_env_consulted = opts.resolution_order.contains(&EnvSource::OsEnv);
+        for v in env_vars {
+            let mut os_val =
+                os_env_var(v, opts.os_env_names).map(|os| os.to_string_lossy().to_string());
+            if opts.deny_os_env && os_val.take().is_some() && opts.verbose {
+                eprintln!("ignoring OS env {v} (--deny-os-env-leak)");
+            }
+            let source_key = env_var_values_path(v);
+
+            let mut resolved = None;
+            for source in opts.resolution_order {
+                resolved = match source {
+                    EnvSource::Values => match values_yaml.as_ref() {
+                        Some(yaml) => resolve_env_from_values(
+                            v,
+                            yaml,
+                            os_val.as_ref().filter(|_| os_env_consulted),
+                            opts,
+                        )?,
+                        None => None,
+                    },
+                    EnvSource::Prompt => opts
+                        .prompted_env_map
+                        .get(v)
+                        .map(|val| (val.clone(), ValueSource::Prompt)),
+                    EnvSource::EnvFile => opts
+                        .env_file
+                        .get(v)
+                        .map(|val| (val.clone(), ValueSource::EnvFile)),
+                    EnvSource::OsEnv => os_val.clone().map(|val| (val, ValueSource::OsEnv)),
+                    EnvSource::Default => annotation_default(opts.annotations, &source_key)
+                        .map(|val| (val, ValueSource::Annotation)),
+                };
+                if resolved.is_some() {
+                    break;
+                }
+            }
+            match resolved {
+                Some((val, source)) => {
+                    res.env_map.insert(v.clone(), val);
+                    res.sources.insert(source_key, source);
+                }
+                None => res.missing_env.push(v.clone()),
+            }
+        }
+    }
+
+    // Resolve values paths
+    for p in values_paths {
+        let yaml = values_yaml
+            .as_ref()
+            .expect("values_yaml must be loaded if values_paths is non-empty");
+        match lookup_yaml_path_cased(yaml, p, opts.key_case)? {
+            Some(v) => {
+                let s = yaml_value_to_string(v)?;
+                res.values_map.insert(p.clone(), s);
+                res.sources.insert(p.clone(), ValueSource::ValuesFile);
+            }
+            None => match annotation_default(opts.annotations, p) {
+                Some(default) => {
+                    res.values_map.insert(p.clone(), default);
+                    res.sources.insert(p.clone(), ValueSource::Annotation);
+                }
+                None if opts.optional_keys.iter().any(|re| re.is_match(p)) => {
+                    if opts.verbose {
+                        eprintln!("optional .Values.{p} is missing; rendering it empty");
+                    }
+                    res.values_map.insert(p.clone(), String::new());
+                    res.sources.insert(p.clone(), ValueSource::Optional);
+                }
+                None => res.missing_values.push(p.clone()),
+            },
+        }
+    }
+
+    // `# @tplenv required` placeholders must not resolve to an empty value.
+    for (key, annotation) in opts.annotations {
+        if !annotation.required {
+            continue;
+        }
+        let resolved = match key.strip_prefix("environment.") {
+            Some(name) if env_vars.contains(name) => res.env_map.get(name),
+            _ => res.values_map.get(key),
+        };
+        if resolved.is_some_and(|v| v.is_empty()) {
+            if key.starts_with("environment.") && !opts.value_file_only {
+                res.missing_env
+                    .push(key.trim_start_matches("environment.").to_string());
+            } else {
+                res.missing_values.push(key.clone());
+            }
+        }
+    }
+
+    // With --strict-unknown-keys, values paths that walk through a scalar or sequence are
+    // reported with the exact place the walk failed instead of as plain missing keys.
+    if opts.strict_unknown_keys
+        && let Some(yaml) = values_yaml.as_ref()
+    {
+        let misshapen = &mut res.misshapen_values;
+        res.missing_values
+            .retain(|p| match lookup_yaml_path_detailed(yaml, p) {
+                Err(
+                    e @ (YamlLookupError::NotMapping { .. } | YamlLookupError::NotSequence { .. }),
+                ) => {
+                    misshapen.push((p.clone(), e.to_string()));
+                    false
+                }
+                _ => true,
+            });
+        // A `[field=value]` selector matching several list elements is an error here.
+        for p in values_paths.iter().filter(|p| p.contains('[')) {
+            if let Err(e @ YamlLookupError::Ambiguous { .. }) = walk_yaml_path(yaml, p, true) {
+                misshapen.push((p.clone(), e.to_string()));
+            }
+        }
+    }
+
+    if let Some(secrets) = opts.secrets {
+        for (key, source) in res.sources.iter_mut() {
+            if *source == ValueSource::ValuesFile
+                && lookup_yaml_path_cased(secrets, key, opts.key_case)?.is_some()
+            {
+                *source = ValueSource::SecretsFile;
+            }
+        }
+    }
+
+    if let Some(yaml) = values_yaml.as_ref() {
+        for (key, source) in &res.sources {
+            if !matches!(source, ValueSource::ValuesFile | ValueSource::SecretsFile) {
+                continue;
+            }
+            if let Some(value) = lookup_yaml_path_cased(yaml, key, opts.key_case)? {
+                if value.is_string() {
+                    res.string_values.insert(key.clone());
+                }
+                res.typed_values.insert(key.clone(), value.clone());
+            }
+        }
+    }
+
+    res.values = values_yaml;
+    Ok(res)
+}
+
+/// --require-key entries that are absent (or, unless `allow_empty`, null or empty).
+#[doc(hidden)]
+pub fn missing_required_keys(
+    values: &YamlValue,
+    keys: &[String],
+    key_case: KeyCase,
+    allow_empty: bool,
+) -> Result<Vec<String>> {
+    let mut missing = Vec::new();
+    for key in keys {
+        let present = match lookup_yaml_path_cased(values, key, key_case)? {
+            None => false,
+            Some(_) if allow_empty => true,
+            Some(YamlValue::Null) => false,
+            Some(YamlValue::String(s)) => !s.is_empty(),
+            Some(YamlValue::Sequence(items)) => !items.is_empty(),
+            Some(YamlValue::Mapping(map)) => !map.is_empty(),
+            Some(_) => true,
+        };
+        if !present {
+            missing.push(key.clone());
+        }
+    }
+    Ok(missing)
+}
+
+/// `| required "message"` filters by key, so missing keys can fail with the template's message.
+#[doc(hidden)]
+pub fn collect_required_messages(
+    templates: &[(PathBuf, String)],
+    re: &Regex,
+) -> Result<HashMap<String, String>> {
+    let mut out = HashMap::new();
+    for (_, input) in templates {
+        for cap in placeholder_captures(re, input) {
+            let (Some(key), Some(pipeline)) = (placeholder_key(&cap), extract_pipeline(&cap))
+            else {
+                continue;
+            };
+            for filter in parse_pipeline(pipeline)? {
+                if filter.name == "required" {
+                    out.entry(key.clone())
+                        .or_insert(filter_message_arg(&filter)?.to_string());
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// The values-file key of a `.Values` or env placeholder (`environment.<VAR>` for env).
+fn placeholder_key(caps: &regex::Captures) -> Option<String> {
+    if let Some(p) = extract_values_path(caps) {
+        Some(p.into_owned())
+    } else {
+        extract_env_key(caps).map(env_var_values_path)
+    }
+}
+
+/// A values path as the templates spell it: with `--path-sep /`, `a\.b.c` is `a.b/c`.
+/// Lookups use the escaped dotted form; messages use this one.
+#[doc(hidden)]
+pub fn values_key_as_written(key: &str, path_sep: char) -> String {
+    if path_sep == '.' || key.starts_with("environment.") {
+        return key.to_string();
+    }
+    split_yaml_path(key).join(&path_sep.to_string())
+}
+
+#[doc(hidden)]
+pub fn display_values_key(key: &str) -> String {
+    if key.starts_with("environment.") {
+        key.to_string()
+    } else {
+        format!(".Values.{key}")
+    }
+}
+
+/// The --compare-values report: one line per referenced key whose value differs between the
+/// two documents or that only one of them sets.
+#[doc(hidden)]
+pub fn render_values_comparison(
+    keys: &BTreeSet<String>,
+    (path_a, a): (&Path, &YamlValue),
+    (path_b, b): (&Path, &YamlValue),
+    key_case: KeyCase,
+    redact: bool,
+) -> Result<String> {
+    let show = |value: &str| {
+        if redact {
+            TRACE_REDACTED.to_string()
+        } else {
+            format!("{value:?}")
+        }
+    };
+    let lookup = |doc: &YamlValue, key: &str| -> Result<Option<String>> {
+        lookup_yaml_path_cased(doc, key, key_case)?
+            .map(yaml_value_to_string)
+            .transpose()
+    };
+    let mut out = String::new();
+    for key in keys {
+        let shown = display_values_key(key);
+        match (lookup(a, key)?, lookup(b, key)?) {
+            (Some(va), Some(vb)) if va != vb => {
+                out.push_str(&format!(
+                    "changed {shown}: {} -> {}\n",
+                    show(&va),
+                    show(&vb)
+                ));
+            }
+            (Some(va), None) => out.push_str(&format!(
+                "missing {shown} in {} ({}: {})\n",
+                path_b.display(),
+                path_a.display(),
+                show(&va)
+            )),
+            (None, Some(vb)) => out.push_str(&format!(
+                "missing {shown} in {} ({}: {})\n",
+                path_a.display(),
+                path_b.display(),
+                show(&vb)
+            )),
+            _ => {}
+        }
+    }
+    if out.is_empty() {
+        out.push_str(&format!(
+            "no differences in {} referenced key(s)\n",
+            keys.len()
+        ));
+    }
+    Ok(out)
+}
+
+/// The --dump-values text: one YAML document per values file, with --secrets-file leaves masked.
+#[doc(hidden)]
+pub fn render_values_dump(
+    resolutions: &[ValuesGroup],
+    secrets: Option<&YamlValue>,
+) -> Result<String> {
+    let mut out = String::new();
+    for (i, group) in resolutions.iter().enumerate() {
+        let mut values = group
+            .resolution
+            .values
+            .clone()
+            .unwrap_or_else(|| YamlValue::Mapping(YamlMapping::new()));
+        if let Some(secrets) = secrets {
+            mask_yaml_leaves(&mut values, secrets);
+        }
+        if resolutions.len() > 1 {
+            if i > 0 {
+                out.push_str("---\n");
+            }
+            out.push_str(&format!("# values file: {}\n", group.values_file.display()));
+        }
+        out.push_str(&serde_yaml::to_string(&values)?);
+    }
+    Ok(out)
+}
+
+/// The --emit-used-values text: per values file, a document rebuilt from the resolved keys
+/// only. Values read from the values file keep their YAML type; --secrets-file values are
+/// masked.
+#[doc(hidden)]
+pub fn render_used_values(resolutions: &[ValuesGroup]) -> Result<String> {
+    let mut out = String::new();
+    for (i, group) in resolutions.iter().enumerate() {
+        let res = &group.resolution;
+        let secret = res.secret_keys();
+        let env_keys = res
+            .env_map
+            .iter()
+            .map(|(name, value)| (env_var_values_path(name), value));
+        let mut keys: Vec<(String, &String)> = res
+            .values_map
+            .iter()
+            .map(|(key, value)| (key.clone(), value))
+            .chain(env_keys)
+            .collect();
+        keys.sort();
+
+        let mut used = YamlValue::Mapping(YamlMapping::new());
+        for (key, resolved) in keys {
+            let typed = match (res.sources.get(&key), res.values.as_ref()) {
+                (Some(ValueSource::ValuesFile), Some(values)) => {
+                    lookup_yaml_path(values, &key).cloned()
+                }
+                _ => None,
+            };
+            let value = if secret.contains(&key) {
+                YamlValue::String(TRACE_REDACTED.to_string())
+            } else {
+                typed.unwrap_or_else(|| YamlValue::String(resolved.clone()))
+            };
+            set_yaml_path(&mut used, &key, value);
+        }
+        if resolutions.len() > 1 {
+            if i > 0 {
+                out.push_str("---\n");
+            }
+            out.push_str(&format!("# values file: {}\n", group.values_file.display()));
+        }
+        out.push_str(&serde_yaml::to_string(&used)?);
+    }
+    Ok(out)
+}
+
+/// The --dry-run report: per values file, every placeholder key in sorted order with the
+/// source it resolved from, or `missing`.
+#[doc(hidden)]
+pub fn render_dry_run_report(resolutions: &[ValuesGroup]) -> String {
+    let mut out = String::new();
+    for group in resolutions {
+        let res = &group.resolution;
+        let mut rows: Vec<(String, String)> = res
+            .sources
+            .iter()
+            .map(|(key, source)| (display_values_key(key), value_source_name(*source)))
+            .chain(
+                res.missing_keys()
+                    .iter()
+                    .map(|key| (display_values_key(key), "missing".to_string())),
+            )
+            .collect();
+        rows.sort();
+        out.push_str(&format!("# values file: {}\n", group.values_file.display()));
+        for (key, source) in rows {
+            out.push_str(&format!("{key}: {source}\n"));
+        }
+    }
+    out
+}
+
+/// The kebab-case name a source has in --trace-file, e.g. `os-env`.
+#[doc(hidden)]
+pub fn value_source_name(source: ValueSource) -> String {
+    serde_yaml::to_value(source)
+        .ok()
+        .and_then(|v| v.as_str().map(str::to_string))
+        .unwrap_or_default()
+}
+
+/// Replaces every leaf of `values` that is also set in `mask` with `<redacted>`.
+fn mask_yaml_leaves(values: &mut YamlValue, mask: &YamlValue) {
+    let (YamlValue::Mapping(values), YamlValue::Mapping(mask)) = (values, mask) else {
+        return;
+    };
+    for (key, mask_value) in mask {
+        let Some(value) = values.get_mut(key) else {
+            continue;
+        };
+        if matches!(mask_value, YamlValue::Mapping(_)) && matches!(value, YamlValue::Mapping(_)) {
+            mask_yaml_leaves(value, mask_value);
+        } else {
+            *value = YamlValue::String(TRACE_REDACTED.to_string());
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn report_missing(values_file: &Path, res: &Resolution, path_sep: char) {
+    if !res.missing_env.is_empty() {
+        eprintln!("Missing/undefined environment variables:");
+        for v in &res.missing_env {
+            eprintln!("- {v}");
+        }
+    }
+    if !res.missing_values.is_empty() {
+        eprintln!("Missing keys in values file ({}):", values_file.display());
+        for p in &res.missing_values {
+            eprintln!(
+                "- {}",
+                display_values_key(&values_key_as_written(p, path_sep))
+            );
+        }
+    }
+    if !res.misshapen_values.is_empty() {
+        eprintln!(
+            "Keys with non-mapping parents in values file ({}):",
+            values_file.display()
+        );
+        for (_, msg) in &res.misshapen_values {
+            eprintln!("- {msg}");
+        }
+    }
+}
+
+/// Parses `--values-by-name 'prod=prod-values.yaml,dev=dev-values.yaml'`. Relative files
+/// are resolved against `base_dir`; every mapped local file must exist.
+#[doc(hidden)]
+pub fn parse_values_by_name(
+    spec: &str,
+    base_dir: Option<&Path>,
+) -> Result<HashMap<String, PathBuf>> {
+    let mut mapping: HashMap<String, PathBuf> = HashMap::new();
+    for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let Some((name, file)) = entry.split_once('=') else {
+            bail!("invalid --values-by-name entry '{entry}': expected NAME=FILE");
+        };
+        let (name, file) = (name.trim(), file.trim());
+        if name.is_empty() || file.is_empty() {
+            bail!("invalid --values-by-name entry '{entry}': expected NAME=FILE");
+        }
+        let mut path = PathBuf::from(file);
+        if let Some(base) = base_dir {
+            path = resolve_against_base(&path, base);
+        }
+        if !is_url(&path) && !path.is_file() {
+            bail!(
+                "--values-by-name: values file for '{name}' not found: {}",
+                path.display()
+            );
+        }
+        if let Some(previous) = mapping.insert(name.to_string(), path) {
+            bail!(
+                "--values-by-name: '{name}' is mapped more than once (already {})",
+                previous.display()
+            );
+        }
+    }
+    if mapping.is_empty() {
+        bail!("--values-by-name needs at least one NAME=FILE entry");
+    }
+    Ok(mapping)
+}
+
+/// Groups template indices by the values file they render against, in first-use order.
+/// The name is the first capture of `name_re` on the template's file name; unmatched
+/// or unmapped names fall back to `default_values`.
+#[doc(hidden)]
+pub fn group_templates_by_values(
+    templates: &[(PathBuf, String)],
+    mapping: &HashMap<String, PathBuf>,
+    name_re: &Regex,
+    default_values: &Path,
+) -> Result<Vec<(PathBuf, Vec<usize>)>> {
+    if name_re.captures_len() < 2 {
+        bail!("--values-name-regex needs a capture group selecting the name");
+    }
+    let mut groups: Vec<(PathBuf, Vec<usize>)> = Vec::new();
+    for (i, (path, _)) in templates.iter().enumerate() {
+        let file_name = path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        let values_file = name_re
+            .captures(&file_name)
+            .and_then(|caps| caps.get(1))
+            .and_then(|m| mapping.get(m.as_str()))
+            .map(PathBuf::as_path)
+            .unwrap_or(default_values);
+        match groups.iter_mut().find(|(file, _)| file == values_file) {
+            Some((_, indices)) => indices.push(i),
+            None => groups.push((values_file.to_path_buf(), vec![i])),
+        }
+    }
+    Ok(groups)
+}
+
+/// Resolves a `{{ .Files.Get "path" }}` reference against the template's directory.
+/// The file must exist and may not escape that directory (absolute paths, `..`, symlinks).
+/// `<dir>/<name>.yaml` for --env; the name must be a plain file name.
+#[doc(hidden)]
+pub fn env_values_file(dir: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+        bail!("invalid --env name '{name}': expected a plain name such as prod");
+    }
+    Ok(dir.join(format!("{name}.yaml")))
+}
+
+fn resolve_template_file(template: &Path, rel: &str) -> Result<PathBuf> {
+    let base = template_dir(template);
+    let rel_path = Path::new(rel);
+    if rel_path.is_absolute() {
+        bail!(
+            "{{{{ .Files.Get \"{rel}\" }}}} in {}: path must be relative to the template directory",
+            template.display()
+        );
+    }
+    let file = base.join(rel_path);
+    let canonical = file.canonicalize().with_context(|| {
+        format!(
+            "{{{{ .Files.Get \"{rel}\" }}}} in {}: file not found: {}",
+            template.display(),
+            file.display()
+        )
+    })?;
+    let canonical_base = base
+        .canonicalize()
+        .with_context(|| format!("failed to resolve directory: {}", base.display()))?;
+    if !canonical.starts_with(&canonical_base) {
+        bail!(
+            "{{{{ .Files.Get \"{rel}\" }}}} in {}: path escapes the template directory {}",
+            template.display(),
+            base.display()
+        );
+    }
+    if !canonical.is_file() {
+        bail!(
+            "{{{{ .Files.Get \"{rel}\" }}}} in {}: not a regular file: {}",
+            template.display(),
+            file.display()
+        );
+    }
+    Ok(canonical)
+}
+
+/// Patterns from --allowed-placeholders, split by placeholder kind.
+#[derive(Debug, Default)]
+#[doc(hidden)]
+pub struct PlaceholderAllowlist {
+    env: Vec<Regex>,
+    values: Vec<Regex>,
+}
+
+/// One entry per line: an env name (`NAMESPACE`) or a values path (`.Values.db.host`),
+/// either of which may be a glob (`APP_*`, `.Values.db.*`).
+#[doc(hidden)]
+pub fn load_placeholder_allowlist(path: &Path, max_file_size: u64) -> Result<PlaceholderAllowlist> {
+    ensure_file_size_within(path, max_file_size)?;
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read file: {}", path.display()))?;
+    let mut allowlist = PlaceholderAllowlist::default();
+    for line in text.lines() {
+        let entry = line.split('#').next().unwrap_or_default().trim();
+        if entry.is_empty() {
+            continue;
+        }
+        match entry.strip_prefix(".Values.") {
+            Some(values_path) => allowlist.values.push(glob_regex(values_path)?),
+            None => allowlist.env.push(glob_regex(entry)?),
+        }
+    }
+    Ok(allowlist)
+}
+
+/// Fails before any resolution, listing every placeholder not on the allowlist by file.
+#[doc(hidden)]
+pub fn ensure_placeholders_allowed(
+    templates: &[(PathBuf, String)],
+    re: &Regex,
+    allowlist: &PlaceholderAllowlist,
+) -> Result<()> {
+    let mut offenders = Vec::new();
+    for (path, input) in templates {
+        let (env_vars, values_paths) = collect_placeholders(input, re);
+        for name in env_vars {
+            if !allowlist.env.iter().any(|re| re.is_match(&name)) {
+                offenders.push(format!("{}: {name}", path.display()));
+            }
+        }
+        for values_path in values_paths {
+            if !allowlist.values.iter().any(|re| re.is_match(&values_path)) {
+                offenders.push(format!(
+                    "{}: {}",
+                    path.display(),
+                    display_values_key(&values_path)
+                ));
+            }
+        }
+    }
+    if !offenders.is_empty() {
+        bail!(
+            "placeholders not in --allowed-placeholders:\n- {}",
+            offenders.join("\n- ")
+        );
+    }
+    Ok(())
+}
+
+/// --strict-env-names: every env placeholder name must match `name_re`; offenders are
+/// listed with file and line.
+#[doc(hidden)]
+pub fn ensure_env_names_match(
+    templates: &[(PathBuf, String)],
+    re: &Regex,
+    name_re: &Regex,
+) -> Result<()> {
+    let mut offenders = Vec::new();
+    for (path, input) in templates {
+        for caps in placeholder_captures(re, input) {
+            let Some(name) = extract_env_key(&caps) else {
+                continue;
+            };
+            if !name_re.is_match(name) {
+                let start = caps.get(0).map_or(0, |m| m.start());
+                let line = input[..start].matches('\n').count() + 1;
+                offenders.push(format!("{}:{line}: {name}", path.display()));
+            }
+        }
+    }
+    if !offenders.is_empty() {
+        bail!(
+            "env placeholder names not matching --env-name-pattern (--strict-env-names):\n- {}",
+            offenders.join("\n- ")
+        );
+    }
+    Ok(())
+}
+
+/// --expect-kind: every document of every rendered file must have one of the `kinds`.
+#[doc(hidden)]
+pub fn ensure_expected_kinds(
+    rendered: &[(PathBuf, String)],
+    kinds: &[String],
+    allow_kindless: bool,
+) -> Result<()> {
+    let mut offenders = Vec::new();
+    for (path, text) in rendered {
+        for (i, document) in serde_yaml::Deserializer::from_str(text).enumerate() {
+            let doc = i + 1;
+            let value = YamlValue::deserialize(document).with_context(|| {
+                format!(
+                    "--expect-kind: rendered {} document {doc} is not valid YAML",
+                    path.display()
+                )
+            })?;
+            if value.is_null() {
+                continue;
+            }
+            match value.get("kind") {
+                Some(YamlValue::String(kind)) if kinds.contains(kind) => {}
+                Some(YamlValue::String(kind)) => {
+                    offenders.push(format!("{} document {doc}: kind {kind}", path.display()));
+                }
+                Some(_) => {
+                    offenders.push(format!(
+                        "{} document {doc}: kind is not a string",
+                        path.display()
+                    ));
+                }
+                None if allow_kindless => {}
+                None => offenders.push(format!("{} document {doc}: no kind", path.display())),
+            }
+        }
+    }
+    if !offenders.is_empty() {
+        bail!(
+            "rendered documents with a kind other than {} (--expect-kind):\n- {}",
+            kinds.join(", "),
+            offenders.join("\n- ")
+        );
+    }
+    Ok(())
+}
+
+/// --fail-on-duplicate-keys: no mapping in a rendered document may repeat a key.
+#[doc(hidden)]
+pub fn ensure_no_duplicate_keys(rendered: &[(PathBuf, String)]) -> Result<()> {
+    let mut offenders = Vec::new();
+    for (path, text) in rendered {
+        for dup in find_duplicate_yaml_keys(text) {
+            offenders.push(format!(
+                "{}:{}: {} (document {}, first on line {})",
+                path.display(),
+                dup.line,
+                dup.path,
+                dup.document,
+                dup.first_line
+            ));
+        }
+    }
+    if !offenders.is_empty() {
+        bail!(
+            "duplicate mapping keys in rendered output (--fail-on-duplicate-keys):\n- {}",
+            offenders.join("\n- ")
+        );
+    }
+    Ok(())
+}
+
+/// --validate: every `---` document of every rendered file must parse as YAML.
+#[doc(hidden)]
+pub fn ensure_rendered_yaml_parses(rendered: &[(PathBuf, String)]) -> Result<()> {
+    let mut offenders = Vec::new();
+    for (path, text) in rendered {
+        for (idx, document) in serde_yaml::Deserializer::from_str(text).enumerate() {
+            if let Err(err) = YamlValue::deserialize(document) {
+                offenders.push(format!("{} (document {}): {err}", path.display(), idx + 1));
+                // The parser cannot resync after a syntax error in this file.
+                break;
+            }
+        }
+    }
+    if !offenders.is_empty() {
+        bail!(
+            "rendered output is not valid YAML (--validate):\n- {}",
+            offenders.join("\n- ")
+        );
+    }
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn ensure_no_partial_placeholders(
+    templates: &[(PathBuf, String)],
+    re: &Regex,
+    delims: &Delimiters,
+) -> Result<()> {
+    let mut offenders = Vec::new();
+    for (path, input) in templates {
+        for (line, snippet) in find_partial_placeholders(input, re, delims) {
+            offenders.push(format!("{}:{line}: {snippet}", path.display()));
+        }
+    }
+    if !offenders.is_empty() {
+        bail!(
+            "partially-formed placeholders (--strict-placeholders):\n- {}",
+            offenders.join("\n- ")
+        );
+    }
+    Ok(())
+}
+
+/// Returns (1-based line, text) for each open delimiter (`{{`) with no close delimiter
+/// (`}}`) and each `${` with no closing `}` later on its line. Real placeholders and complete
+/// `{{ ... }}` spans are masked out first, so only the leftovers are reported.
+fn find_partial_placeholders(input: &str, re: &Regex, delims: &Delimiters) -> Vec<(usize, String)> {
+    const SNIPPET_CHARS: usize = 40;
+    let (open, close) = (regex::escape(&delims.open), regex::escape(&delims.close));
+    let spans = Regex::new(&format!("{open}.*?{close}")).expect("escaped delimiters compile");
+    let starts = Regex::new(&format!(r"{open}|\$\{{")).expect("escaped delimiters compile");
+    let mask = |line: &str, re: &Regex| {
+        re.replace_all(line, |caps: &regex::Captures<'_>| " ".repeat(caps[0].len()))
+            .into_owned()
+    };
+    let mut found = Vec::new();
+    for (idx, line) in input.lines().enumerate() {
+        let masked = mask(&mask(line, re), &spans);
+        let unclosed = starts.find_iter(&masked).find(|m| {
+            let after = &masked[m.end()..];
+            if m.as_str() == "${" {
+                !after.contains('}')
+            } else {
+                !after.contains(delims.close.as_str())
+            }
+        });
+        if let Some(m) = unclosed {
+            let snippet: String = line[m.start()..]
+                .trim_end()
+                .chars()
+                .take(SNIPPET_CHARS)
+                .collect();
+            found.push((idx + 1, snippet));
+        }
+    }
+    found
+}
+
+/// Fails before any prompting when a `{{ .Files.Get }}` reference cannot be read.
+#[doc(hidden)]
+pub fn validate_file_references(templates: &[(PathBuf, String)], re: &Regex) -> Result<()> {
+    for (path, input) in templates {
+        for cap in placeholder_captures(re, input) {
+            if let Some(rel) = cap.name("files_get") {
+                resolve_template_file(path, rel.as_str())?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Decides whether the condition text of an `{{ if ... }}` marker holds.
+type ConditionFn<'a> = dyn Fn(&str) -> Result<bool> + 'a;
+
+/// Applies `{{ if .Values.x }}` / `{{ else }}` / `{{ end }}` blocks (nestable). With
+/// `truthy == None`, both branches are kept and only the markers are removed. A marker
+/// alone on its line is removed with that line.
+#[doc(hidden)]
+pub fn apply_conditionals(
+    input: &str,
+    truthy: Option<&ConditionFn>,
+    delims: &Delimiters,
+) -> Result<String> {
+    struct Frame {
+        line: usize,
+        cond: bool,
+        in_else: bool,
+    }
+    let marker = Regex::new(&format!(
+        r"{}-?\s*(?:if\s+(?P<cond>[^{}]*?)|(?P<else>else)|(?P<end>end))\s*-?{}",
+        regex::escape(&delims.open),
+        delims.close_stop(),
+        regex::escape(&delims.close)
+    ))
+    .expect("escaped delimiters compile");
+    let line_of = |pos: usize| input[..pos].matches('\n').count() + 1;
+
+    let mut out = String::with_capacity(input.len());
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut last = 0usize;
+    for caps in marker.captures_iter(input) {
+        let m = caps.get(0).expect("match has group 0");
+        let (mut start, mut end) = (m.start(), m.end());
+        // Drop the whole line when the marker is the only thing on it.
+        let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = input[end..].find('\n').map_or(input.len(), |i| end + i + 1);
+        if input[line_start..start].trim().is_empty()
+            && input[end..line_end].trim().is_empty()
+            && line_start >= last
+        {
+            (start, end) = (line_start, line_end);
+        }
+
+        let active = stack.iter().all(|f| f.cond != f.in_else);
+        if active {
+            out.push_str(&input[last..start]);
+        }
+        last = end;
+
+        let line = line_of(m.start());
+        if let Some(cond) = caps.name("cond") {
+            let cond = match truthy {
+                Some(truthy) if active => {
+                    truthy(cond.as_str()).with_context(|| format!("{{{{ if }}}} at line {line}"))?
+                }
+                Some(_) => false,
+                None => true,
+            };
+            stack.push(Frame {
+                line,
+                cond,
+                in_else: false,
+            });
+        } else if caps.name("else").is_some() {
+            let Some(frame) = stack.last_mut() else {
+                bail!("{{{{ else }}}} at line {line} without {{{{ if }}}}");
+            };
+            if frame.in_else {
+                bail!(
+                    "second {{{{ else }}}} at line {line} for {{{{ if }}}} at line {}",
+                    frame.line
+                );
+            }
+            frame.in_else = true;
+            if truthy.is_none() {
+                // Keep both branches.
+                frame.cond = false;
+            }
+        } else if stack.pop().is_none() {
+            bail!("{{{{ end }}}} at line {line} without {{{{ if }}}}");
+        }
+    }
+    if let Some(frame) = stack.last() {
+        bail!(
+            "{{{{ if }}}} at line {} is never closed with {{{{ end }}}}",
+            frame.line
+        );
+    }
+    out.push_str(&input[last..]);
+    Ok(out)
+}
+
+/// The values path an `{{ if ... }}` condition tests, if it is a `.Values` path.
+fn condition_values_path(cond: &str, re: &Regex, delims: &Delimiters) -> Option<String> {
+    let placeholder = format!("{} {} {}", delims.open, cond.trim(), delims.close);
+    re.captures(&placeholder)
+        .filter(|caps| caps.get(0).is_some_and(|m| m.as_str() == placeholder))
+        .and_then(|caps| extract_values_path(&caps).map(|p| p.into_owned()))
+}
+
+/// Values paths tested by the `{{ if ... }}` markers of a template. Conditions nested in an
+/// `{{ else }}` branch are not visited.
+#[doc(hidden)]
+pub fn condition_values_paths(input: &str, re: &Regex, delims: &Delimiters) -> BTreeSet<String> {
+    let paths = std::cell::RefCell::new(BTreeSet::new());
+    let record = |cond: &str| {
+        if let Some(path) = condition_values_path(cond, re, delims) {
+            paths.borrow_mut().insert(path);
+        }
+        Ok(true)
+    };
+    // Unbalanced markers are reported when the template is rendered.
+    let _ = apply_conditionals(input, Some(&record), delims);
+    paths.into_inner()
+}
+
+/// Leaf keys of a values file (dotted, below the root mapping) that none of `used` reaches.
+/// A used path covers everything below it, and a leaf covers paths that index into it; the
+/// `environment` section is left out since env placeholders are checked separately, and so
+/// are the `_meta` prompt rules.
+#[doc(hidden)]
+pub fn unused_values_keys(
+    values: &YamlValue,
+    used: &BTreeSet<String>,
+    key_case: KeyCase,
+) -> Vec<String> {
+    fn leaves(value: &YamlValue, prefix: &mut Vec<String>, out: &mut Vec<Vec<String>>) {
+        match value {
+            YamlValue::Mapping(map) if !map.is_empty() => {
+                for (key, child) in map {
+                    let Some(key) = key.as_str() else {
+                        continue;
+                    };
+                    prefix.push(key.to_string());
+                    leaves(child, prefix, out);
+                    prefix.pop();
+                }
+            }
+            _ if !prefix.is_empty() => out.push(prefix.clone()),
+            _ => {}
+        }
+    }
+    let used: Vec<Vec<String>> = used.iter().map(|path| split_yaml_path(path)).collect();
+    let mut all = Vec::new();
+    leaves(values, &mut Vec::new(), &mut all);
+    all.into_iter()
+        .filter(|leaf| leaf[0] != "environment" && leaf[0] != META_SECTION)
+        .filter(|leaf| {
+            !used.iter().any(|path| {
+                path.iter().zip(leaf).all(|(segment, key)| {
+                    key_matches(
+                        parse_path_selector(key).0,
+                        parse_path_selector(segment).0,
+                        key_case,
+                    )
+                })
+            })
+        })
+        .map(|leaf| join_yaml_path(&leaf))
+        .collect()
+}
+
+/// Truthiness for `{{ if .Values.x }}`: missing, null, false, 0, "" and empty
+/// mappings/lists are false; everything else (including the string "false") is true.
+#[doc(hidden)]
+pub fn condition_is_truthy(
+    cond: &str,
+    values: &YamlValue,
+    re: &Regex,
+    key_case: KeyCase,
+    delims: &Delimiters,
+) -> Result<bool> {
+    let Some(path) = condition_values_path(cond, re, delims) else {
+        bail!("condition must be a .Values path, got '{}'", cond.trim());
+    };
+    Ok(match lookup_yaml_path_cased(values, &path, key_case)? {
+        None | Some(YamlValue::Null) => false,
+        Some(YamlValue::Bool(b)) => *b,
+        Some(YamlValue::Number(n)) => n.as_f64() != Some(0.0),
+        Some(YamlValue::String(s)) => !s.is_empty(),
+        Some(YamlValue::Sequence(items)) => !items.is_empty(),
+        Some(YamlValue::Mapping(map)) => !map.is_empty(),
+        Some(YamlValue::Tagged(_)) => true,
+    })
+}
+
+/// How [`render`] formats substituted values.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// Like `--indent`: indent multi-line values to the placeholder's column.
+    pub indent: bool,
+    /// Like `--value-file-only`: env placeholders read only `environment.<NAME>` from the
+    /// values document, never the env map.
+    pub value_file_only: bool,
+    /// Yaml escapes values inside quoted scalars; Json JSON-escapes every value.
+    pub format: TemplateFormat,
+    /// The directory `.Files.Get` paths are relative to, as the template's directory is for
+    /// the CLI. Without one, a `.Files.Get` placeholder is an error.
+    pub files_dir: Option<PathBuf>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            indent: false,
+            value_file_only: false,
+            format: TemplateFormat::Yaml,
+            files_dir: None,
+        }
+    }
+}
+
+/// The name [`render`] gives its template in error messages.
+const LIBRARY_TEMPLATE: &str = "<template>";
+
+/// Renders one template. Env placeholders take `environment.<NAME>` from `values` first,
+/// as the CLI does, then the env map, then an inline or annotation default; `.Values`
+/// placeholders take `values`, then a default. `{{ if }}` blocks, filters and
+/// `.Files.Get` (relative to [`Options::files_dir`]) work as in the CLI. A placeholder
+/// without a value is an error naming every such key.
+pub fn render(
+    template: &str,
+    env: &HashMap<String, String>,
+    values: &YamlValue,
+    opts: &Options,
+) -> Result<String> {
+    let re = placeholder_regex_with_style(PlaceholderStyle::Standard, '.', false)?;
+    let delims = Delimiters::default();
+    let truthy = |cond: &str| condition_is_truthy(cond, values, &re, KeyCase::Exact, &delims);
+    let template_path = match &opts.files_dir {
+        Some(dir) => dir.join(LIBRARY_TEMPLATE),
+        None => PathBuf::from(LIBRARY_TEMPLATE),
+    };
+    let templates = vec![(
+        template_path,
+        apply_conditionals(template, Some(&truthy), &delims)?,
+    )];
+    validate_pipelines(&templates, &re)?;
+    if opts.files_dir.is_none()
+        && let Some(rel) = re
+            .captures_iter(&templates[0].1)
+            .find_map(|caps| caps.name("files_get"))
+    {
+        bail!(
+            "{{{{ .Files.Get \"{}\" }}}} in {LIBRARY_TEMPLATE}: set Options::files_dir to read files",
+            rel.as_str()
+        );
+    }
+    let (env_vars, values_paths) = collect_placeholders_all(&templates, &re);
+    ensure_no_meta_placeholders(&values_paths)?;
+    let annotations = collect_annotations(&templates, &re);
+    let lookup = |path: &str| -> Result<Option<String>> {
+        match lookup_yaml_path(values, path) {
+            Some(value) => Ok(Some(yaml_value_to_string(value)?)),
+            None => Ok(annotation_default(&annotations, path)),
+        }
+    };
+
+    let mut missing = Vec::new();
+    let mut env_map = HashMap::new();
+    for var in &env_vars {
+        let path = env_var_values_path(var);
+        let value = match lookup_yaml_path(values, &path) {
+            Some(value) => Some(yaml_value_to_string(value)?),
+            None => env
+                .get(var)
+                .filter(|_| !opts.value_file_only)
+                .cloned()
+                .or_else(|| annotation_default(&annotations, &path)),
+        };
+        match value {
+            Some(value) => {
+                env_map.insert(var.clone(), value);
+            }
+            None => missing.push(path),
+        }
+    }
+    let mut values_map = HashMap::new();
+    for path in &values_paths {
+        match lookup(path)? {
+            Some(value) => {
+                values_map.insert(path.clone(), value);
+            }
+            None => missing.push(path.clone()),
+        }
+    }
+    if !missing.is_empty() {
+        let required = collect_required_messages(&templates, &re)?;
+        let messages: Vec<String> = missing
+            .iter()
+            .map(|key| match required.get(key) {
+                Some(msg) => format!("{msg} ({})", display_values_key(key)),
+                None => display_values_key(key),
+            })
+            .collect();
+        bail!("no value for {}", messages.join(", "));
+    }
+
+    let masked = BTreeSet::new();
+    let render_opts = RenderOptions {
+        verbose: false,
+        value_file_only: opts.value_file_only,
+        indent: opts.indent,
+        block_scalar: true,
+        format: opts.format,
+        max_file_size: u64::MAX,
+        masked: &masked,
+        quoted: None,
+        typed: None,
+        folded_policy: FoldedPolicy::Warn,
+        strict_multiline: false,
+        on_warning: &|_| {},
+        diff: false,
+    };
+    let mut rendered = render_templates(&templates, &re, &values_map, &env_map, &render_opts)?;
+    Ok(rendered.remove(0).1)
+}
+
+#[doc(hidden)]
+pub struct RenderOptions<'a> {
+    pub verbose: bool,
+    pub value_file_only: bool,
+    pub indent: bool,
+    /// With --no-block-scalar, --indent only indents continuation lines.
+    pub block_scalar: bool,
+    /// With json, values are JSON-escaped and --indent is ignored.
+    pub format: TemplateFormat,
+    pub max_file_size: u64,
+    /// Keys (values-file notation) whose values --verbose must not print.
+    pub masked: &'a BTreeSet<String>,
+    /// With --preserve-quotes, keys (values-file notation) whose values are YAML strings.
+    pub quoted: Option<&'a BTreeSet<String>>,
+    /// With --typed-values, the values-document node of each key from the values file.
+    pub typed: Option<&'a HashMap<String, YamlValue>>,
+    /// With --indent, how multi-line values inside `>` block scalars are written.
+    pub folded_policy: FoldedPolicy,
+    /// With --strict-multiline, misplaced multi-line values are an error.
+    pub strict_multiline: bool,
+    /// Reports a warning: the CLI counts and prints it, [`render`] drops it.
+    pub on_warning: &'a dyn Fn(&str),
+    /// With --diff, each substitution is shown on stderr, grouped by file.
+    pub diff: bool,
+}
+
+#[doc(hidden)]
+pub fn render_templates(
+    templates: &[(PathBuf, String)],
+    re: &Regex,
+    values_map: &HashMap<String, String>,
+    env_map: &HashMap<String, String>,
+    opts: &RenderOptions,
+) -> Result<Vec<(PathBuf, String)>> {
+    let mut rendered_outputs: Vec<(PathBuf, String)> = Vec::new();
+    let mut misplaced = Vec::new();
+    for (path, input) in templates {
+        let mut rendered = String::with_capacity(input.len());
+        let mut last = 0usize;
+        let mut trim_next = false;
+        let mut substitutions = Vec::new();
+        for caps in re.captures_iter(input) {
+            let Some(m) = caps.get(0) else {
+                continue;
+            };
+            if is_escaped_placeholder(input, m.start()) {
+                // `\${VAR}` renders as `${VAR}`: drop the backslash, keep the placeholder text.
+                let mut between = &input[last..m.start() - 1];
+                if trim_next {
+                    between = trim_start_one_newline(between);
+                }
+                rendered.push_str(between);
+                rendered.push_str(m.as_str());
+                last = m.end();
+                trim_next = false;
+                continue;
+            }
+            log_at(3, || {
+                let line = input[..m.start()].matches('\n').count() + 1;
+                format!("match {}:{line}: {}", path.display(), m.as_str())
+            });
+            // `{{-` / `-}}` trim whitespace (up to one newline) next to the placeholder.
+            let mut between = &input[last..m.start()];
+            if trim_next {
+                between = trim_start_one_newline(between);
+            }
+            if caps.name("trim_left").is_some() {
+                between = trim_end_one_newline(between);
+            }
+            rendered.push_str(between);
+            let mut replacement = render_placeholder(
+                &caps,
+                path,
+                input,
+                values_map,
+                env_map,
+                opts,
+                &mut misplaced,
+            )?;
+            if opts.indent
+                && replacement.contains('\n')
+                && let Some(header) = folded_scalar_header(&rendered)
+            {
+                match opts.folded_policy {
+                    FoldedPolicy::Preserve => replacement = unfold_line_breaks(&replacement),
+                    FoldedPolicy::Literal => rendered.replace_range(header..=header, "|"),
+                    FoldedPolicy::Warn => {
+                        let line = input[..m.start()].matches('\n').count() + 1;
+                        (opts.on_warning)(&format!(
+                            "{}:{line}: multi-line value of {} is inside a folded (>) block scalar, so its line breaks become spaces (see --folded-policy)",
+                            path.display(),
+                            m.as_str()
+                        ));
+                    }
+                }
+            }
+            if opts.diff {
+                let key = extract_values_path(&caps)
+                    .map(Cow::into_owned)
+                    .or_else(|| extract_env_key(&caps).map(env_var_values_path));
+                let shown = match key {
+                    Some(key) => verbose_value(opts, &key, &replacement),
+                    None => &replacement,
+                };
+                substitutions.push(Substitution {
+                    start: m.start(),
+                    end: m.end(),
+                    value: shown.to_string(),
+                });
+            }
+            rendered.push_str(&replacement);
+            last = m.end();
+            trim_next = caps.name("trim_right").is_some();
+        }
+        if !substitutions.is_empty() {
+            eprint!(
+                "{}",
+                render_substitution_diff(path, input, &substitutions, io::stderr().is_terminal())
+            );
+        }
+        let tail = &input[last..];
+        rendered.push_str(if trim_next {
+            trim_start_one_newline(tail)
+        } else {
+            tail
+        });
+        rendered_outputs.push((path.clone(), rendered));
+    }
+    if !misplaced.is_empty() {
+        bail!(
+            "{} multi-line value(s) would produce invalid YAML (--strict-multiline):\n  {}",
+            misplaced.len(),
+            misplaced.join("\n  ")
+        );
+    }
+    Ok(rendered_outputs)
+}
+
+/// One replaced placeholder for --diff: its byte range in the template and the value shown.
+struct Substitution {
+    start: usize,
+    end: usize,
+    value: String,
+}
+
+/// Value lines --diff shows of a multi-line replacement before eliding the rest.
+const DIFF_PREVIEW_LINES: usize = 3;
+
+const ANSI_BOLD: &str = "\x1b[1m";
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_GREEN: &str = "\x1b[32m";
+const ANSI_RESET: &str = "\x1b[0m";
+
+/// --diff report for one template: each substitution's source line with the token highlighted
+/// (or underlined with `^` without color) and the value it was replaced with underneath.
+fn render_substitution_diff(
+    path: &Path,
+    input: &str,
+    substitutions: &[Substitution],
+    color: bool,
+) -> String {
+    let paint = |code: &str, text: &str| {
+        if color {
+            format!("{code}{text}{ANSI_RESET}")
+        } else {
+            text.to_string()
+        }
+    };
+    let lines = line_ranges(input);
+    let last_line = substitutions
+        .last()
+        .and_then(|sub| line_index_for_pos(&lines, sub.start))
+        .unwrap_or(0);
+    let width = (last_line + 1).to_string().len();
+    let gutter = " ".repeat(width);
+
+    let mut out = paint(
+        ANSI_BOLD,
+        &format!(
+            "--- {} ({} substitution(s))",
+            path.display(),
+            substitutions.len()
+        ),
+    );
+    out.push('\n');
+    for sub in substitutions {
+        let idx = line_index_for_pos(&lines, sub.start).unwrap_or(0);
+        let (line_start, line_end) = lines[idx];
+        let line = trim_line_ending(&input[line_start..line_end]);
+        let token_end = sub.end.min(line_start + line.len());
+        let (before, token, after) = (
+            &input[line_start..sub.start],
+            &input[sub.start..token_end],
+            &input[token_end..line_start + line.len()],
+        );
+        out.push_str(&format!(
+            "{:>width$} | {before}{}{after}\n",
+            idx + 1,
+            paint(ANSI_RED, token)
+        ));
+        if !color {
+            let pad: String = before
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            out.push_str(&format!(
+                "{gutter} | {pad}{}\n",
+                "^".repeat(token.chars().count().max(1))
+            ));
+        }
+
+        let value = trim_surrounding_newlines(&sub.value);
+        let value_lines: Vec<&str> = value.lines().collect();
+        if value_lines.is_empty() {
+            out.push_str(&format!("{gutter} = {}\n", paint(ANSI_GREEN, "\"\"")));
+            continue;
+        }
+        for (i, value_line) in value_lines.iter().take(DIFF_PREVIEW_LINES).enumerate() {
+            let marker = if i == 0 { '=' } else { '|' };
+            out.push_str(&format!(
+                "{gutter} {marker} {}\n",
+                paint(ANSI_GREEN, value_line)
+            ));
+        }
+        if value_lines.len() > DIFF_PREVIEW_LINES {
+            out.push_str(&format!(
+                "{gutter} | ... {} more line(s)\n",
+                value_lines.len() - DIFF_PREVIEW_LINES
+            ));
+        }
+    }
+    out
+}
+
+fn verbose_value<'v>(opts: &RenderOptions, key: &str, value: &'v str) -> &'v str {
+    if opts.masked.contains(key) {
+        TRACE_REDACTED
+    } else {
+        value
+    }
+}
+
+fn render_placeholder(
+    caps: &regex::Captures,
+    template: &Path,
+    input: &str,
+    values_map: &HashMap<String, String>,
+    env_map: &HashMap<String, String>,
+    opts: &RenderOptions,
+    misplaced: &mut Vec<String>,
+) -> Result<String> {
+    let mut source_key = None;
+    let raw = if let Some(key) = extract_values_path(caps) {
+        let val = values_map.get(key.as_ref()).cloned().unwrap_or_default();
+        if opts.verbose {
+            eprintln!("set .Values.{key} = {}", verbose_value(opts, &key, &val));
+        }
+        source_key = Some(key.into_owned());
+        val
+    } else if let Some(rel) = caps.name("files_get") {
+        let file = resolve_template_file(template, rel.as_str())?;
+        ensure_file_size_within(&file, opts.max_file_size)?;
+        let val = fs::read_to_string(&file)
+            .with_context(|| format!("failed to read file: {}", file.display()))?;
+        if opts.verbose {
+            eprintln!(
+                "set .Files.Get \"{}\" = {} byte(s)",
+                rel.as_str(),
+                val.len()
+            );
+        }
+        val
+    } else {
+        let key = extract_env_key(caps).unwrap_or("");
+        let val = env_map.get(key).cloned().unwrap_or_default();
+        if opts.verbose {
+            let shown = verbose_value(opts, &env_var_values_path(key), &val);
+            if opts.value_file_only {
+                eprintln!("set environment.{key} = {shown}");
+            } else {
+                eprintln!("set env {key} = {shown}");
+            }
+        }
+        source_key = Some(env_var_values_path(key));
+        val
+    };
+
+    let filters = parse_pipeline(extract_pipeline(caps).unwrap_or_default())?;
+    if raw.is_empty()
+        && let Some(required) = filters.iter().find(|f| f.name == "required")
+    {
+        let key = placeholder_key(caps).unwrap_or_default();
+        bail!(
+            "{} ({})",
+            filter_message_arg(required)?,
+            display_values_key(&key)
+        );
+    }
+    let raw = apply_filters(&raw, &filters)?;
+    if opts.format == TemplateFormat::Json {
+        return Ok(json_escape(&raw));
+    }
+    // Inside a quoted YAML scalar (`key: "prefix-{{ .Values.x }}"`), only the injected
+    // text is escaped for that quoting style; the literal parts stay as written.
+    if has_yaml_extension(template)
+        && let Some(m) = caps.get(0)
+        && let Some(quote) = yaml_quote_context(input, m.start())
+    {
+        return Ok(escape_for_yaml_quote(&raw, quote));
+    }
+    // With --typed-values, a whole value from the values file is written as YAML of its type.
+    if has_yaml_extension(template)
+        && filters.is_empty()
+        && let Some(typed) = opts.typed
+        && let Some(value) = source_key.as_ref().and_then(|key| typed.get(key))
+        && let Some(m) = caps.get(0)
+        && is_whole_yaml_value(input, m.start(), m.end())
+    {
+        return typed_yaml_value(value, input, m.start());
+    }
+    // With --preserve-quotes, a string that would read back as another type is quoted again.
+    if has_yaml_extension(template)
+        && let Some(quoted) = opts.quoted
+        && source_key.is_some_and(|key| quoted.contains(&key))
+        && let Some(m) = caps.get(0)
+        && is_whole_yaml_value(input, m.start(), m.end())
+        && !raw.contains('\n')
+        && !reads_back_as_string(&raw)
+    {
+        return Ok(format!("\"{}\"", json_escape(&raw)));
+    }
+    if opts.strict_multiline
+        && raw.contains('\n')
+        && has_yaml_extension(template)
+        && let Some(m) = caps.get(0)
+        && let Some(problem) = multiline_placement_problem(input, m.start(), m.end())
+    {
+        let line = input[..m.start()].matches('\n').count() + 1;
+        misplaced.push(format!(
+            "{}:{line}: {} {problem}",
+            template.display(),
+            m.as_str()
+        ));
+    }
+
+    // An explicit indent/nindent filter replaces the --indent heuristics.
+    let has_indent_filter = filters
+        .iter()
+        .any(|f| f.name == "indent" || f.name == "nindent");
+    if opts.indent
+        && !has_indent_filter
+        && let Some(m) = caps.get(0)
+    {
+        return Ok(format_replacement_with_indent(
+            &raw,
+            input,
+            m.start(),
+            m.end(),
+            opts.block_scalar,
+        ));
+    }
+    Ok(raw)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum YamlQuote {
+    Double,
+    Single,
+}
+
+/// The quoted YAML scalar that byte `pos` of `input` lies in, judged from the text before
+/// it on its line. Other `{{ ... }}` placeholders on the line are skipped, so quoted
+/// filter arguments do not count as quotes.
+fn yaml_quote_context(input: &str, pos: usize) -> Option<YamlQuote> {
+    let line_start = input[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let line = &input[line_start..pos];
+    let mut quote = None;
+    // The last non-space character outside quotes; a quote only opens a scalar after one of
+    // these (or at the start of the line), so `a: it"s` stays plain.
+    let mut prev = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match quote {
+            None if line[i..].starts_with("{{") => {
+                let end = line[i..].find("}}").map_or(line.len(), |e| i + e + 2);
+                while chars.peek().is_some_and(|&(j, _)| j < end) {
+                    chars.next();
+                }
+                prev = Some('}');
+            }
+            None if c == '#' && prev.is_none_or(|_| line[..i].ends_with([' ', '\t'])) => {
+                return None;
+            }
+            None if (c == '"' || c == '\'') && prev.is_none_or(|p| ":-[{,?".contains(p)) => {
+                quote = Some(if c == '"' {
+                    YamlQuote::Double
+                } else {
+                    YamlQuote::Single
+                });
+            }
+            None if !c.is_whitespace() => prev = Some(c),
+            None => {}
+            Some(YamlQuote::Double) if c == '\\' => {
+                chars.next();
+            }
+            Some(YamlQuote::Double) if c == '"' => {
+                quote = None;
+                prev = Some(c);
+            }
+            Some(YamlQuote::Single) if c == '\'' => {
+                if chars.peek().is_some_and(|&(_, n)| n == '\'') {
+                    chars.next();
+                } else {
+                    quote = None;
+                    prev = Some(c);
+                }
+            }
+            Some(_) => {}
+        }
+    }
+    quote
+}
+
+/// Escapes text injected into a quoted YAML scalar. Double-quoted scalars use JSON-style
+/// escapes (valid YAML); single-quoted ones double the quote character.
+fn escape_for_yaml_quote(value: &str, quote: YamlQuote) -> String {
+    match quote {
+        YamlQuote::Double => json_escape(value),
+        YamlQuote::Single => value.replace('\'', "''"),
+    }
+}
+
+/// Whether the placeholder at `start..end` is the whole plain value of a `key:` or `-` line,
+/// optionally followed by a comment.
+fn is_whole_yaml_value(input: &str, start: usize, end: usize) -> bool {
+    let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = input[end..].find('\n').map_or(input.len(), |i| end + i);
+    let prefix = input[line_start..start].trim_end();
+    let suffix = &input[end..line_end];
+    (prefix.ends_with(':') || prefix.ends_with('-'))
+        && (suffix.trim().is_empty()
+            || suffix.starts_with([' ', '\t']) && suffix.trim_start().starts_with('#'))
+}
+
+/// `value` serialized as YAML for the value position at `start` of `input`: scalars stay on
+/// the line (block scalars continue at the line's indentation), and non-empty mappings and
+/// lists start on the next line, nested two spaces deeper than the line.
+fn typed_yaml_value(value: &YamlValue, input: &str, start: usize) -> Result<String> {
+    let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_indent = " ".repeat(leading_spaces(&input[line_start..start]));
+    let text = serde_yaml::to_string(value)?;
+    let text = text.strip_suffix('\n').unwrap_or(&text);
+    let nested = match value {
+        YamlValue::Mapping(map) => !map.is_empty(),
+        YamlValue::Sequence(items) => !items.is_empty(),
+        _ => false,
+    };
+    Ok(if nested {
+        format!("\n{}", indent_every_line(text, &format!("{line_indent}  ")))
+    } else {
+        indent_after_first_line(text, &line_indent)
+    })
+}
+
+/// Whether `value`, written as a plain YAML scalar, parses back as the same string. YAML 1.1
+/// readers are taken into account too, so `01234`, `1_000` and `yes` count as non-strings.
+fn reads_back_as_string(value: &str) -> bool {
+    const YAML11_BOOLS: [&str; 6] = ["y", "n", "yes", "no", "on", "off"];
+    serde_yaml::from_str::<YamlValue>(value).is_ok_and(|v| v.as_str() == Some(value))
+        && !YAML11_BOOLS.contains(&value.to_ascii_lowercase().as_str())
+        && value.replace('_', "").parse::<f64>().is_err()
+}
+
+/// Whether a template is YAML by its name; a template read from stdin counts as YAML.
+fn has_yaml_extension(path: &Path) -> bool {
+    is_stdin_template(path)
+        || path.as_os_str() == LIBRARY_TEMPLATE
+        || path
+            .extension()
+            .is_some_and(|ext| ext == "yaml" || ext == "yml")
+}
+
+/// Escapes a value for use inside a JSON string literal (without the quotes).
+fn json_escape(value: &str) -> String {
+    let quoted = serde_json::Value::from(value).to_string();
+    quoted[1..quoted.len() - 1].to_string()
+}
+
+fn trim_start_one_newline(s: &str) -> &str {
+    let s = s.trim_start_matches([' ', '\t']);
+    let s = s
+        .strip_prefix("\r\n")
+        .or_else(|| s.strip_prefix('\n'))
+        .unwrap_or(s);
+    s.trim_start_matches([' ', '\t'])
+}
+
+fn trim_end_one_newline(s: &str) -> &str {
+    let s = s.trim_end_matches([' ', '\t']);
+    let s = s
+        .strip_suffix("\r\n")
+        .or_else(|| s.strip_suffix('\n'))
+        .unwrap_or(s);
+    s.trim_end_matches([' ', '\t'])
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum LintSeverity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, PartialEq)]
+struct LintFinding {
+    line: usize,
+    severity: LintSeverity,
+    message: String,
+}
+
+/// Names that Helm-style templates use as built-in objects; as env placeholders they are
+/// almost always a typo for a `.Values`-style reference.
+const RESERVED_ENV_NAMES: &[&str] = &["Values", "Files", "Release", "Chart", "Capabilities"];
+
+#[doc(hidden)]
+pub fn run_lint(templates: &[(PathBuf, String)], re: &Regex, delims: &Delimiters) -> Result<()> {
+    let mut errors = 0usize;
+    let mut out = io::stdout().lock();
+    for (path, input) in templates {
+        let findings = lint_template(input, re, is_yaml_file(path), delims);
+        for f in &findings {
+            let label = match f.severity {
+                LintSeverity::Error => {
+                    errors += 1;
+                    "error"
+                }
+                LintSeverity::Warning => {
+                    WARNINGS.set(WARNINGS.get() + 1);
+                    "warning"
+                }
+            };
+            writeln!(out, "{}:{}: {label}: {}", path.display(), f.line, f.message)?;
+        }
+    }
+    if errors > 0 {
+        bail!("lint found {errors} error(s)");
+    }
+    Ok(())
+}
+
+fn lint_template(
+    input: &str,
+    re: &Regex,
+    check_yaml_keys: bool,
+    delims: &Delimiters,
+) -> Vec<LintFinding> {
+    let mut findings = Vec::new();
+    let (open, close) = (delims.open.as_str(), delims.close.as_str());
+    let braces = Regex::new(&format!(
+        "{}(.*?){}",
+        regex::escape(open),
+        regex::escape(close)
+    ))
+    .expect("escaped delimiters compile");
+
+    for (idx, line) in input.lines().enumerate() {
+        let line_no = idx + 1;
+        let mut push = |severity, message: String| {
+            findings.push(LintFinding {
+                line: line_no,
+                severity,
+                message,
+            })
+        };
+
+        let opens = line.matches(open).count();
+        let closes = line.matches(close).count();
+        if opens != closes {
+            push(
+                LintSeverity::Error,
+                format!("unbalanced braces: {opens} '{open}' vs {closes} '{close}'"),
+            );
+        }
+
+        for span in braces.find_iter(line) {
+            let is_placeholder = re
+                .find_at(line, span.start())
+                .is_some_and(|m| m.start() == span.start() && m.end() == span.end());
+            if is_placeholder {
+                continue;
+            }
+            let inner = span.as_str()[open.len()..span.as_str().len() - close.len()].trim();
+            let keyword = inner.trim_matches('-').split_whitespace().next();
+            if matches!(keyword, Some("if" | "else" | "end")) {
+                // Conditional markers are checked by apply_conditionals.
+                continue;
+            }
+            if inner.starts_with(".Values") {
+                push(
+                    LintSeverity::Error,
+                    format!(
+                        "invalid .Values path in {}: expected dot-separated identifiers",
+                        span.as_str()
+                    ),
+                );
+            } else {
+                push(
+                    LintSeverity::Warning,
+                    format!(
+                        "{} is not a tplenv placeholder and is left as-is",
+                        span.as_str()
+                    ),
+                );
+            }
+        }
+
+        for cap in placeholder_captures(re, line) {
+            let Some(m) = cap.get(0) else {
+                continue;
+            };
+            if let Some(name) = extract_env_key(&cap)
+                && RESERVED_ENV_NAMES.contains(&name)
+            {
+                push(
+                    LintSeverity::Warning,
+                    format!("env placeholder {} uses reserved name {name}", m.as_str()),
+                );
+            }
+            if let Some(pipeline) = extract_pipeline(&cap) {
+                let result = parse_pipeline(pipeline)
+                    .and_then(|filters| filters.iter().try_for_each(validate_filter));
+                if let Err(e) = result {
+                    push(LintSeverity::Error, format!("{}: {e:#}", m.as_str()));
+                }
+            }
+            let prefix = line[..m.start()].trim_start();
+            let prefix = prefix.strip_prefix("- ").unwrap_or(prefix).trim();
+            if prefix.is_empty() && line[m.end()..].trim_start().starts_with(':') {
+                push(
+                    LintSeverity::Warning,
+                    format!(
+                        "placeholder {} is in key position; multi-line values will break YAML",
+                        m.as_str()
+                    ),
+                );
+            }
+        }
+    }
+
+    if check_yaml_keys {
+        for dup in find_duplicate_yaml_keys(input) {
+            findings.push(LintFinding {
+                line: dup.line,
+                severity: LintSeverity::Error,
+                message: format!("duplicate key '{}' in the same mapping", dup.key),
+            });
+        }
+        findings.sort_by_key(|f| f.line);
+    }
+    findings
+}
+
+#[derive(Debug, PartialEq)]
+struct DuplicateKey {
+    key: String,
+    /// Dotted key path, with `[n]` for list items, e.g. `spec.containers[0].image`.
+    path: String,
+    document: usize,
+    first_line: usize,
+    line: usize,
+}
+
+/// One block mapping open at `indent` while scanning for duplicate keys.
+struct KeyScope {
+    indent: isize,
+    prefix: String,
+    keys: HashMap<String, usize>,
+    last_key: Option<String>,
+    /// List items seen below `last_key`.
+    items: usize,
+}
+
+impl KeyScope {
+    fn new(indent: isize, prefix: String) -> Self {
+        KeyScope {
+            indent,
+            prefix,
+            keys: HashMap::new(),
+            last_key: None,
+            items: 0,
+        }
+    }
+
+    /// The path of a mapping nested under the last key seen in this one.
+    fn child_prefix(&self) -> String {
+        match (&self.last_key, self.prefix.is_empty()) {
+            (Some(key), true) => key.clone(),
+            (Some(key), false) => format!("{}.{key}", self.prefix),
+            (None, _) => self.prefix.clone(),
+        }
+    }
+}
+
+/// Best-effort, indentation-based scan for repeated keys in one YAML mapping, across the
+/// documents of a stream. Block scalars are skipped; flow mappings (`{a: 1, a: 2}`) are not
+/// inspected.
+fn find_duplicate_yaml_keys(input: &str) -> Vec<DuplicateKey> {
+    let key_re = Regex::new(r#"^("[^"]*"|'[^']*'|[^\s#'"\[\]{}>|-][^:#]*?)\s*:(\s|$)"#)
+        .expect("static regex compiles");
+    let mut duplicates = Vec::new();
+    let mut document = 1;
+    let mut has_content = false;
+    let mut stack = vec![KeyScope::new(-1, String::new())];
+    let mut block_scalar_indent: Option<isize> = None;
+
+    for (idx, raw) in input.lines().enumerate() {
+        let trimmed = raw.trim();
+        let mut indent = leading_spaces(raw) as isize;
+        if let Some(block) = block_scalar_indent {
+            if trimmed.is_empty() || indent > block {
+                continue;
+            }
+            block_scalar_indent = None;
+        }
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if trimmed == "---" || trimmed.starts_with("--- ") {
+            if has_content {
+                document += 1;
+            }
+            has_content = false;
+            stack.truncate(1);
+            stack[0] = KeyScope::new(-1, String::new());
+            continue;
+        }
+        has_content = true;
+
+        let mut content = raw.trim_start_matches(' ');
+        while let Some(rest) = content.strip_prefix("- ") {
+            // A list item starts a fresh mapping at the column after "- ".
+            stack.retain(|scope| scope.indent <= indent);
+            let owner = stack.last_mut().expect("root scope");
+            let prefix = format!("{}[{}]", owner.child_prefix(), owner.items);
+            owner.items += 1;
+            content = rest.trim_start_matches(' ');
+            indent += (2 + rest.len() - content.len()) as isize;
+            stack.push(KeyScope::new(indent, prefix));
+        }
+
+        let Some(cap) = key_re.captures(content) else {
+            continue;
+        };
+        let key = unquote_yaml_key(cap[1].trim()).to_string();
+        stack.retain(|scope| scope.indent <= indent);
+        if stack.last().is_some_and(|scope| scope.indent < indent) {
+            let prefix = stack.last().expect("root scope").child_prefix();
+            stack.push(KeyScope::new(indent, prefix));
+        }
+        let scope = stack.last_mut().expect("root scope");
+        match scope.keys.get(&key) {
+            Some(&first_line) => duplicates.push(DuplicateKey {
+                path: if scope.prefix.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{}.{key}", scope.prefix)
+                },
+                key: key.clone(),
+                document,
+                first_line,
+                line: idx + 1,
+            }),
+            None => {
+                scope.keys.insert(key.clone(), idx + 1);
+            }
+        }
+        scope.last_key = Some(key);
+        scope.items = 0;
+
+        let rest = content[cap.get(0).map(|m| m.end()).unwrap_or(0)..].trim_start();
+        if rest.starts_with('|') || rest.starts_with('>') {
+            block_scalar_indent = Some(indent);
+        }
+    }
+    duplicates
+}
+
+fn unquote_yaml_key(key: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(inner) = key.strip_prefix(quote).and_then(|k| k.strip_suffix(quote)) {
+            return inner;
+        }
+    }
+    key
+}
+
+/// Where a placeholder's value came from, as recorded in `--trace-file`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[doc(hidden)]
+pub enum ValueSource {
+    ValuesFile,
+    SecretsFile,
+    EnvFile,
+    OsEnv,
+    Prompt,
+    Annotation,
+    Override,
+    /// Missing, but matched by --optional-key.
+    Optional,
+}
+
+#[derive(Debug, Default, Serialize)]
+#[doc(hidden)]
+pub struct Trace {
+    pub input_files: Vec<String>,
+    pub placeholders: Vec<TraceFile>,
+    pub resolutions: Vec<TraceResolution>,
+    pub prompts: Vec<TracePrompt>,
+    pub missing: Vec<String>,
+    pub output_bytes: Option<usize>,
+}
+
+/// How many of the most-referenced keys --scan-only reports.
+const SCAN_TOP_KEYS: usize = 10;
+
+/// --scan-only statistics over all input templates.
+#[derive(Debug, Default, Serialize)]
+#[doc(hidden)]
+pub struct ScanReport {
+    files: usize,
+    placeholders: usize,
+    env_vars: usize,
+    values_paths: usize,
+    /// The most-referenced keys, by count and then name.
+    top_keys: Vec<ScanKey>,
+    per_file: Vec<ScanFile>,
+}
+
+#[derive(Debug, Serialize)]
+struct ScanKey {
+    key: String,
+    count: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct ScanFile {
+    file: String,
+    placeholders: usize,
+    env_vars: usize,
+    values_paths: usize,
+}
+
+/// Counts placeholders per file and key. Keys are env names (`NAMESPACE`) and values paths
+/// (`.Values.db.host`); `.Files.Get` placeholders count towards the totals only.
+#[doc(hidden)]
+pub fn scan_placeholders(templates: &[(PathBuf, String)], re: &Regex) -> ScanReport {
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    let mut report = ScanReport {
+        files: templates.len(),
+        ..ScanReport::default()
+    };
+    for (path, input) in templates {
+        let mut occurrences = 0;
+        for caps in placeholder_captures(re, input) {
+            occurrences += 1;
+            let key = if let Some(p) = extract_values_path(&caps) {
+                display_values_key(&p)
+            } else if let Some(name) = extract_env_key(&caps) {
+                name.to_string()
+            } else {
+                continue;
+            };
+            *counts.entry(key).or_default() += 1;
+        }
+        let (env_vars, values_paths) = collect_placeholders(input, re);
+        report.placeholders += occurrences;
+        report.per_file.push(ScanFile {
+            file: path.display().to_string(),
+            placeholders: occurrences,
+            env_vars: env_vars.len(),
+            values_paths: values_paths.len(),
+        });
+    }
+    let (env_vars, values_paths) = collect_placeholders_all(templates, re);
+    report.env_vars = env_vars.len();
+    report.values_paths = values_paths.len();
+    let mut keys: Vec<ScanKey> = counts
+        .into_iter()
+        .map(|(key, count)| ScanKey { key, count })
+        .collect();
+    keys.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.key.cmp(&b.key)));
+    keys.truncate(SCAN_TOP_KEYS);
+    report.top_keys = keys;
+    report
+}
+
+#[doc(hidden)]
+pub fn render_scan_report(report: &ScanReport) -> String {
+    let mut out = format!(
+        "scanned {} file(s): {} placeholder(s), {} unique env var(s), {} unique values path(s)\n",
+        report.files, report.placeholders, report.env_vars, report.values_paths
+    );
+    if !report.top_keys.is_empty() {
+        out.push_str("\nmost referenced:\n");
+        let width = report.top_keys[0].count.to_string().len();
+        for key in &report.top_keys {
+            out.push_str(&format!("  {:>width$}  {}\n", key.count, key.key));
+        }
+    }
+    out.push_str("\nper file:\n");
+    for file in &report.per_file {
+        out.push_str(&format!(
+            "  {}: {} placeholder(s), {} env var(s), {} values path(s)\n",
+            file.file, file.placeholders, file.env_vars, file.values_paths
+        ));
+    }
+    out
+}
+
+#[derive(Debug, Serialize)]
+#[doc(hidden)]
+pub struct TraceFile {
+    file: String,
+    env_vars: Vec<String>,
+    values_paths: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[doc(hidden)]
+pub struct TraceResolution {
+    key: String,
+    source: Option<ValueSource>,
+    value: String,
+}
+
+#[derive(Debug, Serialize)]
+#[doc(hidden)]
+pub struct TracePrompt {
+    key: String,
+    answer: String,
+}
+
+const TRACE_REDACTED: &str = "<redacted>";
+
+impl Trace {
+    pub fn new(templates: &[(PathBuf, String)], re: &Regex) -> Self {
+        let mut trace = Trace::default();
+        for (path, input) in templates {
+            let (env_vars, values_paths) = collect_placeholders(input, re);
+            trace.input_files.push(path.display().to_string());
+            trace.placeholders.push(TraceFile {
+                file: path.display().to_string(),
+                env_vars: env_vars.into_iter().collect(),
+                values_paths: values_paths.into_iter().collect(),
+            });
+        }
+        trace
+    }
+
+    pub fn record_resolution(
+        &mut self,
+        env_map: &HashMap<String, String>,
+        values_map: &HashMap<String, String>,
+        sources: &HashMap<String, ValueSource>,
+        secret_patterns: &[Regex],
+        redact: bool,
+    ) {
+        let mut resolutions: Vec<TraceResolution> = env_map
+            .iter()
+            .map(|(name, value)| (env_var_values_path(name), value))
+            .chain(values_map.iter().map(|(key, value)| (key.clone(), value)))
+            .map(|(key, value)| {
+                let source = sources.get(&key).copied();
+                let secret = source == Some(ValueSource::SecretsFile)
+                    || secret_patterns.iter().any(|p| p.is_match(&key));
+                TraceResolution {
+                    source,
+                    value: trace_value(value, redact || secret),
+                    key,
+                }
+            })
+            .collect();
+        resolutions.sort_by(|a, b| a.key.cmp(&b.key));
+        self.resolutions.extend(resolutions);
+    }
+
+    pub fn record_prompts(
+        &mut self,
+        prompted_values: &[(String, String)],
+        secret_patterns: &[Regex],
+        redact: bool,
+    ) {
+        self.prompts = prompted_values
+            .iter()
+            .map(|(key, answer)| TracePrompt {
+                key: key.clone(),
+                answer: trace_value(
+                    answer,
+                    redact || secret_patterns.iter().any(|p| p.is_match(key)),
+                ),
+            })
+            .collect();
+    }
+}
+
+fn trace_value(value: &str, redact: bool) -> String {
+    if redact {
+        TRACE_REDACTED.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Writes --trace-file owner-only, since it holds resolved values.
+#[doc(hidden)]
+pub fn write_trace(path: &Path, trace: &Trace, format: TraceFormat) -> Result<()> {
+    let text = match format {
+        TraceFormat::Yaml => serde_yaml::to_string(trace)?,
+        TraceFormat::Json => format!("{}\n", yaml_to_json(&serde_yaml::to_value(trace)?)),
+        TraceFormat::Markdown => render_trace_markdown(trace, SystemTime::now())?,
+    };
+    write_private_file(path, text.as_bytes())
+        .with_context(|| format!("failed to write trace file: {}", path.display()))
+}
+
+/// Compact JSON for a YAML document made of plain scalars, sequences and string-keyed maps.
+#[doc(hidden)]
+pub fn yaml_to_json(value: &YamlValue) -> String {
+    json_value(value).to_string()
+}
+
+/// The JSON form of a YAML value: tags are dropped, keys become their string form, and
+/// numbers JSON cannot represent (`.nan`, `.inf`) become null.
+fn json_value(value: &YamlValue) -> serde_json::Value {
+    match value {
+        YamlValue::Null => serde_json::Value::Null,
+        YamlValue::Bool(b) => serde_json::Value::Bool(*b),
+        YamlValue::Number(n) => {
+            if let Some(i) = n.as_i64() {
+                i.into()
+            } else if let Some(u) = n.as_u64() {
+                u.into()
+            } else {
+                n.as_f64()
+                    .and_then(serde_json::Number::from_f64)
+                    .map_or(serde_json::Value::Null, serde_json::Value::Number)
+            }
+        }
+        YamlValue::String(s) => serde_json::Value::String(s.clone()),
+        YamlValue::Sequence(items) => items.iter().map(json_value).collect(),
+        YamlValue::Mapping(map) => map
+            .iter()
+            .map(|(k, v)| (yaml_value_to_string(k).unwrap_or_default(), json_value(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        YamlValue::Tagged(tagged) => json_value(&tagged.value),
+    }
+}
+
+/// A short summary of the trace, meant to be pasted into a pull request.
+fn render_trace_markdown(trace: &Trace, now: SystemTime) -> Result<String> {
+    let mut out = String::from("# tplenv render report\n\n");
+    out.push_str(&format!("- Files: {}\n", trace.input_files.len()));
+    out.push_str(&format!("- Generated: {}\n", format_utc_timestamp(now)));
+    if let Some(bytes) = trace.output_bytes {
+        out.push_str(&format!("- Output: {bytes} bytes\n"));
+    }
+    out.push_str("\n| Placeholder | Source | Value |\n| --- | --- | --- |\n");
+    for r in &trace.resolutions {
+        let source = match r.source {
+            Some(source) => yaml_value_to_string(&serde_yaml::to_value(source)?)?,
+            None => String::new(),
+        };
+        out.push_str(&format!(
+            "| `{}` | {source} | {} |\n",
+            r.key,
+            markdown_cell(&r.value)
+        ));
+    }
+    if !trace.missing.is_empty() {
+        out.push_str("\nMissing:\n\n");
+        for key in &trace.missing {
+            out.push_str(&format!("- `{key}`\n"));
+        }
+    }
+    Ok(out)
+}
+
+/// Escapes a value for a markdown table cell: pipes are escaped, newlines become `<br>`.
+fn markdown_cell(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('|', "\\|")
+        .replace("\r\n", "<br>")
+        .replace('\n', "<br>")
+}
+
+/// `2024-05-01T12:00:00Z` for a point in time (UTC, whole seconds).
+fn format_utc_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
+
+#[doc(hidden)]
+pub fn parse_doc_separator(s: &str) -> Result<String, String> {
+    if s.trim().is_empty() || s.contains(['\n', '\r']) {
+        return Err("expected a non-empty single line".to_string());
+    }
+    Ok(s.to_string())
+}
+
+#[doc(hidden)]
+pub fn parse_export_prefix(s: &str) -> Result<String, String> {
+    let mut chars = s.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err("expected the start of a shell variable name, e.g. TPLENV_".to_string());
+    }
+    Ok(s.to_string())
+}
+
+/// Parses `--override KEY=VALUE` into the key as `sources` records it (`environment.NAME`
+/// for an env name, the dotted path for `.Values.path`) and the value.
+#[doc(hidden)]
+pub fn parse_override(s: &str) -> Result<(String, String), String> {
+    let Some((key, value)) = s.split_once('=') else {
+        return Err("expected KEY=VALUE".to_string());
+    };
+    let key = key.trim();
+    if let Some(path) = key.strip_prefix(".Values.") {
+        if path.is_empty() {
+            return Err("expected a values path after .Values.".to_string());
+        }
+        return Ok((path.to_string(), value.to_string()));
+    }
+    let mut chars = key.chars();
+    let is_env_name = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !is_env_name {
+        return Err(format!(
+            "'{key}' is neither an env name nor a .Values path (e.g. NAMESPACE or .Values.image.tag)"
+        ));
+    }
+    Ok((env_var_values_path(key), value.to_string()))
+}
+
+/// Parses `--set PATH=VALUE`; a leading `.Values.` on the path is accepted.
+#[doc(hidden)]
+pub fn parse_set(s: &str) -> Result<(String, String), String> {
+    let Some((path, value)) = s.split_once('=') else {
+        return Err("expected PATH=VALUE, e.g. image.tag=1.2.3".to_string());
+    };
+    let path = path.trim();
+    let path = path.strip_prefix(".Values.").unwrap_or(path);
+    if path.is_empty() || path.split('.').any(str::is_empty) {
+        return Err(format!("'{path}' is not a dotted values path"));
+    }
+    Ok((path.to_string(), value.to_string()))
+}
+
+/// Expands env placeholders (`$VAR`, `${VAR}`, `{{VAR}}`) in a `--set` value with `lookup`;
+/// `${VAR:-default}` falls back to its default. Other placeholders and escaped ones are
+/// kept as written, and a variable without a value is an error naming it.
+#[doc(hidden)]
+pub fn expand_set_value(
+    path: &str,
+    value: &str,
+    re: &Regex,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String> {
+    let mut out = String::with_capacity(value.len());
+    let mut last = 0;
+    for caps in placeholder_captures(re, value) {
+        let (Some(m), Some(name)) = (caps.get(0), extract_env_key(&caps)) else {
+            continue;
+        };
+        let Some(expanded) =
+            lookup(name).or_else(|| extract_env_default(&caps).map(str::to_string))
+        else {
+            bail!("--set {path}: environment variable {name} is not set");
+        };
+        out.push_str(&value[last..m.start()]);
+        out.push_str(&expanded);
+        last = m.end();
+    }
+    out.push_str(&value[last..]);
+    Ok(out)
+}
+
+/// Turns `--set` pairs into override keys: the values path itself and, for the env
+/// placeholder it maps to (`image.tag` -> IMAGE_TAG, `environment.NS` -> NS),
+/// `environment.NAME`. A path that no template references is an error.
+#[doc(hidden)]
+pub fn set_overrides(
+    sets: &[(String, String)],
+    env_vars: &BTreeSet<String>,
+    values_paths: &BTreeSet<String>,
+) -> Result<Vec<(String, String)>> {
+    let mut out = Vec::new();
+    for (path, value) in sets {
+        let env_name = values_key_to_env_var(path);
+        let before = out.len();
+        if values_paths.contains(path) {
+            out.push((path.clone(), value.clone()));
+        }
+        if env_vars.contains(&env_name) {
+            out.push((env_var_values_path(&env_name), value.clone()));
+        }
+        if out.len() == before {
+            bail!("--set {path}: no template uses .Values.{path} or env placeholder {env_name}");
+        }
+    }
+    Ok(out)
+}
+
+#[doc(hidden)]
+pub fn parse_path_sep(s: &str) -> Result<char, String> {
+    let mut chars = s.chars();
+    let (Some(c), None) = (chars.next(), chars.next()) else {
+        return Err("expected a single character".to_string());
+    };
+    if c.is_alphanumeric() || c.is_whitespace() || "_-{}|\"\\".contains(c) {
+        return Err(format!("'{c}' cannot separate .Values path keys"));
+    }
+    Ok(c)
+}
+
+/// The pair around `{{ ... }}` placeholders and `{{ if }}` markers, set with --delimiters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[doc(hidden)]
+pub struct Delimiters {
+    open: String,
+    close: String,
+}
+
+impl Default for Delimiters {
+    fn default() -> Self {
+        Delimiters {
+            open: "{{".to_string(),
+            close: "}}".to_string(),
+        }
+    }
+}
+
+impl Delimiters {
+    /// The first character of the close delimiter, escaped for use in a character class;
+    /// bare filter arguments and inline defaults stop there.
+    fn close_stop(&self) -> String {
+        regex::escape(&self.close.chars().take(1).collect::<String>())
+    }
+}
+
+#[doc(hidden)]
+pub fn parse_delimiters(s: &str) -> Result<Delimiters, String> {
+    let [open, close] = s.split_whitespace().collect::<Vec<_>>()[..] else {
+        return Err("expected OPEN and CLOSE separated by a space, e.g. '[[ ]]'".to_string());
+    };
+    if open == close {
+        return Err(format!(
+            "open and close delimiters must differ, got '{open}' twice"
+        ));
+    }
+    if open.starts_with('$') {
+        return Err(format!(
+            "'{open}' would clash with $VAR and ${{VAR}} placeholders"
+        ));
+    }
+    Ok(Delimiters {
+        open: open.to_string(),
+        close: close.to_string(),
+    })
+}
+
+fn placeholder_regex_with_style(
+    style: PlaceholderStyle,
+    path_sep: char,
+    bare_values: bool,
+) -> Result<Regex> {
+    placeholder_regex_with_delimiters(style, path_sep, bare_values, &Delimiters::default())
+}
+
+#[doc(hidden)]
+pub fn placeholder_regex_with_delimiters(
+    style: PlaceholderStyle,
+    path_sep: char,
+    bare_values: bool,
+    delims: &Delimiters,
+) -> Result<Regex> {
+    let (open, close, stop) = (
+        regex::escape(&delims.open),
+        regex::escape(&delims.close),
+        delims.close_stop(),
+    );
+    // A filter is a name followed by bare or double-quoted arguments: `| indent 4`.
+    // A bare argument may not be a lone "-", so it cannot swallow the `-}}` trim marker.
+    let filter = format!(
+        r#"[A-Za-z_][A-Za-z0-9_]*(?:\s+(?:"(?:[^"\\]|\\.)*"|[^\s|{stop}"-][^\s|{stop}"]*|-[^\s|{stop}"]+))*"#
+    );
+    // With a custom --path-sep, segments may contain dots (`app.kubernetes.io`) and are captured
+    // as "values_slash" for `/`, or as "values_sep" where segments may also contain slashes.
+    let values = match path_sep {
+        '.' => {
+            // A segment may contain inner dashes (`app-name`) and may select a list element by
+            // field or position: `containers[name=app]`, `hosts[0]`.
+            let segment = r#"[A-Za-z0-9_]+(?:-+[A-Za-z0-9_]+)*(?:\[(?:[A-Za-z0-9_]+=[^\]\s{}|"]+|[0-9]+)\])?"#;
+            format!(r"(?P<values>{segment}(?:\.{segment})*)")
+        }
+        '/' => {
+            let segment = r"[A-Za-z0-9_.]+(?:-+[A-Za-z0-9_.]+)*";
+            format!(r"(?P<values_slash>{segment}(?:/{segment})*)")
+        }
+        _ => {
+            let segment = r"[A-Za-z0-9_./]+(?:-+[A-Za-z0-9_./]+)*";
+            let sep = regex::escape(&path_sep.to_string());
+            format!(r"(?P<values_sep>{segment}(?:{sep}{segment})*)")
+        }
+    };
+    // With --bare-values, only an UPPER_CASE name is an env placeholder; any other bare name
+    // (lowercase, mixed case, dotted or dashed) is tried next as a values path.
+    // An env name may carry a shell-style default: `{{ NAMESPACE:-dev }}`.
+    let env_default = format!(r"(?::-(?P<env_default>[^{stop}|]*?))?");
+    let bare = if bare_values {
+        format!(
+            r"(?P<env>[A-Z_][A-Z0-9_]*){env_default}|{}",
+            values.replace("(?P<values", "(?P<bare_values")
+        )
+    } else {
+        format!(r"(?P<env>[A-Za-z_][A-Za-z0-9_]*){env_default}")
+    };
+    // `{{-` and `-}}` (Go template whitespace trimming) are captured as trim_left/trim_right.
+    let base = format!(
+        r#"{open}(?P<trim_left>-)?\s*(?:\.Values\.{values}|\.Files\.Get\s+"(?P<files_get>[^"]+)"|{bare})(?P<pipe>(?:\s*\|\s*{filter})*)\s*(?P<trim_right>-)?{close}|\$\{{(?P<env_brace>[A-Za-z_][A-Za-z0-9_]*)(?::-(?P<env_brace_default>[^}}]*))?\}}"#
+    );
+    // $ENV{NAME} must be tried before $NAME, otherwise $ENV would match as a short env var.
+    let pattern = match style {
+        PlaceholderStyle::Standard => format!(r"{base}|\$(?P<env_short>[A-Za-z_][A-Za-z0-9_]*)"),
+        PlaceholderStyle::PerlEnv => format!(
+            r"{base}|\$ENV\{{(?P<env_perl>[A-Za-z_][A-Za-z0-9_]*)\}}|\$(?P<env_short>[A-Za-z_][A-Za-z0-9_]*)"
+        ),
+    };
+    Ok(Regex::new(&pattern)?)
+}
+
+/// A placeholder directly preceded by a backslash (`\${VAR}`, `\{{ .Values.x }}`) is
+/// escaped: it renders literally, without the backslash.
+fn is_escaped_placeholder(input: &str, start: usize) -> bool {
+    input[..start].ends_with('\\')
+}
+
+/// Placeholder matches in `input`, without escaped ones.
+#[doc(hidden)]
+pub fn placeholder_captures<'h>(
+    re: &Regex,
+    input: &'h str,
+) -> impl Iterator<Item = regex::Captures<'h>> {
+    re.captures_iter(input).filter(move |caps| {
+        caps.get(0)
+            .is_some_and(|m| !is_escaped_placeholder(input, m.start()))
+    })
+}
+
+fn collect_placeholders(input: &str, re: &Regex) -> (BTreeSet<String>, BTreeSet<String>) {
+    let mut env_vars: BTreeSet<String> = BTreeSet::new();
+    let mut values_paths: BTreeSet<String> = BTreeSet::new();
+
+    for cap in placeholder_captures(re, input) {
+        if let Some(p) = extract_values_path(&cap) {
+            values_paths.insert(p.to_string());
+        } else if let Some(v) = extract_env_key(&cap) {
+            env_vars.insert(v.to_string());
+        }
+    }
+
+    (env_vars, values_paths)
+}
+
+/// The `.Values` path of a placeholder in the internal dotted form. Paths written with a
+/// custom --path-sep are converted: `app.kubernetes.io/name` becomes `app\.kubernetes\.io.name`.
+fn extract_values_path<'a>(caps: &regex::Captures<'a>) -> Option<Cow<'a, str>> {
+    if let Some(m) = caps.name("values").or_else(|| caps.name("bare_values")) {
+        return Some(Cow::Borrowed(m.as_str()));
+    }
+    let split: Vec<&str> = if let Some(m) = caps
+        .name("values_slash")
+        .or_else(|| caps.name("bare_values_slash"))
+    {
+        m.as_str().split('/').collect()
+    } else {
+        // Any character outside the segment class is the separator.
+        let m = caps
+            .name("values_sep")
+            .or_else(|| caps.name("bare_values_sep"))?;
+        m.as_str()
+            .split(|c: char| !(c.is_ascii_alphanumeric() || "_.-/".contains(c)))
+            .collect()
+    };
+    let segments: Vec<String> = split.iter().map(|s| s.to_string()).collect();
+    Some(Cow::Owned(join_yaml_path(&segments)))
+}
+
+/// Splits a dotted values path into keys; `\.` is a literal dot inside a key, and dots
+/// inside a `[field=value]` selector do not split.
+fn split_yaml_path(path: &str) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = path.chars().peekable();
+    let mut in_selector = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if chars.peek() == Some(&'.') => {
+                chars.next();
+                parts.last_mut().expect("parts is never empty").push('.');
+            }
+            '.' if !in_selector => parts.push(String::new()),
+            _ => {
+                in_selector = match c {
+                    '[' => true,
+                    ']' => false,
+                    _ => in_selector,
+                };
+                parts.last_mut().expect("parts is never empty").push(c);
+            }
+        }
+    }
+    parts
+}
+
+/// The list selector of a values path segment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PathSelector<'a> {
+    /// `containers[name=app]`: the first element whose `name` is `app`.
+    Field(&'a str, &'a str),
+    /// `hosts[0]`: the element at a 0-based position.
+    Index(usize),
+}
+
+impl std::fmt::Display for PathSelector<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PathSelector::Field(field, value) => write!(f, "{field}={value}"),
+            PathSelector::Index(index) => write!(f, "{index}"),
+        }
+    }
+}
+
+/// Splits `containers[name=app]` or `hosts[0]` into the key and its list selector.
+fn parse_path_selector(part: &str) -> (&str, Option<PathSelector<'_>>) {
+    let Some((key, sel)) = part
+        .strip_suffix(']')
+        .and_then(|rest| rest.split_once('['))
+        .filter(|(key, _)| !key.is_empty())
+    else {
+        return (part, None);
+    };
+    match sel.split_once('=') {
+        Some((field, value)) if !field.is_empty() => (key, Some(PathSelector::Field(field, value))),
+        None if !sel.is_empty() && sel.bytes().all(|b| b.is_ascii_digit()) => match sel.parse() {
+            Ok(index) => (key, Some(PathSelector::Index(index))),
+            Err(_) => (part, None),
+        },
+        _ => (part, None),
+    }
+}
+
+/// The first element of `items` the selector picks, if any.
+fn select_element<'v>(items: &'v [YamlValue], selector: PathSelector) -> Option<&'v YamlValue> {
+    match selector {
+        PathSelector::Field(field, value) => items
+            .iter()
+            .find(|item| element_has_field(item, field, value)),
+        PathSelector::Index(index) => items.get(index),
+    }
+}
+
+/// Whether a list element is a mapping whose `field` renders as `value`.
+fn element_has_field(item: &YamlValue, field: &str, value: &str) -> bool {
+    item.as_mapping()
+        .and_then(|m| m.get(field))
+        .and_then(|v| yaml_value_to_string(v).ok())
+        .is_some_and(|v| v == value)
+}
+
+fn join_yaml_path(parts: &[String]) -> String {
+    parts
+        .iter()
+        .map(|part| part.replace('.', "\\."))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn extract_env_key<'a>(caps: &'a regex::Captures<'a>) -> Option<&'a str> {
+    caps.name("env")
+        .or_else(|| caps.name("env_brace"))
+        .or_else(|| caps.name("env_perl"))
+        .or_else(|| caps.name("env_short"))
+        .map(|m| m.as_str())
+}
+
+/// The `:-default` of `{{ VAR:-default }}` or `${VAR:-default}`; `Some("")` for `${VAR:-}`.
+fn extract_env_default<'a>(caps: &'a regex::Captures<'a>) -> Option<&'a str> {
+    caps.name("env_default")
+        .map(|m| m.as_str().trim())
+        .or_else(|| caps.name("env_brace_default").map(|m| m.as_str()))
+}
+
+fn extract_pipeline<'a>(caps: &'a regex::Captures<'a>) -> Option<&'a str> {
+    caps.name("pipe")
+        .map(|m| m.as_str())
+        .filter(|p| !p.trim().is_empty())
+}
+
+/// One `| name arg...` step of a `{{ ... }}` placeholder pipeline.
+#[derive(Debug, Clone, PartialEq)]
+struct Filter {
+    name: String,
+    args: Vec<String>,
+}
+
+fn parse_pipeline(text: &str) -> Result<Vec<Filter>> {
+    let mut filters = Vec::new();
+    let mut words: Vec<String> = Vec::new();
+    let mut chars = text.chars().peekable();
+
+    let mut finish = |words: &mut Vec<String>| {
+        if words.is_empty() {
+            return;
+        }
+        let name = words.remove(0);
+        filters.push(Filter {
+            name,
+            args: std::mem::take(words),
+        });
+    };
+
+    while let Some(c) = chars.next() {
+        match c {
+            '|' => finish(&mut words),
+            c if c.is_whitespace() => {}
+            '"' => {
+                let mut word = String::new();
+                let mut closed = false;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '\\' => {
+                            if let Some(escaped) = chars.next() {
+                                word.push(escaped);
+                            }
+                        }
+                        '"' => {
+                            closed = true;
+                            break;
+                        }
+                        _ => word.push(c),
+                    }
+                }
+                if !closed {
+                    bail!("unterminated string in filter pipeline: {}", text.trim());
+                }
+                words.push(word);
+            }
+            _ => {
+                let mut word = String::from(c);
+                while let Some(&next) = chars.peek() {
+                    if next.is_whitespace() || next == '|' {
+                        break;
+                    }
+                    word.push(next);
+                    chars.next();
+                }
+                words.push(word);
+            }
+        }
+    }
+    finish(&mut words);
+    Ok(filters)
+}
+
+fn filter_count_arg(filter: &Filter) -> Result<usize> {
+    match filter.args.as_slice() {
+        [n] => n
+            .parse()
+            .with_context(|| format!("filter {} expects a number, got '{n}'", filter.name)),
+        _ => bail!("filter {} expects exactly one argument", filter.name),
+    }
+}
+
+fn filter_message_arg(filter: &Filter) -> Result<&str> {
+    match filter.args.as_slice() {
+        [msg] => Ok(msg),
+        _ => bail!(
+            "filter {} expects exactly one quoted message argument",
+            filter.name
+        ),
+    }
+}
+
+/// The two arguments of `ternary`, `replace` and `regexReplace`; `example` shows the usage.
+fn filter_two_args<'f>(filter: &'f Filter, example: &str) -> Result<(&'f str, &'f str)> {
+    match filter.args.as_slice() {
+        [first, second] => Ok((first, second)),
+        _ => bail!(
+            "filter {} expects exactly two arguments, e.g. {example}",
+            filter.name
+        ),
+    }
+}
+
+fn filter_ternary_args(filter: &Filter) -> Result<(&str, &str)> {
+    filter_two_args(filter, r#"ternary "yes" "no""#)
+}
+
+/// The compiled pattern and replacement of `regexReplace "pattern" "repl"`.
+fn filter_regex_replace_args(filter: &Filter) -> Result<(Regex, &str)> {
+    let (pattern, replacement) = filter_two_args(filter, r#"regexReplace "^v([0-9.]+)$" "$1""#)?;
+    let re = Regex::new(pattern)
+        .with_context(|| format!("invalid regex \"{pattern}\" in filter {}", filter.name))?;
+    Ok((re, replacement))
+}
+
+/// Truthiness for the `ternary` filter, which only sees the value's text: empty, `false`,
+/// `no` and `0` (in any case, ignoring surrounding whitespace) are false, everything else is
+/// true. A null value resolves to empty text and is false too.
+fn text_is_truthy(value: &str) -> bool {
+    let value = value.trim();
+    !(value.is_empty()
+        || ["false", "no", "0"]
+            .iter()
+            .any(|f| value.eq_ignore_ascii_case(f)))
+}
+
+fn validate_filter(filter: &Filter) -> Result<()> {
+    match filter.name.as_str() {
+        "indent" | "nindent" => filter_count_arg(filter).map(|_| ()),
+        "required" => filter_message_arg(filter).map(|_| ()),
+        "ternary" => filter_ternary_args(filter).map(|_| ()),
+        "replace" => filter_two_args(filter, r#"replace "/" "-""#).map(|_| ()),
+        "regexReplace" => filter_regex_replace_args(filter).map(|_| ()),
+        "sha256" | "sha256sum" | "b64enc" | "b64dec" | "upper" | "lower" | "trim"
+            if filter.args.is_empty() =>
+        {
+            Ok(())
+        }
+        "sha256" | "sha256sum" | "b64enc" | "b64dec" | "upper" | "lower" | "trim" => {
+            bail!("filter {} takes no arguments", filter.name)
+        }
+        other => bail!("unknown filter '{other}'"),
+    }
+}
+
+fn apply_filter(value: &str, filter: &Filter) -> Result<String> {
+    Ok(match filter.name.as_str() {
+        "indent" => indent_after_first_line(value, &" ".repeat(filter_count_arg(filter)?)),
+        "nindent" => format!(
+            "\n{}",
+            indent_every_line(value, &" ".repeat(filter_count_arg(filter)?))
+        ),
+        // A gate, not a transform: checked in render_placeholder, where the key is known.
+        "required" => value.to_string(),
+        "ternary" => {
+            let (when_true, when_false) = filter_ternary_args(filter)?;
+            if text_is_truthy(value) {
+                when_true
+            } else {
+                when_false
+            }
+            .to_string()
+        }
+        "replace" => {
+            let (from, to) = filter_two_args(filter, r#"replace "/" "-""#)?;
+            value.replace(from, to)
+        }
+        "regexReplace" => {
+            let (re, replacement) = filter_regex_replace_args(filter)?;
+            re.replace_all(value, replacement).into_owned()
+        }
+        "sha256" => sha256_hex(value.as_bytes()),
+        "sha256sum" => sha256_hex(canonical_value_text(value).as_bytes()),
+        "b64enc" => base64_encode(value.as_bytes()),
+        "b64dec" => String::from_utf8(
+            base64_decode(value).context("filter b64dec: value is not valid base64")?,
+        )
+        .context("filter b64dec: decoded value is not UTF-8 text")?,
+        "upper" => value.to_uppercase(),
+        "lower" => value.to_lowercase(),
+        "trim" => value.trim().to_string(),
+        other => bail!("unknown filter '{other}'"),
+    })
+}
+
+/// The text `sha256sum` hashes: a mapping or list value (rendered as YAML) becomes compact
+/// JSON with keys sorted at every level, so key order does not change the checksum. Other
+/// values are hashed as they are.
+fn canonical_value_text(value: &str) -> String {
+    match serde_yaml::from_str::<YamlValue>(value) {
+        Ok(yaml @ (YamlValue::Mapping(_) | YamlValue::Sequence(_))) => {
+            yaml_to_json(&sort_yaml_keys(yaml))
+        }
+        _ => value.to_string(),
+    }
+}
+
+fn sort_yaml_keys(value: YamlValue) -> YamlValue {
+    match value {
+        YamlValue::Mapping(map) => {
+            let mut entries: Vec<(String, YamlValue, YamlValue)> = map
+                .into_iter()
+                .map(|(k, v)| (yaml_value_to_string(&k).unwrap_or_default(), k, v))
+                .collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            YamlValue::Mapping(
+                entries
+                    .into_iter()
+                    .map(|(_, k, v)| (k, sort_yaml_keys(v)))
+                    .collect(),
+            )
+        }
+        YamlValue::Sequence(items) => {
+            YamlValue::Sequence(items.into_iter().map(sort_yaml_keys).collect())
+        }
+        other => other,
+    }
+}
+
+fn apply_filters(value: &str, filters: &[Filter]) -> Result<String> {
+    let mut out = value.to_string();
+    for filter in filters {
+        out = apply_filter(&out, filter)?;
+    }
+    Ok(out)
+}
+
+/// Fail before prompting or rendering if any placeholder uses an unknown or malformed filter.
+#[doc(hidden)]
+pub fn validate_pipelines(templates: &[(PathBuf, String)], re: &Regex) -> Result<()> {
+    for (path, input) in templates {
+        for cap in placeholder_captures(re, input) {
+            let Some(pipeline) = extract_pipeline(&cap) else {
+                continue;
+            };
+            let placeholder = cap.get(0).map(|m| m.as_str()).unwrap_or_default();
+            let filters = parse_pipeline(pipeline).with_context(|| {
+                format!("invalid placeholder {placeholder} in {}", path.display())
+            })?;
+            for filter in &filters {
+                validate_filter(filter).with_context(|| {
+                    format!("invalid placeholder {placeholder} in {}", path.display())
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Default for --max-placeholders; far above what hand-written templates use.
+#[doc(hidden)]
+pub const DEFAULT_MAX_PLACEHOLDERS: usize = 1_000_000;
+
+/// Counts placeholder matches across `templates` without collecting them, so a
+/// pathological input fails before any placeholder maps are built.
+#[doc(hidden)]
+pub fn ensure_placeholder_count_within(
+    templates: &[(PathBuf, String)],
+    re: &Regex,
+    max: usize,
+) -> Result<()> {
+    let count: usize = templates
+        .iter()
+        .map(|(_, input)| placeholder_captures(re, input).count())
+        .sum();
+    if count > max {
+        bail!(
+            "{count} placeholders in {} template(s) exceed the limit of {max} (--max-placeholders)",
+            templates.len()
+        );
+    }
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn collect_placeholders_all(
+    templates: &[(PathBuf, String)],
+    re: &Regex,
+) -> (BTreeSet<String>, BTreeSet<String>) {
+    let mut env_vars: BTreeSet<String> = BTreeSet::new();
+    let mut values_paths: BTreeSet<String> = BTreeSet::new();
+
+    for (_, input) in templates {
+        let (env, values) = collect_placeholders(input, re);
+        env_vars.extend(env);
+        values_paths.extend(values);
+    }
+
+    (env_vars, values_paths)
+}
+
+#[doc(hidden)]
+pub struct DiscoveryOptions {
+    pub follow_symlinks: bool,
+    pub verbose: bool,
+}
+
+/// Repeated --file paths are kept in command-line order, unlike --file-pattern matches.
+#[doc(hidden)]
+pub fn discover_input_files(
+    files: &[PathBuf],
+    file_pattern: Option<&str>,
+    opts: &DiscoveryOptions,
+) -> Result<Vec<PathBuf>> {
+    match (files, file_pattern) {
+        ([], Some(pattern)) => find_files_by_pattern(pattern, opts),
+        ([], None) => bail!("one of --file or --file-pattern is required"),
+        (_, Some(_)) => bail!("use only one of --file or --file-pattern"),
+        (files, None) => {
+            if files.iter().filter(|p| p.as_os_str() == "-").count() > 1 {
+                bail!("--file - can only be given once, stdin holds a single template");
+            }
+            Ok(files
+                .iter()
+                .map(|path| match path.as_os_str() == "-" {
+                    true => PathBuf::from(STDIN_TEMPLATE),
+                    false => path.clone(),
+                })
+                .collect())
+        }
+    }
+}
+
+/// The label `--file -` gives the template read from stdin, in messages and prompt contexts.
+const STDIN_TEMPLATE: &str = "<stdin>";
+
+#[doc(hidden)]
+pub fn is_stdin_template(path: &Path) -> bool {
+    path.as_os_str() == STDIN_TEMPLATE
+}
+
+/// Reads a `--file -` template, failing like `ensure_file_size_within` when it is larger
+/// than --max-file-size.
+#[doc(hidden)]
+pub fn read_template_from(reader: &mut impl Read, max_file_size: u64) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    reader
+        .take(max_file_size.saturating_add(1))
+        .read_to_end(&mut bytes)
+        .context("failed to read the template from stdin")?;
+    if bytes.len() as u64 > max_file_size {
+        bail!("{STDIN_TEMPLATE} exceeds --max-file-size {max_file_size} bytes");
+    }
+    Ok(bytes)
+}
+
+/// The --changed-files list; a lone "-" reads one path per line from stdin.
+#[doc(hidden)]
+pub fn read_changed_files(args: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    if args != [Path::new("-")] {
+        return Ok(args.to_vec());
+    }
+    let mut text = String::new();
+    io::stdin()
+        .read_to_string(&mut text)
+        .context("failed to read --changed-files from stdin")?;
+    Ok(text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Keeps the discovered files that are in `changed`, in discovery order. Paths are compared
+/// canonicalized where they exist, so `./a.yaml` and `a.yaml` are the same file; changed
+/// files the pattern did not match are ignored.
+#[doc(hidden)]
+pub fn filter_changed_files(files: Vec<PathBuf>, changed: &[PathBuf]) -> Vec<PathBuf> {
+    let key = |path: &Path| {
+        fs::canonicalize(path).unwrap_or_else(|_| {
+            path.components()
+                .filter(|c| *c != std::path::Component::CurDir)
+                .collect()
+        })
+    };
+    let changed: BTreeSet<PathBuf> = changed.iter().map(|p| key(p)).collect();
+    files
+        .into_iter()
+        .filter(|file| changed.contains(&key(file)))
+        .collect()
+}
+
+fn find_files_by_pattern(pattern: &str, opts: &DiscoveryOptions) -> Result<Vec<PathBuf>> {
+    if pattern.contains("**") {
+        return find_files_recursively(pattern, opts);
+    }
+    let pattern_path = Path::new(pattern);
+    let dir = match pattern_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let filename_pattern = pattern_path
+        .file_name()
+        .and_then(|s| s.to_str())
+        .ok_or_else(|| anyhow::anyhow!("invalid --file-pattern: {pattern}"))?;
+    let re = file_pattern_regex(filename_pattern)?;
+
+    let mut files = Vec::new();
+    for entry in
+        fs::read_dir(&dir).with_context(|| format!("failed to read dir: {}", dir.display()))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !re.is_match(&name) {
+            continue;
+        }
+
+        if entry_is_file(&entry, opts)? {
+            files.push(dir.join(name.as_ref()));
+        }
+    }
+
+    files.sort();
+    if files.is_empty() {
+        bail!("no files matched --file-pattern {pattern}");
+    }
+    Ok(files)
+}
+
+/// Whether a directory entry is a regular file, or with --follow-symlinks a symlink to one.
+fn entry_is_file(entry: &fs::DirEntry, opts: &DiscoveryOptions) -> Result<bool> {
+    let file_type = entry.file_type()?;
+    if !(file_type.is_symlink() && opts.follow_symlinks) {
+        return Ok(file_type.is_file());
+    }
+    // fs::metadata follows the link to the target.
+    match fs::metadata(entry.path()) {
+        Ok(meta) => Ok(meta.is_file()),
+        Err(_) => {
+            if opts.verbose {
+                warn(
+                    false,
+                    &format!("skipping broken symlink {}", entry.path().display()),
+                );
+            }
+            Ok(false)
+        }
+    }
+}
+
+/// `--file-pattern` with `**`: walks the directory named by the leading segments without
+/// wildcards and matches each file's path below it against the rest of the pattern.
+/// Symlinked directories are not descended into, so link cycles cannot loop.
+fn find_files_recursively(pattern: &str, opts: &DiscoveryOptions) -> Result<Vec<PathBuf>> {
+    let (root, rest) = split_recursive_pattern(pattern);
+    let re = file_pattern_regex(&rest)?;
+
+    let mut files = Vec::new();
+    let mut pending = vec![String::new()];
+    while let Some(rel_dir) = pending.pop() {
+        let dir = root.join(&rel_dir);
+        for entry in
+            fs::read_dir(&dir).with_context(|| format!("failed to read dir: {}", dir.display()))?
+        {
+            let entry = entry?;
+            let name = entry.file_name();
+            let rel = if rel_dir.is_empty() {
+                name.to_string_lossy().to_string()
+            } else {
+                format!("{rel_dir}/{}", name.to_string_lossy())
+            };
+            if entry.file_type()?.is_dir() {
+                pending.push(rel);
+            } else if re.is_match(&rel) && entry_is_file(&entry, opts)? {
+                files.push(root.join(rel));
+            }
+        }
+    }
+
+    files.sort();
+    files.dedup();
+    if files.is_empty() {
+        bail!("no files matched --file-pattern {pattern}");
+    }
+    Ok(files)
+}
+
+/// Splits a `**` pattern into the directory named by its leading segments without wildcards
+/// and the rest, which is matched against paths below that directory.
+fn split_recursive_pattern(pattern: &str) -> (PathBuf, String) {
+    let segments: Vec<&str> = pattern.split('/').collect();
+    let literal = segments
+        .iter()
+        .take_while(|s| !s.contains('*') && !s.contains("<NUM>"))
+        .count();
+    let root = match segments[..literal].join("/") {
+        prefix if prefix.is_empty() && pattern.starts_with('/') => PathBuf::from("/"),
+        prefix if prefix.is_empty() => PathBuf::from("."),
+        prefix => PathBuf::from(prefix),
+    };
+    (root, segments[literal..].join("/"))
+}
+
+/// The directory a `**` --file-pattern is walked from; --output-dir keeps the matched files'
+/// paths below it. None for patterns without `**`.
+#[doc(hidden)]
+pub fn recursive_pattern_root(pattern: &str) -> Option<PathBuf> {
+    pattern
+        .contains("**")
+        .then(|| split_recursive_pattern(pattern).0)
+}
+
+/// `<NUM>` matches digits, `*` anything but `/`, `**/` zero or more directories and any other
+/// `**` anything.
+fn file_pattern_regex(pattern: &str) -> Result<Regex> {
+    let escaped = regex::escape(pattern);
+    let with_num = escaped.replace("<NUM>", "[0-9]+");
+    let final_pattern = with_num
+        .split(r"\*\*/")
+        .map(|part| {
+            part.split(r"\*\*")
+                .map(|part| part.replace(r"\*", "[^/]*"))
+                .collect::<Vec<_>>()
+                .join(".*")
+        })
+        .collect::<Vec<_>>()
+        .join("(?:.*/)?");
+    Ok(Regex::new(&format!("^{final_pattern}$"))?)
+}
+
+#[doc(hidden)]
+pub fn template_dir(template: &Path) -> PathBuf {
+    match template.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    }
+}
+
+/// Absolute paths, URLs and `-` (stdout) are kept as-is; relative paths are joined onto `base`.
+#[doc(hidden)]
+pub fn resolve_against_base(path: &Path, base: &Path) -> PathBuf {
+    if path.is_absolute() || path.to_string_lossy() == "-" || is_url(path) {
+        path.to_path_buf()
+    } else {
+        base.join(path)
+    }
+}
+
+#[doc(hidden)]
+pub struct ValuesLoadOptions<'a> {
+    pub max_file_size: u64,
+    pub headers: &'a [String],
+    pub timeout_secs: u64,
+    pub quiet: bool,
+}
+
+#[doc(hidden)]
+pub fn is_url(path: &Path) -> bool {
+    let s = path.to_string_lossy();
+    s.starts_with("http://") || s.starts_with("https://")
+}
+
+fn read_values_text(path: &Path, opts: &ValuesLoadOptions<'_>) -> Result<String> {
+    if is_url(path) {
+        return fetch_url(&path.to_string_lossy(), opts);
+    }
+    ensure_file_size_within(path, opts.max_file_size)?;
+    fs::read_to_string(path)
+        .with_context(|| format!("failed to read values file: {}", path.display()))
+}
+
+/// Fetch a values document over http(s), following redirects. `--values-header` entries are
+/// `Name: value` pairs; a body larger than --max-file-size fails the run.
+fn fetch_url(url: &str, opts: &ValuesLoadOptions<'_>) -> Result<String> {
+    let agent: ureq::Agent = ureq::Agent::config_builder()
+        .timeout_global(Some(Duration::from_secs(opts.timeout_secs)))
+        .http_status_as_error(false)
+        .build()
+        .into();
+    let mut request = agent.get(url);
+    for header in opts.headers {
+        let Some((name, value)) = header.split_once(':') else {
+            bail!("--values-header {header:?}: expected 'Name: value'");
+        };
+        request = request.header(name.trim(), value.trim());
+    }
+    let mut response = request
+        .call()
+        .with_context(|| format!("failed to fetch values from {url}"))?;
+    let status = response.status();
+    if !status.is_success() {
+        bail!(
+            "failed to fetch values from {url}: HTTP status {}",
+            status.as_u16()
+        );
+    }
+    response
+        .body_mut()
+        .with_config()
+        .limit(opts.max_file_size)
+        .read_to_string()
+        .with_context(|| format!("failed to read values from {url}"))
+}
+
+/// Compile a key glob (`*` matches any run of characters, `?` a single character).
+#[doc(hidden)]
+pub fn glob_regex(glob: &str) -> Result<Regex> {
+    let escaped = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+    Regex::new(&format!("^{escaped}$")).with_context(|| format!("invalid glob: {glob}"))
+}
+
+#[doc(hidden)]
+pub fn load_values_yaml(path: &Path, opts: &ValuesLoadOptions<'_>) -> Result<Option<YamlValue>> {
+    // If values placeholders are present, we require the file to exist & parse.
+    let text = read_values_text(path, opts)?;
+    let yaml = parse_values_text(&text, path)?;
+    Ok(Some(resolve_yaml_includes(yaml, path, opts)?))
+}
+
+/// Values files named `*.json` are parsed as JSON, everything else as YAML.
+fn is_json_values_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+}
+
+fn parse_values_text(text: &str, path: &Path) -> Result<YamlValue> {
+    if is_json_values_file(path) {
+        return parse_json(text).with_context(|| format!("failed to parse {}", path.display()));
+    }
+    serde_yaml::from_str(text).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+/// Serializes a values document for writing back to `path`, as JSON for `*.json`.
+fn values_file_text(root: &YamlValue, path: &Path) -> Result<String> {
+    if is_json_values_file(path) {
+        return Ok(format!("{}\n", yaml_to_json_pretty(root)));
+    }
+    Ok(serde_yaml::to_string(root)?)
+}
+
+/// Like `yaml_to_json`, but indented by two spaces per level for files people edit.
+fn yaml_to_json_pretty(value: &YamlValue) -> String {
+    serde_json::to_string_pretty(&json_value(value)).expect("JSON values serialize")
+}
+
+/// Parses a JSON document (RFC 8259) into the YAML value model. Errors name the line and
+/// column.
+fn parse_json(text: &str) -> Result<YamlValue> {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text);
+    let value: serde_json::Value = serde_json::from_str(text).map_err(|e| {
+        let message = e.to_string();
+        let message = message
+            .rsplit_once(" at line ")
+            .map_or(message.as_str(), |(m, _)| m);
+        anyhow::anyhow!(
+            "invalid JSON at line {}, column {}: {message}",
+            e.line(),
+            e.column()
+        )
+    })?;
+    Ok(serde_yaml::to_value(value)?)
+}
+
+/// How deeply `!include` files may nest before we assume something is wrong.
+const MAX_INCLUDE_DEPTH: usize = 16;
+
+fn is_include_tag(yaml: &YamlValue) -> bool {
+    matches!(yaml, YamlValue::Tagged(tagged) if tagged.tag == "include")
+}
+
+/// Replaces every `!include path` scalar with the parsed YAML of that file. Paths are
+/// relative to the including file; nested includes are followed up to `MAX_INCLUDE_DEPTH`.
+fn resolve_yaml_includes(
+    yaml: YamlValue,
+    path: &Path,
+    opts: &ValuesLoadOptions<'_>,
+) -> Result<YamlValue> {
+    splice_includes(yaml, &mut vec![path.to_path_buf()], opts)
+}
+
+fn include_chain(chain: &[PathBuf]) -> String {
+    chain
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
+fn splice_includes(
+    yaml: YamlValue,
+    chain: &mut Vec<PathBuf>,
+    opts: &ValuesLoadOptions<'_>,
+) -> Result<YamlValue> {
+    match yaml {
+        YamlValue::Tagged(tagged) if tagged.tag == "include" => {
+            let Some(target) = tagged.value.as_str() else {
+                bail!(
+                    "!include in {} expects a file path, got {}",
+                    include_chain(chain),
+                    yaml_kind(&tagged.value)
+                );
+            };
+            let including = chain.last().expect("include chain is never empty");
+            if is_url(including) {
+                bail!(
+                    "!include {target} is not supported in remote values ({})",
+                    including.display()
+                );
+            }
+            let target = including.parent().unwrap_or(Path::new("")).join(target);
+            if chain.len() > MAX_INCLUDE_DEPTH {
+                bail!(
+                    "!include nesting exceeds {MAX_INCLUDE_DEPTH} levels: {} -> {}",
+                    include_chain(chain),
+                    target.display()
+                );
+            }
+            if !target.is_file() {
+                bail!(
+                    "included values file not found: {} -> {}",
+                    include_chain(chain),
+                    target.display()
+                );
+            }
+            let canonical = fs::canonicalize(&target).ok();
+            if chain.iter().any(|p| fs::canonicalize(p).ok() == canonical) {
+                bail!(
+                    "!include cycle: {} -> {}",
+                    include_chain(chain),
+                    target.display()
+                );
+            }
+            let text = read_values_text(&target, opts)?;
+            let included: YamlValue = serde_yaml::from_str(&text).with_context(|| {
+                format!(
+                    "failed to parse {} (included from {})",
+                    target.display(),
+                    include_chain(chain)
+                )
+            })?;
+            chain.push(target);
+            let spliced = splice_includes(included, chain, opts);
+            chain.pop();
+            spliced
+        }
+        YamlValue::Tagged(mut tagged) => {
+            tagged.value = splice_includes(std::mem::take(&mut tagged.value), chain, opts)?;
+            Ok(YamlValue::Tagged(tagged))
+        }
+        YamlValue::Mapping(map) => map
+            .into_iter()
+            .map(|(k, v)| Ok((k, splice_includes(v, chain, opts)?)))
+            .collect::<Result<YamlMapping>>()
+            .map(YamlValue::Mapping),
+        YamlValue::Sequence(items) => items
+            .into_iter()
+            .map(|v| splice_includes(v, chain, opts))
+            .collect::<Result<Vec<_>>>()
+            .map(YamlValue::Sequence),
+        other => Ok(other),
+    }
+}
+
+/// Whether `key` reaches into a `!include` in an unresolved values document, where a
+/// write would replace the include with a plain mapping.
+fn include_on_path(root: &YamlValue, key: &str) -> bool {
+    let mut cur = root;
+    for part in split_yaml_path(key) {
+        if is_include_tag(cur) {
+            return true;
+        }
+        match cur.as_mapping().and_then(|m| m.get(part.as_str())) {
+            Some(next) => cur = next,
+            None => return false,
+        }
+    }
+    is_include_tag(cur)
+}
+
+#[doc(hidden)]
+pub fn parse_select(spec: &str) -> Result<(String, String)> {
+    match spec.split_once('=') {
+        Some((field, value)) if !field.trim().is_empty() => {
+            Ok((field.trim().to_string(), value.trim().to_string()))
+        }
+        _ => bail!("invalid --select '{spec}': expected FIELD=VALUE, e.g. name=prod"),
+    }
+}
+
+fn is_empty_mapping(yaml: &YamlValue) -> bool {
+    matches!(yaml, YamlValue::Mapping(m) if m.is_empty())
+}
+
+/// Picks the single mapping in a YAML list whose `field` renders as `value`.
+fn select_yaml_element(yaml: YamlValue, field: &str, value: &str) -> Result<YamlValue> {
+    let YamlValue::Sequence(items) = yaml else {
+        bail!(
+            "expected the values file to be a YAML list, got {}",
+            yaml_kind(&yaml)
+        );
+    };
+    let mut matches = items
+        .into_iter()
+        .filter(|item| element_has_field(item, field, value));
+    let Some(selected) = matches.next() else {
+        bail!("no list element has {field}={value}");
+    };
+    let extra = matches.count();
+    if extra > 0 {
+        bail!(
+            "{} list elements have {field}={value}; expected exactly one",
+            extra + 1
+        );
+    }
+    Ok(selected)
+}
+
+/// Picks the mapping at `index` (0-based) in a YAML list.
+fn select_yaml_index(yaml: YamlValue, index: usize) -> Result<YamlValue> {
+    let YamlValue::Sequence(mut items) = yaml else {
+        bail!(
+            "expected the values file to be a YAML list, got {}",
+            yaml_kind(&yaml)
+        );
+    };
+    if index >= items.len() {
+        bail!(
+            "index {index} is out of range for a list of {} element(s)",
+            items.len()
+        );
+    }
+    let selected = items.swap_remove(index);
+    if !selected.is_mapping() {
+        bail!(
+            "list element {index} is {}, expected a mapping",
+            yaml_kind(&selected)
+        );
+    }
+    Ok(selected)
+}
+
+/// The line after the opening `---` that marks front matter as --front-matter-values values.
+const FRONT_MATTER_MARKER: &str = "# tplenv-values";
+
+/// Splits --front-matter-values front matter off a template: a first line `---`, then
+/// `# tplenv-values`, the values YAML (a mapping) and a closing `---` line. Returns the values
+/// and the rest of the template, or `None` and the whole input if there is no front matter.
+#[doc(hidden)]
+pub fn split_front_matter(input: &str) -> Result<(Option<YamlValue>, &str)> {
+    let mut lines = input.split_inclusive('\n');
+    let is_separator = |line: &str| line.trim_end() == "---";
+    if !lines.next().is_some_and(is_separator)
+        || lines.next().map(str::trim_end) != Some(FRONT_MATTER_MARKER)
+    {
+        return Ok((None, input));
+    }
+    let body_start = input
+        .split_inclusive('\n')
+        .take(2)
+        .map(str::len)
+        .sum::<usize>();
+    let mut end = body_start;
+    for line in lines {
+        if is_separator(line) {
+            let body = &input[body_start..end];
+            let values: YamlValue =
+                serde_yaml::from_str(body).context("front matter is not valid YAML")?;
+            let values = match values {
+                YamlValue::Null => YamlValue::Mapping(YamlMapping::new()),
+                YamlValue::Mapping(_) => values,
+                other => bail!(
+                    "front matter must be a YAML mapping, got {}",
+                    yaml_kind(&other)
+                ),
+            };
+            return Ok((Some(values), &input[end + line.len()..]));
+        }
+        end += line.len();
+    }
+    bail!(
+        "front matter starting with `---` and `{FRONT_MATTER_MARKER}` is not closed by a `---` line"
+    )
+}
+
+#[doc(hidden)]
+pub fn parse_values_default(text: &str) -> Result<YamlValue> {
+    let yaml: YamlValue = serde_yaml::from_str(text)
+        .with_context(|| format!("failed to parse --values-default '{text}'"))?;
+    if !matches!(yaml, YamlValue::Mapping(_)) {
+        bail!(
+            "--values-default must be a YAML mapping, got {}: '{text}'",
+            yaml_kind(&yaml)
+        );
+    }
+    Ok(yaml)
+}
+
+/// Deep-merges `overlay` onto `base`: nested mappings merge key by key, any other overlay
+/// value replaces the base value, and a null overlay keeps the base.
+#[doc(hidden)]
+pub fn merge_yaml(base: YamlValue, overlay: YamlValue) -> YamlValue {
+    match (base, overlay) {
+        (YamlValue::Mapping(mut base), YamlValue::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(slot) => *slot = merge_yaml(std::mem::take(slot), value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+            YamlValue::Mapping(base)
+        }
+        (base, YamlValue::Null) => base,
+        (_, overlay) => overlay,
+    }
+}
+
+/// Decodes a base64 values document; `source` names the option in errors.
+#[doc(hidden)]
+pub fn parse_values_base64(blob: &str, source: &str) -> Result<YamlValue> {
+    let bytes = base64_decode(blob).with_context(|| format!("{source}: invalid base64"))?;
+    let text = String::from_utf8(bytes)
+        .map_err(|_| anyhow::anyhow!("{source}: decoded values are not valid UTF-8"))?;
+    serde_yaml::from_str(&text).with_context(|| format!("{source}: failed to parse values YAML"))
+}
+
+/// JSON Merge Patch (RFC 7386): mappings merge recursively, a null deletes the key,
+/// and anything else (scalars, sequences) replaces the target. Unlike `merge_yaml`,
+/// null is not "keep the base".
+fn apply_merge_patch(target: YamlValue, patch: YamlValue) -> YamlValue {
+    let YamlValue::Mapping(patch) = patch else {
+        return patch;
+    };
+    let mut target = match target {
+        YamlValue::Mapping(map) => map,
+        _ => YamlMapping::new(),
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            target.shift_remove(&key);
+        } else if let Some(slot) = target.get_mut(&key) {
+            *slot = apply_merge_patch(std::mem::take(slot), value);
+        } else {
+            target.insert(key, apply_merge_patch(YamlValue::Null, value));
+        }
+    }
+    YamlValue::Mapping(target)
+}
+
+#[doc(hidden)]
+pub fn load_values_yaml_if_exists(path: &Path, opts: &ValuesLoadOptions<'_>) -> Result<YamlValue> {
+    let yaml = load_values_yaml_unresolved_if_exists(path, opts)?;
+    resolve_yaml_includes(yaml, path, opts)
+}
+
+/// Loads a values file with `!include` tags left in place, for writing it back.
+fn load_values_yaml_unresolved_if_exists(
+    path: &Path,
+    opts: &ValuesLoadOptions<'_>,
+) -> Result<YamlValue> {
+    if !is_url(path) && !path.exists() {
+        return Ok(YamlValue::Mapping(YamlMapping::new()));
+    }
+
+    let text = read_values_text(path, opts)?;
+    parse_values_text(&text, path)
+}
+
+/// Like `load_values_yaml_if_exists`, but a YAML syntax error yields an empty mapping and a
+/// warning instead of failing. Read errors are still fatal.
+fn load_values_yaml_tolerant(path: &Path, opts: &ValuesLoadOptions<'_>) -> Result<YamlValue> {
+    if !is_url(path) && !path.exists() {
+        return Ok(YamlValue::Mapping(YamlMapping::new()));
+    }
+
+    let text = read_values_text(path, opts)?;
+    resolve_yaml_includes(parse_values_tolerant(&text, path, opts.quiet), path, opts)
+}
+
+fn parse_values_tolerant(text: &str, path: &Path, quiet: bool) -> YamlValue {
+    match parse_values_text(text, path) {
+        Ok(yaml) => yaml,
+        Err(e) => {
+            warn(
+                quiet,
+                &format!(
+                    "ignoring unparseable values file {} ({}); no .Values placeholders need it",
+                    path.display(),
+                    e.root_cause()
+                ),
+            );
+            YamlValue::Mapping(YamlMapping::new())
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn parse_byte_size(s: &str) -> Result<u64, String> {
+    let trimmed = s.trim();
+    let split = trimmed
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(trimmed.len());
+    let (digits, suffix) = trimmed.split_at(split);
+    let number: u64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size '{s}', expected e.g. 1048576, 512K, 10M or 1G"))?;
+    let multiplier: u64 = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1024,
+        "M" | "MB" | "MIB" => 1024 * 1024,
+        "G" | "GB" | "GIB" => 1024 * 1024 * 1024,
+        _ => return Err(format!("invalid size suffix in '{s}', expected K, M or G")),
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("size '{s}' is too large"))
+}
+
+#[doc(hidden)]
+pub fn ensure_file_size_within(path: &Path, max_file_size: u64) -> Result<()> {
+    let size = fs::metadata(path)
+        .with_context(|| format!("failed to read file: {}", path.display()))?
+        .len();
+    if size > max_file_size {
+        bail!(
+            "{} is {size} bytes, which exceeds --max-file-size {max_file_size} bytes",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn prompt_and_update_values_file(
+    path: &Path,
+    values_paths: &BTreeSet<String>,
+    env_vars: &BTreeSet<String>,
+    opts: &PromptUpdateOptions<'_>,
+) -> Result<ValuesFileUpdate> {
+    // Keep `!include` tags for the write-back; look keys up in the resolved view.
+    let mut root = load_values_yaml_unresolved_if_exists(path, opts.load)?;
+    let view = resolve_yaml_includes(root.clone(), path, opts.load)?;
+    // The original text, so the write-back can keep its comments and layout.
+    let original_text = fs::read_to_string(path).ok();
+    let mut edits: Vec<ValuesEdit> = Vec::new();
+    let mut prompted_values: Vec<(String, String)> = Vec::new();
+    let mut changed = false;
+    // With --values-key-case, a key counts as set if it resolves under that matching.
+    let is_set = |root: &YamlValue, key: &str| {
+        lookup_yaml_path_cased(root, key, opts.key_case).map(|v| v.is_some())
+    };
+    let in_secrets = |key: &str| match opts.secrets {
+        Some(secrets) => is_set(secrets, key),
+        None => Ok(false),
+    };
+
+    if opts.include_environment_vars && !opts.force {
+        for var in env_vars {
+            let path_key = env_var_values_path(var);
+            if !is_set(&view, &path_key)?
+                && !in_secrets(&path_key)?
+                && let Some(val) = opts.existing_os_env_values.get(var)
+            {
+                ensure_outside_include(&root, &path_key, path)?;
+                set_yaml_path(&mut root, &path_key, YamlValue::String(val.clone()));
+                edits.push(ValuesEdit::Set(
+                    path_key.clone(),
+                    YamlValue::String(val.clone()),
+                ));
+                prompted_values.push((path_key, val.clone()));
+                changed = true;
+            }
+        }
+    }
+
+    let env_skip = if opts.force {
+        BTreeSet::new()
+    } else {
+        opts.skip_existing_env_vars.clone()
+    };
+    let all_prompt_paths = collect_prompt_paths(
+        values_paths,
+        env_vars,
+        opts.include_environment_vars,
+        &env_skip,
+    );
+    // With --skip-empty, --force leaves keys that are intentionally empty alone.
+    let is_empty_string = |root: &YamlValue, key: &str| {
+        lookup_yaml_path_cased(root, key, opts.key_case)
+            .map(|v| v.is_some_and(|v| v.as_str() == Some("")))
+    };
+    let mut prompt_paths: Vec<String> = Vec::new();
+    for p in &all_prompt_paths {
+        let reprompt = opts.force && !(opts.skip_empty && is_empty_string(&view, p)?);
+        if !in_secrets(p)? && (reprompt || !is_set(&view, p)?) {
+            prompt_paths.push(p.clone());
+        }
+    }
+    let rank: HashMap<&str, usize> = opts
+        .prompt_order
+        .iter()
+        .enumerate()
+        .map(|(i, k)| (k.as_str(), i))
+        .collect();
+    prompt_paths.sort_by_key(|k| {
+        (
+            prompt_group_index(opts.prompt_groups, k),
+            rank.get(k.as_str()).copied().unwrap_or(usize::MAX),
+        )
+    });
+
+    if prompt_paths.is_empty() {
+        if opts.verbose {
+            eprintln!("No values to prompt for in {}", path.display());
+        }
+        if !changed && opts.delete_paths.is_empty() {
+            return Ok(ValuesFileUpdate {
+                prompted: prompted_values,
+                unsaved: None,
+            });
+        }
+    } else {
+        let interactive = !opts.no_interactive && opts.stdin_is_terminal;
+        let mut unanswerable: Vec<String> = Vec::new();
+        let mut current_group = None;
+        'keys: for p in prompt_paths {
+            let group = prompt_group_index(opts.prompt_groups, &p);
+            if !opts.prompt_groups.is_empty() && current_group != Some(group) {
+                current_group = Some(group);
+                if interactive {
+                    let name = opts
+                        .prompt_groups
+                        .get(group)
+                        .map_or(MISC_PROMPT_GROUP, |g| g.name.as_str());
+                    eprintln!("\n== {name} ==");
+                }
+            }
+            ensure_outside_include(&root, &p, path)?;
+            let default_value = match lookup_yaml_path_cased(&view, &p, opts.key_case)? {
+                Some(value) => Some(value.clone()),
+                None => match prompt_default(opts.history, &p, opts.key_case)? {
+                    Some(value) => Some(value),
+                    None => prompt_default(opts.prompt_defaults, &p, opts.key_case)?,
+                },
+            };
+            let env_fallback = p
+                .strip_prefix("environment.")
+                .and_then(|name| opts.existing_os_env_values.get(name))
+                .cloned();
+            let default_text = default_value
+                .as_ref()
+                .map(yaml_value_to_string)
+                .transpose()?
+                .or(env_fallback)
+                .or_else(|| annotation_default(opts.annotations, &p));
+            let rule = meta_rule(&view, &p, path)?;
+            let shown = values_key_as_written(&p, opts.path_sep);
+            let context = prompt_context_with_description(
+                opts.prompt_contexts.get(&p).map(|s| s.as_str()),
+                opts.annotations
+                    .get(&p)
+                    .and_then(|a| a.description.as_deref())
+                    .or(rule.as_ref().and_then(|r| r.description.as_deref())),
+            );
+            let chosen = loop {
+                let chosen = if opts.no_interactive {
+                    YamlValue::String(default_text.clone().unwrap_or_default())
+                } else if !opts.stdin_is_terminal && (opts.stdin_at_eof)()? {
+                    // Piped answers ran out: waiting for more would hang or read nothing.
+                    match default_text.clone() {
+                        Some(text) => YamlValue::String(text),
+                        None => {
+                            unanswerable.push(shown);
+                            continue 'keys;
+                        }
+                    }
+                } else if opts.editor_patterns.iter().any(|re| re.is_match(&p)) {
+                    prompt_for_yaml_key_in_editor(
+                        &shown,
+                        default_text.as_deref(),
+                        context.as_deref(),
+                    )?
+                } else if opts.multiline_patterns.iter().any(|re| re.is_match(&p)) {
+                    prompt_for_yaml_key_multiline(
+                        &shown,
+                        default_text.as_deref(),
+                        context.as_deref(),
+                    )?
+                } else {
+                    let hidden = opts.hidden_patterns.iter().any(|re| re.is_match(&p));
+                    if opts.confirm_patterns.iter().any(|re| re.is_match(&p)) {
+                        prompt_for_yaml_key_confirmed(
+                            &shown,
+                            default_text.as_deref(),
+                            context.as_deref(),
+                            hidden,
+                        )?
+                    } else {
+                        prompt_for_yaml_key(
+                            &shown,
+                            default_text.as_deref(),
+                            context.as_deref(),
+                            hidden,
+                        )?
+                    }
+                };
+                let Some(rejection) = rule
+                    .as_ref()
+                    .and_then(|r| r.rejection(&p, &yaml_value_to_string(&chosen).ok()?))
+                else {
+                    break chosen;
+                };
+                // Without a terminal the same answer would come back, so re-asking cannot help.
+                if !interactive {
+                    bail!("{rejection}");
+                }
+                eprintln!("{rejection}, please try again.");
+            };
+            let chosen_text = yaml_value_to_string(&chosen)?;
+            prompted_values.push((p.clone(), chosen_text));
+            set_yaml_path(&mut root, &p, chosen.clone());
+            edits.push(ValuesEdit::Set(p, chosen));
+            changed = true;
+        }
+        if !unanswerable.is_empty() {
+            bail!(
+                "--create-values-file needs input for {} key(s) but stdin is not a terminal and has no more input: {} \
+                 (set them in {}, pass --no-interactive to write empty values, or run interactively)",
+                unanswerable.len(),
+                unanswerable.join(", "),
+                path.display()
+            );
+        }
+    }
+
+    for key in opts.delete_paths {
+        if delete_yaml_path(&mut root, key) {
+            edits.push(ValuesEdit::Delete(key.clone()));
+            changed = true;
+            if opts.verbose {
+                eprintln!("Deleted {key} from {}", path.display());
+            }
+        }
+    }
+
+    if opts.dry_run {
+        let mut unsaved = None;
+        if changed {
+            eprintln!(
+                "--dry-run-values: would write {}:\n{}",
+                path.display(),
+                updated_values_text(original_text.as_deref(), &edits, &root, path)?
+            );
+            unsaved = Some(resolve_yaml_includes(root, path, opts.load)?);
+        }
+        return Ok(ValuesFileUpdate {
+            prompted: prompted_values,
+            unsaved,
+        });
+    }
+
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory: {}", parent.display()))?;
+    }
+
+    if changed {
+        let out = updated_values_text(original_text.as_deref(), &edits, &root, path)?;
+        fs::write(path, out)
+            .with_context(|| format!("failed to write values file: {}", path.display()))?;
+    }
+    Ok(ValuesFileUpdate {
+        prompted: prompted_values,
+        unsaved: None,
+    })
+}
+
+/// One change to a values file, replayed on its text by `edit_values_text`.
+enum ValuesEdit {
+    Set(String, YamlValue),
+    Delete(String),
+}
+
+/// The text to write for the updated values document `root`. For YAML files, `edits` are
+/// applied to the original text so comments, blank lines and key order survive; when the
+/// edited text does not parse back to exactly `root` (flow style, several documents,
+/// pruned parents, ...), the document is serialized afresh instead.
+fn updated_values_text(
+    original: Option<&str>,
+    edits: &[ValuesEdit],
+    root: &YamlValue,
+    path: &Path,
+) -> Result<String> {
+    if !is_json_values_file(path)
+        && let Some(text) = original
+        && let Some(edited) = edit_values_text(text, edits)
+        && serde_yaml::from_str::<YamlValue>(&edited).is_ok_and(|doc| &doc == root)
+    {
+        return Ok(edited);
+    }
+    values_file_text(root, path)
+}
+
+/// Where a values path is in the block-style text of a values file.
+enum KeyLocation {
+    /// The key is on `line`; its nested lines end before `end`.
+    Found {
+        line: usize,
+        indent: usize,
+        end: usize,
+    },
+    /// Keys from `depth` on are missing; they belong at `at` with `indent` spaces.
+    Missing {
+        depth: usize,
+        at: usize,
+        indent: usize,
+    },
+}
+
+/// Applies `edits` to `text` line by line, or `None` if a path cannot be located in it.
+/// Existing single-line values are replaced in place, keeping a trailing comment; other
+/// existing keys are rewritten with their nested lines; new keys go after the last line of
+/// their parent mapping.
+fn edit_values_text(text: &str, edits: &[ValuesEdit]) -> Option<String> {
+    let mut lines: Vec<String> = text.split_inclusive('\n').map(str::to_string).collect();
+    if lines.last().is_some_and(|line| !line.ends_with('\n')) {
+        lines.last_mut()?.push('\n');
+    }
+    for edit in edits {
+        let (ValuesEdit::Set(path, _) | ValuesEdit::Delete(path)) = edit;
+        let segments = split_yaml_path(path);
+        if segments.iter().any(|s| parse_path_selector(s).1.is_some()) {
+            return None;
+        }
+        match (locate_values_key(&lines, &segments)?, edit) {
+            (KeyLocation::Found { line, end, .. }, ValuesEdit::Delete(_)) => {
+                lines.drain(line..end);
+            }
+            (KeyLocation::Missing { .. }, ValuesEdit::Delete(_)) => {}
+            (KeyLocation::Found { line, indent, end }, ValuesEdit::Set(_, value)) => {
+                let (key, value_start) = split_yaml_key_line(&lines[line])?;
+                let head = &lines[line][..value_start];
+                let (current, comment) = split_yaml_comment(lines[line][value_start..].trim_end());
+                let scalar = serde_yaml::to_string(value).ok()?;
+                let inline = end == line + 1
+                    && !current.starts_with(['|', '>'])
+                    && scalar.matches('\n').count() == 1
+                    && !matches!(value, YamlValue::Mapping(m) if !m.is_empty())
+                    && !matches!(value, YamlValue::Sequence(s) if !s.is_empty());
+                if inline {
+                    lines[line] = format!("{head} {}{comment}\n", scalar.trim_end());
+                } else {
+                    let mut entry = YamlMapping::new();
+                    entry.insert(YamlValue::String(key), value.clone());
+                    let fragment = yaml_fragment(&YamlValue::Mapping(entry), indent)?;
+                    lines.splice(line..end, fragment);
+                }
+            }
+            (KeyLocation::Missing { depth, at, indent }, ValuesEdit::Set(_, value)) => {
+                let mut nested = YamlValue::Mapping(YamlMapping::new());
+                set_yaml_path(
+                    &mut nested,
+                    &join_yaml_path(&segments[depth..]),
+                    value.clone(),
+                );
+                let fragment = yaml_fragment(&nested, indent)?;
+                lines.splice(at..at, fragment);
+            }
+        }
+    }
+    Some(lines.concat())
+}
+
+/// `value` serialized as YAML lines, each indented by `indent` spaces.
+fn yaml_fragment(value: &YamlValue, indent: usize) -> Option<Vec<String>> {
+    let text = serde_yaml::to_string(value).ok()?;
+    let pad = " ".repeat(indent);
+    Some(
+        text.split_inclusive('\n')
+            .map(|line| format!("{pad}{line}"))
+            .collect(),
+    )
+}
+
+/// Finds `segments` in block-style YAML `lines`; `None` if the text has a shape this line
+/// editor does not handle (a list, or a scalar where a mapping is needed).
+fn locate_values_key(lines: &[String], segments: &[String]) -> Option<KeyLocation> {
+    let is_content = |line: &str| {
+        let trimmed = line.trim();
+        !trimmed.is_empty() && !trimmed.starts_with('#')
+    };
+    let mut range = 0..lines.len();
+    let mut parent_indent = None;
+    for (depth, segment) in segments.iter().enumerate() {
+        let first = range.clone().find(|&i| is_content(&lines[i]));
+        let Some(first) = first else {
+            let indent = parent_indent.map_or(0, |i| i + 2);
+            return Some(KeyLocation::Missing {
+                depth,
+                at: range.start,
+                indent,
+            });
+        };
+        let indent = leading_spaces(&lines[first]);
+        if lines[first][indent..].starts_with('-') || parent_indent.is_some_and(|p| indent <= p) {
+            return None;
+        }
+        // The block of a key: the lines after it indented deeper, up to its last content line.
+        let block_end = |line: usize| {
+            let mut end = line + 1;
+            for (i, text) in lines.iter().enumerate().take(range.end).skip(line + 1) {
+                if is_content(text) {
+                    if leading_spaces(text) <= indent {
+                        break;
+                    }
+                    end = i + 1;
+                }
+            }
+            end
+        };
+        let found = range.clone().find(|&i| {
+            leading_spaces(&lines[i]) == indent
+                && split_yaml_key_line(&lines[i]).is_some_and(|(key, _)| &key == segment)
+        });
+        let Some(line) = found else {
+            let last = range.clone().rev().find(|&i| is_content(&lines[i]))?;
+            return Some(KeyLocation::Missing {
+                depth,
+                at: last + 1,
+                indent,
+            });
+        };
+        let end = block_end(line);
+        if depth + 1 == segments.len() {
+            return Some(KeyLocation::Found { line, indent, end });
+        }
+        let (_, value_start) = split_yaml_key_line(&lines[line])?;
+        if !split_yaml_comment(&lines[line][value_start..]).0.is_empty() {
+            return None;
+        }
+        range = line + 1..end;
+        parent_indent = Some(indent);
+    }
+    None
+}
+
+/// Splits a `key: value` line into the unquoted key and the byte offset just past the colon.
+fn split_yaml_key_line(line: &str) -> Option<(String, usize)> {
+    let indent = leading_spaces(line);
+    let body = line[indent..].trim_end_matches(['\n', '\r']);
+    let (key, after) = match body.chars().next()? {
+        quote @ ('"' | '\'') => {
+            let close = body[1..].find(quote)? + 1;
+            let key: String = serde_yaml::from_str(&body[..=close]).ok()?;
+            (key, &body[close + 1..])
+        }
+        '#' | '-' | '[' | '{' => return None,
+        _ => {
+            let colon = body
+                .match_indices(':')
+                .map(|(i, _)| i)
+                .find(|&i| body[i + 1..].is_empty() || body[i + 1..].starts_with([' ', '\t']))?;
+            (body[..colon].trim_end().to_string(), &body[colon..])
+        }
+    };
+    let rest = after.trim_start().strip_prefix(':')?;
+    (rest.is_empty() || rest.starts_with([' ', '\t']))
+        .then_some((key, indent + body.len() - rest.len()))
+}
+
+/// Splits the text after a key's colon into its value (trimmed) and a trailing ` # comment`
+/// (with its leading whitespace), skipping `#` inside quotes.
+fn split_yaml_comment(rest: &str) -> (&str, &str) {
+    let mut quote = None;
+    for (i, c) in rest.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(q), _) if c == q => quote = None,
+            (None, '#') if i == 0 || rest[..i].ends_with([' ', '\t']) => {
+                let value_end = rest[..i].trim_end().len();
+                return (rest[..value_end].trim(), &rest[value_end..]);
+            }
+            _ => {}
+        }
+    }
+    (rest.trim(), "")
+}
+
+#[doc(hidden)]
+pub struct ValuesFileUpdate {
+    /// `(key, value)` for every prompted or OS-env-filled key, in prompt order.
+    pub prompted: Vec<(String, String)>,
+    /// With --dry-run-values: the updated document that was not written.
+    pub unsaved: Option<YamlValue>,
+}
+
+/// Looks `key` up in --prompt-defaults, as a nested path or as one flat dotted key.
+fn prompt_default(
+    defaults: Option<&YamlValue>,
+    key: &str,
+    key_case: KeyCase,
+) -> Result<Option<YamlValue>> {
+    let Some(defaults) = defaults else {
+        return Ok(None);
+    };
+    if let Some(value) = lookup_yaml_path_cased(defaults, key, key_case)? {
+        return Ok(Some(value.clone()));
+    }
+    Ok(defaults
+        .as_mapping()
+        .and_then(|map| map.get(key))
+        .filter(|value| !value.is_null())
+        .cloned())
+}
+
+/// The env placeholder values --persist-env writes, per values file: those that came from
+/// the OS environment or --override. Keys matching --secret-key are refused unless
+/// `allow_secrets`.
+#[doc(hidden)]
+pub fn env_values_to_persist(
+    groups: &[ValuesGroup],
+    secret_patterns: &[Regex],
+    allow_secrets: bool,
+) -> Result<Vec<(PathBuf, BTreeMap<String, String>)>> {
+    let mut persisted = Vec::new();
+    let mut secrets = BTreeSet::new();
+    for group in groups {
+        let res = &group.resolution;
+        let values: BTreeMap<String, String> = res
+            .env_map
+            .iter()
+            .map(|(name, value)| (env_var_values_path(name), value.clone()))
+            .filter(|(key, _)| {
+                matches!(
+                    res.sources.get(key),
+                    Some(ValueSource::OsEnv | ValueSource::Override)
+                )
+            })
+            .collect();
+        if !allow_secrets {
+            secrets.extend(
+                values
+                    .keys()
+                    .filter(|key| secret_patterns.iter().any(|p| p.is_match(key)))
+                    .cloned(),
+            );
+        }
+        if !values.is_empty() {
+            persisted.push((group.values_file.clone(), values));
+        }
+    }
+    if !secrets.is_empty() {
+        bail!(
+            "--persist-env would write secret values ({}) to the values file; pass --persist-secrets to allow it",
+            secrets.into_iter().collect::<Vec<_>>().join(", ")
+        );
+    }
+    Ok(persisted)
+}
+
+/// Writes `values` (`environment.<VAR>` keys) into the values file, keeping its other keys.
+#[doc(hidden)]
+pub fn persist_env_values(
+    path: &Path,
+    values: &BTreeMap<String, String>,
+    load: &ValuesLoadOptions<'_>,
+) -> Result<()> {
+    if is_url(path) {
+        bail!(
+            "--persist-env cannot write back to a URL values file: {}",
+            path.display()
+        );
+    }
+    let mut root = load_values_yaml_unresolved_if_exists(path, load)?;
+    let original_text = fs::read_to_string(path).ok();
+    let mut edits = Vec::new();
+    for (key, value) in values {
+        ensure_outside_include(&root, key, path)?;
+        set_yaml_path(&mut root, key, YamlValue::String(value.clone()));
+        edits.push(ValuesEdit::Set(
+            key.clone(),
+            YamlValue::String(value.clone()),
+        ));
+    }
+    let out = updated_values_text(original_text.as_deref(), &edits, &root, path)?;
+    fs::write(path, out).with_context(|| format!("failed to write values file: {}", path.display()))
+}
+
+/// Reads the --prompt-history file, a flat mapping of dotted key to the last answer. A
+/// missing file is an empty history.
+#[doc(hidden)]
+pub fn load_prompt_history(path: &Path) -> Result<YamlValue> {
+    if !path.exists() {
+        return Ok(YamlValue::Mapping(YamlMapping::new()));
+    }
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read prompt history: {}", path.display()))?;
+    let history: YamlValue = serde_yaml::from_str(&text)
+        .with_context(|| format!("failed to parse prompt history: {}", path.display()))?;
+    match history {
+        YamlValue::Null => Ok(YamlValue::Mapping(YamlMapping::new())),
+        YamlValue::Mapping(_) => Ok(history),
+        _ => bail!("prompt history must be a mapping: {}", path.display()),
+    }
+}
+
+/// Records `prompted` answers in the --prompt-history file, sorted by key. Keys matching
+/// --secret-key are left out; the file is owner-only since answers may still be sensitive.
+#[doc(hidden)]
+pub fn save_prompt_history(
+    path: &Path,
+    history: YamlValue,
+    prompted: &[(String, String)],
+    secret_patterns: &[Regex],
+) -> Result<()> {
+    let mut entries: BTreeMap<String, YamlValue> = match history {
+        YamlValue::Mapping(map) => map
+            .into_iter()
+            .filter_map(|(k, v)| Some((k.as_str()?.to_string(), v)))
+            .collect(),
+        _ => BTreeMap::new(),
+    };
+    let before = entries.clone();
+    for (key, value) in prompted {
+        if !secret_patterns.iter().any(|p| p.is_match(key)) {
+            entries.insert(key.clone(), YamlValue::String(value.clone()));
+        }
+    }
+    if entries == before {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent()
+        && !parent.as_os_str().is_empty()
+    {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create directory: {}", parent.display()))?;
+    }
+    let map: YamlMapping = entries
+        .into_iter()
+        .map(|(k, v)| (YamlValue::String(k), v))
+        .collect();
+    write_private_file(path, serde_yaml::to_string(&map)?.as_bytes())
+}
+
+fn ensure_outside_include(root: &YamlValue, key: &str, path: &Path) -> Result<()> {
+    if include_on_path(root, key) {
+        bail!(
+            "cannot write {key} to {}: it is provided by an !include; edit the included file instead",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[doc(hidden)]
+pub struct PromptUpdateOptions<'a> {
+    pub include_environment_vars: bool,
+    pub skip_existing_env_vars: &'a BTreeSet<String>,
+    pub existing_os_env_values: &'a HashMap<String, String>,
+    pub prompt_contexts: &'a HashMap<String, String>,
+    pub prompt_order: &'a [String],
+    pub annotations: &'a HashMap<String, Annotation>,
+    pub delete_paths: &'a [String],
+    pub confirm_patterns: &'a [Regex],
+    /// Keys matching these read a multi-line value (--prompt-multiline).
+    pub multiline_patterns: &'a [Regex],
+    /// Keys matching these are edited in $EDITOR (--prompt-editor).
+    pub editor_patterns: &'a [Regex],
+    /// Keys matching these (--secret-key) are read without echo and their default is not shown.
+    pub hidden_patterns: &'a [Regex],
+    /// Sections from --prompt-description-file; empty means no headers.
+    pub prompt_groups: &'a [PromptGroup],
+    /// Keys set in --secrets-file are never prompted for or written to the values file.
+    pub secrets: Option<&'a YamlValue>,
+    /// Suggested defaults from --prompt-defaults, shown when the values file has none.
+    pub prompt_defaults: Option<&'a YamlValue>,
+    /// Earlier answers from --prompt-history; they win over --prompt-defaults.
+    pub history: Option<&'a YamlValue>,
+    pub key_case: KeyCase,
+    /// --path-sep of the templates; prompts show keys the way the templates spell them.
+    pub path_sep: char,
+    pub load: &'a ValuesLoadOptions<'a>,
+    pub no_interactive: bool,
+    /// Without a terminal on stdin, answers are read from the pipe; once it is at end of input
+    /// (`stdin_at_eof`), keys with a default take it and keys without one fail the run.
+    pub stdin_is_terminal: bool,
+    pub stdin_at_eof: &'a dyn Fn() -> io::Result<bool>,
+    pub force: bool,
+    pub skip_empty: bool,
+    /// Print the updated values file instead of writing it (--dry-run-values).
+    pub dry_run: bool,
+    pub verbose: bool,
+}
+
+/// Section header for prompts not assigned by --prompt-description-file.
+const MISC_PROMPT_GROUP: &str = "Misc";
+
+/// A named section of prompts from --prompt-description-file.
+#[derive(Debug)]
+#[doc(hidden)]
+pub struct PromptGroup {
+    name: String,
+    patterns: Vec<Regex>,
+}
+
+/// Parses a mapping of section name to a list of dotted keys or key globs, e.g.
+/// `Database: [db.host, "db.*"]`. Sections keep the file's order.
+#[doc(hidden)]
+pub fn load_prompt_groups(path: &Path, load: &ValuesLoadOptions<'_>) -> Result<Vec<PromptGroup>> {
+    let yaml = load_values_yaml(path, load)?.unwrap_or(YamlValue::Null);
+    let YamlValue::Mapping(map) = yaml else {
+        bail!(
+            "{}: expected a mapping of section name to key globs, got {}",
+            path.display(),
+            yaml_kind(&yaml)
+        );
+    };
+    let mut groups = Vec::new();
+    for (name, keys) in map {
+        let name = yaml_value_to_string(&name)?;
+        let YamlValue::Sequence(keys) = keys else {
+            bail!(
+                "{}: section '{name}' must be a list of key globs, got {}",
+                path.display(),
+                yaml_kind(&keys)
+            );
+        };
+        let patterns = keys
+            .iter()
+            .map(|key| match key {
+                YamlValue::String(glob) => glob_regex(glob),
+                other => bail!(
+                    "{}: section '{name}' must list keys as strings, got {}",
+                    path.display(),
+                    yaml_kind(other)
+                ),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        groups.push(PromptGroup { name, patterns });
+    }
+    Ok(groups)
+}
+
+/// Index of the first section listing `key`; unassigned keys sort last ("Misc").
+fn prompt_group_index(groups: &[PromptGroup], key: &str) -> usize {
+    groups
+        .iter()
+        .position(|g| g.patterns.iter().any(|re| re.is_match(key)))
+        .unwrap_or(groups.len())
+}
+
+/// Reserved top-level section of the values file with prompt rules per key:
+/// `_meta.<key>.pattern`, `.message` and `.description`.
+const META_SECTION: &str = "_meta";
+
+/// The `_meta.<key>` entry of the values file, checked when `<key>` is prompted for.
+struct MetaRule {
+    pattern: Option<Regex>,
+    message: Option<String>,
+    description: Option<String>,
+}
+
+impl MetaRule {
+    /// Why `value` is not accepted for `key`, or `None` if it is.
+    fn rejection(&self, key: &str, value: &str) -> Option<String> {
+        let pattern = self.pattern.as_ref()?;
+        if pattern.is_match(value) {
+            return None;
+        }
+        Some(match &self.message {
+            Some(message) => format!("{message} ({key})"),
+            None => format!("value for {key} must match {}", pattern.as_str()),
+        })
+    }
+}
+
+fn meta_rule(values: &YamlValue, key: &str, path: &Path) -> Result<Option<MetaRule>> {
+    let Some(YamlValue::Mapping(entry)) =
+        lookup_yaml_path(values, &format!("{META_SECTION}.{key}"))
+    else {
+        return Ok(None);
+    };
+    let field = |name: &str| entry.get(name).map(yaml_value_to_string).transpose();
+    let pattern = field("pattern")?
+        .map(|pattern| {
+            Regex::new(&pattern).with_context(|| {
+                format!("invalid {META_SECTION}.{key}.pattern in {}", path.display())
+            })
+        })
+        .transpose()?;
+    Ok(Some(MetaRule {
+        pattern,
+        message: field("message")?,
+        description: field("description")?,
+    }))
+}
+
+/// The `_meta` section holds prompt rules, not values, so templates may not read it.
+#[doc(hidden)]
+pub fn ensure_no_meta_placeholders(values_paths: &BTreeSet<String>) -> Result<()> {
+    if let Some(path) = values_paths
+        .iter()
+        .find(|p| split_yaml_path(p).first().map(String::as_str) == Some(META_SECTION))
+    {
+        bail!(
+            ".Values.{path}: the {META_SECTION} section of the values file holds prompt rules and cannot be used in templates"
+        );
+    }
+    Ok(())
+}
+
+fn collect_prompt_paths(
+    values_paths: &BTreeSet<String>,
+    env_vars: &BTreeSet<String>,
+    include_environment_vars: bool,
+    skip_existing_env_vars: &BTreeSet<String>,
+) -> BTreeSet<String> {
+    let mut all: BTreeSet<String> = values_paths.clone();
+    if include_environment_vars {
+        for var in env_vars {
+            if skip_existing_env_vars.contains(var) {
+                continue;
+            }
+            all.insert(env_var_values_path(var));
+        }
+    }
+    all
+}
+
+#[doc(hidden)]
+pub fn collect_prompt_contexts(
+    templates: &[(PathBuf, String)],
+    re: &Regex,
+    extended_context: bool,
+    context_lines: Option<usize>,
+) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    let include_file_header = templates.len() > 1;
+
+    for (path, input) in templates {
+        for cap in placeholder_captures(re, input) {
+            let key = if let Some(p) = extract_values_path(&cap) {
+                p.to_string()
+            } else if let Some(env) = extract_env_key(&cap) {
+                env_var_values_path(env)
+            } else {
+                continue;
+            };
+
+            if out.contains_key(&key) {
+                continue;
+            }
+
+            let mut text = match context_lines {
+                Some(n) => extract_context_window(input, &cap, n),
+                None => extract_prompt_context(input, &cap, re, &key, extended_context),
+            };
+            if include_file_header {
+                text = format!("[{}]\n{}", path.display(), text);
+            }
+            out.insert(key, text);
+        }
+    }
+
+    out
+}
+
+#[doc(hidden)]
+pub fn collect_prompt_order(templates: &[(PathBuf, String)], re: &Regex) -> Vec<String> {
+    let mut out = Vec::new();
+    let mut seen = BTreeSet::new();
+
+    for (_, input) in templates {
+        for cap in placeholder_captures(re, input) {
+            let key = if let Some(p) = extract_values_path(&cap) {
+                p.to_string()
+            } else if let Some(env) = extract_env_key(&cap) {
+                env_var_values_path(env)
+            } else {
+                continue;
+            };
+
+            if seen.insert(key.clone()) {
+                out.push(key);
+            }
+        }
+    }
+    out
+}
+
+/// Directives parsed from a trailing `# @tplenv ...` comment on a placeholder line.
+///
+/// Grammar: `# @tplenv <directive>[; <directive>...]` where a directive is one of
+/// `default: <value>`, `description: <text>`, or `required`.
+#[derive(Debug, Default, Clone, PartialEq)]
+#[doc(hidden)]
+pub struct Annotation {
+    default: Option<String>,
+    description: Option<String>,
+    required: bool,
+}
+
+fn annotation_regex() -> Result<Regex> {
+    Ok(Regex::new(r"#\s*@tplenv\s+(.*)$")?)
+}
+
+fn parse_annotation(line: &str, annotation_re: &Regex) -> Option<Annotation> {
+    let body = annotation_re.captures(line)?.get(1)?.as_str();
+    let mut annotation = Annotation::default();
+    for directive in body.split(';') {
+        let directive = directive.trim();
+        let (name, value) = match directive.split_once(':') {
+            Some((name, value)) => (name.trim(), Some(value.trim().to_string())),
+            None => (directive, None),
+        };
+        match name {
+            "default" => annotation.default = value,
+            "description" => annotation.description = value,
+            "required" => annotation.required = true,
+            _ => {}
+        }
+    }
+    Some(annotation)
+}
+
+#[doc(hidden)]
+pub fn collect_annotations(
+    templates: &[(PathBuf, String)],
+    re: &Regex,
+) -> HashMap<String, Annotation> {
+    let mut out = HashMap::new();
+    let annotation_re = match annotation_regex() {
+        Ok(re) => re,
+        Err(_) => return out,
+    };
+
+    for (_, input) in templates {
+        let lines = line_ranges(input);
+        for cap in placeholder_captures(re, input) {
+            let key = if let Some(p) = extract_values_path(&cap) {
+                p.to_string()
+            } else if let Some(env) = extract_env_key(&cap) {
+                env_var_values_path(env)
+            } else {
+                continue;
+            };
+            if out.contains_key(&key) {
+                continue;
+            }
+
+            let start = cap.get(0).map(|m| m.start()).unwrap_or(0);
+            let line_idx = line_index_for_pos(&lines, start).unwrap_or(0);
+            let line = trim_line_ending(&input[lines[line_idx].0..lines[line_idx].1]);
+            if let Some(annotation) = parse_annotation(line, &annotation_re) {
+                out.insert(key, annotation);
+            }
+        }
+    }
+    // An inline `{{ VAR:-default }}` acts like `# @tplenv default:` unless the placeholder
+    // has one; the first inline default of a variable wins.
+    for (_, input) in templates {
+        for cap in placeholder_captures(re, input) {
+            if let (Some(env), Some(default)) = (extract_env_key(&cap), extract_env_default(&cap)) {
+                out.entry(env_var_values_path(env))
+                    .or_default()
+                    .default
+                    .get_or_insert_with(|| default.to_string());
+            }
+        }
+    }
+    out
+}
+
+fn annotation_default(annotations: &HashMap<String, Annotation>, key: &str) -> Option<String> {
+    annotations.get(key).and_then(|a| a.default.clone())
+}
+
+fn prompt_context_with_description(
+    context: Option<&str>,
+    description: Option<&str>,
+) -> Option<String> {
+    match (description, context) {
+        (Some(d), Some(c)) => Some(format!("# {d}\n{c}")),
+        (Some(d), None) => Some(format!("# {d}")),
+        (None, Some(c)) => Some(c.to_string()),
+        (None, None) => None,
+    }
+}
+
+fn extract_prompt_context(
+    input: &str,
+    caps: &regex::Captures,
+    re: &Regex,
+    key: &str,
+    extended_context: bool,
+) -> String {
+    let m = if let Some(m) = caps.get(0) {
+        m
+    } else {
+        return String::new();
+    };
+
+    let lines = line_ranges(input);
+    let line_idx = line_index_for_pos(&lines, m.start()).unwrap_or(0);
+    if !extended_context {
+        return trim_line_ending(&input[lines[line_idx].0..lines[line_idx].1]).to_string();
+    }
+
+    let (para_start, para_end) = paragraph_bounds(input, &lines, line_idx);
+    let para_text = &input[lines[para_start].0..lines[para_end].1];
+    let keys = collect_prompt_keys(para_text, re);
+    if keys.len() == 1 && keys.contains(key) {
+        return trim_surrounding_newlines(para_text).to_string();
+    }
+
+    if let Some((list_start, list_end)) =
+        list_entry_bounds(input, &lines, line_idx, para_start, para_end)
+    {
+        let text = &input[lines[list_start].0..lines[list_end].1];
+        return trim_surrounding_newlines(text).to_string();
+    }
+
+    trim_line_ending(&input[lines[line_idx].0..lines[line_idx].1]).to_string()
+}
+
+fn extract_context_window(input: &str, caps: &regex::Captures, n: usize) -> String {
+    let m = if let Some(m) = caps.get(0) {
+        m
+    } else {
+        return String::new();
+    };
+
+    let lines = line_ranges(input);
+    let line_idx = line_index_for_pos(&lines, m.start()).unwrap_or(0);
+    let start = line_idx.saturating_sub(n);
+    let end = (line_idx + n).min(lines.len() - 1);
+    trim_line_ending(&input[lines[start].0..lines[end].1]).to_string()
+}
+
+fn collect_prompt_keys(text: &str, re: &Regex) -> BTreeSet<String> {
+    let mut keys = BTreeSet::new();
+    for cap in placeholder_captures(re, text) {
+        if let Some(p) = extract_values_path(&cap) {
+            keys.insert(p.to_string());
+        } else if let Some(env) = extract_env_key(&cap) {
+            keys.insert(env_var_values_path(env));
+        }
+    }
+    keys
+}
+
+fn line_ranges(input: &str) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut start = 0usize;
+    for (i, b) in input.bytes().enumerate() {
+        if b == b'\n' {
+            ranges.push((start, i + 1));
+            start = i + 1;
+        }
+    }
+    if start < input.len() {
+        ranges.push((start, input.len()));
+    }
+    if ranges.is_empty() {
+        ranges.push((0, 0));
+    }
+    ranges
+}
+
+fn line_index_for_pos(lines: &[(usize, usize)], pos: usize) -> Option<usize> {
+    lines.iter().position(|(s, e)| *s <= pos && pos < *e)
+}
+
+fn paragraph_bounds(input: &str, lines: &[(usize, usize)], line_idx: usize) -> (usize, usize) {
+    let mut start = line_idx;
+    while start > 0 {
+        let prev = trim_line_ending(&input[lines[start - 1].0..lines[start - 1].1]);
+        if prev.trim().is_empty() {
+            break;
+        }
+        start -= 1;
+    }
+
+    let mut end = line_idx;
+    while end + 1 < lines.len() {
+        let next = trim_line_ending(&input[lines[end + 1].0..lines[end + 1].1]);
+        if next.trim().is_empty() {
+            break;
+        }
+        end += 1;
+    }
+    (start, end)
+}
+
+fn is_list_item_line(line: &str) -> bool {
+    let trimmed = line.trim_start();
+    if trimmed.starts_with("- ") {
+        return true;
+    }
+
+    let mut chars = trimmed.chars().peekable();
+    let mut has_digit = false;
+    while let Some(c) = chars.peek().copied() {
+        if c.is_ascii_digit() {
+            has_digit = true;
+            chars.next();
+        } else {
+            break;
+        }
+    }
+    if !has_digit {
+        return false;
+    }
+    match chars.next() {
+        Some('.') | Some(')') => matches!(chars.next(), Some(' ')),
+        _ => false,
+    }
+}
+
+fn leading_spaces(line: &str) -> usize {
+    line.chars().take_while(|c| *c == ' ').count()
+}
+
+fn list_entry_bounds(
+    input: &str,
+    lines: &[(usize, usize)],
+    line_idx: usize,
+    para_start: usize,
+    para_end: usize,
+) -> Option<(usize, usize)> {
+    let mut anchor = None;
+    for idx in (para_start..=line_idx).rev() {
+        let line = trim_line_ending(&input[lines[idx].0..lines[idx].1]);
+        if is_list_item_line(line) {
+            anchor = Some(idx);
+            break;
+        }
+    }
+    let anchor_idx = anchor?;
+    let anchor_line = trim_line_ending(&input[lines[anchor_idx].0..lines[anchor_idx].1]);
+    let anchor_indent = leading_spaces(anchor_line);
+
+    let mut end = para_end;
+    for idx in (anchor_idx + 1)..=para_end {
+        let line = trim_line_ending(&input[lines[idx].0..lines[idx].1]);
+        if line.trim().is_empty() {
+            end = idx.saturating_sub(1);
+            break;
+        }
+        if is_list_item_line(line) && leading_spaces(line) <= anchor_indent {
+            end = idx.saturating_sub(1);
+            break;
+        }
+    }
+    Some((anchor_idx, end))
+}
+
+fn trim_line_ending(line: &str) -> &str {
+    line.trim_end_matches(['\r', '\n'])
+}
+
+fn trim_surrounding_newlines(s: &str) -> &str {
+    s.trim_matches(['\r', '\n'])
+}
+
+#[doc(hidden)]
+pub fn env_var_values_path(var: &str) -> String {
+    format!("environment.{var}")
+}
+
+#[doc(hidden)]
+pub fn values_key_to_env_var(values_key: &str) -> String {
+    let no_prefix = values_key
+        .strip_prefix("environment.")
+        .unwrap_or(values_key);
+    no_prefix.replace('.', "_").to_uppercase()
+}
+
+fn shell_escape_single_quoted(value: &str) -> String {
+    value.replace('\'', "'\"'\"'")
+}
+
+/// Writes a file readable only by its owner (0600 on Unix), since it may hold secrets.
+#[doc(hidden)]
+pub fn write_private_file(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        // `mode` only applies to new files; tighten an existing one before writing.
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))
+                .with_context(|| format!("failed to set permissions on {}", path.display()))?;
+        }
+    }
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("failed to write file: {}", path.display()))?;
+    file.write_all(bytes)
+        .with_context(|| format!("failed to write file: {}", path.display()))
+}
+
+/// The `export NAME='value'` script for --eval and --env-export-file, sorted by name, with
+/// --export-prefix prepended to every name.
+#[doc(hidden)]
+pub fn render_eval_exports_with_env(
+    prompted_values: &[(String, String)],
+    resolved_env_map: &HashMap<String, String>,
+    prefix: &str,
+) -> String {
+    let mut export_map: HashMap<String, String> = HashMap::new();
+
+    for (key, value) in prompted_values {
+        let env_name = values_key_to_env_var(key);
+        export_map.insert(env_name, value.clone());
+    }
+
+    // Always export resolved env placeholders in --eval mode, even without --force.
+    for (name, value) in resolved_env_map {
+        export_map.insert(name.clone(), value.clone());
+    }
+
+    let mut names: Vec<String> = export_map.keys().cloned().collect();
+    names.sort();
+
+    let mut out = String::new();
+    for name in names {
+        if let Some(value) = export_map.get(&name) {
+            out.push_str(&format!(
+                "export {prefix}{}='{}'\n",
+                name,
+                shell_escape_single_quoted(value)
+            ));
+        }
+    }
+    out
+}
+
+#[doc(hidden)]
+pub fn prompted_environment_values(
+    prompted_values: &[(String, String)],
+) -> HashMap<String, String> {
+    let mut out = HashMap::new();
+    for (key, value) in prompted_values {
+        if let Some(env_name) = key.strip_prefix("environment.") {
+            out.insert(env_name.to_string(), value.clone());
+        }
+    }
+    out
+}
+
+fn indent_multiline_value(value: &str, input: &str, match_start: usize) -> String {
+    if !value.contains('\n') {
+        return value.to_string();
+    }
+
+    let line_start = input[..match_start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let before_match = &input[line_start..match_start];
+    let indent: String = before_match
+        .chars()
+        .take_while(|c| c.is_whitespace())
+        .collect();
+
+    let mut out = String::with_capacity(value.len() + indent.len());
+    let mut chars = value.chars().peekable();
+    while let Some(ch) = chars.next() {
+        out.push(ch);
+        if ch == '\n' && chars.peek().is_some() {
+            out.push_str(&indent);
+        }
+    }
+    out
+}
+
+fn format_replacement_with_indent(
+    value: &str,
+    input: &str,
+    match_start: usize,
+    match_end: usize,
+    block_scalar: bool,
+) -> String {
+    if !value.contains('\n') {
+        return value.to_string();
+    }
+
+    // Flow collections (`[a, {{ x }}]`, `{k: {{ x }}}`) cannot hold block scalars or
+    // indented continuation lines; the value goes on one line as a double-quoted scalar.
+    if in_yaml_flow_context(input, match_start) {
+        return format!("\"{}\"", json_escape(value));
+    }
+
+    if block_scalar && should_use_yaml_block_scalar(input, match_start, match_end) {
+        format_as_yaml_block_scalar(value, input, match_start)
+    } else {
+        indent_multiline_value(value, input, match_start)
+    }
+}
+
+/// Why a multi-line value cannot be written as valid YAML at `start..end` of `input`, if
+/// it cannot: the --indent heuristics only handle a whole value after `key:` / `- ` or a
+/// placeholder alone on its line (e.g. in block scalar content).
+fn multiline_placement_problem(input: &str, start: usize, end: usize) -> Option<&'static str> {
+    if in_yaml_flow_context(input, start) {
+        return Some("is inside a flow collection");
+    }
+    let line_start = input[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = input[end..].find('\n').map_or(input.len(), |i| end + i);
+    let prefix = input[line_start..start].trim();
+    let suffix = input[end..line_end].trim();
+    let item = prefix.strip_prefix('-').unwrap_or(prefix).trim();
+    if item.is_empty() && suffix.starts_with(':') {
+        return Some("is in key position");
+    }
+    let alone_on_line = prefix.is_empty() && suffix.is_empty();
+    if !alone_on_line && !should_use_yaml_block_scalar(input, start, end) {
+        return Some("shares its line with other text");
+    }
+    None
+}
+
+/// Whether byte `pos` of `input` lies inside a YAML flow sequence or mapping opened earlier
+/// on its line. Quoted text and other `{{ ... }}` placeholders on the line are skipped.
+fn in_yaml_flow_context(input: &str, pos: usize) -> bool {
+    let line_start = input[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let line = &input[line_start..pos];
+    let mut depth = 0usize;
+    let mut quote = None;
+    // As in `yaml_quote_context`, a quote only opens a scalar after one of `:-[{,?`.
+    let mut prev = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match (quote, c) {
+            (None, '{') if line[i..].starts_with("{{") => {
+                let end = line[i..].find("}}").map_or(line.len(), |e| i + e + 2);
+                while chars.peek().is_some_and(|&(j, _)| j < end) {
+                    chars.next();
+                }
+            }
+            (None, '#') if line[..i].ends_with([' ', '\t']) => break,
+            (None, '[' | '{') => depth += 1,
+            (None, ']' | '}') => depth = depth.saturating_sub(1),
+            (None, '"' | '\'') if prev.is_none_or(|p| ":-[{,?".contains(p)) => quote = Some(c),
+            (Some('"'), '\\') => {
+                chars.next();
+            }
+            (Some(q), _) if c == q => quote = None,
+            _ => {}
+        }
+        if quote.is_none() && !c.is_whitespace() {
+            prev = Some(c);
+        }
+    }
+    depth > 0
+}
+
+/// If the end of `text` (a rendered prefix ending at a placeholder) lies in the content of
+/// a folded block scalar, returns the byte offset of its `>` indicator.
+fn folded_scalar_header(text: &str) -> Option<usize> {
+    let line_start = text.rfind('\n').map_or(0, |i| i + 1);
+    let indent = leading_spaces(&text[line_start..]);
+    // The header is the first line above that is indented less than the placeholder's line.
+    let mut end = line_start.checked_sub(1)?;
+    loop {
+        let start = text[..end].rfind('\n').map_or(0, |i| i + 1);
+        let line = &text[start..end];
+        if !line.trim().is_empty() && leading_spaces(line) < indent {
+            let code = match line.find(" #") {
+                Some(i) => &line[..i],
+                None => line,
+            };
+            let code = code.trim_end();
+            let indicator =
+                code.trim_end_matches(['-', '+', '1', '2', '3', '4', '5', '6', '7', '8', '9']);
+            let header = indicator.strip_suffix('>')?;
+            let header = header.trim_end();
+            let owner = header.trim_start();
+            return (owner.ends_with(':') || owner == "-" || owner.is_empty())
+                .then_some(start + indicator.len() - 1);
+        }
+        end = start.checked_sub(1)?;
+    }
+}
+
+/// Adds a blank line before every non-blank continuation line, so that a folded scalar
+/// keeps the value's line breaks instead of joining its lines with spaces.
+fn unfold_line_breaks(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() * 2);
+    for (i, line) in value.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+            if !line.trim().is_empty() {
+                out.push('\n');
+            }
+        }
+        out.push_str(line);
+    }
+    out
+}
+
+fn should_use_yaml_block_scalar(input: &str, match_start: usize, match_end: usize) -> bool {
+    let line_start = input[..match_start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = input[match_end..]
+        .find('\n')
+        .map(|i| match_end + i)
+        .unwrap_or(input.len());
+
+    let prefix = &input[line_start..match_start];
+    let suffix = &input[match_end..line_end];
+    let prefix_trimmed = prefix.trim_end();
+    let suffix_trimmed = suffix.trim();
+
+    (prefix_trimmed.ends_with(':') || prefix_trimmed.ends_with('-')) && suffix_trimmed.is_empty()
+}
+
+fn format_as_yaml_block_scalar(value: &str, input: &str, match_start: usize) -> String {
+    let line_start = input[..match_start].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_prefix = &input[line_start..match_start];
+    let line_indent: String = line_prefix
+        .chars()
+        .take_while(|c| c.is_whitespace())
+        .collect();
+    let content_indent = format!("{line_indent}  ");
+
+    let indicator = if has_trailing_empty_lines(value) {
+        "|+"
+    } else {
+        "|"
+    };
+    let content = indent_every_line(value, &content_indent);
+    format!("{indicator}\n{content}")
+}
+
+fn has_trailing_empty_lines(value: &str) -> bool {
+    let mut trailing_newlines = 0usize;
+    for ch in value.chars().rev() {
+        if ch == '\n' {
+            trailing_newlines += 1;
+        } else {
+            break;
+        }
+    }
+    trailing_newlines > 1
+}
+
+fn indent_after_first_line(value: &str, indent: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    for (idx, part) in value.split_inclusive('\n').enumerate() {
+        if idx > 0 {
+            out.push_str(indent);
+        }
+        out.push_str(part);
+    }
+    out
+}
+
+fn indent_every_line(value: &str, indent: &str) -> String {
+    let mut out = String::new();
+    for part in value.split_inclusive('\n') {
+        if let Some(line) = part.strip_suffix('\n') {
+            out.push_str(indent);
+            out.push_str(line);
+            out.push('\n');
+        } else {
+            out.push_str(indent);
+            out.push_str(part);
+        }
+    }
+    out
+}
+
+/// Heuristic for `--conflict-policy prefer-longer`: when both values are multi-line and
+/// differ, returns `Some(true)` if the env value has more non-empty lines (ties keep the
+/// values file). Returns `None` when the heuristic does not apply.
+fn prefer_longer_multiline(env_val: &str, values_val: &str) -> Option<bool> {
+    if env_val == values_val || !env_val.contains('\n') || !values_val.contains('\n') {
+        return None;
+    }
+    let count = |s: &str| s.lines().filter(|l| !l.trim().is_empty()).count();
+    Some(count(env_val) > count(values_val))
+}
+
+fn resolve_env_from_values_file(
+    env_vars: &BTreeSet<String>,
+    yaml: &YamlValue,
+    key_case: KeyCase,
+    ignore_case: bool,
+) -> Result<(HashMap<String, String>, Vec<String>)> {
+    let mut env_map = HashMap::new();
+    let mut missing_paths = Vec::new();
+
+    for var in env_vars {
+        let path = env_var_values_path(var);
+        match lookup_env_values_key(yaml, var, key_case, ignore_case)? {
+            Some(v) => {
+                env_map.insert(var.clone(), yaml_value_to_string(v)?);
+            }
+            None => missing_paths.push(path),
+        }
+    }
+
+    Ok((env_map, missing_paths))
+}
+
+/// `environment.<name>` in the values document. With --env-ignore-case, if that key is missing,
+/// an `environment` key equal to `name` ignoring ASCII case is used instead; several such keys
+/// are ambiguous.
+fn lookup_env_values_key<'a>(
+    yaml: &'a YamlValue,
+    name: &str,
+    key_case: KeyCase,
+    ignore_case: bool,
+) -> Result<Option<&'a YamlValue>> {
+    let exact = lookup_yaml_path_cased(yaml, &env_var_values_path(name), key_case)?;
+    if exact.is_some() || !ignore_case {
+        return Ok(exact);
+    }
+    let Some(YamlValue::Mapping(environment)) =
+        lookup_yaml_path_cased(yaml, "environment", key_case)?
+    else {
+        return Ok(None);
+    };
+    let keys: Vec<String> = environment
+        .keys()
+        .filter_map(|k| k.as_str())
+        .filter(|k| k.eq_ignore_ascii_case(name))
+        .map(str::to_string)
+        .collect();
+    match keys.as_slice() {
+        [] => Ok(None),
+        [key] => Ok(environment.get(key.as_str())),
+        _ => bail!(
+            "ambiguous key 'environment.{name}': {} differ only in case (--env-ignore-case)",
+            keys.join(", ")
+        ),
+    }
+}
+
+/// --env-ignore-case: maps each env placeholder name that is not set as written to the OS
+/// variable equal to it ignoring ASCII case. If several variables match (e.g. `DATABASE_URL`
+/// and `database_url`), a warning is printed and the exact name, then the UPPER_CASE one, wins.
+#[doc(hidden)]
+pub fn env_names_ignoring_case(
+    names: &BTreeSet<String>,
+    os_names: &[String],
+    quiet: bool,
+) -> HashMap<String, String> {
+    let mut aliases = HashMap::new();
+    let mut warned = BTreeSet::new();
+    for name in names {
+        let mut matches: Vec<String> = os_names
+            .iter()
+            .filter(|os| os.eq_ignore_ascii_case(name))
+            .cloned()
+            .collect();
+        matches.sort();
+        let Some(chosen) = pick_case_insensitive_match(name, &matches) else {
+            continue;
+        };
+        if matches.len() > 1 && warned.insert(matches.join(", ")) {
+            warn(
+                quiet,
+                &format!(
+                    "env {name}: variables {} differ only in case; using {chosen}",
+                    matches.join(", ")
+                ),
+            );
+        }
+        if chosen != *name {
+            aliases.insert(name.clone(), chosen);
+        }
+    }
+    aliases
+}
+
+/// Of names that equal `name` ignoring case: `name` itself, else its UPPER_CASE form, else the first.
+fn pick_case_insensitive_match(name: &str, matches: &[String]) -> Option<String> {
+    let upper = name.to_ascii_uppercase();
+    matches
+        .iter()
+        .find(|m| *m == name)
+        .or_else(|| matches.iter().find(|m| **m == upper))
+        .or_else(|| matches.first())
+        .cloned()
+}
+
+/// The OS value of env placeholder `name`, read from the variable --env-ignore-case matched it to.
+#[doc(hidden)]
+pub fn os_env_var(name: &str, os_env_names: &HashMap<String, String>) -> Option<OsString> {
+    env::var_os(os_env_names.get(name).map_or(name, String::as_str))
+}
+
+/// Like `prompt_for_yaml_key`, but asks for the value twice and re-prompts on mismatch.
+/// Accepting a default with an empty entry skips the confirmation; an empty entry for a key
+/// without a default is confirmed like any other value.
+fn prompt_for_yaml_key_confirmed(
+    path: &str,
+    default: Option<&str>,
+    context: Option<&str>,
+    hidden: bool,
+) -> Result<YamlValue> {
+    loop {
+        let (value, entered) = prompt_for_yaml_key_entry(path, default, context, hidden)?;
+        if entered.is_empty() && default.is_some() {
+            return Ok(value);
+        }
+        let confirm = read_prompt_line(
+            &format!("Confirm value for values file key {path}: "),
+            hidden,
+        )?;
+        if confirm == entered {
+            return Ok(value);
+        }
+        eprintln!("Values do not match, please try again.");
+    }
+}
+
+/// Reads one line from stdin after printing `prompt`; with `hidden`, terminal echo is
+/// switched off while the line is typed. Fails when stdin is exhausted instead of
+/// treating end of input as an empty answer.
+fn read_prompt_line(prompt: &str, hidden: bool) -> Result<String> {
+    let mut err = io::stderr().lock();
+    err.write_all(prompt.as_bytes())?;
+    err.flush()?;
+
+    // Piped answers are not echoed anyway, so only a terminal needs `stty`.
+    let hide = hidden && io::stdin().is_terminal();
+    let echo_off = hide && set_terminal_echo(false);
+    if hide && !echo_off {
+        warn(
+            false,
+            "could not turn off terminal echo; the value will be visible",
+        );
+    }
+    let mut line = String::new();
+    let read = io::stdin().read_line(&mut line);
+    if echo_off {
+        set_terminal_echo(true);
+        // The Enter key was not echoed either.
+        err.write_all(b"\n")?;
+    }
+    if read? == 0 {
+        bail!(
+            "stdin has no more input while prompting: {}",
+            prompt.trim_end()
+        );
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// Whether stdin has no more input; blocks until a piped writer sends data or closes it.
+#[doc(hidden)]
+pub fn stdin_at_eof() -> io::Result<bool> {
+    Ok(io::stdin().lock().fill_buf()?.is_empty())
+}
+
+/// Switches echo on the controlling terminal via `stty`; returns whether it worked.
+fn set_terminal_echo(on: bool) -> bool {
+    std::process::Command::new("stty")
+        .arg(if on { "echo" } else { "-echo" })
+        .stdin(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(false)
+}
+
+fn prompt_for_yaml_key(
+    path: &str,
+    default: Option<&str>,
+    context: Option<&str>,
+    hidden: bool,
+) -> Result<YamlValue> {
+    Ok(prompt_for_yaml_key_entry(path, default, context, hidden)?.0)
+}
+
+/// The question asked for a values file key; a hidden key's default is never shown.
+fn yaml_key_prompt(path: &str, default: Option<&str>, hidden: bool) -> String {
+    let mut prompt = format!("Enter value for values file key {path}");
+    match default {
+        Some(_) if hidden => prompt.push_str(&format!(" [{TRACE_REDACTED}]")),
+        Some(default_text) => prompt.push_str(&format!(" [{default_text}]")),
+        None => {}
+    }
+    prompt.push_str(": ");
+    prompt
+}
+
+/// Prompt once; returns the chosen value and the raw text that was typed.
+fn prompt_for_yaml_key_entry(
+    path: &str,
+    default: Option<&str>,
+    context: Option<&str>,
+    hidden: bool,
+) -> Result<(YamlValue, String)> {
+    let prompt = yaml_key_prompt(path, default, hidden);
+    print_prompt_context(context)?;
+    let entered = read_prompt_line(&prompt, hidden)?;
+
+    if entered.is_empty() {
+        if let Some(v) = default {
+            return Ok((YamlValue::String(v.to_string()), entered));
+        }
+        return Ok((YamlValue::String(String::new()), entered));
+    }
+
+    Ok((YamlValue::String(entered.clone()), entered))
+}
+
+fn print_prompt_context(context: Option<&str>) -> Result<()> {
+    if let Some(ctx) = context {
+        let mut err = io::stderr().lock();
+        err.write_all(b"\n")?;
+        err.write_all(ctx.as_bytes())?;
+        err.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Ends a --prompt-multiline value.
+const MULTILINE_TERMINATOR: &str = "EOF";
+
+fn prompt_for_yaml_key_multiline(
+    path: &str,
+    default: Option<&str>,
+    context: Option<&str>,
+) -> Result<YamlValue> {
+    print_prompt_context(context)?;
+    let keep = if default.is_some() {
+        "; an empty first line keeps the current value"
+    } else {
+        ""
+    };
+    eprintln!(
+        "Enter value for values file key {path}, then a line with only {MULTILINE_TERMINATOR}{keep}:"
+    );
+    let entered = read_multiline_value(&mut io::stdin().lock())?;
+    Ok(YamlValue::String(entered.unwrap_or_else(|| {
+        default.unwrap_or_default().to_string()
+    })))
+}
+
+/// Like `git commit`, opens $EDITOR on a temp file holding the default and uses the saved
+/// text. Falls back to the line prompt with a warning if $EDITOR is unset or fails.
+fn prompt_for_yaml_key_in_editor(
+    path: &str,
+    default: Option<&str>,
+    context: Option<&str>,
+) -> Result<YamlValue> {
+    let editor = env::var("EDITOR").unwrap_or_default();
+    if editor.trim().is_empty() {
+        warn(
+            false,
+            &format!("$EDITOR is not set; prompting for {path} instead"),
+        );
+        return prompt_for_yaml_key(path, default, context, false);
+    }
+    print_prompt_context(context)?;
+    eprintln!("Editing values file key {path} in {editor}...");
+    match edit_value_with(&editor, path, default.unwrap_or_default()) {
+        Ok(Some(value)) => Ok(YamlValue::String(value)),
+        Ok(None) => Ok(YamlValue::String(default.unwrap_or_default().to_string())),
+        Err(e) => {
+            warn(false, &format!("{e:#}; prompting for {path} instead"));
+            prompt_for_yaml_key(path, default, context, false)
+        }
+    }
+}
+
+/// Runs `editor` (through `sh`, so it may carry arguments) on a temp file holding `initial`
+/// and returns the saved text without its final line break. `None` if it was left empty.
+fn edit_value_with(editor: &str, key: &str, initial: &str) -> Result<Option<String>> {
+    let name: String = key
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let file = env::temp_dir().join(format!("tplenv-edit-{}-{name}.txt", std::process::id()));
+    let mut text = initial.to_string();
+    if !text.is_empty() && !text.ends_with('\n') {
+        text.push('\n');
+    }
+    write_private_file(&file, text.as_bytes())?;
+    let status = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{editor} \"$1\""))
+        .arg("sh")
+        .arg(&file)
+        .status();
+    let edited = fs::read_to_string(&file);
+    let _ = fs::remove_file(&file);
+    let status = status.with_context(|| format!("failed to run editor '{editor}'"))?;
+    if !status.success() {
+        bail!("editor '{editor}' exited with {status}");
+    }
+    let edited = edited.with_context(|| format!("failed to read {}", file.display()))?;
+    let value = edited
+        .strip_suffix('\n')
+        .map(|v| v.strip_suffix('\r').unwrap_or(v))
+        .unwrap_or(&edited);
+    Ok((!value.trim().is_empty()).then(|| value.to_string()))
+}
+
+/// Reads lines up to a line containing only `MULTILINE_TERMINATOR` (or end of input) and
+/// joins them with `\n`. `None` if the first line is empty, meaning "keep the default".
+fn read_multiline_value(reader: &mut impl io::BufRead) -> Result<Option<String>> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 0 {
+        let text = line.trim_end_matches(['\r', '\n']);
+        if text == MULTILINE_TERMINATOR {
+            break;
+        }
+        if lines.is_empty() && text.is_empty() {
+            return Ok(None);
+        }
+        lines.push(text.to_string());
+        line.clear();
+    }
+    if lines.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(lines.join("\n")))
+}
+
+#[doc(hidden)]
+pub fn set_yaml_path(root: &mut YamlValue, path: &str, value: YamlValue) {
+    let parts = split_yaml_path(path);
+    let mut cur = root;
+    let mut value_opt = Some(value);
+
+    for (idx, part) in parts.iter().enumerate() {
+        let is_last = idx == parts.len() - 1;
+        let (key, selector) = parse_path_selector(part);
+        if !matches!(cur, YamlValue::Mapping(_)) {
+            *cur = YamlValue::Mapping(YamlMapping::new());
+        }
+        let map = cur.as_mapping_mut().expect("cur is a mapping");
+        let key = YamlValue::String(key.to_string());
+
+        let Some(selector) = selector else {
+            if is_last {
+                if let Some(v) = value_opt.take() {
+                    map.insert(key, v);
+                }
+                return;
+            }
+            cur = map
+                .entry(key)
+                .or_insert_with(|| YamlValue::Mapping(YamlMapping::new()));
+            continue;
+        };
+
+        // `key[field=value]` descends into the matching list element, appending
+        // `{field: value}` if there is none; `key[N]` pads the list with nulls up to N.
+        let list = map
+            .entry(key)
+            .or_insert_with(|| YamlValue::Sequence(Vec::new()));
+        if !matches!(list, YamlValue::Sequence(_)) {
+            *list = YamlValue::Sequence(Vec::new());
+        }
+        let items = list.as_sequence_mut().expect("list is a sequence");
+        let pos = match selector {
+            PathSelector::Index(index) => {
+                if items.len() <= index {
+                    items.resize(index + 1, YamlValue::Null);
+                }
+                index
+            }
+            PathSelector::Field(field, field_value) => match items
+                .iter()
+                .position(|item| element_has_field(item, field, field_value))
+            {
+                Some(pos) => pos,
+                None => {
+                    let mut element = YamlMapping::new();
+                    element.insert(
+                        YamlValue::String(field.to_string()),
+                        YamlValue::String(field_value.to_string()),
+                    );
+                    items.push(YamlValue::Mapping(element));
+                    items.len() - 1
+                }
+            },
+        };
+        if is_last {
+            if let Some(v) = value_opt.take() {
+                items[pos] = v;
+            }
+            return;
+        }
+        cur = &mut items[pos];
+    }
+}
+
+/// Why `lookup_yaml_path_detailed` could not reach a values path.
+#[derive(Debug, PartialEq)]
+enum YamlLookupError {
+    /// The key `missing` does not exist in the mapping at `parent` (empty for the root).
+    Missing { parent: String, missing: String },
+    /// The node at `parent` is not a mapping, so `next` cannot be looked up inside it.
+    NotMapping {
+        parent: String,
+        kind: &'static str,
+        next: String,
+    },
+    /// A `[field=value]` or `[N]` selector was applied to a node at `parent` that is not a
+    /// sequence.
+    NotSequence {
+        parent: String,
+        kind: &'static str,
+        selector: String,
+    },
+    /// No element of the sequence at `parent` matches the selector.
+    NoElement { parent: String, selector: String },
+    /// `[index]` is past the end of the sequence at `parent`, which has `len` elements.
+    OutOfRange {
+        parent: String,
+        index: usize,
+        len: usize,
+    },
+    /// Several elements match the selector (only reported by the strict lookup).
+    Ambiguous {
+        parent: String,
+        selector: String,
+        count: usize,
+    },
+}
+
+impl std::fmt::Display for YamlLookupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            YamlLookupError::Missing { parent, missing } if parent.is_empty() => {
+                write!(f, ".Values.{missing} is not set")
+            }
+            YamlLookupError::Missing { parent, missing } => {
+                write!(f, ".Values.{parent} has no key {missing}")
+            }
+            YamlLookupError::NotMapping { parent, kind, next } => {
+                write!(f, ".Values.{parent} is {kind}, cannot access .{next}")
+            }
+            YamlLookupError::NotSequence {
+                parent,
+                kind,
+                selector,
+            } => write!(f, ".Values.{parent} is {kind}, cannot select [{selector}]"),
+            YamlLookupError::NoElement { parent, selector } => {
+                write!(f, ".Values.{parent} has no element with {selector}")
+            }
+            YamlLookupError::OutOfRange { parent, index, len } => write!(
+                f,
+                ".Values.{parent}[{index}] is out of range: .Values.{parent} has {len} element(s)"
+            ),
+            YamlLookupError::Ambiguous {
+                parent,
+                selector,
+                count,
+            } => write!(f, ".Values.{parent} has {count} elements with {selector}"),
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn yaml_kind(v: &YamlValue) -> &'static str {
+    match v {
+        YamlValue::Null | YamlValue::Bool(_) | YamlValue::Number(_) | YamlValue::String(_) => {
+            "a scalar"
+        }
+        YamlValue::Sequence(_) => "a sequence",
+        YamlValue::Mapping(_) => "a mapping",
+        YamlValue::Tagged(_) => "a tagged value",
+    }
+}
+
+fn lookup_yaml_path_detailed<'a>(
+    root: &'a YamlValue,
+    path: &str,
+) -> Result<&'a YamlValue, YamlLookupError> {
+    walk_yaml_path(root, path, false)
+}
+
+/// Walks a values path; a `[field=value]` selector picks the first matching element,
+/// or fails with `Ambiguous` when `unique` is set and several elements match. `[N]` picks
+/// the element at position N.
+fn walk_yaml_path<'a>(
+    root: &'a YamlValue,
+    path: &str,
+    unique: bool,
+) -> Result<&'a YamlValue, YamlLookupError> {
+    let mut cur = root;
+    let mut walked: Vec<String> = Vec::new();
+    for part in split_yaml_path(path) {
+        let (key, selector) = parse_path_selector(&part);
+        match cur {
+            YamlValue::Mapping(map) => {
+                cur = map.get(key).ok_or_else(|| YamlLookupError::Missing {
+                    parent: join_yaml_path(&walked),
+                    missing: key.to_string(),
+                })?;
+            }
+            other => {
+                return Err(YamlLookupError::NotMapping {
+                    parent: join_yaml_path(&walked),
+                    kind: yaml_kind(other),
+                    next: key.to_string(),
+                });
+            }
+        }
+        if let Some(selector) = selector {
+            let parent = join_yaml_path(&[walked.clone(), vec![key.to_string()]].concat());
+            let YamlValue::Sequence(items) = cur else {
+                return Err(YamlLookupError::NotSequence {
+                    parent,
+                    kind: yaml_kind(cur),
+                    selector: selector.to_string(),
+                });
+            };
+            let (field, value) = match selector {
+                PathSelector::Index(index) => {
+                    cur = items.get(index).ok_or(YamlLookupError::OutOfRange {
+                        parent,
+                        index,
+                        len: items.len(),
+                    })?;
+                    walked.push(part);
+                    continue;
+                }
+                PathSelector::Field(field, value) => (field, value),
+            };
+            let selector = selector.to_string();
+            let mut matches = items
+                .iter()
+                .filter(|item| element_has_field(item, field, value));
+            let Some(first) = matches.next() else {
+                return Err(YamlLookupError::NoElement { parent, selector });
+            };
+            let extra = matches.count();
+            if unique && extra > 0 {
+                return Err(YamlLookupError::Ambiguous {
+                    parent,
+                    selector,
+                    count: extra + 1,
+                });
+            }
+            cur = first;
+        }
+        walked.push(part);
+    }
+    Ok(cur)
+}
+
+/// Remove the leaf at `path` and prune parent mappings left empty. Returns whether anything was removed.
+fn delete_yaml_path(root: &mut YamlValue, path: &str) -> bool {
+    fn delete_parts(node: &mut YamlValue, parts: &[String]) -> bool {
+        let YamlValue::Mapping(map) = node else {
+            return false;
+        };
+        let (key, selector) = parse_path_selector(&parts[0]);
+        let key = YamlValue::String(key.to_string());
+        if let Some(selector) = selector {
+            // Selected list elements are removed or descended into, but never pruned.
+            let Some(YamlValue::Sequence(items)) = map.get_mut(&key) else {
+                return false;
+            };
+            let pos = match selector {
+                PathSelector::Index(index) => (index < items.len()).then_some(index),
+                PathSelector::Field(field, value) => items
+                    .iter()
+                    .position(|item| element_has_field(item, field, value)),
+            };
+            let Some(pos) = pos else {
+                return false;
+            };
+            if parts.len() == 1 {
+                items.remove(pos);
+                return true;
+            }
+            return delete_parts(&mut items[pos], &parts[1..]);
+        }
+        if parts.len() == 1 {
+            return map.remove(&key).is_some();
+        }
+        let Some(child) = map.get_mut(&key) else {
+            return false;
+        };
+        let removed = delete_parts(child, &parts[1..]);
+        if removed && matches!(child, YamlValue::Mapping(m) if m.is_empty()) {
+            map.remove(&key);
+        }
+        removed
+    }
+
+    delete_parts(root, &split_yaml_path(path))
+}
+
+/// `lookup_yaml_path` with --values-key-case matching applied to each path segment.
+/// Fails if two keys of one mapping match the same segment.
+fn lookup_yaml_path_cased<'a>(
+    root: &'a YamlValue,
+    path: &str,
+    key_case: KeyCase,
+) -> Result<Option<&'a YamlValue>> {
+    if key_case == KeyCase::Exact {
+        return Ok(lookup_yaml_path(root, path));
+    }
+    let mut cur = root;
+    for part in split_yaml_path(path) {
+        let YamlValue::Mapping(map) = cur else {
+            return Ok(None);
+        };
+        let (part, selector) = parse_path_selector(&part);
+        let mut found = map
+            .iter()
+            .filter(|(k, _)| k.as_str().is_some_and(|k| key_matches(k, part, key_case)));
+        match (found.next(), found.next()) {
+            (None, _) => return Ok(None),
+            (Some((_, value)), None) => cur = value,
+            (Some((a, _)), Some((b, _))) => bail!(
+                "ambiguous key '{part}' in {path}: both '{}' and '{}' match",
+                a.as_str().unwrap_or_default(),
+                b.as_str().unwrap_or_default()
+            ),
+        }
+        if let Some(selector) = selector {
+            let YamlValue::Sequence(items) = cur else {
+                return Ok(None);
+            };
+            match select_element(items, selector) {
+                Some(item) => cur = item,
+                None => return Ok(None),
+            }
+        }
+    }
+    Ok(Some(cur))
+}
+
+/// Whether values file key `key` matches the path segment `segment` under `key_case`.
+fn key_matches(key: &str, segment: &str, key_case: KeyCase) -> bool {
+    match key_case {
+        // One-way transforms: only the segment is converted, and snake_case takes no part,
+        // so `app-name` finds `appName` but not `app_name`.
+        KeyCase::KebabToCamel => {
+            key == segment
+                || (!segment.contains('_') && key == normalize_key(segment, KeyCase::Camel))
+        }
+        KeyCase::CamelToKebab => {
+            key == segment
+                || (!segment.contains('_') && key == normalize_key(segment, KeyCase::Kebab))
+        }
+        _ => normalize_key(key, key_case) == normalize_key(segment, key_case),
+    }
+}
+
+fn normalize_key(key: &str, key_case: KeyCase) -> String {
+    match key_case {
+        KeyCase::Exact | KeyCase::KebabToCamel | KeyCase::CamelToKebab => key.to_string(),
+        KeyCase::Snake => {
+            let mut out = String::with_capacity(key.len() + 4);
+            for (i, c) in key.chars().enumerate() {
+                if c == '-' {
+                    out.push('_');
+                } else if c.is_uppercase() {
+                    if i > 0 && !out.ends_with('_') {
+                        out.push('_');
+                    }
+                    out.extend(c.to_lowercase());
+                } else {
+                    out.push(c);
+                }
+            }
+            out
+        }
+        KeyCase::Camel => {
+            let mut out = String::with_capacity(key.len());
+            let mut upper_next = false;
+            for c in key.chars() {
+                if c == '_' || c == '-' {
+                    upper_next = !out.is_empty();
+                } else if upper_next {
+                    out.extend(c.to_uppercase());
+                    upper_next = false;
+                } else {
+                    out.push(c);
+                }
+            }
+            out
+        }
+        KeyCase::Kebab => normalize_key(key, KeyCase::Snake).replace('_', "-"),
+        KeyCase::Insensitive => key
+            .chars()
+            .filter(|c| *c != '_' && *c != '-')
+            .flat_map(char::to_lowercase)
+            .collect(),
+    }
+}
+
+fn lookup_yaml_path<'a>(root: &'a YamlValue, path: &str) -> Option<&'a YamlValue> {
+    // path like "foo.bar.baz"
+    lookup_yaml_path_detailed(root, path).ok()
+}
+
+#[doc(hidden)]
+pub fn yaml_value_to_string(v: &YamlValue) -> Result<String> {
+    Ok(match v {
+        YamlValue::Null => "".to_string(),
+        YamlValue::Bool(b) => b.to_string(),
+        YamlValue::Number(n) => n.to_string(),
+        YamlValue::String(s) => s.clone(),
+        // For sequences/maps, serialize to YAML (trim trailing newline).
+        other => serde_yaml::to_string(other)?.trim_end().to_string(),
+    })
+}
+
+/// Keys whose value is a secret: those from --secrets-file and those matching --secret-key.
+#[doc(hidden)]
+pub fn secret_keys_matching(res: &Resolution, patterns: &[Regex]) -> BTreeSet<String> {
+    let mut keys = res.secret_keys();
+    keys.extend(
+        res.sources
+            .keys()
+            .filter(|key| patterns.iter().any(|p| p.is_match(key)))
+            .cloned(),
+    );
+    keys
+}
+
+/// Warns that `secrets` were written to `path` if it may end up in a git commit.
+#[doc(hidden)]
+pub fn warn_if_secrets_committable(path: &Path, secrets: &BTreeSet<String>, quiet: bool) {
+    if secrets.is_empty() || path.to_string_lossy() == "-" || !may_be_committed(path) {
+        return;
+    }
+    let keys: Vec<String> = secrets.iter().map(|k| display_values_key(k)).collect();
+    warn(
+        quiet,
+        &format!(
+            "{} holds secret values ({}) but no .gitignore covers it; they may be committed",
+            path.display(),
+            keys.join(", ")
+        ),
+    );
+}
+
+/// Heuristic: `path` lies in a git work tree (an ancestor directory has `.git`) and no
+/// `.gitignore` from the work tree root down to the file ignores it or one of its parent
+/// directories. Global excludes, `.git/info/exclude` and the index are not consulted.
+fn may_be_committed(path: &Path) -> bool {
+    let Ok(cwd) = env::current_dir() else {
+        return false;
+    };
+    let abs = cwd.join(path);
+    let abs = match (abs.parent().map(fs::canonicalize), abs.file_name()) {
+        (Some(Ok(dir)), Some(name)) => dir.join(name),
+        _ => abs,
+    };
+    let Some(root) = abs
+        .ancestors()
+        .skip(1)
+        .find(|dir| dir.join(".git").exists())
+    else {
+        return false;
+    };
+    let Ok(rel) = abs.strip_prefix(root) else {
+        return false;
+    };
+    let parts: Vec<String> = rel
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    // ignored[i]: whether `parts[..=i]` is ignored; deeper files and later lines win.
+    let mut ignored = vec![false; parts.len()];
+    for depth in 0..parts.len() {
+        let dir: PathBuf = root.join(parts[..depth].iter().collect::<PathBuf>());
+        let Ok(text) = fs::read_to_string(dir.join(".gitignore")) else {
+            continue;
+        };
+        for rule in text.lines().filter_map(parse_gitignore_rule) {
+            for i in depth..parts.len() {
+                if rule.matches(&parts[depth..=i], i + 1 < parts.len()) {
+                    ignored[i] = !rule.negated;
+                }
+            }
+        }
+    }
+    // Like git, a file inside an ignored directory cannot be re-included.
+    !ignored.contains(&true)
+}
+
+struct GitignoreRule {
+    regex: Regex,
+    negated: bool,
+    dir_only: bool,
+    /// A pattern with a `/` (other than a trailing one) matches from the .gitignore's
+    /// directory; otherwise it matches a file or directory name at any depth.
+    anchored: bool,
+}
+
+impl GitignoreRule {
+    fn matches(&self, parts: &[String], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.regex.is_match(&parts.join("/"))
+        } else {
+            parts.last().is_some_and(|name| self.regex.is_match(name))
+        }
+    }
+}
+
+/// Parses one .gitignore line (`*`, `?`, `**` and `!` negation; no `[...]` classes).
+fn parse_gitignore_rule(line: &str) -> Option<GitignoreRule> {
+    let line = line.trim_end();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let (negated, pattern) = match line.strip_prefix('!') {
+        Some(rest) => (true, rest),
+        None => (false, line),
+    };
+    let (dir_only, pattern) = match pattern.strip_suffix('/') {
+        Some(rest) => (true, rest),
+        None => (false, pattern),
+    };
+    let anchored = pattern.contains('/');
+    let mut rest = pattern.trim_start_matches('/');
+    if rest.is_empty() {
+        return None;
+    }
+    let mut re = String::from("^");
+    while let Some(c) = rest.chars().next() {
+        if let Some(tail) = rest.strip_prefix("**/") {
+            re.push_str("(?:.*/)?");
+            rest = tail;
+            continue;
+        }
+        if let Some(tail) = rest.strip_prefix("/**") {
+            re.push_str("/.*");
+            rest = tail;
+            continue;
+        }
+        match c {
+            '*' => re.push_str("[^/]*"),
+            '?' => re.push_str("[^/]"),
+            _ => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+        rest = &rest[c.len_utf8()..];
+    }
+    re.push('$');
+    Some(GitignoreRule {
+        regex: Regex::new(&re).ok()?,
+        negated,
+        dir_only,
+        anchored,
+    })
+}
+
+#[doc(hidden)]
+pub fn write_output(output: Option<&PathBuf>, bytes: &[u8]) -> Result<()> {
+    match output.map(|p| p.as_path()) {
+        None => {
+            let mut out = io::stdout().lock();
+            out.write_all(bytes)?;
+        }
+        Some(p) if p.to_string_lossy() == "-" => {
+            let mut out = io::stdout().lock();
+            out.write_all(bytes)?;
+        }
+        Some(p) => {
+            fs::write(p, bytes)
+                .with_context(|| format!("failed to write output file: {}", p.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// `--inplace` guard: the file must be tracked by git and have no uncommitted changes.
+#[doc(hidden)]
+pub fn ensure_git_clean(path: &Path) -> Result<()> {
+    let file_name = path.file_name().unwrap_or(path.as_os_str());
+    let output = std::process::Command::new("git")
+        .arg("-C")
+        .arg(template_dir(path))
+        .args(["status", "--porcelain", "--ignored", "--"])
+        .arg(file_name)
+        .output()
+        .context("failed to run git to check --inplace targets (use --force to skip the check)")?;
+    if !output.status.success() {
+        bail!(
+            "--inplace: {} is not inside a git work tree; use --force to overwrite it anyway",
+            path.display()
+        );
+    }
+    if !String::from_utf8_lossy(&output.stdout).trim().is_empty() {
+        bail!(
+            "--inplace: {} is untracked or has uncommitted changes; use --force to overwrite it anyway",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Writes each rendered file back over its template, optionally keeping `<file>.bak`.
+#[doc(hidden)]
+pub fn write_inplace(
+    rendered: &[(PathBuf, String)],
+    trailing_newline: TrailingNewline,
+    backup: bool,
+    verbose: bool,
+) -> Result<()> {
+    for (path, content) in rendered {
+        if backup {
+            let mut backup_path = path.clone().into_os_string();
+            backup_path.push(".bak");
+            fs::copy(path, &backup_path).with_context(|| {
+                format!(
+                    "failed to write backup: {}",
+                    Path::new(&backup_path).display()
+                )
+            })?;
+        }
+        fs::write(
+            path,
+            apply_trailing_newline(content, trailing_newline).as_bytes(),
+        )
+        .with_context(|| format!("failed to write file: {}", path.display()))?;
+        if verbose {
+            eprintln!("rewrote {}", path.display());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[doc(hidden)]
+pub enum TrailingNewline {
+    /// Single files keep the template's ending; streams end with one newline
+    Keep,
+    /// --no-trailing-newline
+    Strip,
+    /// --ensure-trailing-newline
+    Ensure,
+}
+
+fn apply_trailing_newline(content: &str, mode: TrailingNewline) -> Cow<'_, str> {
+    match mode {
+        TrailingNewline::Keep => Cow::Borrowed(content),
+        TrailingNewline::Strip => Cow::Borrowed(content.trim_end_matches(['\n', '\r'])),
+        TrailingNewline::Ensure => {
+            Cow::Owned(format!("{}\n", content.trim_end_matches(['\n', '\r'])))
+        }
+    }
+}
+
+#[doc(hidden)]
+pub struct OutputOptions<'a> {
+    pub trailing_newline: TrailingNewline,
+    pub encoding: Option<OutputEncoding>,
+    /// Character encoding the text is transcoded to before --encode-output applies.
+    pub charset: Charset,
+    /// Line width for encoded output; 0 keeps it on one line.
+    pub wrap: usize,
+    pub format: TemplateFormat,
+    pub doc_separator: &'a str,
+    pub leading_separator: bool,
+    /// --output-jsonl: one compact JSON object per rendered document.
+    pub json_lines: bool,
+    /// --output-format json: the rendered documents as pretty-printed JSON.
+    pub json: bool,
+}
+
+/// The --output destinations in command-line order, without repeats.
+#[doc(hidden)]
+pub fn output_destinations(outputs: &[PathBuf]) -> Vec<&PathBuf> {
+    let mut seen = BTreeSet::new();
+    outputs
+        .iter()
+        .filter(|p| seen.insert(p.as_path()))
+        .collect()
+}
+
+/// Writes the rendered stream to every --output destination (stdout if there is none).
+#[doc(hidden)]
+pub fn write_outputs(
+    outputs: &[PathBuf],
+    rendered: &[(PathBuf, String)],
+    opts: &OutputOptions,
+) -> Result<()> {
+    let content = if opts.json_lines {
+        Cow::Owned(render_json_lines(rendered)?)
+    } else if opts.json {
+        Cow::Owned(render_json_documents(rendered)?)
+    } else if rendered.len() == 1 {
+        Cow::Borrowed(rendered[0].1.as_str())
+    } else if opts.format == TemplateFormat::Json {
+        Cow::Owned(render_json_array(rendered))
+    } else {
+        Cow::Owned(render_multi_document_yaml(
+            rendered,
+            opts.doc_separator,
+            opts.leading_separator,
+        ))
+    };
+    let content = apply_trailing_newline(&content, opts.trailing_newline);
+    let content = encode_charset(&content, opts.charset)?;
+    let bytes = match opts.encoding {
+        None => content,
+        Some(OutputEncoding::Base64) => {
+            let encoded = wrap_columns(&base64_encode(&content), opts.wrap);
+            format!("{encoded}\n").into_bytes()
+        }
+    };
+    if outputs.is_empty() {
+        return write_output(None, &bytes);
+    }
+    for output in output_destinations(outputs) {
+        write_output(Some(output), &bytes)?;
+    }
+    Ok(())
+}
+
+/// Transcodes rendered text for --output-encoding. Characters the charset cannot represent
+/// are an error rather than being replaced.
+fn encode_charset(text: &str, charset: Charset) -> Result<Vec<u8>> {
+    Ok(match charset {
+        Charset::Utf8 => text.as_bytes().to_vec(),
+        Charset::Utf16le => [0xFF, 0xFE]
+            .into_iter()
+            .chain(text.encode_utf16().flat_map(u16::to_le_bytes))
+            .collect(),
+        Charset::Utf16be => [0xFE, 0xFF]
+            .into_iter()
+            .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+            .collect(),
+        Charset::Latin1 => {
+            let mut bytes = Vec::with_capacity(text.len());
+            for (pos, c) in text.char_indices() {
+                let Ok(b) = u8::try_from(c) else {
+                    let line = text[..pos].matches('\n').count() + 1;
+                    bail!(
+                        "output line {line}: '{c}' (U+{:04X}) cannot be encoded as latin1 (--output-encoding)",
+                        u32::from(c)
+                    );
+                };
+                bytes.push(b);
+            }
+            bytes
+        }
+    })
+}
+
+/// Decodes a template read with --input-encoding. Invalid input is an error, never replaced.
+#[doc(hidden)]
+pub fn decode_charset(bytes: Vec<u8>, charset: Charset) -> Result<String> {
+    let utf16 = |bom: [u8; 2], unit: fn([u8; 2]) -> u16, name: &str| -> Result<String> {
+        let data = bytes.strip_prefix(&bom).unwrap_or(&bytes);
+        if !data.len().is_multiple_of(2) {
+            bail!("invalid {name}: odd number of bytes (--input-encoding)");
+        }
+        let units: Vec<u16> = data.chunks(2).map(|b| unit([b[0], b[1]])).collect();
+        String::from_utf16(&units)
+            .map_err(|_| anyhow::anyhow!("invalid {name}: unpaired surrogate (--input-encoding)"))
+    };
+    match charset {
+        Charset::Utf8 => String::from_utf8(bytes).map_err(|e| {
+            anyhow::anyhow!(
+                "invalid UTF-8 at byte {} (see --input-encoding)",
+                e.utf8_error().valid_up_to()
+            )
+        }),
+        Charset::Utf16le => utf16([0xFF, 0xFE], u16::from_le_bytes, "UTF-16LE"),
+        Charset::Utf16be => utf16([0xFE, 0xFF], u16::from_be_bytes, "UTF-16BE"),
+        Charset::Latin1 => Ok(bytes.iter().map(|&b| char::from(b)).collect()),
+    }
+}
+
+/// Names the files for `documents` split into chunks of `chunk_size`: `chunk-001.yaml`, ...
+/// (wider numbers if there are more than 999 chunks).
+fn chunk_file_names(documents: usize, chunk_size: u64, format: TemplateFormat) -> Vec<String> {
+    let size = usize::try_from(chunk_size).unwrap_or(usize::MAX);
+    let chunks = documents.div_ceil(size);
+    let width = chunks.to_string().len().max(3);
+    let ext = match format {
+        TemplateFormat::Yaml => "yaml",
+        TemplateFormat::Json => "json",
+    };
+    (1..=chunks)
+        .map(|n| format!("chunk-{n:0width$}.{ext}"))
+        .collect()
+}
+
+/// --output-dir: writes each rendered template to `dir/<its file name>`, or to
+/// `dir/<its path below nested_root>` for a `**` --file-pattern, or with --chunk-size numbered
+/// chunks of templates; each file is written like a complete --output. Returns the files
+/// written.
+#[doc(hidden)]
+pub fn write_output_dir(
+    dir: &Path,
+    rendered: &[(PathBuf, String)],
+    chunk_size: Option<u64>,
+    nested_root: Option<&Path>,
+    opts: &OutputOptions,
+) -> Result<Vec<PathBuf>> {
+    fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create directory: {}", dir.display()))?;
+    let mut written = Vec::new();
+    if let Some(chunk_size) = chunk_size {
+        let names = chunk_file_names(rendered.len(), chunk_size, opts.format);
+        let size = usize::try_from(chunk_size).unwrap_or(usize::MAX);
+        for (chunk, name) in rendered.chunks(size).zip(names) {
+            let path = dir.join(name);
+            write_outputs(std::slice::from_ref(&path), chunk, opts)?;
+            written.push(path);
+        }
+        return Ok(written);
+    }
+    let mut sources: HashMap<PathBuf, &Path> = HashMap::new();
+    let mut paths = Vec::new();
+    for (source, _) in rendered {
+        let nested = nested_root.and_then(|root| source.strip_prefix(root).ok());
+        let Some(name) = nested.or_else(|| source.file_name().map(Path::new)) else {
+            bail!(
+                "cannot name an --output-dir file after {}",
+                source.display()
+            );
+        };
+        let path = dir.join(name);
+        if let Some(first) = sources.insert(path.clone(), source) {
+            bail!(
+                "{} and {} would both be written to {} (--output-dir)",
+                first.display(),
+                source.display(),
+                path.display()
+            );
+        }
+        paths.push(path);
+    }
+    for (item, path) in rendered.iter().zip(paths) {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("failed to create directory: {}", parent.display()))?;
+        }
+        write_outputs(
+            std::slice::from_ref(&path),
+            std::slice::from_ref(item),
+            opts,
+        )?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Lowercase hex SHA-256 of `data`, for the `sha256` filters.
+fn sha256_hex(data: &[u8]) -> String {
+    sha2::Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Standard-alphabet base64 that pads on encode and accepts input with or without padding.
+const BASE64: base64::engine::GeneralPurpose = base64::engine::GeneralPurpose::new(
+    &base64::alphabet::STANDARD,
+    base64::engine::GeneralPurposeConfig::new()
+        .with_decode_padding_mode(base64::engine::DecodePaddingMode::Indifferent),
+);
+
+fn base64_encode(bytes: &[u8]) -> String {
+    BASE64.encode(bytes)
+}
+
+/// Decodes standard base64; whitespace is ignored and trailing `=` padding is optional.
+fn base64_decode(text: &str) -> Result<Vec<u8>> {
+    let clean: Vec<u8> = text.bytes().filter(|c| !c.is_ascii_whitespace()).collect();
+    BASE64
+        .decode(clean)
+        .map_err(|e| anyhow::anyhow!("invalid base64: {e}"))
+}
+
+/// Splits ASCII text into lines of at most `width` characters; 0 leaves it unchanged.
+fn wrap_columns(text: &str, width: usize) -> String {
+    if width == 0 {
+        return text.to_string();
+    }
+    text.as_bytes()
+        .chunks(width)
+        .map(|line| String::from_utf8_lossy(line).into_owned())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Joins documents with `separator` on a line of its own; with `leading`, the first
+/// document is preceded by it as well.
+fn render_multi_document_yaml(
+    rendered: &[(PathBuf, String)],
+    separator: &str,
+    leading: bool,
+) -> String {
+    let mut out = String::new();
+    if leading {
+        out.push_str(separator);
+        out.push('\n');
+    }
+    for (idx, (_, content)) in rendered.iter().enumerate() {
+        if idx > 0 {
+            out.push_str(&format!("\n{separator}\n"));
+        }
+        out.push_str(content);
+        if !content.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// Joins rendered JSON documents into one JSON array, in input order.
+fn render_json_array(rendered: &[(PathBuf, String)]) -> String {
+    let docs: Vec<&str> = rendered.iter().map(|(_, content)| content.trim()).collect();
+    format!("[\n{}\n]\n", docs.join(",\n"))
+}
+
+/// Parses every YAML document of the rendered files, in input order, for the JSON output
+/// modes named by `flag`. Empty documents are skipped.
+fn rendered_yaml_documents(rendered: &[(PathBuf, String)], flag: &str) -> Result<Vec<YamlValue>> {
+    let mut documents = Vec::new();
+    for (path, content) in rendered {
+        for document in serde_yaml::Deserializer::from_str(content) {
+            let value = YamlValue::deserialize(document).with_context(|| {
+                format!(
+                    "failed to parse rendered YAML for {flag}: {}",
+                    path.display()
+                )
+            })?;
+            if !value.is_null() {
+                documents.push(value);
+            }
+        }
+    }
+    Ok(documents)
+}
+
+/// --output-jsonl: writes every rendered YAML document as compact JSON on its own line.
+fn render_json_lines(rendered: &[(PathBuf, String)]) -> Result<String> {
+    let mut out = String::new();
+    for value in rendered_yaml_documents(rendered, "--output-jsonl")? {
+        out.push_str(&yaml_to_json(&value));
+        out.push('\n');
+    }
+    Ok(out)
+}
+
+/// --output-format json: one template with one document gives that document as JSON;
+/// anything else gives a JSON array of all documents, in input order.
+fn render_json_documents(rendered: &[(PathBuf, String)]) -> Result<String> {
+    let mut documents = rendered_yaml_documents(rendered, "--output-format json")?;
+    let value = match documents.len() {
+        1 if rendered.len() == 1 => documents.remove(0),
+        _ => YamlValue::Sequence(documents),
+    };
+    Ok(format!("{}\n", yaml_to_json_pretty(&value)))
+}
+
+#[doc(hidden)]
+pub fn detect_template_format(input_files: &[PathBuf]) -> TemplateFormat {
+    let is_json = |p: &PathBuf| p.extension().is_some_and(|ext| ext == "json");
+    if !input_files.is_empty() && input_files.iter().all(is_json) {
+        TemplateFormat::Json
+    } else {
+        TemplateFormat::Yaml
+    }
+}
+
+#[doc(hidden)]
+pub fn ensure_all_yaml_files(input_files: &[PathBuf]) -> Result<()> {
+    for path in input_files {
+        if !is_yaml_file(path) {
+            bail!(
+                "all input files must be *.yaml or *.yml for multi-file output, but found {}",
+                path.display()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Removes non-YAML files from a multi-file input list and returns them in order.
+#[doc(hidden)]
+pub fn skip_non_yaml_files(input_files: &mut Vec<PathBuf>) -> Vec<PathBuf> {
+    let (yaml, skipped): (Vec<PathBuf>, Vec<PathBuf>) =
+        input_files.drain(..).partition(|p| is_yaml_file(p));
+    *input_files = yaml;
+    skipped
+}
+
+fn is_yaml_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.ends_with(".yaml") || n.ends_with(".yml"))
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The placeholder regex with the default --placeholder-style and --path-sep.
+    fn placeholder_regex() -> Result<Regex> {
+        placeholder_regex_with_style(PlaceholderStyle::Standard, '.', false)
+    }
+
+    /// The --resolution-order default.
+    const DEFAULT_RESOLUTION_ORDER: [EnvSource; 5] = [
+        EnvSource::Values,
+        EnvSource::Prompt,
+        EnvSource::EnvFile,
+        EnvSource::OsEnv,
+        EnvSource::Default,
+    ];
+
+    #[test]
+    fn collect_placeholders_finds_unique_env_and_values() {
+        let input = r#"
+apiVersion: v1
+metadata:
+  namespace: {{NAMESPACE}}
+  name: {{ APP_NAME }}
+  short_env: $SHORT_ENV
+  brace_env: ${BRACE_ENV}
+spec:
+  image: {{ .Values.image.repository }}:{{.Values.image.tag}}
+  replicas: {{ .Values.replicas }}
+  namespace2: {{NAMESPACE}}
+"#;
+        let re = placeholder_regex().expect("regex must compile");
+        let (env_vars, values_paths) = collect_placeholders(input, &re);
+
+        assert_eq!(
+            env_vars,
+            BTreeSet::from([
+                "APP_NAME".to_string(),
+                "BRACE_ENV".to_string(),
+                "NAMESPACE".to_string(),
+                "SHORT_ENV".to_string()
+            ])
+        );
+        assert_eq!(
+            values_paths,
+            BTreeSet::from([
+                "image.repository".to_string(),
+                "image.tag".to_string(),
+                "replicas".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn escaped_placeholders_render_literally_and_are_not_collected() {
+        let input = "cmd: echo \\${HOME} $NAME\nraw: \\{{ .Values.x }}-{{ .Values.y -}}\n";
+        let re = placeholder_regex().expect("regex compiles");
+        let (env_vars, values_paths) = collect_placeholders(input, &re);
+        assert_eq!(env_vars, BTreeSet::from(["NAME".to_string()]));
+        assert_eq!(values_paths, BTreeSet::from(["y".to_string()]));
+
+        let opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: false,
+            block_scalar: false,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let out = render_templates(
+            &[(PathBuf::from("t.yaml"), input.to_string())],
+            &re,
+            &HashMap::from([("y".to_string(), "2".to_string())]),
+            &HashMap::from([("NAME".to_string(), "app".to_string())]),
+            &opts,
+        )
+        .expect("render succeeds");
+        assert_eq!(out[0].1, "cmd: echo ${HOME} app\nraw: {{ .Values.x }}-2");
+    }
+
+    #[test]
+    fn set_yaml_path_creates_nested_mappings() {
+        let mut root = YamlValue::Mapping(YamlMapping::new());
+        set_yaml_path(
+            &mut root,
+            "service.port",
+            YamlValue::Number(serde_yaml::Number::from(8080)),
+        );
+
+        let got = lookup_yaml_path(&root, "service.port");
+        assert_eq!(
+            got,
+            Some(&YamlValue::Number(serde_yaml::Number::from(8080)))
+        );
+    }
+
+    #[test]
+    fn set_yaml_path_replaces_non_mapping_intermediate_nodes() {
+        let mut root: YamlValue = serde_yaml::from_str("service: api\n").expect("valid yaml");
+        set_yaml_path(
+            &mut root,
+            "service.port",
+            YamlValue::Number(serde_yaml::Number::from(80)),
+        );
+
+        let got = lookup_yaml_path(&root, "service.port");
+        assert_eq!(got, Some(&YamlValue::Number(serde_yaml::Number::from(80))));
+    }
+
+    #[test]
+    fn lookup_yaml_path_detailed_distinguishes_missing_from_non_mapping_parent() {
+        let root: YamlValue =
+            serde_yaml::from_str("a: scalar\nb:\n  c: 1\nl: [1, 2]\n").expect("valid yaml");
+
+        let err = lookup_yaml_path_detailed(&root, "a.b").expect_err("a is a scalar");
+        assert_eq!(err.to_string(), ".Values.a is a scalar, cannot access .b");
+
+        let err = lookup_yaml_path_detailed(&root, "l.x").expect_err("l is a sequence");
+        assert_eq!(err.to_string(), ".Values.l is a sequence, cannot access .x");
+
+        let err = lookup_yaml_path_detailed(&root, "b.d").expect_err("b.d is absent");
+        assert_eq!(
+            err,
+            YamlLookupError::Missing {
+                parent: "b".to_string(),
+                missing: "d".to_string()
+            }
+        );
+        assert!(lookup_yaml_path_detailed(&root, "b.c").is_ok());
+    }
+
+    #[test]
+    fn delete_yaml_path_removes_leaf_and_prunes_empty_parents() {
+        let mut root: YamlValue =
+            serde_yaml::from_str("db:\n  old:\n    user: x\n  host: h\nkeep: 1\n")
+                .expect("valid yaml");
+
+        assert!(delete_yaml_path(&mut root, "db.old.user"));
+        assert!(lookup_yaml_path(&root, "db.old").is_none());
+        assert!(lookup_yaml_path(&root, "db.host").is_some());
+
+        assert!(delete_yaml_path(&mut root, "db.host"));
+        assert!(lookup_yaml_path(&root, "db").is_none());
+        assert!(lookup_yaml_path(&root, "keep").is_some());
+
+        assert!(!delete_yaml_path(&mut root, "missing.key"));
+        assert!(!delete_yaml_path(&mut root, "keep.child"));
+    }
+
+    #[test]
+    fn yaml_value_to_string_handles_scalars_and_mappings() {
+        assert_eq!(
+            yaml_value_to_string(&YamlValue::Bool(true)).expect("bool string"),
+            "true"
+        );
+        assert_eq!(
+            yaml_value_to_string(&YamlValue::String("abc".to_string())).expect("string value"),
+            "abc"
+        );
+
+        let mapping: YamlValue = serde_yaml::from_str("foo: bar\n").expect("valid map yaml");
+        let rendered = yaml_value_to_string(&mapping).expect("mapping string");
+        assert!(rendered.contains("foo: bar"));
+    }
+
+    #[test]
+    fn env_var_values_path_builds_expected_key() {
+        assert_eq!(env_var_values_path("NAMESPACE"), "environment.NAMESPACE");
+    }
+
+    #[test]
+    fn prefer_longer_multiline_only_applies_to_differing_multiline_values() {
+        let full = "-----BEGIN-----\nAAA\nBBB\n-----END-----";
+        let truncated = "-----BEGIN-----\nAAA\n";
+        assert_eq!(prefer_longer_multiline(full, truncated), Some(true));
+        assert_eq!(prefer_longer_multiline(truncated, full), Some(false));
+        assert_eq!(prefer_longer_multiline(full, full), None);
+        assert_eq!(prefer_longer_multiline("a", "b\nc"), None);
+    }
+
+    #[test]
+    fn resolve_env_from_values_file_reads_environment_section() {
+        let yaml: YamlValue = serde_yaml::from_str(
+            r#"
+environment:
+  APP_NAME: api
+  NAMESPACE: prod
+"#,
+        )
+        .expect("valid yaml");
+        let env_vars = BTreeSet::from(["APP_NAME".to_string(), "NAMESPACE".to_string()]);
+
+        let (resolved, missing) =
+            resolve_env_from_values_file(&env_vars, &yaml, KeyCase::Exact, false)
+                .expect("env values resolve");
+
+        assert_eq!(resolved.get("APP_NAME"), Some(&"api".to_string()));
+        assert_eq!(resolved.get("NAMESPACE"), Some(&"prod".to_string()));
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn resolve_env_from_values_file_reports_missing_keys() {
+        let yaml: YamlValue = serde_yaml::from_str(
+            r#"
+environment:
+  APP_NAME: api
+"#,
+        )
+        .expect("valid yaml");
+        let env_vars = BTreeSet::from(["APP_NAME".to_string(), "NAMESPACE".to_string()]);
+
+        let (resolved, missing) =
+            resolve_env_from_values_file(&env_vars, &yaml, KeyCase::Exact, false)
+                .expect("env values resolve");
+
+        assert_eq!(resolved.get("APP_NAME"), Some(&"api".to_string()));
+        assert!(!resolved.contains_key("NAMESPACE"));
+        assert_eq!(missing, vec!["environment.NAMESPACE".to_string()]);
+    }
+
+    #[test]
+    fn env_ignore_case_matches_os_names_and_environment_keys() {
+        let names = BTreeSet::from([
+            "database_url".to_string(),
+            "PORT".to_string(),
+            "Region".to_string(),
+            "MISSING".to_string(),
+        ]);
+        let os_names: Vec<String> = ["DATABASE_URL", "PORT", "port", "REGION", "region"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let aliases = env_names_ignoring_case(&names, &os_names, true);
+        assert_eq!(
+            aliases,
+            HashMap::from([
+                ("database_url".to_string(), "DATABASE_URL".to_string()),
+                ("Region".to_string(), "REGION".to_string()),
+            ])
+        );
+
+        let yaml: YamlValue = serde_yaml::from_str(
+            "environment:\n  DATABASE_URL: postgres://db\n  port: '80'\n  Port: '81'\n",
+        )
+        .expect("valid yaml");
+        let env_vars = BTreeSet::from(["database_url".to_string()]);
+        let (resolved, missing) =
+            resolve_env_from_values_file(&env_vars, &yaml, KeyCase::Exact, true)
+                .expect("env values resolve");
+        assert_eq!(resolved["database_url"], "postgres://db");
+        assert!(missing.is_empty());
+        let (_, missing) = resolve_env_from_values_file(&env_vars, &yaml, KeyCase::Exact, false)
+            .expect("env values resolve");
+        assert_eq!(missing, vec!["environment.database_url".to_string()]);
+
+        let err = lookup_env_values_key(&yaml, "PORT", KeyCase::Exact, true)
+            .expect_err("port and Port both match");
+        assert!(err.to_string().contains("port, Port"), "{err}");
+    }
+
+    #[test]
+    fn file_pattern_regex_supports_num_token_and_wildcard() {
+        let yml = file_pattern_regex("<NUM>-*.yml").expect("pattern compiles");
+        assert!(yml.is_match("10-demo.yml"));
+        assert!(!yml.is_match("10-demo.yaml"));
+        assert!(!yml.is_match("10-demo.ymlx"));
+        let re = file_pattern_regex("<NUM>-*.yaml").expect("pattern compiles");
+        assert!(re.is_match("1-demo.yaml"));
+        assert!(re.is_match("42-x.yaml"));
+        assert!(!re.is_match("demo.yaml"));
+        assert!(!re.is_match("a-demo.yaml"));
+
+        let re = file_pattern_regex("**/*.yaml").expect("pattern compiles");
+        assert!(re.is_match("deploy.yaml"));
+        assert!(re.is_match("envs/prod/app/deploy.yaml"));
+        let re = file_pattern_regex("envs/*/deploy.yaml").expect("pattern compiles");
+        assert!(re.is_match("envs/prod/deploy.yaml"));
+        assert!(!re.is_match("envs/prod/app/deploy.yaml"));
+    }
+
+    #[test]
+    fn repeated_file_args_are_discovered_in_command_line_order() {
+        let opts = DiscoveryOptions {
+            follow_symlinks: false,
+            verbose: false,
+        };
+        let files = [
+            PathBuf::from("z.yaml"),
+            PathBuf::from("a.yaml"),
+            PathBuf::from("-"),
+        ];
+        let found = discover_input_files(&files, None, &opts).expect("discovers");
+        assert_eq!(
+            found,
+            [
+                PathBuf::from("z.yaml"),
+                PathBuf::from("a.yaml"),
+                PathBuf::from("<stdin>")
+            ]
+        );
+        let err = discover_input_files(&[PathBuf::from("-"), PathBuf::from("-")], None, &opts)
+            .expect_err("stdin twice");
+        assert!(err.to_string().contains("only be given once"), "{err:#}");
+    }
+
+    #[test]
+    fn file_dash_reads_the_template_from_stdin() {
+        let opts = DiscoveryOptions {
+            follow_symlinks: false,
+            verbose: false,
+        };
+        let files = discover_input_files(&[PathBuf::from("-")], None, &opts).expect("discovers");
+        assert_eq!(files, [PathBuf::from("<stdin>")]);
+        assert!(is_stdin_template(&files[0]));
+        assert!(has_yaml_extension(&files[0]));
+
+        let bytes = read_template_from(&mut "a: {{ .Values.a }}\n".as_bytes(), 64).expect("reads");
+        assert_eq!(bytes, b"a: {{ .Values.a }}\n");
+        let err = read_template_from(&mut "a: 1\n".as_bytes(), 4).expect_err("too large");
+        assert_eq!(err.to_string(), "<stdin> exceeds --max-file-size 4 bytes");
+    }
+
+    #[test]
+    fn find_files_by_pattern_recurses_for_double_star() {
+        let dir = env::temp_dir().join(format!("tplenv-recursive-{}", std::process::id()));
+        fs::create_dir_all(dir.join("envs/prod/app")).expect("create temp dir");
+        fs::create_dir_all(dir.join("envs/dev")).expect("create temp dir");
+        fs::write(dir.join("envs/prod/app/deploy.yaml"), "a: 1\n").expect("write file");
+        fs::write(dir.join("envs/dev/deploy.yaml"), "a: 1\n").expect("write file");
+        fs::write(dir.join("envs/top.yaml"), "a: 1\n").expect("write file");
+        fs::write(dir.join("envs/dev/notes.txt"), "").expect("write file");
+        let opts = DiscoveryOptions {
+            follow_symlinks: false,
+            verbose: false,
+        };
+        let find =
+            |pattern: &str| find_files_by_pattern(&dir.join(pattern).to_string_lossy(), &opts);
+
+        assert_eq!(
+            find("envs/**/*.yaml").expect("files found"),
+            [
+                dir.join("envs/dev/deploy.yaml"),
+                dir.join("envs/prod/app/deploy.yaml"),
+                dir.join("envs/top.yaml"),
+            ]
+        );
+        assert_eq!(
+            find("envs/*/**/deploy.yaml").expect("files found"),
+            [
+                dir.join("envs/dev/deploy.yaml"),
+                dir.join("envs/prod/app/deploy.yaml"),
+            ]
+        );
+        let err = find("envs/**/*.json").expect_err("no match");
+        assert!(
+            err.to_string()
+                .starts_with("no files matched --file-pattern")
+        );
+
+        fs::remove_dir_all(&dir).expect("cleanup temp dir");
+    }
+
+    #[cfg(unix)]
+    #[cfg(unix)]
+    #[test]
+    fn find_files_by_pattern_follows_symlinks_only_when_enabled() {
+        let dir = env::temp_dir().join(format!("tplenv-symlinks-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create temp dir");
+        fs::write(dir.join("1-real.yaml"), "a: 1\n").expect("write file");
+        std::os::unix::fs::symlink(dir.join("1-real.yaml"), dir.join("2-link.yaml"))
+            .expect("create symlink");
+        std::os::unix::fs::symlink(dir.join("missing.yaml"), dir.join("3-broken.yaml"))
+            .expect("create broken symlink");
+        let pattern = dir.join("<NUM>-*.yaml").to_string_lossy().to_string();
+
+        let mut opts = DiscoveryOptions {
+            follow_symlinks: false,
+            verbose: false,
+        };
+        let files = find_files_by_pattern(&pattern, &opts).expect("files found");
+        assert_eq!(files, vec![dir.join("1-real.yaml")]);
+
+        opts.follow_symlinks = true;
+        let files = find_files_by_pattern(&pattern, &opts).expect("files found");
+        assert_eq!(
+            files,
+            vec![dir.join("1-real.yaml"), dir.join("2-link.yaml")]
+        );
+
+        fs::remove_dir_all(&dir).expect("cleanup temp dir");
+    }
+
+    #[test]
+    fn parse_values_by_name_checks_files_and_duplicates() {
+        let dir = env::temp_dir().join(format!("tplenv-values-by-name-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create temp dir");
+        fs::write(dir.join("prod.yaml"), "a: 1\n").expect("write file");
+
+        let mapping = parse_values_by_name("prod=prod.yaml", Some(&dir)).expect("mapping parses");
+        assert_eq!(mapping.get("prod"), Some(&dir.join("prod.yaml")));
+
+        let err = parse_values_by_name("prod=prod.yaml,dev=dev.yaml", Some(&dir))
+            .expect_err("missing file");
+        assert!(err.to_string().contains("values file for 'dev' not found"));
+        let err = parse_values_by_name("prod=prod.yaml,prod=prod.yaml", Some(&dir))
+            .expect_err("duplicate name");
+        assert!(err.to_string().contains("mapped more than once"));
+        assert!(parse_values_by_name("prod", Some(&dir)).is_err());
+
+        fs::remove_dir_all(&dir).expect("cleanup temp dir");
+    }
+
+    #[test]
+    fn group_templates_by_values_falls_back_to_default() {
+        let templates = vec![
+            (PathBuf::from("cfg/1-prod-app.yaml"), String::new()),
+            (PathBuf::from("cfg/2-dev-app.yaml"), String::new()),
+            (PathBuf::from("cfg/3-prod-db.yaml"), String::new()),
+            (PathBuf::from("cfg/4-other.yaml"), String::new()),
+        ];
+        let mapping = HashMap::from([
+            ("prod".to_string(), PathBuf::from("prod.yaml")),
+            ("dev".to_string(), PathBuf::from("dev.yaml")),
+        ]);
+        let re = Regex::new(DEFAULT_VALUES_NAME_REGEX).expect("regex compiles");
+        let groups = group_templates_by_values(&templates, &mapping, &re, Path::new("Values.yaml"))
+            .expect("groups");
+        assert_eq!(
+            groups,
+            vec![
+                (PathBuf::from("prod.yaml"), vec![0, 2]),
+                (PathBuf::from("dev.yaml"), vec![1]),
+                (PathBuf::from("Values.yaml"), vec![3]),
+            ]
+        );
+
+        let no_group = Regex::new("prod").expect("regex compiles");
+        assert!(
+            group_templates_by_values(&templates, &mapping, &no_group, Path::new("Values.yaml"))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn write_inplace_overwrites_and_keeps_backup() {
+        let dir = env::temp_dir().join(format!("tplenv-inplace-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create temp dir");
+        let file = dir.join("app.yaml");
+        fs::write(&file, "name: {{ .Values.name }}\n").expect("write file");
+
+        write_inplace(
+            &[(file.clone(), "name: demo\n".to_string())],
+            TrailingNewline::Keep,
+            true,
+            false,
+        )
+        .expect("rewrite succeeds");
+        assert_eq!(
+            fs::read_to_string(&file).expect("read file"),
+            "name: demo\n"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("app.yaml.bak")).expect("read backup"),
+            "name: {{ .Values.name }}\n"
+        );
+
+        fs::remove_dir_all(&dir).expect("cleanup temp dir");
+    }
+
+    #[test]
+    fn path_sep_addresses_keys_containing_dots() {
+        let re = placeholder_regex_with_style(PlaceholderStyle::Standard, '/', false)
+            .expect("regex compiles");
+        let cap = re
+            .captures("{{ .Values.labels/app.kubernetes.io/name }}")
+            .expect("placeholder matches");
+        let path = extract_values_path(&cap).expect("values path");
+        assert_eq!(path, "labels.app\\.kubernetes\\.io.name");
+        // Messages spell the key the way the template does.
+        assert_eq!(
+            values_key_as_written(&path, '/'),
+            "labels/app.kubernetes.io/name"
+        );
+        assert_eq!(
+            values_key_as_written("environment.VAR", '/'),
+            "environment.VAR"
+        );
+
+        let yaml: YamlValue =
+            serde_yaml::from_str("labels:\n  app.kubernetes.io:\n    name: demo\n")
+                .expect("yaml parses");
+        assert_eq!(
+            lookup_yaml_path(&yaml, &path).and_then(|v| v.as_str()),
+            Some("demo")
+        );
+
+        // Slashes are part of the key with any other separator.
+        let re = placeholder_regex_with_style(PlaceholderStyle::Standard, ':', false)
+            .expect("regex compiles");
+        let cap = re
+            .captures("{{ .Values.labels:app.kubernetes.io/name }}")
+            .expect("placeholder matches");
+        let path = extract_values_path(&cap).expect("values path");
+        let yaml: YamlValue =
+            serde_yaml::from_str("labels:\n  app.kubernetes.io/name: demo\n").expect("yaml parses");
+        assert_eq!(
+            lookup_yaml_path(&yaml, &path).and_then(|v| v.as_str()),
+            Some("demo")
+        );
+
+        let mut root = YamlValue::Null;
+        set_yaml_path(&mut root, "a\\.b.c", YamlValue::from("x"));
+        assert_eq!(
+            serde_yaml::to_string(&root).expect("yaml"),
+            "a.b:\n  c: x\n"
+        );
+        assert!(parse_path_sep("-").is_err());
+        assert_eq!(parse_path_sep(":"), Ok(':'));
+    }
+
+    #[test]
+    fn files_get_embeds_file_relative_to_template() {
+        let dir = env::temp_dir().join(format!("tplenv-files-get-{}", std::process::id()));
+        fs::create_dir_all(dir.join("scripts")).expect("create temp dir");
+        fs::write(dir.join("scripts/init.sh"), "#!/bin/sh\necho hi\n").expect("write file");
+        let template = dir.join("app.yaml");
+        let input = "script: |\n  {{ .Files.Get \"scripts/init.sh\" }}\n".to_string();
+
+        let re = placeholder_regex().expect("regex compiles");
+        let opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: true,
+            block_scalar: true,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let out = render_templates(
+            &[(template.clone(), input)],
+            &re,
+            &HashMap::new(),
+            &HashMap::new(),
+            &opts,
+        )
+        .expect("render succeeds");
+        assert_eq!(out[0].1, "script: |\n  #!/bin/sh\n  echo hi\n\n");
+
+        let err = resolve_template_file(&template, "../outside.txt").expect_err("escapes");
+        assert!(err.to_string().contains("app.yaml"));
+        let err = resolve_template_file(&template, "missing.sh").expect_err("missing");
+        assert!(err.to_string().contains("file not found"));
+        fs::write(
+            dir.parent().expect("parent").join("tplenv-outside.txt"),
+            "x",
+        )
+        .ok();
+        let err = resolve_template_file(&template, "../tplenv-outside.txt").expect_err("escapes");
+        assert!(err.to_string().contains("escapes the template directory"));
+
+        fs::remove_dir_all(&dir).expect("cleanup temp dir");
+    }
+
+    #[test]
+    fn merge_yaml_lets_values_file_override_inline_defaults() {
+        let defaults =
+            parse_values_default("{replicas: 2, image: {tag: latest, repo: app}}").expect("parses");
+        let file: YamlValue =
+            serde_yaml::from_str("image:\n  tag: '1.2'\nname: demo\n").expect("yaml parses");
+        let merged = merge_yaml(defaults.clone(), file);
+        assert_eq!(
+            serde_yaml::to_string(&merged).expect("yaml"),
+            "replicas: 2\nimage:\n  tag: '1.2'\n  repo: app\nname: demo\n"
+        );
+        assert_eq!(merge_yaml(defaults.clone(), YamlValue::Null), defaults);
+
+        let err = parse_values_default("{replicas: [").expect_err("invalid yaml");
+        assert!(format!("{err:#}").contains("--values-default"));
+        assert!(parse_values_default("- a").is_err());
+    }
+
+    #[test]
+    fn apply_merge_patch_deletes_on_null_and_merges_nested_mappings() {
+        let base: YamlValue = serde_yaml::from_str(
+            "db:\n  debug: true\n  host: db\n  port: 5432\nlegacy: x\ntags: [a, b]\n",
+        )
+        .expect("yaml parses");
+        let patch: YamlValue =
+            serde_yaml::from_str("db:\n  port: 6432\n  debug: null\ntags: [c]\nlegacy: null\n")
+                .expect("yaml parses");
+        let patched = apply_merge_patch(base, patch);
+        assert_eq!(
+            serde_yaml::to_string(&patched).expect("yaml"),
+            "db:\n  host: db\n  port: 6432\ntags:\n- c\n"
+        );
+
+        // A mapping patch replaces a scalar target; nulls in a new subtree are dropped.
+        let patched = apply_merge_patch(
+            serde_yaml::from_str("a: text\n").expect("yaml parses"),
+            serde_yaml::from_str("a:\n  b: 1\n  c: null\n").expect("yaml parses"),
+        );
+        assert_eq!(
+            serde_yaml::to_string(&patched).expect("yaml"),
+            "a:\n  b: 1\n"
+        );
+        // Deleting an absent key is a no-op.
+        let patched = apply_merge_patch(
+            serde_yaml::from_str("a: 1\n").expect("yaml parses"),
+            serde_yaml::from_str("missing: null\n").expect("yaml parses"),
+        );
+        assert_eq!(serde_yaml::to_string(&patched).expect("yaml"), "a: 1\n");
+    }
+
+    #[test]
+    fn select_yaml_element_picks_one_list_entry() {
+        let yaml: YamlValue = serde_yaml::from_str(
+            "- name: dev\n  replicas: 1\n- name: prod\n  replicas: 3\n- name: qa\n- name: qa\n",
+        )
+        .expect("yaml parses");
+        let selected = select_yaml_element(yaml.clone(), "name", "prod").expect("selects prod");
+        assert_eq!(
+            lookup_yaml_path(&selected, "replicas").and_then(|v| v.as_u64()),
+            Some(3)
+        );
+        let err = select_yaml_element(yaml.clone(), "name", "stage").expect_err("no match");
+        assert!(err.to_string().contains("no list element"));
+        let err = select_yaml_element(yaml, "name", "qa").expect_err("ambiguous");
+        assert!(err.to_string().contains("2 list elements"));
+        assert_eq!(
+            parse_select("name = prod").expect("parses"),
+            ("name".to_string(), "prod".to_string())
+        );
+        assert!(parse_select("prod").is_err());
+    }
+
+    #[test]
+    fn select_yaml_index_picks_the_nth_list_entry() {
+        let yaml: YamlValue =
+            serde_yaml::from_str("- replicas: 1\n- replicas: 2\n- replicas: 3\n- plain\n")
+                .expect("yaml parses");
+        let selected = select_yaml_index(yaml.clone(), 1).expect("selects the middle one");
+        assert_eq!(
+            lookup_yaml_path(&selected, "replicas").and_then(|v| v.as_u64()),
+            Some(2)
+        );
+        let err = select_yaml_index(yaml.clone(), 4).expect_err("out of range");
+        assert_eq!(
+            err.to_string(),
+            "index 4 is out of range for a list of 4 element(s)"
+        );
+        let err = select_yaml_index(yaml, 3).expect_err("not a mapping");
+        assert!(err.to_string().contains("expected a mapping"), "{err}");
+        let mapping: YamlValue = serde_yaml::from_str("a: 1\n").expect("yaml parses");
+        assert!(select_yaml_index(mapping, 0).is_err());
+    }
+
+    #[test]
+    fn apply_trailing_newline_strips_or_ensures_exactly_one() {
+        let text = "a: 1\n\n";
+        assert_eq!(
+            apply_trailing_newline(text, TrailingNewline::Keep),
+            "a: 1\n\n"
+        );
+        assert_eq!(apply_trailing_newline(text, TrailingNewline::Strip), "a: 1");
+        assert_eq!(
+            apply_trailing_newline(text, TrailingNewline::Ensure),
+            "a: 1\n"
+        );
+        assert_eq!(
+            apply_trailing_newline("a: 1", TrailingNewline::Ensure),
+            "a: 1\n"
+        );
+        assert_eq!(
+            apply_trailing_newline("a: 1\r\n", TrailingNewline::Strip),
+            "a: 1"
+        );
+    }
+
+    #[test]
+    fn resolve_placeholders_lets_secrets_file_win() {
+        let dir = env::temp_dir().join(format!("tplenv-secrets-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create temp dir");
+        let values_file = dir.join("Values.yaml");
+        fs::write(&values_file, "db:\n  host: db\n  password: changeme\n").expect("write file");
+        let secrets: YamlValue =
+            serde_yaml::from_str("db:\n  password: s3cret\n").expect("yaml parses");
+
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let opts = ResolveOptions {
+            value_file_only: false,
+            verbose: false,
+            quiet: true,
+            conflict_policy: ConflictPolicy::ValuesFile,
+            resolution_order: &DEFAULT_RESOLUTION_ORDER,
+            key_case: KeyCase::Exact,
+            strict_unknown_keys: false,
+            tolerant_values: false,
+            always_load: false,
+            annotations: &HashMap::new(),
+            prompted_env_map: &HashMap::new(),
+            overrides: &HashMap::new(),
+            deny_os_env: false,
+            env_ignore_case: false,
+            os_env_names: &HashMap::new(),
+            env_file: &HashMap::new(),
+            optional_keys: &[],
+            values_default: None,
+            values_inline: None,
+            values_patch: None,
+            secrets: Some(&secrets),
+            select: None,
+            load: &load,
+        };
+        let values_paths = BTreeSet::from(["db.host".to_string(), "db.password".to_string()]);
+        let no_env = BTreeSet::new();
+        let values_yaml = load_values_layer(&values_file, &no_env, &values_paths, &opts)
+            .expect("values load")
+            .map(|yaml| merge_values_layers(yaml, &opts));
+        let res =
+            resolve_placeholders(values_yaml, &no_env, &values_paths, &opts).expect("resolves");
+        assert_eq!(res.values_map["db.host"], "db");
+        assert_eq!(res.values_map["db.password"], "s3cret");
+        assert_eq!(res.sources["db.host"], ValueSource::ValuesFile);
+        assert_eq!(
+            res.secret_keys(),
+            BTreeSet::from(["db.password".to_string()])
+        );
+
+        fs::remove_dir_all(&dir).expect("cleanup temp dir");
+    }
+
+    #[test]
+    fn overrides_beat_every_other_source() {
+        assert_eq!(
+            parse_override(".Values.image.tag=v2").expect("values path"),
+            ("image.tag".to_string(), "v2".to_string())
+        );
+        assert_eq!(
+            parse_override("NAMESPACE=a=b").expect("env name"),
+            ("environment.NAMESPACE".to_string(), "a=b".to_string())
+        );
+        assert!(parse_override("image.tag=v2").is_err());
+        assert!(parse_override("NAMESPACE").is_err());
+
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let overrides = HashMap::from([
+            ("image.tag".to_string(), "v2".to_string()),
+            (
+                "environment.TPLENV_OVERRIDE_TEST".to_string(),
+                "pinned".to_string(),
+            ),
+        ]);
+        let prompted =
+            HashMap::from([("TPLENV_OVERRIDE_TEST".to_string(), "prompted".to_string())]);
+        let opts = ResolveOptions {
+            value_file_only: false,
+            verbose: false,
+            quiet: true,
+            conflict_policy: ConflictPolicy::ValuesFile,
+            resolution_order: &DEFAULT_RESOLUTION_ORDER,
+            key_case: KeyCase::Exact,
+            strict_unknown_keys: false,
+            tolerant_values: false,
+            always_load: false,
+            annotations: &HashMap::new(),
+            prompted_env_map: &prompted,
+            overrides: &overrides,
+            deny_os_env: false,
+            env_ignore_case: false,
+            os_env_names: &HashMap::new(),
+            env_file: &HashMap::new(),
+            optional_keys: &[],
+            values_default: None,
+            values_inline: None,
+            values_patch: None,
+            secrets: None,
+            select: None,
+            load: &load,
+        };
+        let values: YamlValue = serde_yaml::from_str(
+            "image:\n  tag: v1\n  name: app\nenvironment:\n  TPLENV_OVERRIDE_TEST: from-values\n",
+        )
+        .expect("yaml parses");
+        let env_vars = BTreeSet::from(["TPLENV_OVERRIDE_TEST".to_string()]);
+        let values_paths = BTreeSet::from(["image.tag".to_string(), "image.name".to_string()]);
+        let res =
+            resolve_placeholders(Some(values), &env_vars, &values_paths, &opts).expect("resolves");
+        assert_eq!(res.values_map["image.tag"], "v2");
+        assert_eq!(res.values_map["image.name"], "app");
+        assert_eq!(res.env_map["TPLENV_OVERRIDE_TEST"], "pinned");
+        assert_eq!(res.sources["image.tag"], ValueSource::Override);
+        assert_eq!(res.sources["image.name"], ValueSource::ValuesFile);
+    }
+
+    #[test]
+    fn optional_keys_render_empty_and_others_stay_missing() {
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let optional = [glob_regex("featureX.*").expect("glob compiles")];
+        let opts = ResolveOptions {
+            value_file_only: false,
+            verbose: false,
+            quiet: true,
+            conflict_policy: ConflictPolicy::ValuesFile,
+            resolution_order: &DEFAULT_RESOLUTION_ORDER,
+            key_case: KeyCase::Exact,
+            strict_unknown_keys: false,
+            tolerant_values: false,
+            always_load: false,
+            annotations: &HashMap::new(),
+            prompted_env_map: &HashMap::new(),
+            overrides: &HashMap::new(),
+            deny_os_env: false,
+            env_ignore_case: false,
+            os_env_names: &HashMap::new(),
+            env_file: &HashMap::new(),
+            optional_keys: &optional,
+            values_default: None,
+            values_inline: None,
+            values_patch: None,
+            secrets: None,
+            select: None,
+            load: &load,
+        };
+        let values: YamlValue = serde_yaml::from_str("name: api\n").expect("yaml parses");
+        let values_paths = BTreeSet::from([
+            "name".to_string(),
+            "featureX.enabled".to_string(),
+            "replicas".to_string(),
+        ]);
+        let res = resolve_placeholders(Some(values), &BTreeSet::new(), &values_paths, &opts)
+            .expect("resolves");
+        assert_eq!(res.values_map["featureX.enabled"], "");
+        assert_eq!(res.sources["featureX.enabled"], ValueSource::Optional);
+        assert_eq!(res.missing_values, vec!["replicas".to_string()]);
+    }
+
+    #[test]
+    fn set_overrides_map_paths_to_values_and_env_placeholders() {
+        assert_eq!(
+            parse_set(".Values.image.tag=1.2=3").expect("parses"),
+            ("image.tag".to_string(), "1.2=3".to_string())
+        );
+        assert!(parse_set("image.tag").is_err());
+        assert!(parse_set("image..tag=1").is_err());
+
+        let env_vars = BTreeSet::from(["IMAGE_TAG".to_string(), "NAMESPACE".to_string()]);
+        let values_paths = BTreeSet::from(["image.tag".to_string(), "replicas".to_string()]);
+        let sets = vec![
+            ("image.tag".to_string(), "1.2.3".to_string()),
+            ("replicas".to_string(), "3".to_string()),
+            ("environment.NAMESPACE".to_string(), "dev".to_string()),
+        ];
+        assert_eq!(
+            set_overrides(&sets, &env_vars, &values_paths).expect("all referenced"),
+            [
+                ("image.tag".to_string(), "1.2.3".to_string()),
+                ("environment.IMAGE_TAG".to_string(), "1.2.3".to_string()),
+                ("replicas".to_string(), "3".to_string()),
+                ("environment.NAMESPACE".to_string(), "dev".to_string()),
+            ]
+        );
+        let typo = vec![("image.tga".to_string(), "1".to_string())];
+        let err = set_overrides(&typo, &env_vars, &values_paths).expect_err("unreferenced");
+        assert_eq!(
+            err.to_string(),
+            "--set image.tga: no template uses .Values.image.tga or env placeholder IMAGE_TGA"
+        );
+    }
+
+    #[test]
+    fn expand_set_value_reads_env_placeholders_only() {
+        let re = placeholder_regex().expect("regex compiles");
+        let lookup = |name: &str| (name == "REGISTRY").then(|| "r.example".to_string());
+        assert_eq!(
+            expand_set_value(
+                "image.repo",
+                "$REGISTRY/app:${TAG:-latest} {{REGISTRY}} {{ .Values.x }} \\$REGISTRY",
+                &re,
+                lookup,
+            )
+            .expect("expands"),
+            "r.example/app:latest r.example {{ .Values.x }} \\$REGISTRY"
+        );
+        let err = expand_set_value("image.tag", "${TAG}", &re, lookup).expect_err("unset");
+        assert_eq!(
+            err.to_string(),
+            "--set image.tag: environment variable TAG is not set"
+        );
+    }
+
+    #[test]
+    fn expand_nested_values_substitutes_until_no_placeholders_remain() {
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let overrides = HashMap::from([(
+            "environment.TPLENV_EXPAND_DOMAIN".to_string(),
+            "example.com".to_string(),
+        )]);
+        let opts = ResolveOptions {
+            value_file_only: false,
+            verbose: false,
+            quiet: true,
+            conflict_policy: ConflictPolicy::ValuesFile,
+            resolution_order: &DEFAULT_RESOLUTION_ORDER,
+            key_case: KeyCase::Exact,
+            strict_unknown_keys: false,
+            tolerant_values: false,
+            always_load: false,
+            annotations: &HashMap::new(),
+            prompted_env_map: &HashMap::new(),
+            overrides: &overrides,
+            deny_os_env: false,
+            env_ignore_case: false,
+            os_env_names: &HashMap::new(),
+            env_file: &HashMap::new(),
+            optional_keys: &[],
+            values_default: None,
+            values_inline: None,
+            values_patch: None,
+            secrets: None,
+            select: None,
+            load: &load,
+        };
+        let re = placeholder_regex().expect("regex compiles");
+        let values: YamlValue = serde_yaml::from_str(
+            "url: \"https://{{ .Values.host }}/api\"\nhost: \"api.{{ TPLENV_EXPAND_DOMAIN }}\"\nloop: \"{{ .Values.again }}\"\nagain: \"<{{ .Values.loop }}>\"\n",
+        )
+        .expect("yaml parses");
+        let paths = BTreeSet::from(["url".to_string()]);
+        let res = resolve_placeholders(Some(values.clone()), &BTreeSet::new(), &paths, &opts)
+            .expect("resolves");
+        let res = expand_nested_values(res, Some(values.clone()), &re, &opts).expect("expands");
+        assert_eq!(res.values_map["url"], "https://api.example.com/api");
+        assert_eq!(res.values_map["host"], "api.example.com");
+        assert!(!res.has_missing());
+
+        let paths = BTreeSet::from(["loop".to_string()]);
+        let res = resolve_placeholders(Some(values.clone()), &BTreeSet::new(), &paths, &opts)
+            .expect("resolves");
+        let Err(err) = expand_nested_values(res, Some(values), &re, &opts) else {
+            panic!("a reference cycle must fail");
+        };
+        assert_eq!(
+            err.to_string(),
+            "values still contain placeholders after 10 expansion passes (a reference cycle?): .Values.again, .Values.loop"
+        );
+    }
+
+    #[test]
+    fn deny_os_env_leak_treats_os_only_env_as_missing() {
+        // SAFETY: the variables are unique to this test.
+        unsafe {
+            env::set_var("TPLENV_LEAK_OS_ONLY", "from-os");
+            env::set_var("TPLENV_LEAK_IN_VALUES", "from-os");
+        }
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let overrides = HashMap::from([(
+            "environment.TPLENV_LEAK_OVERRIDDEN".to_string(),
+            "pinned".to_string(),
+        )]);
+        let opts = ResolveOptions {
+            value_file_only: false,
+            verbose: false,
+            quiet: true,
+            conflict_policy: ConflictPolicy::ValuesFile,
+            resolution_order: &DEFAULT_RESOLUTION_ORDER,
+            key_case: KeyCase::Exact,
+            strict_unknown_keys: false,
+            tolerant_values: false,
+            always_load: false,
+            annotations: &HashMap::new(),
+            prompted_env_map: &HashMap::new(),
+            overrides: &overrides,
+            deny_os_env: true,
+            env_ignore_case: false,
+            os_env_names: &HashMap::new(),
+            env_file: &HashMap::new(),
+            optional_keys: &[],
+            values_default: None,
+            values_inline: None,
+            values_patch: None,
+            secrets: None,
+            select: None,
+            load: &load,
+        };
+        let values: YamlValue =
+            serde_yaml::from_str("environment:\n  TPLENV_LEAK_IN_VALUES: from-values\n")
+                .expect("yaml parses");
+        let env_vars = BTreeSet::from([
+            "TPLENV_LEAK_OS_ONLY".to_string(),
+            "TPLENV_LEAK_IN_VALUES".to_string(),
+            "TPLENV_LEAK_OVERRIDDEN".to_string(),
+        ]);
+        let res = resolve_placeholders(Some(values), &env_vars, &BTreeSet::new(), &opts)
+            .expect("resolves");
+        assert_eq!(res.missing_env, vec!["TPLENV_LEAK_OS_ONLY".to_string()]);
+        assert_eq!(res.env_map["TPLENV_LEAK_IN_VALUES"], "from-values");
+        assert_eq!(res.env_map["TPLENV_LEAK_OVERRIDDEN"], "pinned");
+        assert!(!res.sources.values().any(|s| *s == ValueSource::OsEnv));
+    }
+
+    #[test]
+    fn env_file_sits_between_values_file_and_os_env() {
+        let vars = parse_env_file(
+            "# comment\n\nexport TPLENV_ENVFILE_A=plain # note\nTPLENV_ENVFILE_B=\"two\\nlines \\\"q\\\"\"\nTPLENV_ENVFILE_C='$raw # kept'\nTPLENV_ENVFILE_D=\n",
+        )
+        .expect("parses");
+        assert_eq!(vars["TPLENV_ENVFILE_A"], "plain");
+        assert_eq!(vars["TPLENV_ENVFILE_B"], "two\nlines \"q\"");
+        assert_eq!(vars["TPLENV_ENVFILE_C"], "$raw # kept");
+        assert_eq!(vars["TPLENV_ENVFILE_D"], "");
+        let err = parse_env_file("A=1\nnot a pair\n").expect_err("no =");
+        assert_eq!(err.to_string(), "line 2: expected KEY=VALUE");
+        assert!(parse_env_file("A=\"open\n").is_err());
+        assert!(parse_env_file("1A=x\n").is_err());
+
+        // SAFETY: the variables are unique to this test.
+        unsafe {
+            env::set_var("TPLENV_ENVFILE_A", "from-os");
+            env::set_var("TPLENV_ENVFILE_OS", "from-os");
+        }
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let opts = ResolveOptions {
+            value_file_only: false,
+            verbose: false,
+            quiet: true,
+            conflict_policy: ConflictPolicy::ValuesFile,
+            resolution_order: &DEFAULT_RESOLUTION_ORDER,
+            key_case: KeyCase::Exact,
+            strict_unknown_keys: false,
+            tolerant_values: false,
+            always_load: false,
+            annotations: &HashMap::new(),
+            prompted_env_map: &HashMap::new(),
+            overrides: &HashMap::new(),
+            deny_os_env: false,
+            env_ignore_case: false,
+            os_env_names: &HashMap::new(),
+            env_file: &vars,
+            optional_keys: &[],
+            values_default: None,
+            values_inline: None,
+            values_patch: None,
+            secrets: None,
+            select: None,
+            load: &load,
+        };
+        let values: YamlValue =
+            serde_yaml::from_str("environment:\n  TPLENV_ENVFILE_C: from-values\n")
+                .expect("yaml parses");
+        let env_vars = BTreeSet::from([
+            "TPLENV_ENVFILE_A".to_string(),
+            "TPLENV_ENVFILE_C".to_string(),
+            "TPLENV_ENVFILE_OS".to_string(),
+            "TPLENV_ENVFILE_MISSING".to_string(),
+        ]);
+        let res = resolve_placeholders(Some(values), &env_vars, &BTreeSet::new(), &opts)
+            .expect("resolves");
+        assert_eq!(res.env_map["TPLENV_ENVFILE_A"], "plain");
+        assert_eq!(res.env_map["TPLENV_ENVFILE_C"], "from-values");
+        assert_eq!(res.env_map["TPLENV_ENVFILE_OS"], "from-os");
+        assert_eq!(res.missing_env, ["TPLENV_ENVFILE_MISSING"]);
+        assert_eq!(
+            res.sources["environment.TPLENV_ENVFILE_A"],
+            ValueSource::EnvFile
+        );
+        assert_eq!(value_source_name(ValueSource::EnvFile), "env-file");
+    }
+
+    #[test]
+    fn may_be_committed_follows_gitignore_files() {
+        let dir = env::temp_dir().join(format!("tplenv-gitignore-{}", std::process::id()));
+        fs::create_dir_all(dir.join(".git")).expect("create repo dir");
+        fs::create_dir_all(dir.join("secrets")).expect("create dir");
+        fs::create_dir_all(dir.join("deploy/out")).expect("create dir");
+        fs::write(
+            dir.join(".gitignore"),
+            "# local files\n*.local.yaml\n!keep.local.yaml\nsecrets/\n/deploy/out/*.yaml\n",
+        )
+        .expect("write file");
+        fs::write(dir.join("deploy/.gitignore"), "rendered-*.yaml\n").expect("write file");
+
+        assert!(may_be_committed(&dir.join("values.yaml")));
+        assert!(!may_be_committed(&dir.join("deploy/prod.local.yaml")));
+        assert!(may_be_committed(&dir.join("keep.local.yaml")));
+        assert!(!may_be_committed(&dir.join("secrets/values.yaml")));
+        assert!(!may_be_committed(&dir.join("deploy/out/app.yaml")));
+        assert!(may_be_committed(&dir.join("deploy/out/app.json")));
+        assert!(!may_be_committed(&dir.join("deploy/rendered-app.yaml")));
+        assert!(may_be_committed(&dir.join("rendered-app.yaml")));
+
+        fs::remove_dir_all(dir.join(".git")).expect("remove repo dir");
+        assert!(!may_be_committed(&dir.join("values.yaml")));
+
+        fs::remove_dir_all(&dir).expect("cleanup temp dir");
+    }
+
+    #[test]
+    fn resolution_order_picks_first_source_with_a_value() {
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let prompted = HashMap::from([("TPLENV_ORDER_TEST".to_string(), "prompted".to_string())]);
+        let annotations = HashMap::from([(
+            "environment.TPLENV_ORDER_TEST".to_string(),
+            Annotation {
+                default: Some("annotated".to_string()),
+                description: None,
+                required: false,
+            },
+        )]);
+        let values: YamlValue =
+            serde_yaml::from_str("environment:\n  TPLENV_ORDER_TEST: from-values\n")
+                .expect("yaml parses");
+        let env_vars = BTreeSet::from(["TPLENV_ORDER_TEST".to_string()]);
+        let resolve = |order: &[EnvSource]| {
+            let opts = ResolveOptions {
+                value_file_only: false,
+                verbose: false,
+                quiet: true,
+                conflict_policy: ConflictPolicy::ValuesFile,
+                resolution_order: order,
+                key_case: KeyCase::Exact,
+                strict_unknown_keys: false,
+                tolerant_values: false,
+                always_load: false,
+                annotations: &annotations,
+                prompted_env_map: &prompted,
+                overrides: &HashMap::new(),
+                deny_os_env: false,
+                env_ignore_case: false,
+                os_env_names: &HashMap::new(),
+                env_file: &HashMap::new(),
+                optional_keys: &[],
+                values_default: None,
+                values_inline: None,
+                values_patch: None,
+                secrets: None,
+                select: None,
+                load: &load,
+            };
+            resolve_placeholders(Some(values.clone()), &env_vars, &BTreeSet::new(), &opts)
+                .expect("resolves")
+        };
+
+        let res = resolve(&DEFAULT_RESOLUTION_ORDER);
+        assert_eq!(res.env_map["TPLENV_ORDER_TEST"], "from-values");
+        let res = resolve(&[EnvSource::Prompt, EnvSource::Values]);
+        assert_eq!(res.env_map["TPLENV_ORDER_TEST"], "prompted");
+        assert_eq!(
+            res.sources["environment.TPLENV_ORDER_TEST"],
+            ValueSource::Prompt
+        );
+        let res = resolve(&[EnvSource::OsEnv, EnvSource::Default]);
+        assert_eq!(res.env_map["TPLENV_ORDER_TEST"], "annotated");
+        let res = resolve(&[EnvSource::OsEnv]);
+        assert_eq!(res.missing_env, vec!["TPLENV_ORDER_TEST".to_string()]);
+    }
+
+    #[test]
+    fn render_used_values_keeps_only_referenced_keys() {
+        let values: YamlValue =
+            serde_yaml::from_str("db:\n  host: db\n  port: 5432\n  password: s3cret\nunused: x\n")
+                .expect("yaml parses");
+        let resolution = Resolution {
+            env_map: HashMap::from([("NAMESPACE".to_string(), "prod".to_string())]),
+            values_map: HashMap::from([
+                ("db.port".to_string(), "5432".to_string()),
+                ("db.password".to_string(), "s3cret".to_string()),
+            ]),
+            sources: HashMap::from([
+                ("environment.NAMESPACE".to_string(), ValueSource::OsEnv),
+                ("db.port".to_string(), ValueSource::ValuesFile),
+                ("db.password".to_string(), ValueSource::SecretsFile),
+            ]),
+            values: Some(values),
+            ..Resolution::default()
+        };
+        let group = ValuesGroup {
+            values_file: PathBuf::from("Values.yaml"),
+            indices: vec![0],
+            templates: Vec::new(),
+            resolution,
+        };
+        assert_eq!(
+            render_used_values(&[group]).expect("renders"),
+            "db:\n  password: <redacted>\n  port: 5432\nenvironment:\n  NAMESPACE: prod\n"
+        );
+    }
+
+    #[test]
+    fn render_dry_run_report_lists_sorted_keys_with_sources() {
+        let resolution = Resolution {
+            sources: HashMap::from([
+                ("environment.NAMESPACE".to_string(), ValueSource::OsEnv),
+                ("db.port".to_string(), ValueSource::ValuesFile),
+                ("environment.TAG".to_string(), ValueSource::Prompt),
+            ]),
+            missing_env: vec!["REGION".to_string()],
+            missing_values: vec!["db.host".to_string()],
+            ..Resolution::default()
+        };
+        let group = ValuesGroup {
+            values_file: PathBuf::from("Values.yaml"),
+            indices: vec![0],
+            templates: Vec::new(),
+            resolution,
+        };
+        assert_eq!(
+            render_dry_run_report(&[group]),
+            "# values file: Values.yaml\n\
+             .Values.db.host: missing\n\
+             .Values.db.port: values-file\n\
+             environment.NAMESPACE: os-env\n\
+             environment.REGION: missing\n\
+             environment.TAG: prompt\n"
+        );
+    }
+
+    #[test]
+    fn filter_changed_files_keeps_discovered_files_in_the_changed_set() {
+        let dir = env::temp_dir().join(format!("tplenv-changed-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create temp dir");
+        let files: Vec<PathBuf> = ["10-a.yaml", "20-b.yaml", "30-c.yaml"]
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                fs::write(&path, "a: 1\n").expect("write file");
+                path
+            })
+            .collect();
+        let changed = vec![
+            dir.join(".").join("30-c.yaml"),
+            dir.join("10-a.yaml"),
+            dir.join("README.md"),
+        ];
+        assert_eq!(
+            filter_changed_files(files, &changed),
+            vec![dir.join("10-a.yaml"), dir.join("30-c.yaml")]
+        );
+        fs::remove_dir_all(&dir).expect("cleanup");
+    }
+
+    #[test]
+    fn split_front_matter_strips_marked_block_only() {
+        let input = "---\n# tplenv-values\nreplicas: 2\nimage: {tag: v1}\n---\nkind: Deployment\n";
+        let (values, rest) = split_front_matter(input).expect("splits");
+        let values = values.expect("front matter");
+        assert_eq!(rest, "kind: Deployment\n");
+        assert_eq!(
+            lookup_yaml_path(&values, "image.tag").and_then(|v| v.as_str()),
+            Some("v1")
+        );
+
+        let plain = "---\nkind: Deployment\n---\nkind: Service\n";
+        let (values, rest) = split_front_matter(plain).expect("no front matter");
+        assert!(values.is_none());
+        assert_eq!(rest, plain);
+
+        let (values, rest) =
+            split_front_matter("---\n# tplenv-values\n---\na: 1\n").expect("empty");
+        assert_eq!(values, Some(YamlValue::Mapping(YamlMapping::new())));
+        assert_eq!(rest, "a: 1\n");
+
+        let err = split_front_matter("---\n# tplenv-values\na: [\n---\n").expect_err("bad yaml");
+        assert_eq!(err.to_string(), "front matter is not valid YAML");
+        let err = split_front_matter("---\n# tplenv-values\n- a\n---\n").expect_err("list");
+        assert!(err.to_string().contains("must be a YAML mapping"), "{err}");
+        let err = split_front_matter("---\n# tplenv-values\na: 1\n").expect_err("unclosed");
+        assert!(err.to_string().contains("not closed"), "{err}");
+    }
+
+    #[test]
+    fn scan_placeholders_counts_keys_and_files() {
+        let re = placeholder_regex().expect("regex compiles");
+        let templates = vec![
+            (
+                PathBuf::from("a.yaml"),
+                "ns: $NAMESPACE\nimg: {{ .Values.image }}:{{ .Values.tag }}\nx: {{ NAMESPACE }}\n"
+                    .to_string(),
+            ),
+            (
+                PathBuf::from("b.yaml"),
+                "ns: ${NAMESPACE}\ntag: {{ .Values.tag }}\nf: {{ .Files.Get \"x.sh\" }}\n"
+                    .to_string(),
+            ),
+        ];
+        let report = scan_placeholders(&templates, &re);
+        assert_eq!(
+            render_scan_report(&report),
+            concat!(
+                "scanned 2 file(s): 7 placeholder(s), 1 unique env var(s), 2 unique values path(s)\n",
+                "\nmost referenced:\n",
+                "  3  NAMESPACE\n",
+                "  2  .Values.tag\n",
+                "  1  .Values.image\n",
+                "\nper file:\n",
+                "  a.yaml: 4 placeholder(s), 1 env var(s), 2 values path(s)\n",
+                "  b.yaml: 3 placeholder(s), 1 env var(s), 1 values path(s)\n",
+            )
+        );
+        let json = yaml_to_json(&serde_yaml::to_value(&report).expect("serializes"));
+        assert!(
+            json.starts_with(r#"{"files":2,"placeholders":7,"env_vars":1,"values_paths":2,"top_keys":[{"key":"NAMESPACE","count":3}"#),
+            "{json}"
+        );
+    }
+
+    #[test]
+    fn render_value_explanation_lists_layers_and_winner() {
+        let doc = |text: &str| serde_yaml::from_str::<YamlValue>(text).expect("yaml");
+        let layers = vec![
+            (
+                "values/base.yaml".to_string(),
+                doc("db: {host: base, user: app}"),
+            ),
+            ("--values-default".to_string(), doc("db: {host: inline}")),
+            ("values/prod.yaml".to_string(), doc("db: {host: prod}")),
+            ("--values-patch p.yaml".to_string(), doc("db: {user: null}")),
+            (
+                "--secrets-file s.yaml".to_string(),
+                doc("db: {password: hunter2}"),
+            ),
+        ];
+        let effective = layers.iter().fold(YamlValue::Null, |acc, (name, layer)| {
+            if name.starts_with("--values-patch") {
+                apply_merge_patch(acc, layer.clone())
+            } else {
+                merge_yaml(acc, layer.clone())
+            }
+        });
+        let explain = |key: &str| {
+            render_value_explanation(key, &layers, &effective, KeyCase::Exact, Some(4))
+                .expect("explains")
+        };
+        assert_eq!(
+            explain(".Values.db.host"),
+            concat!(
+                "explain .Values.db.host:\n",
+                "  values/base.yaml: \"base\"\n",
+                "  --values-default: \"inline\"\n",
+                "  values/prod.yaml: \"prod\"\n",
+                "  final: \"prod\" (from values/prod.yaml)\n",
+            )
+        );
+        assert_eq!(
+            explain("db.user"),
+            "explain .Values.db.user:\n  values/base.yaml: \"app\"\n  --values-patch p.yaml: deleted\n  final: not set\n"
+        );
+        assert_eq!(
+            explain("db.password"),
+            "explain .Values.db.password:\n  --secrets-file s.yaml: <redacted>\n  final: <redacted> (from --secrets-file s.yaml)\n"
+        );
+        assert_eq!(
+            explain("db.port"),
+            "explain .Values.db.port:\n  final: not set\n"
+        );
+    }
+
+    #[test]
+    fn render_values_comparison_lists_changed_and_missing_keys() {
+        let a: YamlValue = serde_yaml::from_str(
+            "db:\n  host: staging\n  port: 5432\nonly_a: 1\nunused: x\nenvironment:\n  NS: dev\n",
+        )
+        .expect("yaml parses");
+        let b: YamlValue =
+            serde_yaml::from_str("db:\n  host: prod\n  port: 5432\nenvironment:\n  NS: dev\n")
+                .expect("yaml parses");
+        let keys = BTreeSet::from([
+            "db.host".to_string(),
+            "db.port".to_string(),
+            "only_a".to_string(),
+            "environment.NS".to_string(),
+        ]);
+        let sides = |redact| {
+            render_values_comparison(
+                &keys,
+                (Path::new("a.yaml"), &a),
+                (Path::new("b.yaml"), &b),
+                KeyCase::Exact,
+                redact,
+            )
+            .expect("compares")
+        };
+        assert_eq!(
+            sides(false),
+            "changed .Values.db.host: \"staging\" -> \"prod\"\n\
+             missing .Values.only_a in b.yaml (a.yaml: \"1\")\n"
+        );
+        assert!(sides(true).contains("changed .Values.db.host: <redacted> -> <redacted>"));
+    }
+
+    #[test]
+    fn skip_non_yaml_files_keeps_yaml_in_order() {
+        let mut files = vec![
+            PathBuf::from("1-a.yaml"),
+            PathBuf::from("2-notes.txt"),
+            PathBuf::from("3-b.yaml"),
+            PathBuf::from("4-c.yml"),
+        ];
+        let skipped = skip_non_yaml_files(&mut files);
+        assert_eq!(
+            files,
+            vec![
+                PathBuf::from("1-a.yaml"),
+                PathBuf::from("3-b.yaml"),
+                PathBuf::from("4-c.yml")
+            ]
+        );
+        assert_eq!(skipped, vec![PathBuf::from("2-notes.txt")]);
+    }
+
+    #[test]
+    fn required_filter_fails_on_empty_and_names_missing_keys() {
+        let re = placeholder_regex().expect("regex compiles");
+        let templates = vec![(
+            PathBuf::from("app.yaml"),
+            "tag: {{ .Values.image.tag | required \"image tag is required\" }}\n".to_string(),
+        )];
+        let opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: false,
+            block_scalar: true,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+
+        let present = HashMap::from([("image.tag".to_string(), "1.2".to_string())]);
+        let out = render_templates(&templates, &re, &present, &HashMap::new(), &opts)
+            .expect("render succeeds");
+        assert_eq!(out[0].1, "tag: 1.2\n");
+
+        let empty = HashMap::from([("image.tag".to_string(), String::new())]);
+        let err = render_templates(&templates, &re, &empty, &HashMap::new(), &opts)
+            .expect_err("empty value fails");
+        assert_eq!(err.to_string(), "image tag is required (.Values.image.tag)");
+
+        let messages = collect_required_messages(&templates, &re).expect("pipelines parse");
+        assert_eq!(
+            messages.get("image.tag").map(String::as_str),
+            Some("image tag is required")
+        );
+        assert!(
+            validate_filter(&Filter {
+                name: "required".to_string(),
+                args: Vec::new(),
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn base64_encode_round_trips_rendered_output() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        let rendered = render_multi_document_yaml(
+            &[
+                (PathBuf::from("1.yaml"), "a: 1\n".to_string()),
+                (PathBuf::from("2.yaml"), "b: ümlaut\n".to_string()),
+            ],
+            "---",
+            false,
+        );
+        let encoded = wrap_columns(&base64_encode(rendered.as_bytes()), 8);
+        assert!(encoded.lines().all(|l| l.len() <= 8));
+        assert_eq!(
+            base64_decode(&encoded).expect("valid base64"),
+            rendered.as_bytes()
+        );
+        assert_eq!(base64_decode("Zm8").expect("unpadded"), b"fo");
+        assert!(base64_decode("Zm9v!").is_err());
+        assert!(base64_decode("Z").is_err());
+        assert!(base64_decode("Zg==Zg==").is_err());
+    }
+
+    #[test]
+    fn values_paths_select_list_elements_by_field() {
+        let re = placeholder_regex_with_style(PlaceholderStyle::Standard, '.', false)
+            .expect("regex compiles");
+        let caps = re
+            .captures("{{ .Values.containers[name=app.v1].image | indent 2 }}")
+            .expect("placeholder matches");
+        let path = extract_values_path(&caps).expect("values path");
+        assert_eq!(path, "containers[name=app.v1].image");
+        assert_eq!(split_yaml_path(&path), ["containers[name=app.v1]", "image"]);
+
+        let mut root: YamlValue = serde_yaml::from_str(
+            "containers:\n- name: app.v1\n  image: x\n- name: sidecar\n  image: y\n- name: sidecar\n  image: z\nscalar: 1\n",
+        )
+        .expect("yaml parses");
+        let get = |root: &YamlValue, path: &str| {
+            lookup_yaml_path(root, path).and_then(|v| yaml_value_to_string(v).ok())
+        };
+        assert_eq!(get(&root, &path).as_deref(), Some("x"));
+        // Without strictness, the first of several matches wins.
+        assert_eq!(
+            get(&root, "containers[name=sidecar].image").as_deref(),
+            Some("y")
+        );
+        let err = walk_yaml_path(&root, "containers[name=sidecar].image", true)
+            .expect_err("ambiguous selector");
+        assert_eq!(
+            err.to_string(),
+            ".Values.containers has 2 elements with name=sidecar"
+        );
+        let err =
+            lookup_yaml_path_detailed(&root, "containers[name=db].image").expect_err("no element");
+        assert_eq!(
+            err.to_string(),
+            ".Values.containers has no element with name=db"
+        );
+        let err = lookup_yaml_path_detailed(&root, "scalar[name=db]").expect_err("not a list");
+        assert_eq!(
+            err.to_string(),
+            ".Values.scalar is a scalar, cannot select [name=db]"
+        );
+        assert_eq!(
+            lookup_yaml_path_cased(&root, "Containers[name=app.v1].Image", KeyCase::Insensitive)
+                .expect("not ambiguous")
+                .and_then(|v| v.as_str()),
+            Some("x")
+        );
+
+        set_yaml_path(
+            &mut root,
+            "containers[name=db].image",
+            YamlValue::from("pg"),
+        );
+        assert_eq!(
+            get(&root, "containers[name=db].image").as_deref(),
+            Some("pg")
+        );
+        assert!(delete_yaml_path(&mut root, "containers[name=sidecar]"));
+        assert_eq!(
+            get(&root, "containers[name=sidecar].image").as_deref(),
+            Some("z")
+        );
+    }
+
+    #[test]
+    fn values_paths_select_list_elements_by_index() {
+        let re = placeholder_regex().expect("regex compiles");
+        let caps = re
+            .captures("{{ .Values.hosts[0] }}")
+            .expect("placeholder matches");
+        assert_eq!(extract_values_path(&caps).as_deref(), Some("hosts[0]"));
+
+        let mut root: YamlValue = serde_yaml::from_str(
+            "hosts: [a, b, c]
+scalar: 1
+",
+        )
+        .expect("yaml parses");
+        let get = |root: &YamlValue, path: &str| {
+            lookup_yaml_path(root, path).and_then(|v| yaml_value_to_string(v).ok())
+        };
+        assert_eq!(get(&root, "hosts[1]").as_deref(), Some("b"));
+        assert_eq!(get(&root, "hosts[5]"), None);
+        let err = lookup_yaml_path_detailed(&root, "hosts[5]").expect_err("out of range");
+        assert_eq!(
+            err.to_string(),
+            ".Values.hosts[5] is out of range: .Values.hosts has 3 element(s)"
+        );
+        let err = lookup_yaml_path_detailed(&root, "scalar[0]").expect_err("not a list");
+        assert_eq!(
+            err.to_string(),
+            ".Values.scalar is a scalar, cannot select [0]"
+        );
+
+        set_yaml_path(&mut root, "hosts[4]", YamlValue::from("e"));
+        assert_eq!(get(&root, "hosts[4]").as_deref(), Some("e"));
+        assert_eq!(lookup_yaml_path(&root, "hosts[3]"), Some(&YamlValue::Null));
+        assert!(delete_yaml_path(&mut root, "hosts[0]"));
+        assert!(!delete_yaml_path(&mut root, "hosts[9]"));
+        assert_eq!(get(&root, "hosts[0]").as_deref(), Some("b"));
+
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let opts = ResolveOptions {
+            value_file_only: true,
+            verbose: false,
+            quiet: true,
+            conflict_policy: ConflictPolicy::ValuesFile,
+            resolution_order: &DEFAULT_RESOLUTION_ORDER,
+            key_case: KeyCase::Exact,
+            strict_unknown_keys: false,
+            tolerant_values: false,
+            always_load: false,
+            annotations: &HashMap::new(),
+            prompted_env_map: &HashMap::new(),
+            overrides: &HashMap::new(),
+            deny_os_env: false,
+            env_ignore_case: false,
+            os_env_names: &HashMap::new(),
+            env_file: &HashMap::new(),
+            optional_keys: &[],
+            values_default: None,
+            values_inline: None,
+            values_patch: None,
+            secrets: None,
+            select: None,
+            load: &load,
+        };
+        let values = serde_yaml::from_str("hosts: [a, b, c]\n").expect("yaml parses");
+        let values_paths = BTreeSet::from(["hosts[0]".to_string(), "hosts[5]".to_string()]);
+        let res = resolve_placeholders(Some(values), &BTreeSet::new(), &values_paths, &opts)
+            .expect("resolves");
+        assert_eq!(res.values_map["hosts[0]"], "a");
+        assert_eq!(res.missing_values, ["hosts[5]"]);
+        assert_eq!(display_values_key("hosts[5]"), ".Values.hosts[5]");
+    }
+
+    #[test]
+    fn lookup_yaml_path_cased_normalizes_each_segment() {
+        let yaml: YamlValue =
+            serde_yaml::from_str("image:\n  imageTag: '1.2'\nserviceAccount:\n  create: true\n")
+                .expect("yaml parses");
+        let get = |path: &str, key_case| {
+            lookup_yaml_path_cased(&yaml, path, key_case)
+                .expect("not ambiguous")
+                .and_then(|v| yaml_value_to_string(v).ok())
+        };
+        assert_eq!(get("image.image_tag", KeyCase::Exact), None);
+        assert_eq!(
+            get("image.image_tag", KeyCase::Snake).as_deref(),
+            Some("1.2")
+        );
+        assert_eq!(
+            get("image.image_tag", KeyCase::Camel).as_deref(),
+            Some("1.2")
+        );
+        assert_eq!(
+            get("SERVICE-ACCOUNT.Create", KeyCase::Insensitive).as_deref(),
+            Some("true")
+        );
+        assert_eq!(normalize_key("imageTag", KeyCase::Snake), "image_tag");
+        assert_eq!(normalize_key("image_tag", KeyCase::Camel), "imageTag");
+        assert_eq!(normalize_key("appName", KeyCase::Kebab), "app-name");
+
+        let re = placeholder_regex().expect("regex compiles");
+        let caps = re
+            .captures("{{ .Values.image.app-name -}}")
+            .expect("dashed segment matches");
+        let path = extract_values_path(&caps).expect("values path");
+        assert_eq!(path, "image.app-name");
+        let labels: YamlValue =
+            serde_yaml::from_str("image:\n  appName: demo\n").expect("yaml parses");
+        for key_case in [KeyCase::Camel, KeyCase::Kebab, KeyCase::KebabToCamel] {
+            assert_eq!(
+                lookup_yaml_path_cased(&labels, &path, key_case)
+                    .expect("not ambiguous")
+                    .and_then(|v| v.as_str()),
+                Some("demo")
+            );
+        }
+
+        // Only the dashed segment is transformed; a snake_case sibling is not a candidate.
+        let both: YamlValue =
+            serde_yaml::from_str("appName: camel\napp_name: snake\n").expect("yaml parses");
+        assert_eq!(
+            lookup_yaml_path_cased(&both, "app-name", KeyCase::KebabToCamel)
+                .expect("not ambiguous")
+                .and_then(|v| v.as_str()),
+            Some("camel")
+        );
+        let kebab: YamlValue =
+            serde_yaml::from_str("app-name: kebab\napp_name: snake\n").expect("yaml parses");
+        assert_eq!(
+            lookup_yaml_path_cased(&kebab, "appName", KeyCase::CamelToKebab)
+                .expect("not ambiguous")
+                .and_then(|v| v.as_str()),
+            Some("kebab")
+        );
+        assert_eq!(
+            lookup_yaml_path_cased(&kebab, "app_name", KeyCase::CamelToKebab)
+                .expect("not ambiguous")
+                .and_then(|v| v.as_str()),
+            Some("snake")
+        );
+
+        let ambiguous: YamlValue =
+            serde_yaml::from_str("imageTag: a\nimage_tag: b\n").expect("yaml parses");
+        let err = lookup_yaml_path_cased(&ambiguous, "imagetag", KeyCase::Insensitive)
+            .expect_err("ambiguous");
+        assert!(err.to_string().contains("ambiguous key"));
+    }
+
+    #[test]
+    fn render_values_dump_masks_secrets_file_leaves() {
+        let values: YamlValue =
+            serde_yaml::from_str("db:\n  host: db\n  password: s3cret\nreplicas: 2\n")
+                .expect("yaml parses");
+        let secrets: YamlValue =
+            serde_yaml::from_str("db:\n  password: s3cret\n").expect("yaml parses");
+        let resolution = Resolution {
+            values: Some(values),
+            ..Resolution::default()
+        };
+        let dump = render_values_dump(
+            &[ValuesGroup {
+                values_file: PathBuf::from("Values.yaml"),
+                indices: vec![0],
+                templates: Vec::new(),
+                resolution,
+            }],
+            Some(&secrets),
+        )
+        .expect("dump renders");
+        assert_eq!(
+            dump,
+            "db:\n  host: db\n  password: <redacted>\nreplicas: 2\n"
+        );
+    }
+
+    #[test]
+    fn apply_conditionals_keeps_truthy_branches_and_reports_unbalanced_markers() {
+        let re = placeholder_regex_with_style(PlaceholderStyle::Standard, '.', false)
+            .expect("regex compiles");
+        let values: YamlValue =
+            serde_yaml::from_str("enabled: true\nempty: \"\"\nflag: \"false\"\nlist: []\n")
+                .expect("yaml parses");
+        let truthy = |cond: &str| {
+            condition_is_truthy(cond, &values, &re, KeyCase::Exact, &Delimiters::default())
+        };
+        let input = "a\n{{ if .Values.enabled }}\nb\n{{ if .Values.empty }}c{{ else }}d{{ end }}\n{{ else }}\ne\n{{ end }}\n{{ if .Values.flag }}f{{ end }}{{ if .Values.list }}g{{ end }}{{ if .Values.missing }}h{{ end }}\n";
+        assert_eq!(
+            apply_conditionals(input, Some(&truthy), &Delimiters::default()).expect("renders"),
+            "a\nb\nd\nf\n"
+        );
+        assert_eq!(
+            apply_conditionals(input, None, &Delimiters::default()).expect("strips markers"),
+            "a\nb\ncd\ne\nfgh\n"
+        );
+
+        let err = apply_conditionals(
+            "a\n{{ if .Values.enabled }}\nb\n",
+            Some(&truthy),
+            &Delimiters::default(),
+        )
+        .expect_err("unclosed if fails");
+        assert!(format!("{err:#}").contains("line 2"), "{err:#}");
+        let err = apply_conditionals("a\n{{ end }}\n", None, &Delimiters::default())
+            .expect_err("stray end fails");
+        assert!(format!("{err:#}").contains("line 2"), "{err:#}");
+        let err = apply_conditionals(
+            "{{ if env }}x{{ end }}",
+            Some(&truthy),
+            &Delimiters::default(),
+        )
+        .expect_err("non-values condition fails");
+        assert!(format!("{err:#}").contains(".Values path"), "{err:#}");
+        assert!(
+            lint_template(
+                "{{ if .Values.enabled }}x{{ end }}",
+                &re,
+                false,
+                &Delimiters::default()
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn library_render_does_not_count_warnings() {
+        let values: YamlValue = serde_yaml::from_str("notes: \"a\\nb\"\n").expect("yaml parses");
+        let opts = Options {
+            indent: true,
+            ..Options::default()
+        };
+        let warnings = WARNINGS.get();
+        // A multi-line value inside a folded scalar is a warning for the CLI.
+        render(
+            "notes: >\n  {{ .Values.notes }}\n",
+            &HashMap::new(),
+            &values,
+            &opts,
+        )
+        .expect("renders");
+        assert_eq!(WARNINGS.get(), warnings);
+    }
+
+    #[test]
+    fn check_warnings_fails_only_with_fail_on_warning() {
+        warn(true, "counted even when quiet");
+        assert!(check_warnings(false).is_ok());
+        let err = check_warnings(true).expect_err("warnings fail the run");
+        assert!(format!("{err:#}").contains("--fail-on-warning"), "{err:#}");
+    }
+
+    #[test]
+    fn render_templates_escapes_values_for_json_templates() {
+        let re = placeholder_regex_with_style(PlaceholderStyle::Standard, '.', false)
+            .expect("regex compiles");
+        let templates = vec![(
+            PathBuf::from("app.json"),
+            "{\n  \"cert\": \"{{ .Values.cert }}\",\n  \"name\": \"${NAME}\"\n}\n".to_string(),
+        )];
+        let values_map = HashMap::from([(
+            "cert".to_string(),
+            "line \"one\"\n\tline\\two\u{1}".to_string(),
+        )]);
+        let env_map = HashMap::from([("NAME".to_string(), "demo".to_string())]);
+        let opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: true,
+            block_scalar: true,
+            format: TemplateFormat::Json,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let out = render_templates(&templates, &re, &values_map, &env_map, &opts).expect("ok");
+        assert_eq!(
+            out[0].1,
+            "{\n  \"cert\": \"line \\\"one\\\"\\n\\tline\\\\two\\u0001\",\n  \"name\": \"demo\"\n}\n"
+        );
+
+        let rendered = vec![
+            (PathBuf::from("a.json"), "{\"a\": 1}\n".to_string()),
+            (PathBuf::from("b.json"), "{\"b\": 2}".to_string()),
+        ];
+        assert_eq!(
+            render_json_array(&rendered),
+            "[\n{\"a\": 1},\n{\"b\": 2}\n]\n"
+        );
+        assert_eq!(
+            detect_template_format(&[PathBuf::from("a.json"), PathBuf::from("b.json")]),
+            TemplateFormat::Json
+        );
+        assert_eq!(
+            detect_template_format(&[PathBuf::from("a.json"), PathBuf::from("b.yaml")]),
+            TemplateFormat::Yaml
+        );
+    }
+
+    #[test]
+    fn missing_required_keys_treats_empty_as_missing_unless_allowed() {
+        let values: YamlValue =
+            serde_yaml::from_str("team: core\ncostCenter: ''\nowner: ~\nreplicas: 0\n")
+                .expect("yaml parses");
+        let keys: Vec<String> = ["team", "costCenter", "owner", "replicas", "region"]
+            .iter()
+            .map(|k| k.to_string())
+            .collect();
+        assert_eq!(
+            missing_required_keys(&values, &keys, KeyCase::Exact, false).expect("checks"),
+            ["costCenter", "owner", "region"]
+        );
+        assert_eq!(
+            missing_required_keys(&values, &keys, KeyCase::Exact, true).expect("checks"),
+            ["region"]
+        );
+        assert!(
+            missing_required_keys(&values, &["cost_center".to_string()], KeyCase::Snake, true)
+                .expect("checks")
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn render_templates_preserve_quotes_keeps_numeric_looking_strings() {
+        let re = placeholder_regex().expect("regex compiles");
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            concat!(
+                "zip: {{ .Values.zip }}\n",
+                "version: {{ .Values.version }} # pinned\n",
+                "quoted: \"{{ .Values.zip }}\"\n",
+                "name: {{ .Values.name }}\n",
+                "replicas: {{ .Values.replicas }}\n",
+                "inline: v{{ .Values.version }}\n",
+            )
+            .to_string(),
+        )];
+        let values: YamlValue =
+            serde_yaml::from_str("zip: \"01234\"\nversion: '1.10'\nname: api\nreplicas: 3\n")
+                .expect("yaml");
+        let values_map: HashMap<String, String> = ["zip", "version", "name", "replicas"]
+            .into_iter()
+            .map(|key| {
+                let value = lookup_yaml_path(&values, key).expect("key");
+                (
+                    key.to_string(),
+                    yaml_value_to_string(value).expect("scalar"),
+                )
+            })
+            .collect();
+        let strings =
+            BTreeSet::from(["zip".to_string(), "version".to_string(), "name".to_string()]);
+        let mut opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: false,
+            block_scalar: true,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: Some(&strings),
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let out = render_templates(&templates, &re, &values_map, &HashMap::new(), &opts)
+            .expect("renders");
+        assert_eq!(
+            out[0].1,
+            concat!(
+                "zip: \"01234\"\n",
+                "version: \"1.10\" # pinned\n",
+                "quoted: \"01234\"\n",
+                "name: api\n",
+                "replicas: 3\n",
+                "inline: v1.10\n",
+            )
+        );
+        let parsed: YamlValue = serde_yaml::from_str(&out[0].1).expect("valid yaml");
+        assert_eq!(
+            lookup_yaml_path(&parsed, "version").and_then(|v| v.as_str()),
+            Some("1.10")
+        );
+
+        opts.quoted = None;
+        let out = render_templates(&templates, &re, &values_map, &HashMap::new(), &opts)
+            .expect("renders");
+        assert!(out[0].1.starts_with("zip: 01234\nversion: 1.10 # pinned\n"));
+    }
+
+    #[test]
+    fn render_templates_typed_values_round_trip_through_yaml() {
+        let re = placeholder_regex().expect("regex compiles");
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            concat!(
+                "config:\n",
+                "  note: {{ .Values.note }}\n",
+                "  tag: {{ .Values.tag }} # pinned\n",
+                "  answer: {{ .Values.answer }}\n",
+                "  replicas: {{ .Values.replicas }}\n",
+                "  unset: {{ .Values.unset }}\n",
+                "  labels: {{ .Values.labels }}\n",
+                "  hosts: {{ .Values.hosts }}\n",
+                "  script: {{ .Values.script }}\n",
+                "  items:\n",
+                "    - {{ .Values.labels }}\n",
+                "  inline: v{{ .Values.replicas }}\n",
+                "  replaced: {{ .Values.note | replace \":\" \"=\" }}\n",
+            )
+            .to_string(),
+        )];
+        let values: YamlValue = serde_yaml::from_str(concat!(
+            "note: 'a: b'\n",
+            "tag: '#1'\n",
+            "answer: 'yes'\n",
+            "replicas: 3\n",
+            "unset: null\n",
+            "labels: {app: api, tier: web}\n",
+            "hosts: [a, b]\n",
+            "script: \"echo hi\\necho bye\\n\"\n",
+        ))
+        .expect("yaml");
+        let keys = [
+            "note", "tag", "answer", "replicas", "unset", "labels", "hosts", "script",
+        ];
+        let lookup = |key: &str| lookup_yaml_path(&values, key).expect("key").clone();
+        let values_map: HashMap<String, String> = keys
+            .iter()
+            .map(|key| {
+                (
+                    key.to_string(),
+                    yaml_value_to_string(&lookup(key)).expect("value"),
+                )
+            })
+            .collect();
+        let typed: HashMap<String, YamlValue> = keys
+            .iter()
+            .map(|key| (key.to_string(), lookup(key)))
+            .collect();
+        let opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: false,
+            block_scalar: true,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: Some(&typed),
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let out = render_templates(&templates, &re, &values_map, &HashMap::new(), &opts)
+            .expect("renders");
+        assert!(
+            out[0]
+                .1
+                .contains("  labels: \n    app: api\n    tier: web\n"),
+            "{}",
+            out[0].1
+        );
+        let parsed: YamlValue = serde_yaml::from_str(&out[0].1).expect("valid yaml");
+        for key in keys {
+            assert_eq!(
+                lookup_yaml_path(&parsed, &format!("config.{key}")),
+                Some(&lookup(key)),
+                "{key}"
+            );
+        }
+        assert_eq!(
+            lookup_yaml_path(&parsed, "config.items[0]"),
+            Some(&lookup("labels"))
+        );
+        assert_eq!(
+            lookup_yaml_path(&parsed, "config.inline").and_then(|v| v.as_str()),
+            Some("v3")
+        );
+        // Filtered placeholders are interpolated as text, as without --typed-values.
+        assert!(out[0].1.ends_with("  replaced: a= b\n"));
+    }
+
+    #[test]
+    fn render_templates_applies_folded_policy_to_multi_line_values() {
+        let re = placeholder_regex().expect("regex compiles");
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            "data:\n  notes: >-\n    Intro\n    {{ .Values.notes }}\n  other: x\n".to_string(),
+        )];
+        let values_map = HashMap::from([("notes".to_string(), "first\nsecond".to_string())]);
+        let mut opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: true,
+            block_scalar: true,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let notes = |opts: &RenderOptions| {
+            let out = render_templates(&templates, &re, &values_map, &HashMap::new(), opts)
+                .expect("renders");
+            let parsed: YamlValue = serde_yaml::from_str(&out[0].1).expect("valid yaml");
+            let notes = lookup_yaml_path(&parsed, "data.notes")
+                .and_then(|v| v.as_str())
+                .expect("notes")
+                .to_string();
+            (out[0].1.clone(), notes)
+        };
+
+        let warnings = WARNINGS.get();
+        let (_, folded) = notes(&opts);
+        assert_eq!(folded, "Intro first second");
+        assert!(WARNINGS.get() > warnings);
+
+        opts.folded_policy = FoldedPolicy::Preserve;
+        let (text, preserved) = notes(&opts);
+        assert!(text.contains("notes: >-\n"), "{text}");
+        assert_eq!(preserved, "Intro first\nsecond");
+
+        opts.folded_policy = FoldedPolicy::Literal;
+        let (text, literal) = notes(&opts);
+        assert!(text.contains("notes: |-\n"), "{text}");
+        assert_eq!(literal, "Intro\nfirst\nsecond");
+
+        assert_eq!(folded_scalar_header("cmp: a->\n  "), None);
+        assert_eq!(folded_scalar_header("- >\n  "), Some(2));
+    }
+
+    #[test]
+    fn render_templates_escapes_only_injected_text_in_quoted_scalars() {
+        let re = placeholder_regex().expect("regex compiles");
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            concat!(
+                "a: \"prefix-{{ .Values.v }}-suffix\"\n",
+                "b: 'it''s {{ .Values.v }}'\n",
+                "c: plain {{ .Values.v }}\n",
+                "d: \"{{ .Values.v | required \"x\" }}\" # \"{{ .Values.v }}\"\n",
+                "e: say \"{{ .Values.v }}\"\n",
+            )
+            .to_string(),
+        )];
+        let values_map = HashMap::from([("v".to_string(), "a \"b\" \\ 'c'\nd".to_string())]);
+        let opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: false,
+            block_scalar: true,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let out = render_templates(&templates, &re, &values_map, &HashMap::new(), &opts)
+            .expect("renders");
+        let raw = "a \"b\" \\ 'c'\nd";
+        let expected = [
+            r#"a: "prefix-a \"b\" \\ 'c'\nd-suffix""#.to_string(),
+            "b: 'it''s a \"b\" \\ ''c''\nd'".to_string(),
+            format!("c: plain {raw}"),
+            format!(r#"d: "a \"b\" \\ 'c'\nd" # "{raw}""#),
+            format!("e: say \"{raw}\""),
+        ]
+        .join("\n");
+        assert_eq!(out[0].1, format!("{expected}\n"));
+
+        let parsed: YamlValue =
+            serde_yaml::from_str(out[0].1.lines().next().expect("line")).expect("valid yaml");
+        assert_eq!(
+            lookup_yaml_path(&parsed, "a").and_then(|v| v.as_str()),
+            Some(format!("prefix-{raw}-suffix").as_str())
+        );
+    }
+
+    #[test]
+    fn prompt_skip_empty_keeps_empty_keys_under_force() {
+        let path = env::temp_dir().join(format!("tplenv-skip-empty-{}.yaml", std::process::id()));
+        fs::write(&path, "a: ''\nb: x\n").expect("write file");
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let empty_set = BTreeSet::new();
+        let empty_env = HashMap::new();
+        let prompt = |skip_empty| {
+            let opts = PromptUpdateOptions {
+                include_environment_vars: false,
+                skip_existing_env_vars: &empty_set,
+                existing_os_env_values: &empty_env,
+                prompt_contexts: &HashMap::new(),
+                prompt_order: &[],
+                annotations: &HashMap::new(),
+                delete_paths: &[],
+                confirm_patterns: &[],
+                multiline_patterns: &[],
+                editor_patterns: &[],
+                hidden_patterns: &[],
+                prompt_groups: &[],
+                secrets: None,
+                prompt_defaults: None,
+                history: None,
+                key_case: KeyCase::Exact,
+                path_sep: '.',
+                load: &load,
+                no_interactive: true,
+                stdin_is_terminal: true,
+                stdin_at_eof: &|| Ok(true),
+                force: true,
+                skip_empty,
+                dry_run: false,
+                verbose: false,
+            };
+            let paths = BTreeSet::from(["a".to_string(), "b".to_string(), "c".to_string()]);
+            prompt_and_update_values_file(&path, &paths, &empty_set, &opts)
+                .expect("prompts")
+                .prompted
+        };
+        let keys = |prompted: Vec<(String, String)>| -> Vec<String> {
+            prompted.into_iter().map(|(k, _)| k).collect()
+        };
+        assert_eq!(keys(prompt(true)), ["b", "c"]);
+        assert_eq!(keys(prompt(false)), ["a", "b", "c"]);
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn unused_values_keys_lists_leaves_no_template_reaches() {
+        let re = placeholder_regex_with_style(PlaceholderStyle::Standard, '.', false)
+            .expect("regex compiles");
+        let values: YamlValue = serde_yaml::from_str(
+            "db:\n  host: h\n  port: 1\n  old: x\nimage:\n  tag: v1\n  repo: r\nhosts:\n  - a\nflag: true\nstale: {}\nenvironment:\n  NS: dev\n",
+        )
+        .expect("yaml parses");
+        let template = "{{ .Values.db.host }} {{ .Values.image }} {{ .Values.hosts[0] }}\n{{ if .Values.flag }}{{ .Values.db.port }}{{ end }}\n";
+        let (_, mut used) = collect_placeholders(template, &re);
+        used.extend(condition_values_paths(
+            template,
+            &re,
+            &Delimiters::default(),
+        ));
+        assert_eq!(
+            unused_values_keys(&values, &used, KeyCase::Exact),
+            vec!["db.old".to_string(), "stale".to_string()]
+        );
+    }
+
+    #[test]
+    fn secret_key_prompts_do_not_show_the_default() {
+        assert_eq!(
+            yaml_key_prompt("db.password", Some("hunter2"), true),
+            "Enter value for values file key db.password [<redacted>]: "
+        );
+        assert_eq!(
+            yaml_key_prompt("db.host", Some("localhost"), false),
+            "Enter value for values file key db.host [localhost]: "
+        );
+        assert_eq!(
+            yaml_key_prompt("db.password", None, true),
+            "Enter value for values file key db.password: "
+        );
+    }
+
+    #[test]
+    fn updated_values_text_keeps_comments_and_key_order() {
+        let original = concat!(
+            "# Service settings\n",
+            "image:\n",
+            "  repo: app # the registry path\n",
+            "  tag: latest\n",
+            "\n",
+            "# Database\n",
+            "db:\n",
+            "  host: db\n",
+            "  old: x\n",
+            "replicas: 2\n",
+        );
+        let path = Path::new("Values.yaml");
+        let mut root: YamlValue = serde_yaml::from_str(original).expect("yaml parses");
+        let edits = vec![
+            ValuesEdit::Set("image.repo".to_string(), YamlValue::from("ghcr.io/app")),
+            ValuesEdit::Set("db.port".to_string(), YamlValue::from("5432")),
+            ValuesEdit::Set("db.tls.mode".to_string(), YamlValue::from("yes")),
+            ValuesEdit::Set("environment.TOKEN".to_string(), YamlValue::from("t")),
+            ValuesEdit::Delete("db.old".to_string()),
+        ];
+        for edit in &edits {
+            match edit {
+                ValuesEdit::Set(key, value) => set_yaml_path(&mut root, key, value.clone()),
+                ValuesEdit::Delete(key) => {
+                    delete_yaml_path(&mut root, key);
+                }
+            }
+        }
+        let text = updated_values_text(Some(original), &edits, &root, path).expect("text");
+        assert_eq!(
+            text,
+            concat!(
+                "# Service settings\n",
+                "image:\n",
+                "  repo: ghcr.io/app # the registry path\n",
+                "  tag: latest\n",
+                "\n",
+                "# Database\n",
+                "db:\n",
+                "  host: db\n",
+                "  port: '5432'\n",
+                "  tls:\n",
+                "    mode: yes\n",
+                "replicas: 2\n",
+                "environment:\n",
+                "  TOKEN: t\n",
+            )
+        );
+
+        // A multi-line value replaces the key's line; other keys are untouched.
+        let mut root: YamlValue = serde_yaml::from_str(original).expect("yaml parses");
+        let script = YamlValue::from("a\nb");
+        set_yaml_path(&mut root, "image.tag", script.clone());
+        let edits = [ValuesEdit::Set("image.tag".to_string(), script)];
+        let text = updated_values_text(Some(original), &edits, &root, path).expect("text");
+        assert!(
+            text.contains(
+                "  repo: app # the registry path\n  tag: |-\n    a\n    b\n\n# Database\n"
+            )
+        );
+
+        // Text the line editor cannot reproduce falls back to a fresh serialization.
+        let flow = "{a: 1}\n";
+        let mut root: YamlValue = serde_yaml::from_str(flow).expect("yaml parses");
+        set_yaml_path(&mut root, "b", YamlValue::from("2"));
+        let edits = [ValuesEdit::Set("b".to_string(), YamlValue::from("2"))];
+        let text = updated_values_text(Some(flow), &edits, &root, path).expect("text");
+        assert_eq!(text, "a: 1\nb: '2'\n");
+    }
+
+    #[test]
+    fn prompt_defaults_fill_unset_keys_only_and_dry_run_skips_the_write() {
+        let path = env::temp_dir().join(format!(
+            "tplenv-prompt-defaults-{}.yaml",
+            std::process::id()
+        ));
+        fs::write(&path, "db:\n  host: existing\n").expect("write file");
+        let defaults: YamlValue =
+            serde_yaml::from_str("db:\n  host: suggested\n  port: 5432\nimage.tag: v1\n")
+                .expect("yaml parses");
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let empty_set = BTreeSet::new();
+        let mut opts = PromptUpdateOptions {
+            include_environment_vars: false,
+            skip_existing_env_vars: &empty_set,
+            existing_os_env_values: &HashMap::new(),
+            prompt_contexts: &HashMap::new(),
+            prompt_order: &[],
+            annotations: &HashMap::new(),
+            delete_paths: &[],
+            confirm_patterns: &[],
+            multiline_patterns: &[],
+            editor_patterns: &[],
+            hidden_patterns: &[],
+            prompt_groups: &[],
+            secrets: None,
+            prompt_defaults: Some(&defaults),
+            history: None,
+            key_case: KeyCase::Exact,
+            path_sep: '.',
+            load: &load,
+            no_interactive: true,
+            stdin_is_terminal: true,
+            stdin_at_eof: &|| Ok(true),
+            force: true,
+            skip_empty: false,
+            dry_run: false,
+            verbose: false,
+        };
+        let paths = BTreeSet::from([
+            "db.host".to_string(),
+            "db.port".to_string(),
+            "image.tag".to_string(),
+            "other".to_string(),
+        ]);
+        opts.dry_run = true;
+        let update =
+            prompt_and_update_values_file(&path, &paths, &empty_set, &opts).expect("prompts");
+        let unsaved = update.unsaved.expect("dry run keeps the document");
+        assert_eq!(
+            lookup_yaml_path(&unsaved, "db.port"),
+            Some(&YamlValue::String("5432".to_string()))
+        );
+        assert_eq!(
+            fs::read_to_string(&path).expect("read file"),
+            "db:\n  host: existing\n"
+        );
+
+        opts.dry_run = false;
+        let mut prompted = prompt_and_update_values_file(&path, &paths, &empty_set, &opts)
+            .expect("prompts")
+            .prompted;
+        prompted.sort();
+        fs::remove_file(&path).ok();
+        assert_eq!(
+            prompted,
+            [
+                ("db.host".to_string(), "existing".to_string()),
+                ("db.port".to_string(), "5432".to_string()),
+                ("image.tag".to_string(), "v1".to_string()),
+                ("other".to_string(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn non_terminal_stdin_uses_defaults_and_fails_on_keys_without_one() {
+        let path = env::temp_dir().join(format!("tplenv-no-tty-{}.yaml", std::process::id()));
+        fs::write(&path, "db:\n  host: existing\n").expect("write file");
+        let defaults: YamlValue = serde_yaml::from_str("db:\n  port: 5432\n").expect("yaml parses");
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let empty_set = BTreeSet::new();
+        let mut opts = PromptUpdateOptions {
+            include_environment_vars: false,
+            skip_existing_env_vars: &empty_set,
+            existing_os_env_values: &HashMap::new(),
+            prompt_contexts: &HashMap::new(),
+            prompt_order: &[],
+            annotations: &HashMap::new(),
+            delete_paths: &[],
+            confirm_patterns: &[],
+            multiline_patterns: &[],
+            editor_patterns: &[],
+            hidden_patterns: &[],
+            prompt_groups: &[],
+            secrets: None,
+            prompt_defaults: Some(&defaults),
+            history: None,
+            key_case: KeyCase::Exact,
+            path_sep: '.',
+            load: &load,
+            no_interactive: false,
+            stdin_is_terminal: false,
+            stdin_at_eof: &|| Ok(true),
+            force: true,
+            skip_empty: false,
+            dry_run: false,
+            verbose: false,
+        };
+        let paths = BTreeSet::from([
+            "db.host".to_string(),
+            "db.port".to_string(),
+            "db.user".to_string(),
+            "other".to_string(),
+        ]);
+        let Err(err) = prompt_and_update_values_file(&path, &paths, &empty_set, &opts) else {
+            panic!("keys without a default need a terminal");
+        };
+        assert!(
+            err.to_string().starts_with(
+                "--create-values-file needs input for 2 key(s) but stdin is not a terminal and has no more input: db.user, other"
+            ),
+            "{err}"
+        );
+        assert_eq!(
+            fs::read_to_string(&path).expect("read file"),
+            "db:\n  host: existing\n"
+        );
+
+        // Keys that all have a default are filled silently.
+        let paths = BTreeSet::from(["db.host".to_string(), "db.port".to_string()]);
+        opts.force = false;
+        let update =
+            prompt_and_update_values_file(&path, &paths, &empty_set, &opts).expect("no prompt");
+        fs::remove_file(&path).ok();
+        assert_eq!(
+            update.prompted,
+            [("db.port".to_string(), "5432".to_string())]
+        );
+    }
+
+    #[test]
+    fn meta_patterns_reject_non_interactive_answers_that_do_not_match() {
+        let path = env::temp_dir().join(format!("tplenv-meta-{}.yaml", std::process::id()));
+        let original = "_meta:\n  replicas:\n    pattern: '^[0-9]+$'\n    message: replicas must be a number\n  namespace:\n    pattern: '^[a-z0-9-]+$'\n    description: Kubernetes namespace\n";
+        fs::write(&path, original).expect("write file");
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let empty_set = BTreeSet::new();
+        let mut defaults: YamlValue =
+            serde_yaml::from_str("replicas: three\nnamespace: dev\n").expect("yaml parses");
+        let paths = BTreeSet::from(["namespace".to_string(), "replicas".to_string()]);
+        let update = |defaults: &YamlValue| {
+            let opts = PromptUpdateOptions {
+                include_environment_vars: false,
+                skip_existing_env_vars: &empty_set,
+                existing_os_env_values: &HashMap::new(),
+                prompt_contexts: &HashMap::new(),
+                prompt_order: &[],
+                annotations: &HashMap::new(),
+                delete_paths: &[],
+                confirm_patterns: &[],
+                multiline_patterns: &[],
+                editor_patterns: &[],
+                hidden_patterns: &[],
+                prompt_groups: &[],
+                secrets: None,
+                prompt_defaults: Some(defaults),
+                history: None,
+                key_case: KeyCase::Exact,
+                path_sep: '.',
+                load: &load,
+                no_interactive: true,
+                stdin_is_terminal: false,
+                stdin_at_eof: &|| Ok(true),
+                force: false,
+                skip_empty: false,
+                dry_run: false,
+                verbose: false,
+            };
+            prompt_and_update_values_file(&path, &paths, &empty_set, &opts)
+        };
+        let Err(err) = update(&defaults) else {
+            panic!("replicas must be numeric");
+        };
+        assert_eq!(err.to_string(), "replicas must be a number (replicas)");
+        assert_eq!(fs::read_to_string(&path).expect("read file"), original);
+
+        set_yaml_path(
+            &mut defaults,
+            "replicas",
+            YamlValue::String("3".to_string()),
+        );
+        update(&defaults).expect("valid defaults are written");
+        let written = fs::read_to_string(&path).expect("read file");
+        fs::remove_file(&path).ok();
+        assert!(written.starts_with(original), "{written}");
+
+        let values: YamlValue = serde_yaml::from_str(original).expect("yaml parses");
+        let rule = meta_rule(&values, "namespace", &path)
+            .expect("rule parses")
+            .expect("rule exists");
+        assert_eq!(rule.description.as_deref(), Some("Kubernetes namespace"));
+        assert_eq!(
+            rule.rejection("namespace", "Dev").as_deref(),
+            Some("value for namespace must match ^[a-z0-9-]+$")
+        );
+        assert!(
+            meta_rule(&values, "other", &path)
+                .expect("no rule")
+                .is_none()
+        );
+
+        let err =
+            ensure_no_meta_placeholders(&BTreeSet::from(["_meta.replicas.pattern".to_string()]))
+                .expect_err("templates cannot read _meta");
+        assert!(err.to_string().contains("holds prompt rules"), "{err}");
+    }
+
+    #[test]
+    fn prompt_history_ranks_between_values_file_and_prompt_defaults() {
+        let dir = env::temp_dir().join(format!("tplenv-history-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let values = dir.join("values.yaml");
+        let history_path = dir.join("nested/history.yaml");
+        fs::write(&values, "db:\n  host: existing\n").expect("write file");
+        fs::create_dir_all(history_path.parent().expect("parent")).expect("create dir");
+        fs::write(&history_path, "db.host: remembered\nimage.tag: v0\n").expect("write file");
+        let history = load_prompt_history(&history_path).expect("history loads");
+        let defaults: YamlValue =
+            serde_yaml::from_str("image:\n  tag: v1\ndb:\n  password: changeme\n")
+                .expect("yaml parses");
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let empty_set = BTreeSet::new();
+        let opts = PromptUpdateOptions {
+            include_environment_vars: false,
+            skip_existing_env_vars: &empty_set,
+            existing_os_env_values: &HashMap::new(),
+            prompt_contexts: &HashMap::new(),
+            prompt_order: &[],
+            annotations: &HashMap::new(),
+            delete_paths: &[],
+            confirm_patterns: &[],
+            multiline_patterns: &[],
+            editor_patterns: &[],
+            hidden_patterns: &[],
+            prompt_groups: &[],
+            secrets: None,
+            prompt_defaults: Some(&defaults),
+            history: Some(&history),
+            key_case: KeyCase::Exact,
+            path_sep: '.',
+            load: &load,
+            no_interactive: true,
+            stdin_is_terminal: true,
+            stdin_at_eof: &|| Ok(true),
+            force: true,
+            skip_empty: false,
+            dry_run: false,
+            verbose: false,
+        };
+        let paths = BTreeSet::from([
+            "db.host".to_string(),
+            "db.password".to_string(),
+            "image.tag".to_string(),
+        ]);
+        let mut prompted = prompt_and_update_values_file(&values, &paths, &empty_set, &opts)
+            .expect("prompts")
+            .prompted;
+        prompted.sort();
+        assert_eq!(
+            prompted,
+            [
+                ("db.host".to_string(), "existing".to_string()),
+                ("db.password".to_string(), "changeme".to_string()),
+                ("image.tag".to_string(), "v0".to_string()),
+            ]
+        );
+
+        let secret = [glob_regex("*.password").expect("glob")];
+        save_prompt_history(&history_path, history, &prompted, &secret).expect("saves");
+        assert_eq!(
+            fs::read_to_string(&history_path).expect("read history"),
+            "db.host: existing\nimage.tag: v0\n"
+        );
+        assert_eq!(
+            load_prompt_history(&dir.join("missing.yaml")).expect("empty history"),
+            YamlValue::Mapping(YamlMapping::new())
+        );
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn persist_env_writes_os_env_and_override_values_only() {
+        let path = env::temp_dir().join(format!("tplenv-persist-{}.yaml", std::process::id()));
+        fs::write(&path, "environment:\n  KEPT: file\nimage: app\n").expect("write file");
+        let mut resolution = Resolution::default();
+        for (name, value, source) in [
+            ("KEPT", "file", ValueSource::ValuesFile),
+            ("NAMESPACE", "dev", ValueSource::OsEnv),
+            ("TAG", "v2", ValueSource::Override),
+            ("DEFAULTED", "x", ValueSource::Annotation),
+            ("DB_PASSWORD", "s3cret", ValueSource::OsEnv),
+        ] {
+            resolution
+                .env_map
+                .insert(name.to_string(), value.to_string());
+            resolution.sources.insert(env_var_values_path(name), source);
+        }
+        let groups = [ValuesGroup {
+            values_file: path.clone(),
+            indices: vec![0],
+            templates: Vec::new(),
+            resolution,
+        }];
+        let secret = [glob_regex("*PASSWORD*").expect("glob")];
+
+        let err = env_values_to_persist(&groups, &secret, false)
+            .expect_err("secret refused")
+            .to_string();
+        assert!(err.contains("environment.DB_PASSWORD"), "{err}");
+
+        let persisted = env_values_to_persist(&groups, &[], false).expect("no secrets");
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        assert_eq!(persisted.len(), 1);
+        persist_env_values(&persisted[0].0, &persisted[0].1, &load).expect("persists");
+        let written = fs::read_to_string(&path).expect("read file");
+        fs::remove_file(&path).ok();
+        assert_eq!(
+            written,
+            "environment:\n  KEPT: file\n  DB_PASSWORD: s3cret\n  NAMESPACE: dev\n  TAG: v2\nimage: app\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn edit_value_with_reads_back_the_saved_file() {
+        let edited = edit_value_with(
+            "printf 'line1\\nline2\\n' >",
+            "environment.APP_CONFIG",
+            "old",
+        )
+        .expect("editor runs");
+        assert_eq!(edited.as_deref(), Some("line1\nline2"));
+        // An editor that keeps the pre-filled default returns it without the added newline.
+        assert_eq!(
+            edit_value_with("true", "a.b", "keep").expect("editor runs"),
+            Some("keep".to_string())
+        );
+        assert_eq!(
+            edit_value_with(": >", "a.b", "old").expect("editor runs"),
+            None
+        );
+        assert!(edit_value_with("false", "a.b", "").is_err());
+    }
+
+    #[test]
+    fn read_multiline_value_stops_at_terminator() {
+        let mut input =
+            io::Cursor::new("-----BEGIN CERT-----\r\nabc\n-----END CERT-----\nEOF\nrest\n");
+        assert_eq!(
+            read_multiline_value(&mut input).expect("reads").as_deref(),
+            Some("-----BEGIN CERT-----\nabc\n-----END CERT-----")
+        );
+        assert_eq!(
+            read_multiline_value(&mut io::Cursor::new("\nignored\nEOF\n")).expect("reads"),
+            None
+        );
+        assert_eq!(
+            read_multiline_value(&mut io::Cursor::new("a\n\nb"))
+                .expect("reads")
+                .as_deref(),
+            Some("a\n\nb")
+        );
+    }
+
+    #[test]
+    fn env_values_file_joins_plain_names_only() {
+        assert_eq!(
+            env_values_file(Path::new("values"), "prod").expect("valid name"),
+            PathBuf::from("values/prod.yaml")
+        );
+        for name in ["", "../prod", "a/b", ".hidden"] {
+            assert!(
+                env_values_file(Path::new("values"), name).is_err(),
+                "{name}"
+            );
+        }
+    }
+
+    #[test]
+    fn ensure_env_names_match_lists_lowercase_names_with_lines() {
+        let re = placeholder_regex().expect("regex compiles");
+        let upper = Regex::new(&format!("^(?:{DEFAULT_ENV_NAME_PATTERN})$")).expect("regex");
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            "ns: $NAMESPACE\nrun: cd ${path} && {{ .Values.cmd }}\nid: {{ App_Id }} $API_2\n"
+                .to_string(),
+        )];
+        let err = ensure_env_names_match(&templates, &re, &upper).expect_err("lowercase");
+        assert_eq!(
+            err.to_string(),
+            "env placeholder names not matching --env-name-pattern (--strict-env-names):\n- a.yaml:2: path\n- a.yaml:3: App_Id"
+        );
+
+        let any = Regex::new("^(?:[A-Za-z_][A-Za-z0-9_]*)$").expect("regex");
+        assert!(ensure_env_names_match(&templates, &re, &any).is_ok());
+    }
+
+    #[test]
+    fn ensure_placeholders_allowed_names_file_and_placeholder() {
+        let path = env::temp_dir().join(format!("tplenv-allow-{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            "# approved\nNAMESPACE\nAPP_*  # app family\n.Values.db.*\n",
+        )
+        .expect("write file");
+        let allowlist = load_placeholder_allowlist(&path, u64::MAX).expect("loads");
+        fs::remove_file(&path).ok();
+
+        let re = placeholder_regex().expect("regex compiles");
+        let ok = vec![(
+            PathBuf::from("a.yaml"),
+            "ns: $NAMESPACE\nimg: ${APP_IMAGE}\nhost: {{ .Values.db.host }}\n".to_string(),
+        )];
+        assert!(ensure_placeholders_allowed(&ok, &re, &allowlist).is_ok());
+
+        let bad = vec![(
+            PathBuf::from("b.yaml"),
+            "x: {{ SECRET }}\ny: {{ .Values.image.tag }}\n".to_string(),
+        )];
+        let err = ensure_placeholders_allowed(&bad, &re, &allowlist).expect_err("not allowed");
+        assert_eq!(
+            err.to_string(),
+            "placeholders not in --allowed-placeholders:\n- b.yaml: SECRET\n- b.yaml: .Values.image.tag"
+        );
+    }
+
+    #[test]
+    fn parse_values_base64_decodes_yaml_and_reports_bad_input() {
+        let blob = base64_encode(b"db:\n  host: db\n");
+        let values = parse_values_base64(&blob, "--values-base64").expect("decodes");
+        assert_eq!(
+            lookup_yaml_path(&values, "db.host").and_then(|v| v.as_str()),
+            Some("db")
+        );
+        let err = parse_values_base64("not base64!", "--values-base64").expect_err("bad base64");
+        assert!(format!("{err:#}").contains("invalid base64"), "{err:#}");
+        let err = parse_values_base64(&base64_encode(&[0xff, 0xfe]), "--values-base64")
+            .expect_err("not UTF-8");
+        assert!(format!("{err:#}").contains("UTF-8"), "{err:#}");
+    }
+
+    #[test]
+    fn json_values_files_parse_and_write_back_as_json() {
+        let json = r#"{"image": {"tag": "1.2", "pull": true}, "replicas": 3, "ratio": -0.5e1,
+            "empty": null, "ports": [80, 443], "note": "a\"b\u00e9\ud83d\ude00\n"}"#;
+        let yaml = parse_json(json).expect("valid JSON");
+        assert_eq!(
+            lookup_yaml_path(&yaml, "image.tag").and_then(|v| v.as_str()),
+            Some("1.2")
+        );
+        assert_eq!(
+            lookup_yaml_path(&yaml, "replicas").and_then(|v| v.as_u64()),
+            Some(3)
+        );
+        assert_eq!(
+            lookup_yaml_path(&yaml, "ratio").and_then(|v| v.as_f64()),
+            Some(-5.0)
+        );
+        assert_eq!(
+            lookup_yaml_path(&yaml, "note").and_then(|v| v.as_str()),
+            Some("a\"b\u{e9}\u{1f600}\n")
+        );
+        for (bad, message) in [
+            ("{\"a\": 1,}", "line 1, column 9: trailing comma"),
+            ("{\"a\": 01}", "line 1, column 8: invalid number"),
+            ("[1, 2]\n]", "line 2, column 1: trailing characters"),
+            ("{'a': 1}", "key must be a string"),
+        ] {
+            let err = parse_json(bad).expect_err(bad);
+            assert!(err.to_string().contains(message), "{bad}: {err}");
+        }
+
+        let dir = env::temp_dir().join(format!("tplenv-json-values-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let path = dir.join("values.json");
+        fs::write(&path, "{\"db\": {\"host\": \"localhost\"}}").expect("write");
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let values = BTreeMap::from([("environment.NAMESPACE".to_string(), "dev".to_string())]);
+        persist_env_values(&path, &values, &load).expect("writes back");
+        assert_eq!(
+            fs::read_to_string(&path).expect("read"),
+            "{\n  \"db\": {\n    \"host\": \"localhost\"\n  },\n  \"environment\": {\n    \"NAMESPACE\": \"dev\"\n  }\n}\n"
+        );
+        let reloaded = load_values_yaml(&path, &load)
+            .expect("loads")
+            .expect("some");
+        assert_eq!(
+            lookup_yaml_path(&reloaded, "environment.NAMESPACE").and_then(|v| v.as_str()),
+            Some("dev")
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn values_includes_splice_nested_files_and_report_cycles() {
+        let dir = env::temp_dir().join(format!("tplenv-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("parts")).expect("create temp dir");
+        let values_file = dir.join("Values.yaml");
+        fs::write(&values_file, "app: demo\ndb: !include parts/db.yaml\n").expect("write file");
+        fs::write(
+            dir.join("parts/db.yaml"),
+            "host: db\nauth: !include auth.yaml\n",
+        )
+        .expect("write file");
+        fs::write(dir.join("parts/auth.yaml"), "user: admin\n").expect("write file");
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+
+        let yaml = load_values_yaml_if_exists(&values_file, &load).expect("includes resolve");
+        assert_eq!(
+            lookup_yaml_path(&yaml, "db.auth.user"),
+            Some(&YamlValue::String("admin".to_string()))
+        );
+        let raw = load_values_yaml_unresolved_if_exists(&values_file, &load).expect("loads");
+        assert!(include_on_path(&raw, "db.password"));
+        assert!(!include_on_path(&raw, "app"));
+
+        fs::write(
+            dir.join("parts/auth.yaml"),
+            "user: !include ../Values.yaml\n",
+        )
+        .expect("write file");
+        let err = load_values_yaml_if_exists(&values_file, &load).expect_err("cycle");
+        assert!(err.to_string().contains("!include cycle"), "{err}");
+
+        fs::remove_file(dir.join("parts/auth.yaml")).expect("remove file");
+        let err = load_values_yaml_if_exists(&values_file, &load).expect_err("missing");
+        assert!(
+            err.to_string().contains("Values.yaml -> ") && err.to_string().contains("db.yaml -> "),
+            "{err}"
+        );
+        fs::remove_dir_all(&dir).expect("cleanup");
+    }
+
+    #[test]
+    fn find_partial_placeholders_reports_near_misses_with_line_numbers() {
+        let re = placeholder_regex().expect("regex compiles");
+        let input = "ok: {{ .Values.a }} ${B} $C\nbad: {{ .Values.x }\nenv: {{NAMESPACE }\n\
+                     brace: ${HOME\nhelm: {{ include \"x\" . }}\njson: {\"a\": 1}\n";
+        assert_eq!(
+            find_partial_placeholders(input, &re, &Delimiters::default()),
+            vec![
+                (2, "{{ .Values.x }".to_string()),
+                (3, "{{NAMESPACE }".to_string()),
+                (4, "${HOME".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn glob_regex_matches_dotted_keys() {
+        let re = glob_regex("environment.*PASSWORD*").expect("glob compiles");
+        assert!(re.is_match("environment.DB_PASSWORD"));
+        assert!(re.is_match("environment.PASSWORD_FILE"));
+        assert!(!re.is_match("db.password"));
+        let re = glob_regex("db.?ser").expect("glob compiles");
+        assert!(re.is_match("db.user"));
+        assert!(!re.is_match("db.users"));
+    }
+
+    #[test]
+    fn collect_prompt_paths_deduplicates_shared_keys() {
+        let values_paths = BTreeSet::from(["db.user".to_string()]);
+        let env_vars = BTreeSet::from(["APP_NAME".to_string(), "APP_NAME".to_string()]);
+        let skip = BTreeSet::new();
+
+        let all = collect_prompt_paths(&values_paths, &env_vars, true, &skip);
+
+        assert!(all.contains("db.user"));
+        assert!(all.contains("environment.APP_NAME"));
+        assert_eq!(all.len(), 2);
+    }
+
+    #[test]
+    fn collect_prompt_paths_skips_existing_env_vars_without_force() {
+        let values_paths = BTreeSet::new();
+        let env_vars = BTreeSet::from(["IMAGE".to_string(), "SIGNER".to_string()]);
+        let skip = BTreeSet::from(["IMAGE".to_string()]);
+
+        let all = collect_prompt_paths(&values_paths, &env_vars, true, &skip);
+        assert!(!all.contains("environment.IMAGE"));
+        assert!(all.contains("environment.SIGNER"));
+    }
+
+    #[test]
+    fn extract_prompt_context_defaults_to_single_line() {
+        let input = "image: ${IMAGE}\n";
+        let re = placeholder_regex().expect("regex compiles");
+        let cap = re.captures(input).expect("capture exists");
+        let got = extract_prompt_context(input, &cap, &re, "environment.IMAGE", false);
+        assert_eq!(got, "image: ${IMAGE}");
+    }
+
+    #[test]
+    fn extract_prompt_context_uses_paragraph_for_single_variable() {
+        let input = "title: ${IMAGE}\nnotes: hello\n\nother: x\n";
+        let re = placeholder_regex().expect("regex compiles");
+        let cap = re.captures(input).expect("capture exists");
+        let got = extract_prompt_context(input, &cap, &re, "environment.IMAGE", true);
+        assert_eq!(got, "title: ${IMAGE}\nnotes: hello");
+    }
+
+    #[test]
+    fn extract_prompt_context_uses_list_entry_when_paragraph_has_multiple_variables() {
+        let input = "items:\n  - user: ${A}\n    note: x\n  - user: ${B}\n";
+        let re = placeholder_regex().expect("regex compiles");
+        let cap = re
+            .captures_iter(input)
+            .nth(1)
+            .expect("second placeholder capture");
+        let got = extract_prompt_context(input, &cap, &re, "environment.B", true);
+        assert_eq!(got, "  - user: ${B}");
+    }
+
+    #[test]
+    fn extract_context_window_shows_n_lines_around_placeholder() {
+        let input = "a: 1\nb: 2\nimage: ${IMAGE}\nc: 3\nd: 4\n";
+        let re = placeholder_regex().expect("regex compiles");
+        let cap = re.captures(input).expect("capture exists");
+        let got = extract_context_window(input, &cap, 1);
+        assert_eq!(got, "b: 2\nimage: ${IMAGE}\nc: 3");
+    }
+
+    #[test]
+    fn parse_annotation_reads_default_description_and_required() {
+        let re = annotation_regex().expect("regex compiles");
+        let got = parse_annotation(
+            "namespace: {{NAMESPACE}}  # @tplenv default: default-ns; description: Target namespace; required",
+            &re,
+        )
+        .expect("annotation parsed");
+        assert_eq!(got.default.as_deref(), Some("default-ns"));
+        assert_eq!(got.description.as_deref(), Some("Target namespace"));
+        assert!(got.required);
+        assert!(parse_annotation("namespace: {{NAMESPACE}} # plain comment", &re).is_none());
+    }
+
+    #[test]
+    fn collect_annotations_keys_by_prompt_path() {
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            "ns: {{NAMESPACE}} # @tplenv default: dev\nrep: {{ .Values.replicas }} # @tplenv default: 2\n"
+                .to_string(),
+        )];
+        let re = placeholder_regex().expect("regex compiles");
+        let got = collect_annotations(&templates, &re);
+        assert_eq!(
+            annotation_default(&got, "environment.NAMESPACE"),
+            Some("dev".to_string())
+        );
+        assert_eq!(annotation_default(&got, "replicas"), Some("2".to_string()));
+    }
+
+    #[test]
+    fn env_placeholder_defaults_become_annotation_defaults() {
+        let re = placeholder_regex().expect("regex compiles");
+        let input = "port: ${PORT:-8080}\nns: {{ NAMESPACE:-default }}\nempty: \"${EMPTY:-}\"\nhost: {{ HOST:-a.example }} # @tplenv default: b.example\n";
+        let (env_vars, values_paths) = collect_placeholders(input, &re);
+        assert_eq!(
+            env_vars.into_iter().collect::<Vec<_>>(),
+            ["EMPTY", "HOST", "NAMESPACE", "PORT"]
+        );
+        assert!(values_paths.is_empty());
+
+        let got = collect_annotations(&[(PathBuf::from("a.yaml"), input.to_string())], &re);
+        let default = |name: &str| annotation_default(&got, &env_var_values_path(name));
+        assert_eq!(default("PORT").as_deref(), Some("8080"));
+        assert_eq!(default("NAMESPACE").as_deref(), Some("default"));
+        assert_eq!(default("EMPTY").as_deref(), Some(""));
+        assert_eq!(default("HOST").as_deref(), Some("b.example"));
+
+        let env_map = HashMap::from([("PORT".to_string(), "9090".to_string())]);
+        let caps = re.captures("${PORT:-8080}").expect("matches");
+        assert_eq!(extract_env_key(&caps), Some("PORT"));
+        let opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: false,
+            block_scalar: true,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let templates = vec![(PathBuf::from("a.yaml"), "port: ${PORT:-8080}\n".to_string())];
+        let out =
+            render_templates(&templates, &re, &HashMap::new(), &env_map, &opts).expect("renders");
+        assert_eq!(out[0].1, "port: 9090\n");
+    }
+
+    #[test]
+    fn prompt_groups_assign_keys_to_first_matching_section() {
+        let path = env::temp_dir().join(format!("tplenv-groups-{}.yaml", std::process::id()));
+        fs::write(
+            &path,
+            "Networking:\n  - ingress.host\nDatabase:\n  - \"db.*\"\n  - environment.DB_PASSWORD\n",
+        )
+        .expect("write file");
+        let load = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &[],
+            timeout_secs: 1,
+            quiet: true,
+        };
+        let groups = load_prompt_groups(&path, &load).expect("groups load");
+        let names: Vec<&str> = groups.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, ["Networking", "Database"]);
+        assert_eq!(prompt_group_index(&groups, "ingress.host"), 0);
+        assert_eq!(prompt_group_index(&groups, "db.user"), 1);
+        assert_eq!(prompt_group_index(&groups, "environment.DB_PASSWORD"), 1);
+        assert_eq!(prompt_group_index(&groups, "replicas"), 2);
+
+        fs::write(&path, "Database: db.host\n").expect("write file");
+        let err = load_prompt_groups(&path, &load).expect_err("scalar section fails");
+        assert!(format!("{err:#}").contains("section 'Database'"), "{err:#}");
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn collect_prompt_order_follows_file_occurrence() {
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            "x: ${B}\ny: {{ .Values.alpha }}\nz: ${A}\n".to_string(),
+        )];
+        let re = placeholder_regex().expect("regex compiles");
+        let order = collect_prompt_order(&templates, &re);
+        assert_eq!(
+            order,
+            vec![
+                "environment.B".to_string(),
+                "alpha".to_string(),
+                "environment.A".to_string()
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_byte_size_accepts_plain_and_suffixed_values() {
+        assert_eq!(parse_byte_size("1024"), Ok(1024));
+        assert_eq!(parse_byte_size("512K"), Ok(512 * 1024));
+        assert_eq!(parse_byte_size("10M"), Ok(10 * 1024 * 1024));
+        assert_eq!(parse_byte_size("1g"), Ok(1024 * 1024 * 1024));
+        assert!(parse_byte_size("10X").is_err());
+        assert!(parse_byte_size("M").is_err());
+    }
+
+    #[test]
+    fn resolve_against_base_keeps_absolute_paths_and_stdout() {
+        let base = Path::new("deploy/app");
+        assert_eq!(
+            resolve_against_base(Path::new("Values.yaml"), base),
+            PathBuf::from("deploy/app/Values.yaml")
+        );
+        assert_eq!(
+            resolve_against_base(Path::new("/etc/Values.yaml"), base),
+            PathBuf::from("/etc/Values.yaml")
+        );
+        assert_eq!(
+            resolve_against_base(Path::new("-"), base),
+            PathBuf::from("-")
+        );
+        assert_eq!(template_dir(Path::new("app.yaml")), PathBuf::from("."));
+    }
+
+    #[test]
+    fn is_url_detects_http_and_https_values_sources() {
+        assert!(is_url(Path::new("https://config.internal/app/values.yaml")));
+        assert!(is_url(Path::new("http://localhost:8080/values.yaml")));
+        assert!(!is_url(Path::new("Values.yaml")));
+        assert_eq!(
+            resolve_against_base(Path::new("https://x/values.yaml"), Path::new("base")),
+            PathBuf::from("https://x/values.yaml")
+        );
+    }
+
+    #[test]
+    fn fetch_url_sends_headers_and_reports_http_errors() {
+        use std::net::TcpListener;
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+        let url = format!("http://{}", listener.local_addr().expect("address"));
+        let server = std::thread::spawn(move || {
+            let mut requests = Vec::new();
+            for (status, body) in [("200 OK", "tag: v1\n"), ("404 Not Found", "")] {
+                let (mut stream, _) = listener.accept().expect("accept");
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let n = stream.read(&mut buf).expect("read request");
+                    request.extend_from_slice(&buf[..n]);
+                }
+                requests.push(String::from_utf8_lossy(&request).to_lowercase());
+                write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    body.len()
+                )
+                .expect("write response");
+            }
+            requests
+        });
+        let headers = ["X-Token: s3cret".to_string()];
+        let opts = ValuesLoadOptions {
+            max_file_size: u64::MAX,
+            headers: &headers,
+            timeout_secs: 5,
+            quiet: true,
+        };
+        assert_eq!(
+            fetch_url(&format!("{url}/values.yaml"), &opts).expect("fetches"),
+            "tag: v1\n"
+        );
+        let err = fetch_url(&format!("{url}/missing.yaml"), &opts).expect_err("404 fails");
+        assert!(err.to_string().ends_with("HTTP status 404"), "{err}");
+        let requests = server.join().expect("server");
+        assert!(requests[0].contains("x-token: s3cret"), "{}", requests[0]);
+    }
+
+    #[test]
+    fn trace_records_sources_and_redacts_values() {
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            "ns: ${NAMESPACE}\nrep: {{ .Values.replicas }}\n".to_string(),
+        )];
+        let re = placeholder_regex().expect("regex compiles");
+        let mut trace = Trace::new(&templates, &re);
+        let env_map = HashMap::from([("NAMESPACE".to_string(), "prod".to_string())]);
+        let values_map = HashMap::from([("replicas".to_string(), "3".to_string())]);
+        let sources = HashMap::from([
+            ("environment.NAMESPACE".to_string(), ValueSource::OsEnv),
+            ("replicas".to_string(), ValueSource::ValuesFile),
+        ]);
+        trace.record_resolution(&env_map, &values_map, &sources, &[], true);
+
+        assert_eq!(
+            trace.placeholders[0].env_vars,
+            vec!["NAMESPACE".to_string()]
+        );
+        assert_eq!(trace.resolutions[0].key, "environment.NAMESPACE");
+        assert_eq!(trace.resolutions[0].source, Some(ValueSource::OsEnv));
+        assert_eq!(trace.resolutions[0].value, TRACE_REDACTED);
+        let text = serde_yaml::to_string(&trace).expect("trace serializes");
+        assert!(text.contains("source: values-file"));
+        assert!(!text.contains("prod"));
+    }
+
+    #[test]
+    fn trace_redacts_secret_keys_and_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            "pw: ${DB_PASSWORD}\nuser: {{ .Values.db.user }}\n".to_string(),
+        )];
+        let re = placeholder_regex().expect("regex compiles");
+        let mut trace = Trace::new(&templates, &re);
+        let env_map = HashMap::from([("DB_PASSWORD".to_string(), "hunter2".to_string())]);
+        let values_map = HashMap::from([("db.user".to_string(), "app".to_string())]);
+        let sources = HashMap::from([
+            ("environment.DB_PASSWORD".to_string(), ValueSource::OsEnv),
+            ("db.user".to_string(), ValueSource::ValuesFile),
+        ]);
+        let secrets = [glob_regex("environment.*PASSWORD*").expect("glob compiles")];
+        trace.record_resolution(&env_map, &values_map, &sources, &secrets, false);
+        trace.record_prompts(
+            &[
+                ("environment.DB_PASSWORD".to_string(), "hunter2".to_string()),
+                ("db.user".to_string(), "app".to_string()),
+            ],
+            &secrets,
+            false,
+        );
+
+        let path = env::temp_dir().join(format!("tplenv-trace-{}.yaml", std::process::id()));
+        write_trace(&path, &trace, TraceFormat::Yaml).expect("writes");
+        let meta = fs::metadata(&path).expect("metadata");
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        let text = fs::read_to_string(&path).expect("read");
+        fs::remove_file(&path).ok();
+        assert!(!text.contains("hunter2"), "{text}");
+        assert_eq!(text.matches(TRACE_REDACTED).count(), 2, "{text}");
+        assert!(text.contains("value: app"), "{text}");
+    }
+
+    #[test]
+    fn trace_renders_as_markdown_table_and_json() {
+        let templates = vec![(PathBuf::from("a.yaml"), "{{ .Values.cmd }}\n".to_string())];
+        let re = placeholder_regex().expect("regex compiles");
+        let mut trace = Trace::new(&templates, &re);
+        let values_map = HashMap::from([("cmd".to_string(), "a | b\nc".to_string())]);
+        let sources = HashMap::from([("cmd".to_string(), ValueSource::ValuesFile)]);
+        trace.record_resolution(&HashMap::new(), &values_map, &sources, &[], false);
+        trace.missing.push("image.tag".to_string());
+
+        let now = UNIX_EPOCH + std::time::Duration::from_secs(1_714_564_800);
+        let md = render_trace_markdown(&trace, now).expect("markdown renders");
+        assert_eq!(
+            md,
+            "# tplenv render report\n\n- Files: 1\n- Generated: 2024-05-01T12:00:00Z\n\n\
+             | Placeholder | Source | Value |\n| --- | --- | --- |\n\
+             | `cmd` | values-file | a \\| b<br>c |\n\nMissing:\n\n- `image.tag`\n"
+        );
+
+        let json = yaml_to_json(&serde_yaml::to_value(&trace).expect("trace serializes"));
+        assert!(
+            json.contains(r#"{"key":"cmd","source":"values-file","value":"a | b\nc"}"#),
+            "{json}"
+        );
+        assert!(json.contains(r#""output_bytes":null"#), "{json}");
+    }
+
+    #[test]
+    fn render_templates_is_repeatable_and_applies_values() {
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            "ns: ${NAMESPACE}\nrep: {{ .Values.replicas }}\n".to_string(),
+        )];
+        let re = placeholder_regex().expect("regex compiles");
+        let values_map = HashMap::from([("replicas".to_string(), "3".to_string())]);
+        let env_map = HashMap::from([("NAMESPACE".to_string(), "prod".to_string())]);
+        let opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: false,
+            block_scalar: true,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let first = render_templates(&templates, &re, &values_map, &env_map, &opts).expect("ok");
+        let second = render_templates(&templates, &re, &values_map, &env_map, &opts).expect("ok");
+        assert_eq!(first, second);
+        assert_eq!(first[0].1, "ns: prod\nrep: 3\n");
+    }
+
+    #[test]
+    fn lint_template_reports_brace_path_and_key_issues() {
+        let input = "a: {{ .Values.ok }}\nb: {{ .Values.bad$path }}\nc: {{ NAME\n{{ KEY }}: x\nd: {{ Values }}\n";
+        let re = placeholder_regex().expect("regex compiles");
+        let findings = lint_template(input, &re, false, &Delimiters::default());
+
+        let at = |line: usize| -> Vec<&LintFinding> {
+            findings.iter().filter(|f| f.line == line).collect()
+        };
+        assert!(at(1).is_empty());
+        assert_eq!(at(2)[0].severity, LintSeverity::Error);
+        assert!(at(2)[0].message.contains("invalid .Values path"));
+        assert!(at(3)[0].message.contains("unbalanced braces"));
+        assert!(at(4)[0].message.contains("key position"));
+        assert!(at(5)[0].message.contains("reserved name Values"));
+    }
+
+    #[test]
+    fn find_duplicate_yaml_keys_tracks_nesting_lists_and_block_scalars() {
+        let input = r#"metadata:
+  name: a
+  labels:
+    app: x
+    app: y
+  name: b
+items:
+  - name: one
+    port: 1
+  - name: two
+    port: 2
+script: |
+  name: inside-block
+  name: inside-block
+script: again
+---
+metadata: new-doc
+"#;
+        let got: Vec<(usize, String)> = find_duplicate_yaml_keys(input)
+            .into_iter()
+            .map(|d| (d.line, d.key))
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                (5, "app".to_string()),
+                (6, "name".to_string()),
+                (15, "script".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_values_tolerant_falls_back_to_empty_mapping() {
+        let path = Path::new("Values.yaml");
+        assert_eq!(
+            parse_values_tolerant("environment: [unclosed\n", path, true),
+            YamlValue::Mapping(YamlMapping::new())
+        );
+        let parsed = parse_values_tolerant("environment:\n  A: b\n", path, true);
+        assert!(lookup_yaml_path(&parsed, "environment.A").is_some());
+    }
+
+    #[test]
+    fn is_yaml_file_accepts_yaml_and_yml_suffixes() {
+        assert!(is_yaml_file(Path::new("1-a.yaml")));
+        assert!(is_yaml_file(Path::new("1-a.yml")));
+        assert!(!is_yaml_file(Path::new("1-a.txt")));
+        assert!(!is_yaml_file(Path::new("1-a.yml.bak")));
+    }
+
+    #[test]
+    fn chunk_file_names_pad_to_the_chunk_count() {
+        assert_eq!(
+            chunk_file_names(5, 2, TemplateFormat::Yaml),
+            ["chunk-001.yaml", "chunk-002.yaml", "chunk-003.yaml"]
+        );
+        assert_eq!(
+            chunk_file_names(2, 10, TemplateFormat::Json),
+            ["chunk-001.json"]
+        );
+        let many = chunk_file_names(1000, 1, TemplateFormat::Yaml);
+        assert_eq!(many[0], "chunk-0001.yaml");
+        assert_eq!(many[999], "chunk-1000.yaml");
+    }
+
+    #[test]
+    fn write_output_dir_writes_each_template_under_its_file_name() {
+        let dir = env::temp_dir().join(format!("tplenv-output-dir-{}", std::process::id()));
+        let opts = OutputOptions {
+            trailing_newline: TrailingNewline::Keep,
+            encoding: None,
+            charset: Charset::Utf8,
+            wrap: 0,
+            format: TemplateFormat::Yaml,
+            doc_separator: "---",
+            leading_separator: false,
+            json_lines: false,
+            json: false,
+        };
+        let rendered = vec![
+            (PathBuf::from("configs/10-app.yaml"), "a: 1\n".to_string()),
+            (PathBuf::from("configs/20-db.yaml"), "b: 2\n".to_string()),
+        ];
+        let written =
+            write_output_dir(&dir.join("out"), &rendered, None, None, &opts).expect("writes");
+        assert_eq!(
+            written,
+            [dir.join("out/10-app.yaml"), dir.join("out/20-db.yaml")]
+        );
+        assert_eq!(fs::read_to_string(&written[0]).expect("first"), "a: 1\n");
+        assert_eq!(fs::read_to_string(&written[1]).expect("second"), "b: 2\n");
+
+        let chunks = write_output_dir(&dir.join("chunks"), &rendered, Some(5), None, &opts)
+            .expect("writes chunks");
+        assert_eq!(chunks, [dir.join("chunks/chunk-001.yaml")]);
+
+        let clash = vec![
+            (PathBuf::from("a/app.yaml"), "a: 1\n".to_string()),
+            (PathBuf::from("b/app.yaml"), "b: 2\n".to_string()),
+        ];
+        let err =
+            write_output_dir(&dir.join("clash"), &clash, None, None, &opts).expect_err("clash");
+        assert!(err.to_string().contains("would both be written"), "{err}");
+
+        // A `**` pattern keeps each file's path below the pattern root.
+        let nested = vec![
+            (
+                PathBuf::from("configs/svc1/10-deploy.yaml"),
+                "a: 1\n".to_string(),
+            ),
+            (
+                PathBuf::from("configs/svc2/10-deploy.yaml"),
+                "b: 2\n".to_string(),
+            ),
+        ];
+        let root = recursive_pattern_root("configs/**/*.yaml").expect("recursive pattern");
+        assert_eq!(root, PathBuf::from("configs"));
+        assert_eq!(recursive_pattern_root("configs/*.yaml"), None);
+        let written = write_output_dir(&dir.join("nested"), &nested, None, Some(&root), &opts)
+            .expect("writes");
+        assert_eq!(
+            written,
+            [
+                dir.join("nested/svc1/10-deploy.yaml"),
+                dir.join("nested/svc2/10-deploy.yaml")
+            ]
+        );
+        assert_eq!(fs::read_to_string(&written[1]).expect("second"), "b: 2\n");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn write_outputs_tees_to_each_distinct_destination() {
+        let dir = env::temp_dir().join(format!("tplenv-tee-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let first = dir.join("a.yaml");
+        let second = dir.join("b.yaml");
+        let outputs = vec![
+            first.clone(),
+            PathBuf::from("-"),
+            second.clone(),
+            first.clone(),
+            PathBuf::from("-"),
+        ];
+        assert_eq!(
+            output_destinations(&outputs),
+            [&first, &PathBuf::from("-"), &second]
+        );
+
+        let rendered = vec![
+            (PathBuf::from("1-a.yaml"), "a: 1\n".to_string()),
+            (PathBuf::from("2-b.yaml"), "b: 2\n".to_string()),
+        ];
+        let opts = OutputOptions {
+            trailing_newline: TrailingNewline::Keep,
+            encoding: None,
+            charset: Charset::Utf8,
+            wrap: 0,
+            format: TemplateFormat::Yaml,
+            doc_separator: "---",
+            leading_separator: false,
+            json_lines: false,
+            json: false,
+        };
+        write_outputs(&[first.clone(), second.clone()], &rendered, &opts).expect("writes");
+        let expected = "a: 1\n\n---\nb: 2\n";
+        assert_eq!(fs::read_to_string(&first).expect("first"), expected);
+        assert_eq!(fs::read_to_string(&second).expect("second"), expected);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn encode_charset_round_trips_utf16_and_rejects_non_latin1() {
+        let text = "name: Zoë 🚀\n";
+        let le = encode_charset(text, Charset::Utf16le).expect("utf16le");
+        assert_eq!(le[..2], [0xFF, 0xFE]);
+        let units: Vec<u16> = le[2..]
+            .chunks(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(String::from_utf16(&units).expect("decodes"), text);
+
+        let be = encode_charset(text, Charset::Utf16be).expect("utf16be");
+        assert_eq!(be[..2], [0xFE, 0xFF]);
+        let units: Vec<u16> = be[2..]
+            .chunks(2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+            .collect();
+        assert_eq!(String::from_utf16(&units).expect("decodes"), text);
+
+        assert_eq!(
+            encode_charset("a: é\n", Charset::Latin1).expect("latin1"),
+            b"a: \xE9\n"
+        );
+        let err = encode_charset("a: 1\nb: 5 €\n", Charset::Latin1)
+            .expect_err("euro is not latin1")
+            .to_string();
+        assert!(err.contains("line 2"), "{err}");
+        assert!(err.contains("U+20AC"), "{err}");
+        assert_eq!(
+            encode_charset(text, Charset::Utf8).expect("utf8"),
+            text.as_bytes()
+        );
+    }
+
+    #[test]
+    fn ensure_expected_kinds_names_offending_documents() {
+        let kinds = vec!["ConfigMap".to_string(), "Deployment".to_string()];
+        let rendered = vec![
+            (
+                PathBuf::from("app.yaml"),
+                "kind: Deployment\n---\nkind: Secret\n---\n".to_string(),
+            ),
+            (
+                PathBuf::from("cm.yaml"),
+                "kind: ConfigMap\n---\nname: x\n".to_string(),
+            ),
+        ];
+        let err = ensure_expected_kinds(&rendered, &kinds, false)
+            .expect_err("Secret is not allowed")
+            .to_string();
+        assert!(err.contains("- app.yaml document 2: kind Secret"), "{err}");
+        assert!(err.contains("- cm.yaml document 2: no kind"), "{err}");
+        assert!(!err.contains("document 1"), "{err}");
+        assert!(!err.contains("document 3"), "{err}");
+
+        let err = ensure_expected_kinds(&rendered, &kinds, true)
+            .expect_err("Secret is still not allowed")
+            .to_string();
+        assert!(!err.contains("no kind"), "{err}");
+
+        let ok = vec![(PathBuf::from("cm.yaml"), "kind: ConfigMap\n".to_string())];
+        assert!(ensure_expected_kinds(&ok, &kinds, false).is_ok());
+    }
+
+    #[test]
+    fn find_duplicate_yaml_keys_reports_key_paths_per_document() {
+        let text = "\
+kind: Deployment
+spec:
+  replicas: 1
+  template:
+    spec:
+      containers:
+        - name: app
+          image: a
+          image: b
+        - name: sidecar
+          image: c
+  replicas: 2
+---
+data:
+  script: |
+    a: 1
+    a: 2
+  \"script\": x
+items:
+- a: 1
+- a: 2
+  a: 3
+";
+        let summary: Vec<(String, usize, usize, usize)> = find_duplicate_yaml_keys(text)
+            .into_iter()
+            .map(|d| (d.path, d.document, d.first_line, d.line))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (
+                    "spec.template.spec.containers[0].image".to_string(),
+                    1,
+                    8,
+                    9
+                ),
+                ("spec.replicas".to_string(), 1, 3, 12),
+                ("data.script".to_string(), 2, 15, 18),
+                ("items[1].a".to_string(), 2, 21, 22),
+            ]
+        );
+
+        let rendered = vec![(PathBuf::from("a.yaml"), "a: 1\nb: 2\na: 3\n".to_string())];
+        let err = ensure_no_duplicate_keys(&rendered)
+            .expect_err("duplicate a")
+            .to_string();
+        assert!(
+            err.contains("- a.yaml:3: a (document 1, first on line 1)"),
+            "{err}"
+        );
+        let clean = vec![(
+            PathBuf::from("b.yaml"),
+            "a:\n  x: 1\nb:\n  x: 2\n".to_string(),
+        )];
+        assert!(ensure_no_duplicate_keys(&clean).is_ok());
+    }
+
+    #[test]
+    fn validate_reports_the_file_and_document_that_does_not_parse() {
+        let rendered = vec![
+            (
+                PathBuf::from("ok.yaml"),
+                "a: 1\n---\nb: [1, 2]\n".to_string(),
+            ),
+            (
+                PathBuf::from("bad.yaml"),
+                "a: 1\n---\nkey: value\n  broken: indent\n".to_string(),
+            ),
+        ];
+        let err = ensure_rendered_yaml_parses(&rendered)
+            .expect_err("bad.yaml fails")
+            .to_string();
+        assert!(err.contains("- bad.yaml (document 2): "), "{err}");
+        assert!(!err.contains("ok.yaml"), "{err}");
+        assert!(ensure_rendered_yaml_parses(&rendered[..1]).is_ok());
+    }
+
+    #[test]
+    fn diff_shows_each_substitution_under_its_line() {
+        let input = "a: 1\nimage: {{ .Values.image }}\ncert: |\n  {{ .Values.cert }}\n";
+        let token = |t: &str| {
+            let start = input.find(t).expect("token present");
+            (start, start + t.len())
+        };
+        let (s1, e1) = token("{{ .Values.image }}");
+        let (s2, e2) = token("{{ .Values.cert }}");
+        let subs = vec![
+            Substitution {
+                start: s1,
+                end: e1,
+                value: "nginx:1.25".to_string(),
+            },
+            Substitution {
+                start: s2,
+                end: e2,
+                value: "l1\nl2\nl3\nl4\nl5".to_string(),
+            },
+        ];
+        let plain = render_substitution_diff(Path::new("app.yaml"), input, &subs, false);
+        assert_eq!(
+            plain,
+            "--- app.yaml (2 substitution(s))\n\
+             2 | image: {{ .Values.image }}\n  \
+             |        ^^^^^^^^^^^^^^^^^^^\n  \
+             = nginx:1.25\n\
+             4 |   {{ .Values.cert }}\n  \
+             |   ^^^^^^^^^^^^^^^^^^\n  \
+             = l1\n  \
+             | l2\n  \
+             | l3\n  \
+             | ... 2 more line(s)\n"
+        );
+
+        let colored = render_substitution_diff(Path::new("app.yaml"), input, &subs[..1], true);
+        assert!(colored.contains("image: \x1b[31m{{ .Values.image }}\x1b[0m\n"));
+        assert!(colored.contains("= \x1b[32mnginx:1.25\x1b[0m\n"));
+        assert!(!colored.contains('^'));
+    }
+
+    #[test]
+    fn ensure_placeholder_count_within_reports_count_and_limit() {
+        let re = placeholder_regex().expect("regex compiles");
+        let templates = vec![
+            (PathBuf::from("a.yaml"), "a: {{ A }} ${B}\n".to_string()),
+            (PathBuf::from("b.yaml"), "b: {{ .Values.b }}\n".to_string()),
+        ];
+        assert!(ensure_placeholder_count_within(&templates, &re, 3).is_ok());
+        let err = ensure_placeholder_count_within(&templates, &re, 2)
+            .expect_err("three placeholders")
+            .to_string();
+        assert_eq!(
+            err,
+            "3 placeholders in 2 template(s) exceed the limit of 2 (--max-placeholders)"
+        );
+        assert!(ensure_placeholder_count_within(&templates, &re, 0).is_err());
+    }
+
+    #[test]
+    fn decode_charset_reads_latin1_and_utf16_templates() {
+        assert_eq!(
+            decode_charset(b"name: Zo\xEB\n".to_vec(), Charset::Latin1).expect("latin1"),
+            "name: Zoë\n"
+        );
+        let text = "a: {{ .Values.a }} 🚀\n";
+        for charset in [Charset::Utf16le, Charset::Utf16be] {
+            let bytes = encode_charset(text, charset).expect("encodes");
+            assert_eq!(
+                decode_charset(bytes.clone(), charset).expect("with bom"),
+                text
+            );
+            assert_eq!(
+                decode_charset(bytes[2..].to_vec(), charset).expect("without bom"),
+                text
+            );
+        }
+        let err = decode_charset(vec![0x61, 0x00, 0x62], Charset::Utf16le).expect_err("odd");
+        assert!(err.to_string().contains("odd number of bytes"), "{err}");
+        let err = decode_charset(vec![0x00, 0xD8], Charset::Utf16le).expect_err("surrogate");
+        assert!(err.to_string().contains("unpaired surrogate"), "{err}");
+        let err = decode_charset(b"a: \xE9\n".to_vec(), Charset::Utf8).expect_err("latin1 bytes");
+        assert_eq!(
+            err.to_string(),
+            "invalid UTF-8 at byte 3 (see --input-encoding)"
+        );
+    }
+
+    #[test]
+    fn output_format_json_converts_rendered_documents() {
+        let single = vec![(
+            PathBuf::from("a.yaml"),
+            "name: app\nports: [80, 443]\n".to_string(),
+        )];
+        assert_eq!(
+            render_json_documents(&single).expect("renders"),
+            "{\n  \"name\": \"app\",\n  \"ports\": [\n    80,\n    443\n  ]\n}\n"
+        );
+        let multi = vec![
+            (PathBuf::from("a.yaml"), "a: 1\n".to_string()),
+            (PathBuf::from("b.yaml"), "b: true\n".to_string()),
+        ];
+        assert_eq!(
+            render_json_documents(&multi).expect("renders"),
+            "[\n  {\n    \"a\": 1\n  },\n  {\n    \"b\": true\n  }\n]\n"
+        );
+        let bad = vec![(PathBuf::from("bad.yaml"), "a: [1\n".to_string())];
+        let err = render_json_documents(&bad).expect_err("invalid YAML fails");
+        assert_eq!(
+            err.to_string(),
+            "failed to parse rendered YAML for --output-format json: bad.yaml"
+        );
+    }
+
+    #[test]
+    fn render_json_lines_writes_one_object_per_document() {
+        let rendered = vec![
+            (
+                PathBuf::from("a.yaml"),
+                "name: a\nports: [80, 443]\n".to_string(),
+            ),
+            (
+                PathBuf::from("b.yaml"),
+                "---\nname: b\n---\n# empty\n---\nname: \"c\\nd\"\n".to_string(),
+            ),
+        ];
+        let out = render_json_lines(&rendered).expect("renders");
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"name":"a","ports":[80,443]}"#,
+                r#"{"name":"b"}"#,
+                r#"{"name":"c\nd"}"#,
+            ]
+        );
+        for line in lines {
+            let parsed: YamlValue = serde_yaml::from_str(line).expect("each line is JSON");
+            assert!(parsed.is_mapping());
+        }
+
+        let bad = vec![(PathBuf::from("bad.yaml"), "a: [1\n".to_string())];
+        let err = render_json_lines(&bad).expect_err("invalid YAML fails");
+        assert!(format!("{err:#}").contains("bad.yaml"), "{err:#}");
+    }
+
+    #[test]
+    fn render_multi_document_yaml_uses_doc_separator() {
+        let rendered = vec![
+            (PathBuf::from("1-a.yaml"), "a: 1\n".to_string()),
+            (PathBuf::from("2-b.yaml"), "b: 2\n".to_string()),
+        ];
+        let out = render_multi_document_yaml(&rendered, "---", false);
+        assert_eq!(out, "a: 1\n\n---\nb: 2\n");
+    }
+
+    #[test]
+    fn render_multi_document_yaml_uses_custom_and_leading_separator() {
+        let rendered = vec![
+            (PathBuf::from("1-a.yaml"), "a: 1\n".to_string()),
+            (PathBuf::from("2-b.yaml"), "b: 2".to_string()),
+        ];
+        let out = render_multi_document_yaml(&rendered, "--- # next", true);
+        assert_eq!(out, "--- # next\na: 1\n\n--- # next\nb: 2\n");
+        assert!(parse_doc_separator("").is_err());
+        assert!(parse_doc_separator("---\n---").is_err());
+    }
+
+    #[test]
+    fn bare_values_classifies_bare_names_by_case() {
+        let input = concat!(
+            "{{ db.host }} {{ NAMESPACE }} {{name}} {{ Region }} {{ app-name | indent 2 }}\n",
+            "{{ .Values.x }} ${lower} $lower {{ API_2 }} {{ _TOKEN }} {{ a_b }}\n",
+        );
+        let set = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<BTreeSet<_>>();
+
+        let re = placeholder_regex_with_style(PlaceholderStyle::Standard, '.', true)
+            .expect("regex compiles");
+        let (env_vars, values_paths) = collect_placeholders(input, &re);
+        assert_eq!(env_vars, set(&["API_2", "NAMESPACE", "_TOKEN", "lower"]));
+        assert_eq!(
+            values_paths,
+            set(&["Region", "a_b", "app-name", "db.host", "name", "x"])
+        );
+
+        // Without the flag, bare names are env placeholders and dotted ones do not match.
+        let (env_vars, values_paths) =
+            collect_placeholders(input, &placeholder_regex().expect("regex"));
+        assert_eq!(
+            env_vars,
+            set(&[
+                "API_2",
+                "NAMESPACE",
+                "Region",
+                "_TOKEN",
+                "a_b",
+                "lower",
+                "name"
+            ])
+        );
+        assert_eq!(values_paths, set(&["x"]));
+
+        let re = placeholder_regex_with_style(PlaceholderStyle::Standard, '/', true)
+            .expect("regex compiles");
+        let caps = re
+            .captures("{{ labels/app.kubernetes.io }}")
+            .expect("matches");
+        assert_eq!(
+            extract_values_path(&caps).as_deref(),
+            Some(r"labels.app\.kubernetes\.io")
+        );
+    }
+
+    #[test]
+    fn extract_env_key_supports_three_env_styles() {
+        let re = placeholder_regex().expect("regex compiles");
+
+        let c1 = re
+            .captures("{{NAMESPACE}}")
+            .expect("must capture handlebars env");
+        assert_eq!(extract_env_key(&c1), Some("NAMESPACE"));
+
+        let c2 = re.captures("${APP_NAME}").expect("must capture brace env");
+        assert_eq!(extract_env_key(&c2), Some("APP_NAME"));
+
+        let c3 = re.captures("$REGION").expect("must capture short env");
+        assert_eq!(extract_env_key(&c3), Some("REGION"));
+    }
+
+    #[test]
+    fn perl_env_style_collects_and_substitutes_env_braces() {
+        let input = "a: $ENV{FOO}\nb: ${BAR}\nc: $BAZ\n";
+
+        let standard = placeholder_regex().expect("regex compiles");
+        let (env_vars, _) = collect_placeholders(input, &standard);
+        assert!(env_vars.contains("ENV"));
+        assert!(!env_vars.contains("FOO"));
+
+        let perl = placeholder_regex_with_style(PlaceholderStyle::PerlEnv, '.', false)
+            .expect("regex compiles");
+        let (env_vars, _) = collect_placeholders(input, &perl);
+        assert_eq!(
+            env_vars,
+            BTreeSet::from(["BAR".to_string(), "BAZ".to_string(), "FOO".to_string()])
+        );
+
+        let rendered = perl.replace_all(input, |caps: &regex::Captures| {
+            extract_env_key(caps).unwrap_or_default().to_lowercase()
+        });
+        assert_eq!(rendered, "a: foo\nb: bar\nc: baz\n");
+    }
+
+    #[test]
+    fn placeholder_regex_captures_filter_pipeline() {
+        let re = placeholder_regex().expect("regex compiles");
+        let cap = re
+            .captures("data: {{ .Values.cert | indent 4 }}")
+            .expect("must capture values placeholder with filter");
+        assert_eq!(extract_values_path(&cap).as_deref(), Some("cert"));
+        assert_eq!(
+            parse_pipeline(extract_pipeline(&cap).expect("pipeline present")).expect("parses"),
+            vec![Filter {
+                name: "indent".to_string(),
+                args: vec!["4".to_string()],
+            }]
+        );
+
+        let cap = re.captures("{{ NAME }}").expect("must capture env");
+        assert_eq!(extract_pipeline(&cap), None);
+    }
+
+    #[test]
+    fn sha256_filters_hash_values_and_canonical_subtrees() {
+        assert_eq!(
+            sha256_hex(b""),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        let long = "a".repeat(1000);
+        assert_eq!(
+            sha256_hex(long.as_bytes()),
+            "41edece42d63e8d9bf515a9ba6932e1c20cbc9f5a5d134645adb5db1b9737ea3"
+        );
+
+        let filters = parse_pipeline("sha256").expect("parses");
+        assert_eq!(
+            apply_filters("abc", &filters).expect("applies"),
+            sha256_hex(b"abc")
+        );
+        let filters = parse_pipeline("sha256sum").expect("parses");
+        let a = apply_filters("b: 2\na:\n  y: 1\n  x: [1, 2]", &filters).expect("applies");
+        let b = apply_filters("a:\n  x: [1, 2]\n  y: 1\nb: 2", &filters).expect("applies");
+        assert_eq!(a, b);
+        assert_eq!(a, sha256_hex(br#"{"a":{"x":[1,2],"y":1},"b":2}"#));
+        assert!(validate_filter(&parse_pipeline("sha256 x").expect("parses")[0]).is_err());
+    }
+
+    #[test]
+    fn replace_filters_substitute_literal_text_and_regex_matches() {
+        let filters = parse_pipeline(r#"replace "/" "-""#).expect("parses");
+        assert_eq!(
+            apply_filters("feature/login.v2", &filters).expect("applies"),
+            "feature-login.v2"
+        );
+        let filters = parse_pipeline(r#"replace "." """#).expect("parses");
+        assert_eq!(apply_filters("1.2.3", &filters).expect("applies"), "123");
+
+        // As in Go templates, a regex backslash is written `\\` inside a quoted argument.
+        let filters =
+            parse_pipeline(r#"regexReplace "^v([0-9]+)\\.([0-9]+)$" "$1-$2""#).expect("parses");
+        assert_eq!(apply_filters("v1.10", &filters).expect("applies"), "1-10");
+        assert_eq!(apply_filters("v1x10", &filters).expect("applies"), "v1x10");
+
+        let bad = &parse_pipeline(r#"regexReplace "a(" "b""#).expect("parses")[0];
+        let err = validate_filter(bad).expect_err("invalid regex");
+        assert!(
+            format!("{err:#}").contains(r#"invalid regex "a(""#),
+            "{err:#}"
+        );
+        assert!(validate_filter(&parse_pipeline(r#"replace "a""#).expect("parses")[0]).is_err());
+    }
+
+    #[test]
+    fn custom_delimiters_replace_the_brace_pair_only() {
+        let delims = parse_delimiters("[[ ]]").expect("parses");
+        let re = placeholder_regex_with_delimiters(PlaceholderStyle::Standard, '.', false, &delims)
+            .expect("regex compiles");
+        let input = "a: [[ .Values.a | replace \"x\" \"y\" ]]\nb: [[B]] ${C} {{ .Values.other }}\n{{ if x }}keep{{ end }}\n";
+        let (env_vars, values_paths) = collect_placeholders(input, &re);
+        assert_eq!(env_vars, ["B", "C"].map(String::from).into());
+        assert_eq!(values_paths, ["a"].map(String::from).into());
+
+        let values: YamlValue =
+            serde_yaml::from_str("on: true\noff: false\n").expect("yaml parses");
+        let truthy = |cond: &str| condition_is_truthy(cond, &values, &re, KeyCase::Exact, &delims);
+        assert_eq!(
+            apply_conditionals(
+                "[[ if .Values.on ]]1[[ end ]][[ if .Values.off ]]2[[ else ]]3[[ end ]]{{ if x }}",
+                Some(&truthy),
+                &delims
+            )
+            .expect("renders"),
+            "13{{ if x }}"
+        );
+
+        let lint = lint_template(
+            "a: [[ .Values.bad$path ]] {{ helm.thing }}\nb: [[ .Values.a\n",
+            &re,
+            false,
+            &delims,
+        );
+        let messages: Vec<(usize, &str)> =
+            lint.iter().map(|f| (f.line, f.message.as_str())).collect();
+        assert_eq!(
+            messages,
+            [
+                (
+                    1,
+                    "invalid .Values path in [[ .Values.bad$path ]]: expected dot-separated identifiers"
+                ),
+                (2, "unbalanced braces: 1 '[[' vs 0 ']]'"),
+            ]
+        );
+        assert_eq!(
+            find_partial_placeholders("a: [[ .Values.name\nb: {{ x\n", &re, &delims),
+            vec![(1, "[[ .Values.name".to_string())]
+        );
+
+        assert!(parse_delimiters("[[").is_err());
+        assert!(parse_delimiters("<< <<").is_err());
+        assert!(parse_delimiters("${ }").is_err());
+        assert_eq!(parse_delimiters("{{ }}"), Ok(Delimiters::default()));
+    }
+
+    #[test]
+    fn case_trim_and_base64_filters_transform_text() {
+        let filters = parse_pipeline("trim | b64enc").expect("parses");
+        assert_eq!(
+            apply_filters("  s3cret\n", &filters).expect("applies"),
+            "czNjcmV0"
+        );
+        let filters = parse_pipeline("b64dec | upper").expect("parses");
+        assert_eq!(
+            apply_filters("czNjcmV0", &filters).expect("applies"),
+            "S3CRET"
+        );
+        let filters = parse_pipeline("lower").expect("parses");
+        assert_eq!(
+            apply_filters("Dev-EU", &filters).expect("applies"),
+            "dev-eu"
+        );
+
+        let err = apply_filters("not base64!", &parse_pipeline("b64dec").expect("parses"))
+            .expect_err("invalid base64");
+        assert!(format!("{err:#}").contains("not valid base64"), "{err:#}");
+        assert!(validate_filter(&parse_pipeline("upper 2").expect("parses")[0]).is_err());
+        let err = validate_filter(&parse_pipeline("uppercase").expect("parses")[0])
+            .expect_err("unknown filter");
+        assert_eq!(err.to_string(), "unknown filter 'uppercase'");
+    }
+
+    #[test]
+    fn ternary_filter_maps_truthiness_to_its_arguments() {
+        let filters = parse_pipeline(r#"ternary "yes" "no""#).expect("parses");
+        for (value, expected) in [
+            ("true", "yes"),
+            ("1", "yes"),
+            ("enabled", "yes"),
+            ("false", "no"),
+            ("False", "no"),
+            ("no", "no"),
+            ("0", "no"),
+            ("", "no"),
+            ("  ", "no"),
+        ] {
+            assert_eq!(
+                apply_filters(value, &filters).expect("applies"),
+                expected,
+                "{value:?}"
+            );
+        }
+        let filters = parse_pipeline(r#"ternary "on" "" | sha256"#).expect("parses");
+        assert_eq!(
+            apply_filters("x", &filters).expect("applies"),
+            sha256_hex(b"on")
+        );
+
+        for bad in ["ternary", r#"ternary "a""#, r#"ternary "a" "b" "c""#] {
+            let err = validate_filter(&parse_pipeline(bad).expect("parses")[0]).expect_err(bad);
+            assert!(err.to_string().contains("exactly two arguments"), "{err}");
+        }
+    }
+
+    #[test]
+    fn parse_pipeline_handles_quoted_arguments_and_chains() {
+        let filters = parse_pipeline(r#" | replace "a b" "c\"d" | indent 2"#).expect("parses");
+        assert_eq!(filters.len(), 2);
+        assert_eq!(filters[0].args, vec!["a b".to_string(), "c\"d".to_string()]);
+        assert_eq!(filters[1].name, "indent");
+        assert!(parse_pipeline(r#"| replace "open"#).is_err());
+    }
+
+    #[test]
+    fn indent_filter_indents_lines_after_the_first() {
+        let indent = Filter {
+            name: "indent".to_string(),
+            args: vec!["4".to_string()],
+        };
+        assert_eq!(apply_filter("single", &indent).expect("ok"), "single");
+        assert_eq!(
+            apply_filters("a\nb\nc", &[indent]).expect("ok"),
+            "a\n    b\n    c"
+        );
+    }
+
+    #[test]
+    fn nindent_filter_prepends_newline_and_indents_every_line() {
+        let nindent = Filter {
+            name: "nindent".to_string(),
+            args: vec!["2".to_string()],
+        };
+        assert_eq!(apply_filter("x", &nindent).expect("ok"), "\n  x");
+        assert_eq!(apply_filters("a\nb", &[nindent]).expect("ok"), "\n  a\n  b");
+        assert!(
+            validate_filter(&Filter {
+                name: "indent".to_string(),
+                args: vec!["four".to_string()],
+            })
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn render_templates_trims_whitespace_for_dash_delimiters() {
+        let templates = vec![(
+            PathBuf::from("a.yaml"),
+            "a: x  \n  {{- .Values.v -}}  \nb: y\nc: {{ .Values.v }} \nd: {{ .Values.v | indent 2 -}}\n".to_string(),
+        )];
+        let re = placeholder_regex().expect("regex compiles");
+        let values_map = HashMap::from([("v".to_string(), "V".to_string())]);
+        let opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: false,
+            block_scalar: true,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: false,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let out = render_templates(&templates, &re, &values_map, &HashMap::new(), &opts)
+            .expect("renders");
+        assert_eq!(out[0].1, "a: xVb: y\nc: V \nd: V");
+    }
+
+    #[test]
+    fn values_key_to_env_var_handles_environment_prefix_and_dots() {
+        assert_eq!(values_key_to_env_var("environment.APP_NAME"), "APP_NAME");
+        assert_eq!(values_key_to_env_var("image.tag"), "IMAGE_TAG");
+    }
+
+    #[test]
+    fn render_eval_exports_outputs_bash_exports() {
+        let prompted = vec![
+            ("environment.APP_NAME".to_string(), "demo-app".to_string()),
+            ("image.tag".to_string(), "1.2.3".to_string()),
+        ];
+        let out = render_eval_exports_with_env(&prompted, &HashMap::new(), "");
+        assert!(out.contains("export APP_NAME='demo-app'"));
+        assert!(out.contains("export IMAGE_TAG='1.2.3'"));
+    }
+
+    #[test]
+    fn render_eval_exports_with_env_always_includes_resolved_env_values() {
+        let prompted = vec![("image.tag".to_string(), "1.2.3".to_string())];
+        let resolved_env = HashMap::from([("IMAGE".to_string(), "repo/app:7".to_string())]);
+        let out = render_eval_exports_with_env(&prompted, &resolved_env, "");
+        assert!(out.contains("export IMAGE='repo/app:7'"));
+        assert!(out.contains("export IMAGE_TAG='1.2.3'"));
+    }
+
+    #[test]
+    fn render_eval_exports_with_env_prefixes_every_name() {
+        let prompted = vec![
+            ("image.tag".to_string(), "1.2.3".to_string()),
+            ("environment.REGION".to_string(), "eu".to_string()),
+        ];
+        let resolved_env = HashMap::from([("IMAGE".to_string(), "repo/app:7".to_string())]);
+        let out = render_eval_exports_with_env(&prompted, &resolved_env, "TPLENV_");
+        assert_eq!(
+            out,
+            "export TPLENV_IMAGE='repo/app:7'\nexport TPLENV_IMAGE_TAG='1.2.3'\nexport TPLENV_REGION='eu'\n"
+        );
+        assert!(parse_export_prefix("tplenv_").is_ok());
+        assert!(parse_export_prefix("1X").is_err());
+        assert!(parse_export_prefix("A-").is_err());
+        assert!(parse_export_prefix("").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_private_file_is_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+        let path = env::temp_dir().join(format!("tplenv-export-{}.sh", std::process::id()));
+        fs::write(&path, "old").expect("write file");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).expect("chmod");
+        write_private_file(&path, b"export A='1'\n").expect("writes");
+        let meta = fs::metadata(&path).expect("metadata");
+        assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(&path).expect("read"), "export A='1'\n");
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn prompted_environment_values_only_keeps_environment_entries() {
+        let prompted = vec![
+            ("environment.IMAGE".to_string(), "nginx:1.2".to_string()),
+            ("db.user".to_string(), "app".to_string()),
+        ];
+        let out = prompted_environment_values(&prompted);
+        assert_eq!(out.get("IMAGE"), Some(&"nginx:1.2".to_string()));
+        assert!(!out.contains_key("DB_USER"));
+    }
+
+    #[test]
+    fn render_templates_strict_multiline_names_each_misplaced_placeholder() {
+        let template = "\
+block: {{ .Values.cert }}
+list: [a, {{ .Values.cert }}]
+{{ .Values.cert }}: x
+note: see {{ .Values.cert }} here
+data: |
+  {{ .Values.cert }}
+";
+        let re = placeholder_regex().expect("regex compiles");
+        let values_map = HashMap::from([("cert".to_string(), "a\nb".to_string())]);
+        let mut opts = RenderOptions {
+            verbose: false,
+            value_file_only: false,
+            indent: true,
+            block_scalar: true,
+            format: TemplateFormat::Yaml,
+            max_file_size: u64::MAX,
+            masked: &BTreeSet::new(),
+            quoted: None,
+            typed: None,
+            folded_policy: FoldedPolicy::Warn,
+            strict_multiline: true,
+            on_warning: &|message| warn(true, message),
+            diff: false,
+        };
+        let templates = vec![(PathBuf::from("t.yaml"), template.to_string())];
+        let err = render_templates(&templates, &re, &values_map, &HashMap::new(), &opts)
+            .expect_err("misplaced values fail");
+        assert_eq!(
+            err.to_string(),
+            "3 multi-line value(s) would produce invalid YAML (--strict-multiline):
+  t.yaml:2: {{ .Values.cert }} is inside a flow collection
+  t.yaml:3: {{ .Values.cert }} is in key position
+  t.yaml:4: {{ .Values.cert }} shares its line with other text"
+        );
+
+        opts.strict_multiline = false;
+        assert!(render_templates(&templates, &re, &values_map, &HashMap::new(), &opts).is_ok());
+    }
+
+    #[test]
+    fn indent_multiline_value_uses_placeholder_line_indent() {
+        let input = "data:\n  script: |\n    {{ .Values.script }}\n";
+        let match_start = input
+            .find("{{ .Values.script }}")
+            .expect("placeholder should exist");
+        let value = "echo first\necho second";
+
+        let out = indent_multiline_value(value, input, match_start);
+        assert_eq!(out, "echo first\n    echo second");
+    }
+
+    #[test]
+    fn format_replacement_with_indent_uses_yaml_block_scalar_for_inline_value() {
+        let input = "data:\n  script: {{ .Values.script }}\n";
+        let token = "{{ .Values.script }}";
+        let match_start = input.find(token).expect("placeholder should exist");
+        let match_end = match_start + token.len();
+        let value = "echo first\necho second";
+
+        let out = format_replacement_with_indent(value, input, match_start, match_end, true);
+        assert_eq!(out, "|\n    echo first\n    echo second");
+    }
+
+    #[test]
+    fn format_replacement_with_indent_keeps_flow_collections_on_one_line() {
+        let value = "line one\n\"two\"";
+        for input in [
+            "args: [ {{ X }} ]\n",
+            "args: [a, {{ X }}, b]\n",
+            "env: {k: {{ X }}}\n",
+            "env: {\"a]\": \"{{ Y }}\", k: {{ X }}}\n",
+        ] {
+            let token = "{{ X }}";
+            let match_start = input.find(token).expect("placeholder should exist");
+            let match_end = match_start + token.len();
+            let out = format_replacement_with_indent(value, input, match_start, match_end, true);
+            assert_eq!(out, r#""line one\n\"two\"""#, "{input}");
+            let rendered = format!("{}{out}{}", &input[..match_start], &input[match_end..]);
+            serde_yaml::from_str::<YamlValue>(&rendered).expect("valid yaml");
+        }
+
+        for input in [
+            "a: [b] {{ X }}\n",
+            "a: \"[b\" {{ X }}\n",
+            "a: it's [b {{ X }}\n",
+        ] {
+            let pos = input.find("{{").expect("placeholder");
+            assert_eq!(
+                in_yaml_flow_context(input, pos),
+                input.contains("it's"),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn format_replacement_with_indent_skips_block_scalar_when_disabled() {
+        let input = "data:\n  script: {{ .Values.script }}\n";
+        let token = "{{ .Values.script }}";
+        let match_start = input.find(token).expect("placeholder should exist");
+        let match_end = match_start + token.len();
+        let value = "echo first\necho second";
+
+        let out = format_replacement_with_indent(value, input, match_start, match_end, false);
+        assert!(!out.contains('|'));
+        assert_eq!(out, "echo first\n  echo second");
+    }
+
+    #[test]
+    fn format_replacement_with_indent_uses_block_scalar_keep_for_trailing_empty_lines() {
+        let input = "data:\n  script: {{ .Values.script }}\n";
+        let token = "{{ .Values.script }}";
+        let match_start = input.find(token).expect("placeholder should exist");
+        let match_end = match_start + token.len();
+        let value = "echo first\n\n";
+
+        let out = format_replacement_with_indent(value, input, match_start, match_end, true);
+        assert_eq!(out, "|+\n    echo first\n    \n");
+    }
+
+    #[test]
+    fn indent_multiline_signer_in_yaml_list_items_stays_valid_yaml() {
+        let input = r#"name: kbs-certs
+version: "0.3.11"
+
+access_policy:
+    read:
+      - ANY
+    update:
+      - ${SIGNER}
+    create_sessions:
+      - ${SIGNER}
+"#;
+        let signer = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAtestkeyline\n-----END PUBLIC KEY-----";
+        let re = placeholder_regex().expect("regex compiles");
+
+        let rendered = re.replace_all(input, |caps: &regex::Captures| {
+            if let Some(key) = extract_env_key(caps)
+                && key == "SIGNER"
+            {
+                let m = caps.get(0).expect("full match present");
+                return format_replacement_with_indent(signer, input, m.start(), m.end(), true);
+            }
+            caps.get(0)
+                .map(|m| m.as_str().to_string())
+                .unwrap_or_default()
+        });
+
+        let rendered = rendered.to_string();
+        assert!(rendered.contains("- |\n        -----BEGIN PUBLIC KEY-----"));
+        assert_eq!(rendered.matches("- |").count(), 2);
+        let parsed: YamlValue = serde_yaml::from_str(&rendered).expect("rendered yaml is valid");
+        assert!(matches!(parsed, YamlValue::Mapping(_)));
+    }
+}
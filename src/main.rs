@@ -1,16 +1,18 @@
 // src/main.rs
 use anyhow::{Context, Result, bail};
-use clap::{ArgGroup, Parser};
+use clap::parser::ValueSource as ArgValueSource;
+use clap::{ArgAction, ArgGroup, ArgMatches, CommandFactory, FromArgMatches, Parser, ValueHint};
 use regex::Regex;
 use serde_yaml::{Mapping as YamlMapping, Value as YamlValue};
 use std::collections::{BTreeSet, HashMap};
 use std::env;
+use std::ffi::OsString;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Write};
 use std::path::{Path, PathBuf};
-
-const BASH_COMPLETION: &str = include_str!("../completions/tplenv.bash");
-const ZSH_COMPLETION: &str = include_str!("../completions/_tplenv");
+use std::sync::mpsc;
+use std::time::Duration;
+use tplenv::*;
 
 /// Substitute env placeholders using environment variables (`{{VARNAME}}`, `$VARNAME`, `${VARNAME}`),
 /// and {{ .Values.key }} placeholders using a YAML values file (default: Values.yaml).
@@ -24,41 +26,250 @@ const ZSH_COMPLETION: &str = include_str!("../completions/_tplenv");
     name = "tplenv",
     version,
     about = "Fill placeholders in YAML templates using env vars and/or a values file",
-    long_about = "tplenv reads one or more template files and replaces placeholders:\n- {{VARNAME}}, $VARNAME, ${VARNAME} from environment variables\n- {{ .Values.key }} from a YAML values file\n\nYou can also run in values-only mode so env placeholders are read from environment.VARNAME in the values file.\n\nFile patterns:\n- --file-pattern matches files in one directory using * and <NUM>\n- matched files are processed in sorted filename order\n- output is one YAML multi-document stream (documents separated by ---)\n\nEval mode:\n- --eval prints prompted values as bash export statements\n- designed for: eval \"$(tplenv ... --create-values-file --eval)\"",
+    long_about = "tplenv reads one or more template files and replaces placeholders:\n- {{VARNAME}}, $VARNAME, ${VARNAME} from environment variables\n- {{ .Values.key }} from a YAML values file\n\nYou can also run in values-only mode so env placeholders are read from environment.VARNAME in the values file.\n\nFile patterns:\n- --file-pattern matches files using * and <NUM> within a directory, and ** across subdirectories (configs/**/*.yaml)\n- matched files are processed in sorted path order\n- output is one YAML multi-document stream (documents separated by ---)\n\nEval mode:\n- --eval prints prompted values as bash export statements\n- designed for: eval \"$(tplenv ... --create-values-file --eval)\"",
     after_help = "Quick examples:\n  tplenv --file app.yaml --values Values.yaml\n  tplenv --file app.yaml --indent\n  tplenv --file app.yaml --create-values-file\n  tplenv --file app.yaml --value-file-only --create-values-file --force\n  tplenv --file-pattern \"configs/<NUM>-*.yaml\" --values Values.yaml\n  tplenv --file-pattern \"configs/<NUM>-*.yaml\" --output rendered.yaml\n  eval \"$(tplenv --file app.yaml --create-values-file --eval)\"\n  tplenv --install-completion\n  tplenv --install-completion zsh\n",
     disable_help_flag = false,
     next_line_help = true,
     group(
         ArgGroup::new("input")
-            .args(["file", "file_pattern"])
+            .args(["file", "file_pattern", "pipeline"])
+    ),
+    group(
+        ArgGroup::new("exports")
+            .args(["eval", "env_export_file"])
+            .multiple(true)
     )
 )]
 struct Args {
-    /// Single template file to render
-    #[arg(short = 'f', long = "file")]
-    file: Option<PathBuf>,
+    /// Template file to render ("-" reads the template from stdin); repeatable, several files
+    /// render in the given order into one YAML multi-document stream
+    #[arg(short = 'f', long = "file", value_hint = ValueHint::FilePath)]
+    file: Vec<PathBuf>,
 
-    /// Render all files matching this pattern (supports * and <NUM>)
+    /// Render all files matching this pattern (supports *, <NUM> and ** for subdirectories)
     /// Output becomes one YAML multi-document stream.
-    #[arg(long = "file-pattern")]
+    #[arg(long = "file-pattern", value_hint = ValueHint::FilePath)]
     file_pattern: Option<String>,
 
-    /// Values YAML file used for {{ .Values.* }} lookups and environment.* in --value-file-only mode
+    /// With --file-pattern: skip matched files that are not *.yaml or *.yml (with a warning) instead of failing
+    #[arg(long = "skip-non-yaml", default_value_t = false)]
+    skip_non_yaml: bool,
+
+    /// With --file-pattern: only process matched files that are also listed here, e.g. from
+    /// `git diff --name-only`; "-" reads a newline-separated list from stdin
+    #[arg(
+        long = "changed-files",
+        alias = "validate-only-changed",
+        value_name = "FILE",
+        num_args = 1..,
+        requires = "file_pattern",
+        value_hint = ValueHint::FilePath
+    )]
+    changed_files: Vec<PathBuf>,
+
+    /// Print the files that would be rendered, one per line, and exit
+    #[arg(long = "list-files", default_value_t = false)]
+    list_files: bool,
+
+    /// Print placeholder statistics for the input files (totals, most-referenced keys,
+    /// per-file counts) and exit without reading values or rendering
+    #[arg(long = "scan-only", default_value_t = false)]
+    scan_only: bool,
+
+    /// With --scan-only: format of the statistics
+    #[arg(
+        long = "scan-format",
+        value_enum,
+        default_value = "text",
+        requires = "scan_only"
+    )]
+    scan_format: ScanFormat,
+
+    /// With --file-pattern: also match symlinks that point to regular files
+    #[arg(long = "follow-symlinks", default_value_t = false)]
+    follow_symlinks: bool,
+
+    /// Values YAML file used for {{ .Values.* }} lookups and environment.* in --value-file-only mode.
+    /// Repeat to deep-merge several files; later files win and --create-values-file writes
+    /// to the last one.
     #[arg(
         long = "values-file",
         visible_alias = "values",
-        default_value = "Values.yaml"
+        default_value = "Values.yaml",
+        value_hint = ValueHint::FilePath
     )]
-    values: PathBuf,
+    values: Vec<PathBuf>,
 
     /// Output file path (default: stdout). Use "-" to force stdout.
     /// With multiple input files, output becomes one YAML multi-document stream.
-    #[arg(short = 'o', long = "output")]
-    output: Option<PathBuf>,
+    /// Repeat to write the same output to several destinations (like tee).
+    #[arg(short = 'o', long = "output", value_hint = ValueHint::FilePath)]
+    output: Vec<PathBuf>,
+
+    /// Write each rendered template to DIR/<its file name> instead of one stream (for a `**`
+    /// --file-pattern, DIR/<its path below the pattern's directory>); with --chunk-size,
+    /// write numbered chunk files (chunk-001.yaml, ...) instead
+    #[arg(
+        long = "output-dir",
+        value_name = "DIR",
+        conflicts_with_all = ["output", "inplace"],
+        value_hint = ValueHint::DirPath
+    )]
+    output_dir: Option<PathBuf>,
+
+    /// Write JSON Lines: every rendered YAML document as one compact JSON object per line
+    #[arg(
+        long = "output-jsonl",
+        visible_alias = "output-json-lines",
+        default_value_t = false,
+        conflicts_with_all = ["output_dir", "inplace"]
+    )]
+    output_jsonl: bool,
+
+    /// Output format; json parses every rendered YAML document and writes it as JSON
+    /// (several documents become a JSON array)
+    #[arg(
+        long = "output-format",
+        value_enum,
+        default_value_t = OutputFormat::Yaml,
+        conflicts_with_all = ["output_jsonl", "output_dir", "inplace"]
+    )]
+    output_format: OutputFormat,
+
+    /// With --output-dir: put at most N rendered documents into each chunk file, in input order
+    #[arg(
+        long = "chunk-size",
+        value_name = "N",
+        requires = "output_dir",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    chunk_size: Option<u64>,
+
+    /// Strip trailing newlines from the written output
+    #[arg(
+        long = "no-trailing-newline",
+        default_value_t = false,
+        conflicts_with = "ensure_trailing_newline"
+    )]
+    no_trailing_newline: bool,
+
+    /// End the written output with exactly one newline
+    #[arg(long = "ensure-trailing-newline", default_value_t = false)]
+    ensure_trailing_newline: bool,
+
+    /// Encode the whole written output, e.g. base64 for a cloud-init write_files content field
+    #[arg(
+        long = "encode-output",
+        value_enum,
+        value_name = "ENCODING",
+        conflicts_with_all = ["eval", "inplace"]
+    )]
+    encode_output: Option<OutputEncoding>,
+
+    /// Character encoding of the written output; UTF-16 gets a byte order mark. Characters
+    /// the encoding cannot represent are an error
+    #[arg(
+        long = "output-encoding",
+        value_enum,
+        value_name = "CHARSET",
+        default_value = "utf8",
+        conflicts_with = "inplace"
+    )]
+    output_encoding: Charset,
+
+    /// Character encoding of the input templates; a UTF-16 byte order mark is skipped.
+    /// Bytes that are invalid in the encoding are an error
+    #[arg(
+        long = "input-encoding",
+        value_enum,
+        value_name = "CHARSET",
+        default_value = "utf8",
+        conflicts_with = "inplace"
+    )]
+    input_encoding: Charset,
+
+    /// With --encode-output base64: wrap the encoded text at N columns (0 = one line)
+    #[arg(
+        long = "b64-wrap",
+        value_name = "N",
+        default_value_t = 0,
+        requires = "encode_output"
+    )]
+    b64_wrap: usize,
+
+    /// Line written between documents in multi-file YAML output
+    #[arg(
+        long = "doc-separator",
+        value_name = "LINE",
+        default_value = "---",
+        value_parser = parse_doc_separator,
+        allow_hyphen_values = true
+    )]
+    doc_separator: String,
+
+    /// In multi-file YAML output, also write the separator before the first document
+    #[arg(long = "leading-separator", default_value_t = false)]
+    leading_separator: bool,
+
+    /// Write each rendered file back over its template instead of to --output.
+    /// Refuses files that are not tracked and unmodified in git unless --force is given.
+    #[arg(
+        long = "inplace",
+        default_value_t = false,
+        conflicts_with_all = ["output", "eval"]
+    )]
+    inplace: bool,
+
+    /// With --inplace: keep each original file as <file>.bak
+    #[arg(long = "backup", default_value_t = false, requires = "inplace")]
+    backup: bool,
 
-    /// Show each placeholder replacement while rendering
-    #[arg(short = 'v', long = "verbose", default_value_t = false)]
-    verbose: bool,
+    /// Resolve relative --values-file and --output paths against this directory
+    #[arg(long = "base-dir", value_name = "DIR", value_hint = ValueHint::DirPath)]
+    base_dir: Option<PathBuf>,
+
+    /// Resolve relative --values-file and --output paths against the template's directory
+    /// Only valid with a single --file other than -; use --base-dir for --file-pattern.
+    #[arg(
+        long = "relative-to-template",
+        default_value_t = false,
+        conflicts_with = "base_dir"
+    )]
+    relative_to_template: bool,
+
+    /// Statically check templates (braces, placeholder syntax, duplicate YAML keys) without values, then exit
+    #[arg(long = "lint", default_value_t = false)]
+    lint: bool,
+
+    /// Print the effective configuration (inputs, resolved paths, every option and its source) to stderr before rendering
+    #[arg(
+        long = "show-config",
+        visible_alias = "show-defaults",
+        default_value_t = false
+    )]
+    show_config: bool,
+
+    /// Print details to stderr; repeat for more: -v placeholder replacements, -vv also value
+    /// sources and discovered files, -vvv also every placeholder match. --verbose=N sets the level
+    #[arg(short = 'v', long = "verbose", action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Suppress warnings and informational messages on stderr; errors are still printed
+    #[arg(
+        short = 'q',
+        long = "quiet",
+        default_value_t = false,
+        conflicts_with = "verbose"
+    )]
+    quiet: bool,
+
+    /// Exit non-zero after the run if any warning was emitted (also counts warnings hidden by --quiet)
+    #[arg(long = "fail-on-warning", alias = "Werror", default_value_t = false)]
+    fail_on_warning: bool,
+
+    /// List values file keys that no template references (also with --verbose); never fails the run
+    #[arg(long = "warn-unused", default_value_t = false)]
+    warn_unused: bool,
 
     /// Ask questions for missing placeholders, then write/update the values file first
     /// Env placeholders are stored under environment.<VAR>.
@@ -66,1088 +277,1898 @@ struct Args {
     #[arg(long = "create-values-file", default_value_t = false)]
     create_values_file: bool,
 
-    /// With --create-values-file: ask for all keys, even if already set
+    /// Has two meanings: with --create-values-file, ask for all keys even if already set;
+    /// with --inplace, overwrite files even if they are untracked or modified in git (both apply
+    /// when the two are combined)
     #[arg(long = "force", default_value_t = false)]
     force: bool,
 
+    /// With --force: do not re-prompt keys whose current value is an empty string
+    #[arg(
+        long = "skip-empty",
+        alias = "prompt-skip-empty-default",
+        default_value_t = false,
+        requires = "force"
+    )]
+    skip_empty: bool,
+
+    /// With --create-values-file: remove this dotted key from the values file (repeatable)
+    #[arg(long = "values-delete", value_name = "KEY")]
+    values_delete: Vec<String>,
+
+    /// With --create-values-file: ask twice for keys matching this glob, e.g. 'environment.*PASSWORD*' (repeatable)
+    #[arg(long = "prompt-confirm", value_name = "GLOB")]
+    prompt_confirm: Vec<String>,
+
+    /// With --create-values-file: read a multi-line value, ended by a line containing only
+    /// EOF, for keys matching this glob, e.g. 'environment.*_KEY' (repeatable)
+    #[arg(long = "prompt-multiline", value_name = "GLOB")]
+    prompt_multiline: Vec<String>,
+
+    /// With --create-values-file: edit the value in $EDITOR, pre-filled with the default, for
+    /// keys matching this glob, e.g. 'environment.*_CONFIG' (repeatable)
+    #[arg(long = "prompt-editor", value_name = "GLOB")]
+    prompt_editor: Vec<String>,
+
+    /// With --create-values-file: YAML mapping of section name to key globs; prompts are grouped under section headers
+    #[arg(long = "prompt-description-file", value_name = "PATH")]
+    prompt_description_file: Option<PathBuf>,
+
+    /// With --create-values-file: run the prompts and render with the answers, but print the
+    /// values file that would be written to stderr instead of writing it
+    #[arg(
+        long = "dry-run-values",
+        alias = "no-write",
+        default_value_t = false,
+        conflicts_with = "values_by_name"
+    )]
+    dry_run_values: bool,
+
+    /// Resolve every placeholder and print its source (os-env, values-file, prompt, ... or
+    /// missing) per values file to stdout, then exit without rendering or writing output
+    #[arg(long = "dry-run", default_value_t = false)]
+    dry_run: bool,
+
+    /// After a successful render, write each env placeholder value that came from the OS
+    /// environment or --override into environment.<VAR> of the values file
+    #[arg(
+        long = "persist-env",
+        visible_alias = "render-env-into-values",
+        default_value_t = false,
+        conflicts_with_all = ["select", "select_index", "values_base64", "values_env_base64"]
+    )]
+    persist_env: bool,
+
+    /// With --persist-env: also write values of keys matching --secret-key
+    #[arg(
+        long = "persist-secrets",
+        default_value_t = false,
+        requires = "persist_env"
+    )]
+    persist_secrets: bool,
+
+    /// With --create-values-file: YAML file of suggested prompt defaults for keys the values
+    /// file does not set yet (nested, or flat dotted keys such as `db.host`)
+    #[arg(long = "prompt-defaults", value_name = "PATH", value_hint = ValueHint::FilePath)]
+    prompt_defaults: Option<PathBuf>,
+
+    /// With --create-values-file: remember prompted answers in this YAML file and offer them
+    /// as defaults in later runs, after the values file and before --prompt-defaults
+    #[arg(
+        long = "prompt-history",
+        visible_alias = "history",
+        value_name = "PATH",
+        value_hint = ValueHint::FilePath
+    )]
+    prompt_history: Option<PathBuf>,
+
+    /// With --create-values-file: never prompt; fill defaults (or empty strings) instead
+    #[arg(long = "no-interactive", default_value_t = false)]
+    no_interactive: bool,
+
+    /// Report .Values paths whose parent is not a mapping (e.g. a scalar) separately from missing keys
+    #[arg(long = "strict-unknown-keys", default_value_t = false)]
+    strict_unknown_keys: bool,
+
+    /// Pin one placeholder to VALUE, ahead of every other source: `NAME=VALUE` for an env
+    /// placeholder, `.Values.path=VALUE` for a values path (repeatable; the last one wins)
+    #[arg(long = "override", value_name = "KEY=VALUE", value_parser = parse_override)]
+    overrides: Vec<(String, String)>,
+
+    /// Set the values path PATH to VALUE, like `helm --set` (repeatable). Applies to
+    /// `.Values.PATH` and to the env placeholder PATH maps to (`image.tag` -> IMAGE_TAG).
+    /// Env placeholders in VALUE are expanded: `--set 'image.repo=$REGISTRY/app'`
+    #[arg(long = "set", value_name = "PATH=VALUE", value_parser = parse_set)]
+    sets: Vec<(String, String)>,
+
+    /// Fail unless this dotted key is set to a non-empty value, whether or not a template uses it (repeatable)
+    #[arg(
+        long = "require-key",
+        alias = "values-required-key",
+        value_name = "KEY"
+    )]
+    require_key: Vec<String>,
+
+    /// With --require-key: accept required keys that are present but empty
+    #[arg(
+        long = "allow-empty-required",
+        default_value_t = false,
+        requires = "require_key"
+    )]
+    allow_empty_required: bool,
+
     /// Do not read OS environment variables; use values file key environment.<VAR> for env placeholders
     #[arg(long = "value-file-only", default_value_t = false)]
     value_file_only: bool,
 
+    /// Never resolve env placeholders from the OS environment; values that would only come
+    /// from there are reported as missing (values file, --override and prompts still apply)
+    #[arg(long = "deny-os-env-leak", default_value_t = false)]
+    deny_os_env_leak: bool,
+
+    /// Match env placeholder names ignoring case, in the OS environment and in the values
+    /// file's `environment` section (`${database_url}` uses `DATABASE_URL`)
+    #[arg(long = "env-ignore-case", default_value_t = false)]
+    env_ignore_case: bool,
+
+    /// Read env placeholder values from a dotenv file of KEY=VALUE lines; they win over the
+    /// OS environment but not over environment.<VAR> in the values file
+    #[arg(
+        long = "env-file",
+        value_name = "PATH",
+        value_hint = ValueHint::FilePath,
+        conflicts_with = "value_file_only"
+    )]
+    env_file: Option<PathBuf>,
+
     /// Print prompted values as bash export statements (for use with eval "$( ... )")
     #[arg(long = "eval", default_value_t = false)]
     eval: bool,
 
+    /// Write resolved env placeholders (and prompted values) as a sourceable export script
+    /// to PATH, created with owner-only permissions; rendering output is unaffected
+    #[arg(long = "env-export-file", value_name = "PATH", value_hint = ValueHint::FilePath)]
+    env_export_file: Option<PathBuf>,
+
+    /// With --eval or --env-export-file: prepend PREFIX to every exported name
+    /// (e.g. TPLENV_ turns IMAGE into TPLENV_IMAGE)
+    #[arg(
+        long = "export-prefix",
+        value_name = "PREFIX",
+        value_parser = parse_export_prefix,
+        requires = "exports"
+    )]
+    export_prefix: Option<String>,
+
     /// Preserve indentation for multiline replacement values
     #[arg(long = "indent", default_value_t = false)]
     indent: bool,
 
-    /// Show template context before each --create-values-file prompt
-    #[arg(long = "context", default_value_t = false)]
-    context: bool,
+    /// With --indent: never emit `|` block scalars; indent continuation lines to the placeholder's line instead
+    #[arg(long = "no-block-scalar", default_value_t = false, requires = "indent")]
+    no_block_scalar: bool,
 
-    /// Install shell completion (auto, bash, or zsh)
+    /// With --indent: what to do with a multi-line value inside a folded (`>`) block scalar,
+    /// where line breaks would be folded into spaces
     #[arg(
-        long = "install-completion",
-        num_args = 0..=1,
-        default_missing_value = "auto",
-        value_name = "SHELL"
+        long = "folded-policy",
+        value_enum,
+        value_name = "POLICY",
+        default_value = "warn",
+        requires = "indent"
     )]
-    install_completion: Option<String>,
-}
+    folded_policy: FoldedPolicy,
 
-fn main() {
-    if let Err(e) = run() {
-        eprintln!("error: {e:#}");
-        std::process::exit(1);
-    }
-}
+    /// With --indent: fail instead of writing a multi-line value where YAML cannot hold it
+    /// (inside a flow collection, in key position, or sharing its line with other text)
+    #[arg(
+        long = "strict-multiline",
+        default_value_t = false,
+        requires = "indent"
+    )]
+    strict_multiline: bool,
 
-fn run() -> Result<()> {
-    let args = Args::parse();
+    /// Keep values-file strings strings: a whole-value placeholder (`zip: {{ .Values.zip }}`) whose
+    /// string value would read back as a number, bool or null is written double-quoted
+    #[arg(long = "preserve-quotes", default_value_t = false)]
+    preserve_quotes: bool,
 
-    if let Some(shell_arg) = args.install_completion.as_deref() {
-        install_completion(shell_arg)?;
-        return Ok(());
-    }
+    /// Write a whole-value placeholder (`key: {{ .Values.x }}`) whose value comes from the values
+    /// file as YAML of the same type: strings are quoted where needed, mappings and lists nest
+    #[arg(long = "typed-values", default_value_t = false)]
+    typed_values: bool,
 
-    let input_files = discover_input_files(args.file.as_ref(), args.file_pattern.as_deref())?;
-    if input_files.len() > 1 {
-        ensure_all_yaml_files(&input_files)?;
-    }
+    /// Substitute placeholders inside resolved values too (`url: "https://{{ .Values.host }}"`),
+    /// repeating until none are left
+    #[arg(long = "expand-values", default_value_t = false)]
+    expand_values: bool,
 
-    let mut templates: Vec<(PathBuf, String)> = Vec::new();
-    for file in &input_files {
-        let input = fs::read_to_string(file)
-            .with_context(|| format!("failed to read file: {}", file.display()))?;
-        templates.push((file.clone(), input));
-    }
+    /// Show template context before each --create-values-file prompt
+    #[arg(long = "context", default_value_t = false)]
+    context: bool,
 
-    // One regex to match all supported placeholders:
-    //   {{ .Values.namespace }}               -> capture group 1 (path)
-    //   {{NAMESPACE}}                         -> capture group 2
-    //   ${NAMESPACE}                          -> capture group 3
-    //   $NAMESPACE                            -> capture group 4
-    //
-    // Values paths are dot-separated identifiers: foo.bar.baz
-    let re = placeholder_regex()?;
-    let (env_vars, values_paths) = collect_placeholders_all(&templates, &re);
-    let prompt_contexts = collect_prompt_contexts(&templates, &re, args.context);
-    let prompt_order = collect_prompt_order(&templates, &re);
+    /// Show N lines before and after the placeholder line in prompts (overrides --context)
+    #[arg(long = "context-lines", value_name = "N")]
+    context_lines: Option<usize>,
 
-    if args.force && !args.create_values_file {
-        bail!("--force can only be used together with --create-values-file");
-    }
-    if args.eval && !args.create_values_file {
-        bail!("--eval can only be used together with --create-values-file");
-    }
+    /// Fail if a template uses a placeholder not listed in PATH (one env name, `.Values.path`
+    /// or glob per line; `#` starts a comment)
+    #[arg(
+        long = "allowed-placeholders",
+        alias = "placeholder-allowlist",
+        value_name = "PATH",
+        value_hint = ValueHint::FilePath
+    )]
+    allowed_placeholders: Option<PathBuf>,
 
-    let include_environment_vars_in_prompts = args.create_values_file;
-    let existing_os_env_vars: BTreeSet<String> = if args.value_file_only {
-        BTreeSet::new()
-    } else {
-        env_vars
-            .iter()
-            .filter(|v| env::var_os(v).is_some())
-            .cloned()
-            .collect()
-    };
-    let existing_os_env_values: HashMap<String, String> = if args.value_file_only {
-        HashMap::new()
-    } else {
-        env_vars
-            .iter()
-            .filter_map(|v| env::var_os(v).map(|os| (v.clone(), os.to_string_lossy().to_string())))
-            .collect()
-    };
-    let needs_values_prompt =
-        !values_paths.is_empty() || (include_environment_vars_in_prompts && !env_vars.is_empty());
-    let mut prompted_values: Vec<(String, String)> = Vec::new();
-    if args.create_values_file && needs_values_prompt {
-        let prompt_opts = PromptUpdateOptions {
-            include_environment_vars: include_environment_vars_in_prompts,
-            skip_existing_env_vars: &existing_os_env_vars,
-            existing_os_env_values: &existing_os_env_values,
-            prompt_contexts: &prompt_contexts,
-            prompt_order: &prompt_order,
-            force: args.force,
-            verbose: args.verbose,
-        };
-        prompted_values =
-            prompt_and_update_values_file(&args.values, &values_paths, &env_vars, &prompt_opts)?;
-    }
-    let prompted_env_map = prompted_environment_values(&prompted_values);
+    /// Fail if none of the templates contains a placeholder (a likely wrong path or syntax)
+    #[arg(long = "fail-if-no-placeholders", default_value_t = false)]
+    fail_if_no_placeholders: bool,
 
-    // Load values YAML:
-    // - required when .Values placeholders exist
-    // - optional (if exists) for env placeholder precedence via environment.<VAR>
-    let values_yaml: Option<YamlValue> = if !values_paths.is_empty() {
-        load_values_yaml(&args.values)?
-    } else if !env_vars.is_empty() {
-        Some(load_values_yaml_if_exists(&args.values)?)
-    } else {
-        None
-    };
+    /// Fail on near-miss placeholders such as `{{ .Values.x }` or `${NAME` (a `{{` without
+    /// a closing `}}`, or `${` without a closing `}`, on the same line)
+    #[arg(long = "strict-placeholders", default_value_t = false)]
+    strict_placeholders: bool,
 
-    // Resolve placeholders
-    let mut missing_values: Vec<String> = Vec::new();
-    let mut missing_env: Vec<String> = Vec::new();
-    let mut env_map: HashMap<String, String> = HashMap::new();
-    if args.value_file_only {
-        if !env_vars.is_empty() {
-            let yaml = values_yaml
-                .as_ref()
-                .expect("values_yaml must be loaded in --value-file-only mode");
-            let (resolved, missing_paths) = resolve_env_from_values_file(&env_vars, yaml)?;
-            if args.verbose {
-                for (name, val) in &resolved {
-                    if let Some(os) = env::var_os(name) {
-                        let env_val = os.to_string_lossy().to_string();
-                        if env_val != *val {
-                            eprintln!(
-                                "warning: env {name} differs from values file environment.{name}; using values file value"
-                            );
-                        }
-                    }
-                }
-            }
-            env_map = resolved;
+    /// Fail if an env placeholder name does not match --env-name-pattern (default: UPPER_CASE)
+    #[arg(long = "strict-env-names", default_value_t = false)]
+    strict_env_names: bool,
 
-            // Treat missing env substitutions as missing values file keys.
-            missing_env.clear();
-            for p in missing_paths {
-                missing_values.push(p);
-            }
-        }
-    } else {
-        for v in &env_vars {
-            let os_val = env::var_os(v).map(|os| os.to_string_lossy().to_string());
-
-            if let Some(yaml) = values_yaml.as_ref() {
-                let path = env_var_values_path(v);
-                if let Some(val) = lookup_yaml_path(yaml, &path) {
-                    let values_val = yaml_value_to_string(val)?;
-                    if args.verbose
-                        && let Some(env_val) = os_val.as_ref()
-                        && env_val != &values_val
-                    {
-                        eprintln!(
-                            "warning: env {v} differs from values file {path}; using values file value"
-                        );
-                    }
-                    env_map.insert(v.clone(), values_val);
-                    continue;
-                }
-            }
-            if let Some(val) = prompted_env_map.get(v) {
-                env_map.insert(v.clone(), val.clone());
-                continue;
-            }
-            if let Some(val) = os_val {
-                env_map.insert(v.clone(), val);
-            } else {
-                missing_env.push(v.clone());
-            }
-        }
-    }
+    /// Fail unless every rendered document's `kind` is one of these, e.g. 'ConfigMap,Deployment'
+    #[arg(
+        long = "expect-kind",
+        visible_alias = "assert-yaml-kind",
+        value_name = "KINDS",
+        value_delimiter = ','
+    )]
+    expect_kind: Vec<String>,
 
-    // Resolve values paths
-    let mut values_map: HashMap<String, String> = HashMap::new();
-    for p in &values_paths {
-        let yaml = values_yaml
-            .as_ref()
-            .expect("values_yaml must be loaded if values_paths is non-empty");
-        match lookup_yaml_path(yaml, p) {
-            Some(v) => {
-                let s = yaml_value_to_string(v)?;
-                values_map.insert(p.clone(), s);
-            }
-            None => missing_values.push(p.clone()),
-        }
-    }
+    /// With --expect-kind: accept rendered documents that have no `kind` field
+    #[arg(
+        long = "allow-kindless",
+        default_value_t = false,
+        requires = "expect_kind"
+    )]
+    allow_kindless: bool,
 
-    // If anything missing, print all missing and fail
-    if !missing_env.is_empty() || !missing_values.is_empty() {
-        if !missing_env.is_empty() {
-            eprintln!("Missing/undefined environment variables:");
-            for v in &missing_env {
-                eprintln!("- {v}");
-            }
-        }
-        if !missing_values.is_empty() {
-            eprintln!("Missing keys in values file ({}):", args.values.display());
-            for p in &missing_values {
-                if p.starts_with("environment.") {
-                    eprintln!("- {p}");
-                } else {
-                    eprintln!("- .Values.{p}");
-                }
-            }
-        }
-        bail!("not all placeholders could be resolved");
-    }
+    /// Fail if a rendered document repeats a key within one mapping (YAML keeps the last one)
+    #[arg(long = "fail-on-duplicate-keys", default_value_t = false)]
+    fail_on_duplicate_keys: bool,
 
-    // Render with logging (if verbose)
-    let mut rendered_outputs: Vec<(PathBuf, String)> = Vec::new();
-    for (path, input) in &templates {
-        let rendered = re.replace_all(input, |caps: &regex::Captures| {
-            let raw = if let Some(p) = caps.get(1) {
-                let key = p.as_str();
-                let val = values_map.get(key).cloned().unwrap_or_default();
-                if args.verbose {
-                    eprintln!("set .Values.{key} = {val}");
-                }
-                val
-            } else {
-                let key = extract_env_key(caps).unwrap_or("");
-                let val = env_map.get(key).cloned().unwrap_or_default();
-                if args.verbose {
-                    if args.value_file_only {
-                        eprintln!("set environment.{key} = {val}");
-                    } else {
-                        eprintln!("set env {key} = {val}");
-                    }
-                }
-                val
-            };
+    /// Fail before writing if a rendered document does not parse as YAML
+    #[arg(long = "validate", default_value_t = false)]
+    validate: bool,
 
-            if args.indent {
-                if let Some(m) = caps.get(0) {
-                    format_replacement_with_indent(&raw, input, m.start(), m.end())
-                } else {
-                    raw
-                }
-            } else {
-                raw
-            }
-        });
-        rendered_outputs.push((path.clone(), rendered.to_string()));
-    }
+    /// Show each substitution (line, token and value) on stderr, grouped by file
+    #[arg(long = "diff", default_value_t = false)]
+    diff: bool,
 
-    if args.eval {
-        // In eval mode, stdout should stay parseable as shell exports.
-        if args.output.is_some()
-            && args
-                .output
-                .as_ref()
-                .map(|p| p.to_string_lossy() == "-")
-                .unwrap_or(false)
-        {
-            bail!("with --eval, --output - is not supported");
-        }
-        if args.output.is_some() {
-            write_outputs(args.output.as_ref(), &rendered_outputs)?;
-        }
-        let script = render_eval_exports_with_env(&prompted_values, &env_map);
-        let mut out = io::stdout().lock();
-        out.write_all(script.as_bytes())?;
-    } else {
-        write_outputs(args.output.as_ref(), &rendered_outputs)?;
-    }
-    Ok(())
-}
+    /// With --strict-env-names: regex every env placeholder name must match in full
+    #[arg(
+        long = "env-name-pattern",
+        value_name = "REGEX",
+        default_value = DEFAULT_ENV_NAME_PATTERN,
+        requires = "strict_env_names"
+    )]
+    env_name_pattern: String,
 
-fn placeholder_regex() -> Result<Regex> {
-    Ok(Regex::new(
-        r"\{\{\s*(?:\.Values\.([A-Za-z0-9_]+(?:\.[A-Za-z0-9_]+)*)|([A-Za-z_][A-Za-z0-9_]*))\s*\}\}|\$\{([A-Za-z_][A-Za-z0-9_]*)\}|\$([A-Za-z_][A-Za-z0-9_]*)",
-    )?)
-}
+    /// Extra placeholder syntax to recognize; perl-env adds $ENV{VARNAME} as an env placeholder
+    #[arg(
+        long = "placeholder-style",
+        value_enum,
+        default_value_t = PlaceholderStyle::Standard
+    )]
+    placeholder_style: PlaceholderStyle,
 
-fn collect_placeholders(input: &str, re: &Regex) -> (BTreeSet<String>, BTreeSet<String>) {
-    let mut env_vars: BTreeSet<String> = BTreeSet::new();
-    let mut values_paths: BTreeSet<String> = BTreeSet::new();
+    /// Treat bare `{{ name }}` placeholders that are not UPPER_CASE (lowercase, mixed case,
+    /// dotted or dashed) as .Values paths: `{{ db.host }}` is `{{ .Values.db.host }}`
+    #[arg(long = "bare-values", default_value_t = false)]
+    bare_values: bool,
 
-    for cap in re.captures_iter(input) {
-        if let Some(p) = cap.get(1) {
-            values_paths.insert(p.as_str().to_string());
-        } else if let Some(v) = extract_env_key(&cap) {
-            env_vars.insert(v.to_string());
-        }
-    }
+    /// Template language; json JSON-escapes values, disables --indent and joins multiple files
+    /// into a JSON array. Default: json if all input files end in .json, else yaml.
+    #[arg(long = "template-format", value_enum, value_name = "FORMAT")]
+    template_format: Option<TemplateFormat>,
 
-    (env_vars, values_paths)
-}
+    /// Separator between keys in {{ .Values.* }} paths, e.g. / for {{ .Values.app.kubernetes.io/name }}
+    /// With a separator other than ".", dots inside a path are part of the key name.
+    #[arg(
+        long = "path-sep",
+        value_name = "CHAR",
+        default_value_t = '.',
+        value_parser = parse_path_sep
+    )]
+    path_sep: char,
 
-fn extract_env_key<'a>(caps: &'a regex::Captures<'a>) -> Option<&'a str> {
-    caps.get(2)
-        .or_else(|| caps.get(3))
-        .or_else(|| caps.get(4))
-        .map(|m| m.as_str())
-}
+    /// Delimiters of {{ }} placeholders and {{ if }} markers as "OPEN CLOSE", e.g. '[[ ]]'
+    /// for [[ .Values.x ]] and [[VAR]]; $VAR and ${VAR} are not affected
+    #[arg(
+        long = "delimiters",
+        value_name = "OPEN CLOSE",
+        default_value = "{{ }}",
+        value_parser = parse_delimiters
+    )]
+    delimiters: Delimiters,
 
-fn collect_placeholders_all(
-    templates: &[(PathBuf, String)],
-    re: &Regex,
-) -> (BTreeSet<String>, BTreeSet<String>) {
-    let mut env_vars: BTreeSet<String> = BTreeSet::new();
-    let mut values_paths: BTreeSet<String> = BTreeSet::new();
+    /// How .Values path segments match values file keys; insensitive ignores case, '_' and '-'
+    #[arg(
+        long = "values-key-case",
+        alias = "values-key-transform",
+        value_enum,
+        default_value_t = KeyCase::Exact
+    )]
+    values_key_case: KeyCase,
 
-    for (_, input) in templates {
-        let (env, values) = collect_placeholders(input, re);
-        env_vars.extend(env);
-        values_paths.extend(values);
-    }
+    /// How to resolve env placeholders set both in the OS env and in environment.<VAR>
+    /// prefer-longer is a heuristic for multi-line values (e.g. certificates) only.
+    #[arg(
+        long = "conflict-policy",
+        value_enum,
+        default_value_t = ConflictPolicy::ValuesFile
+    )]
+    conflict_policy: ConflictPolicy,
 
-    (env_vars, values_paths)
-}
+    /// Comma-separated sources to consult, in order, for each env placeholder; the first
+    /// one that has a value wins. Sources left out are not consulted.
+    #[arg(
+        long = "resolution-order",
+        value_enum,
+        value_delimiter = ',',
+        value_name = "SOURCES",
+        default_value = "values,prompt,env-file,os-env,default",
+        conflicts_with = "value_file_only"
+    )]
+    resolution_order: Vec<EnvSource>,
 
-fn discover_input_files(
-    file: Option<&PathBuf>,
-    file_pattern: Option<&str>,
-) -> Result<Vec<PathBuf>> {
-    match (file, file_pattern) {
-        (Some(path), None) => Ok(vec![path.clone()]),
-        (None, Some(pattern)) => find_files_by_pattern(pattern),
-        (Some(_), Some(_)) => bail!("use only one of --file or --file-pattern"),
-        (None, None) => bail!("one of --file or --file-pattern is required"),
-    }
-}
+    /// For a values file that is a YAML list, use the one element whose FIELD equals VALUE, e.g. 'name=prod'
+    #[arg(
+        long = "select",
+        value_name = "FIELD=VALUE",
+        conflicts_with = "create_values_file"
+    )]
+    select: Option<String>,
 
-fn find_files_by_pattern(pattern: &str) -> Result<Vec<PathBuf>> {
-    let pattern_path = Path::new(pattern);
-    let dir = match pattern_path.parent() {
-        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
-        _ => PathBuf::from("."),
-    };
-    let filename_pattern = pattern_path
-        .file_name()
-        .and_then(|s| s.to_str())
-        .ok_or_else(|| anyhow::anyhow!("invalid --file-pattern: {pattern}"))?;
-    let re = file_pattern_regex(filename_pattern)?;
-
-    let mut files = Vec::new();
-    for entry in
-        fs::read_dir(&dir).with_context(|| format!("failed to read dir: {}", dir.display()))?
-    {
-        let entry = entry?;
-        let file_type = entry.file_type()?;
-        if !file_type.is_file() {
-            continue;
-        }
+    /// For a values file that is a YAML list, use the element at INDEX (0-based)
+    #[arg(
+        long = "select-index",
+        value_name = "INDEX",
+        conflicts_with_all = ["select", "create_values_file"]
+    )]
+    select_index: Option<usize>,
 
-        let name = entry.file_name();
-        let name = name.to_string_lossy();
-        if re.is_match(&name) {
-            files.push(dir.join(name.as_ref()));
-        }
-    }
+    /// Second values file merged on top of --values-file (its keys win); never written by --create-values-file
+    /// Values taken from it are masked in --verbose output and --trace-file.
+    #[arg(long = "secrets-file", value_name = "PATH", value_hint = ValueHint::FilePath)]
+    secrets_file: Option<PathBuf>,
 
-    files.sort();
-    if files.is_empty() {
-        bail!("no files matched --file-pattern {pattern}");
-    }
-    Ok(files)
-}
+    /// Treat keys matching this glob as secrets, e.g. 'environment.*PASSWORD*' (repeatable):
+    /// masked in --verbose output and --trace-file, prompted for without echo, and a warning
+    /// if written to a path no .gitignore covers
+    #[arg(long = "secret-key", value_name = "GLOB")]
+    secret_key: Vec<String>,
 
-fn file_pattern_regex(pattern: &str) -> Result<Regex> {
-    let escaped = regex::escape(pattern);
-    let with_num = escaped.replace("<NUM>", "[0-9]+");
-    let final_pattern = with_num.replace(r"\*", ".*");
-    Ok(Regex::new(&format!("^{final_pattern}$"))?)
-}
+    /// Render `.Values` keys matching this glob as empty when the values file lacks them,
+    /// e.g. 'featureX.*', instead of failing (repeatable)
+    #[arg(
+        long = "optional-key",
+        visible_alias = "ignore-missing-values-file-keys-for",
+        value_name = "GLOB"
+    )]
+    optional_key: Vec<String>,
 
-fn load_values_yaml(path: &Path) -> Result<Option<YamlValue>> {
-    // If values placeholders are present, we require the file to exist & parse.
-    let text = fs::read_to_string(path)
-        .with_context(|| format!("failed to read values file: {}", path.display()))?;
-    let yaml: YamlValue = serde_yaml::from_str(&text)
-        .with_context(|| format!("failed to parse {}", path.display()))?;
-    Ok(Some(yaml))
-}
+    /// Values document as a base64-encoded YAML blob, instead of --values-file
+    #[arg(
+        long = "values-base64",
+        value_name = "BLOB",
+        conflicts_with_all = ["values", "values_by_name", "env_name", "create_values_file"]
+    )]
+    values_base64: Option<String>,
 
-fn load_values_yaml_if_exists(path: &Path) -> Result<YamlValue> {
-    if !path.exists() {
-        return Ok(YamlValue::Mapping(YamlMapping::new()));
-    }
+    /// Like --values-base64, but read the blob from the environment variable VAR
+    #[arg(
+        long = "values-env-base64",
+        value_name = "VAR",
+        conflicts_with_all = ["values", "values_by_name", "env_name", "create_values_file", "values_base64"]
+    )]
+    values_env_base64: Option<String>,
 
-    let text = fs::read_to_string(path)
-        .with_context(|| format!("failed to read values file: {}", path.display()))?;
-    let yaml: YamlValue = serde_yaml::from_str(&text)
-        .with_context(|| format!("failed to parse {}", path.display()))?;
-    Ok(yaml)
-}
+    /// RFC 7386 merge patch applied to the loaded values: mappings merge, null deletes a key,
+    /// anything else replaces. Never written by --create-values-file.
+    #[arg(long = "values-patch", value_name = "PATH", value_hint = ValueHint::FilePath)]
+    values_patch: Option<PathBuf>,
 
-fn prompt_and_update_values_file(
-    path: &Path,
-    values_paths: &BTreeSet<String>,
-    env_vars: &BTreeSet<String>,
-    opts: &PromptUpdateOptions<'_>,
-) -> Result<Vec<(String, String)>> {
-    let mut root = load_values_yaml_if_exists(path)?;
-    let mut prompted_values: Vec<(String, String)> = Vec::new();
-    let mut changed = false;
-
-    if opts.include_environment_vars && !opts.force {
-        for var in env_vars {
-            let path_key = env_var_values_path(var);
-            if lookup_yaml_path(&root, &path_key).is_none()
-                && let Some(val) = opts.existing_os_env_values.get(var)
-            {
-                set_yaml_path(&mut root, &path_key, YamlValue::String(val.clone()));
-                prompted_values.push((path_key, val.clone()));
-                changed = true;
-            }
-        }
-    }
+    /// Inline base values as YAML, e.g. '{replicas: 2, image: {tag: latest}}'
+    /// The values file (if any) is merged on top and wins for keys set in both.
+    #[arg(long = "values-default", value_name = "YAML")]
+    values_default: Option<String>,
 
-    let env_skip = if opts.force {
-        BTreeSet::new()
-    } else {
-        opts.skip_existing_env_vars.clone()
-    };
-    let all_prompt_paths = collect_prompt_paths(
-        values_paths,
-        env_vars,
-        opts.include_environment_vars,
-        &env_skip,
-    );
-    let mut prompt_paths: Vec<String> = if opts.force {
-        all_prompt_paths.iter().cloned().collect()
-    } else {
-        all_prompt_paths
-            .iter()
-            .filter(|p| lookup_yaml_path(&root, p).is_none())
-            .cloned()
-            .collect()
-    };
-    let rank: HashMap<&str, usize> = opts
-        .prompt_order
-        .iter()
-        .enumerate()
-        .map(|(i, k)| (k.as_str(), i))
-        .collect();
-    prompt_paths.sort_by_key(|k| rank.get(k.as_str()).copied().unwrap_or(usize::MAX));
+    /// Read base values from a template's front matter: a leading `---` block whose first line
+    /// is `# tplenv-values`. It is removed from the output and sits below --values-default.
+    #[arg(
+        long = "front-matter-values",
+        default_value_t = false,
+        conflicts_with = "inplace"
+    )]
+    front_matter_values: bool,
 
-    if prompt_paths.is_empty() {
-        if opts.verbose {
-            eprintln!("No values to prompt for in {}", path.display());
-        }
-        if !changed {
-            return Ok(prompted_values);
-        }
-    } else {
-        for p in prompt_paths {
-            let default_value = lookup_yaml_path(&root, &p).cloned();
-            let env_fallback = p
-                .strip_prefix("environment.")
-                .and_then(|name| opts.existing_os_env_values.get(name))
-                .cloned();
-            let default_text = default_value
-                .as_ref()
-                .map(yaml_value_to_string)
-                .transpose()?
-                .or(env_fallback);
-            let context = opts.prompt_contexts.get(&p).map(|s| s.as_str());
-            let chosen = prompt_for_yaml_key(&p, default_text.as_deref(), context)?;
-            let chosen_text = yaml_value_to_string(&chosen)?;
-            prompted_values.push((p.clone(), chosen_text));
-            set_yaml_path(&mut root, &p, chosen);
-            changed = true;
-        }
-    }
+    /// Environment overlay: use <ENV_DIR>/<NAME>.yaml as the values file, layered over
+    /// <ENV_DIR>/base.yaml, and set ENVIRONMENT=<NAME> for placeholders.
+    #[arg(
+        long = "env",
+        value_name = "NAME",
+        conflicts_with_all = ["values", "values_by_name"]
+    )]
+    env_name: Option<String>,
 
-    if let Some(parent) = path.parent()
-        && !parent.as_os_str().is_empty()
-    {
-        fs::create_dir_all(parent)
-            .with_context(|| format!("failed to create directory: {}", parent.display()))?;
-    }
+    /// With --env: directory holding base.yaml and <NAME>.yaml
+    #[arg(
+        long = "env-dir",
+        value_name = "DIR",
+        default_value = "values",
+        requires = "env_name"
+    )]
+    env_dir: PathBuf,
 
-    if changed {
-        let out = serde_yaml::to_string(&root)?;
-        fs::write(path, out)
-            .with_context(|| format!("failed to write values file: {}", path.display()))?;
-    }
-    Ok(prompted_values)
-}
+    /// With --env: base values file instead of <ENV_DIR>/base.yaml (must exist)
+    #[arg(long = "base-values", value_name = "PATH", requires = "env_name")]
+    base_values: Option<PathBuf>,
 
-struct PromptUpdateOptions<'a> {
-    include_environment_vars: bool,
-    skip_existing_env_vars: &'a BTreeSet<String>,
-    existing_os_env_values: &'a HashMap<String, String>,
-    prompt_contexts: &'a HashMap<String, String>,
-    prompt_order: &'a [String],
-    force: bool,
-    verbose: bool,
-}
+    /// With --env: a missing <NAME>.yaml is not an error
+    #[arg(long = "env-optional", default_value_t = false, requires = "env_name")]
+    env_optional: bool,
 
-fn collect_prompt_paths(
-    values_paths: &BTreeSet<String>,
-    env_vars: &BTreeSet<String>,
-    include_environment_vars: bool,
-    skip_existing_env_vars: &BTreeSet<String>,
-) -> BTreeSet<String> {
-    let mut all: BTreeSet<String> = values_paths.clone();
-    if include_environment_vars {
-        for var in env_vars {
-            if skip_existing_env_vars.contains(var) {
-                continue;
-            }
-            all.insert(env_var_values_path(var));
-        }
-    }
-    all
-}
+    /// Per-file values files, e.g. 'prod=prod-values.yaml,dev=dev-values.yaml'
+    /// Each template uses the file whose name matches --values-name-regex's capture;
+    /// templates without a match use --values-file.
+    #[arg(
+        long = "values-by-name",
+        value_name = "NAME=FILE,...",
+        conflicts_with = "create_values_file"
+    )]
+    values_by_name: Option<String>,
 
-fn collect_prompt_contexts(
-    templates: &[(PathBuf, String)],
-    re: &Regex,
-    extended_context: bool,
-) -> HashMap<String, String> {
-    let mut out = HashMap::new();
-    let include_file_header = templates.len() > 1;
-
-    for (path, input) in templates {
-        for cap in re.captures_iter(input) {
-            let key = if let Some(p) = cap.get(1) {
-                p.as_str().to_string()
-            } else if let Some(env) = extract_env_key(&cap) {
-                env_var_values_path(env)
-            } else {
-                continue;
-            };
+    /// With --values-by-name: regex whose first capture group picks the name from the template file name
+    #[arg(
+        long = "values-name-regex",
+        value_name = "REGEX",
+        default_value = DEFAULT_VALUES_NAME_REGEX,
+        requires = "values_by_name"
+    )]
+    values_name_regex: String,
 
-            if out.contains_key(&key) {
-                continue;
-            }
+    /// Without .Values placeholders, treat an unparseable values file as empty (with a warning)
+    #[arg(long = "tolerant-values", default_value_t = false)]
+    tolerant_values: bool,
 
-            let mut text = extract_prompt_context(input, &cap, re, &key, extended_context);
-            if include_file_header {
-                text = format!("[{}]\n{}", path.display(), text);
-            }
-            out.insert(key, text);
-        }
+    /// Extra HTTP header for an http(s) --values-file URL, e.g. 'Authorization: Bearer ...' (repeatable)
+    #[arg(long = "values-header", value_name = "HEADER")]
+    values_header: Vec<String>,
+
+    /// Timeout in seconds when fetching an http(s) --values-file URL
+    #[arg(long = "values-timeout", value_name = "SECONDS", default_value_t = 30)]
+    values_timeout: u64,
+
+    /// Print the effective values document (after all merges) to PATH, or stdout if omitted or "-"
+    #[arg(
+        long = "dump-values",
+        value_name = "PATH",
+        num_args = 0..=1,
+        default_missing_value = "-",
+        value_hint = ValueHint::FilePath
+    )]
+    dump_values: Option<PathBuf>,
+
+    /// With --dump-values: stop after dumping, without rendering or failing on missing keys
+    #[arg(
+        long = "dump-values-only",
+        default_value_t = false,
+        requires = "dump_values"
+    )]
+    dump_values_only: bool,
+
+    /// Compare two values files on the keys the templates reference and print the ones whose
+    /// values differ, without rendering
+    #[arg(
+        long = "compare-values",
+        value_names = ["A", "B"],
+        num_args = 2,
+        conflicts_with_all = ["create_values_file", "inplace", "lint"],
+        value_hint = ValueHint::FilePath
+    )]
+    compare_values: Vec<PathBuf>,
+
+    /// With --compare-values: list differing keys without showing their values
+    #[arg(
+        long = "compare-redact",
+        default_value_t = false,
+        requires = "compare_values"
+    )]
+    compare_redact: bool,
+
+    /// Explain where the final value of a dotted values key (e.g. db.password) comes from: each
+    /// values layer that sets it, in merge order, and the one that wins (repeatable)
+    #[arg(long = "explain-value", value_name = "KEY")]
+    explain_value: Vec<String>,
+
+    /// Write a values file with only the keys the templates reference (and environment.<VAR>
+    /// for env placeholders), holding their resolved values, to PATH ("-" for stdout)
+    #[arg(long = "emit-used-values", value_name = "PATH", value_hint = ValueHint::FilePath)]
+    emit_used_values: Option<PathBuf>,
+
+    /// Write a YAML trace of discovery, placeholders, resolution sources, and prompts to PATH
+    #[arg(long = "trace-file", value_name = "PATH", value_hint = ValueHint::FilePath)]
+    trace_file: Option<PathBuf>,
+
+    /// Replace resolved values and prompt answers with <redacted> in --trace-file
+    #[arg(long = "trace-redact", default_value_t = false)]
+    trace_redact: bool,
+
+    /// Format of --trace-file: yaml, json, or a markdown summary table for PR descriptions
+    #[arg(
+        long = "trace-format",
+        value_enum,
+        default_value_t = TraceFormat::Yaml,
+        alias = "report-format"
+    )]
+    trace_format: TraceFormat,
+
+    /// Render the templates N times in-process (for profiling); only the last result is written
+    #[arg(long = "repeat", value_name = "N", default_value_t = 1, hide = true)]
+    repeat: usize,
+
+    /// Print elapsed render time to stderr
+    #[arg(long = "time", default_value_t = false, hide = true)]
+    time: bool,
+
+    /// Refuse to read template or values files larger than this (e.g. 512K, 10M, 1G)
+    #[arg(
+        long = "max-file-size",
+        value_name = "BYTES",
+        default_value = "50M",
+        value_parser = parse_byte_size
+    )]
+    max_file_size: u64,
+
+    /// Fail if the input templates hold more than N placeholders in total
+    #[arg(
+        long = "max-placeholders",
+        value_name = "N",
+        default_value_t = DEFAULT_MAX_PLACEHOLDERS
+    )]
+    max_placeholders: usize,
+
+    /// Abort the whole run with an error if it has not finished after this many seconds
+    /// (0 = no limit). Once output writing has started it is allowed to finish.
+    #[arg(long = "timeout", value_name = "SECONDS", default_value_t = 0)]
+    timeout: u64,
+
+    /// Install shell completion (auto, bash, zsh, or fish)
+    #[arg(
+        long = "install-completion",
+        num_args = 0..=1,
+        default_missing_value = "auto",
+        value_name = "SHELL"
+    )]
+    install_completion: Option<String>,
+
+    /// With --install-completion: write the script into DIR (e.g. a package staging root)
+    /// instead of the home directory, without editing shell startup files
+    #[arg(
+        long = "completion-dir",
+        value_name = "DIR",
+        requires = "install_completion",
+        value_hint = ValueHint::DirPath
+    )]
+    completion_dir: Option<PathBuf>,
+
+    /// Print the shell completion script to stdout (auto, bash, zsh, or fish)
+    #[arg(
+        long = "print-completion",
+        visible_alias = "generate-completion",
+        value_name = "SHELL"
+    )]
+    print_completion: Option<String>,
+
+    /// Run the render steps listed in a YAML file in order; each step maps long flag names
+    /// to values (`file: app.yaml`, `indent: true`, `set: [a=1, b=2]`)
+    #[arg(
+        long = "pipeline",
+        visible_alias = "pipeline-file",
+        value_name = "PATH",
+        value_hint = ValueHint::FilePath
+    )]
+    pipeline: Option<PathBuf>,
+
+    /// With --pipeline: run the remaining steps after one fails (the run still fails)
+    #[arg(long = "keep-going", default_value_t = false, requires = "pipeline")]
+    keep_going: bool,
+}
+
+impl Args {
+    /// The values file lookups report against and --create-values-file writes to: the last
+    /// --values-file.
+    fn values_file(&self) -> &Path {
+        self.values.last().expect("--values-file has a default")
     }
 
-    out
+    /// The --values-file occurrences before the last, merged below it in order.
+    fn lower_values_files(&self) -> &[PathBuf] {
+        &self.values[..self.values.len() - 1]
+    }
 }
 
-fn collect_prompt_order(templates: &[(PathBuf, String)], re: &Regex) -> Vec<String> {
+/// Rewrites `--verbose=N` into N `--verbose` flags, since a counted flag takes no value.
+fn expand_verbose_level(argv: impl IntoIterator<Item = OsString>) -> Vec<OsString> {
     let mut out = Vec::new();
-    let mut seen = BTreeSet::new();
-
-    for (_, input) in templates {
-        for cap in re.captures_iter(input) {
-            let key = if let Some(p) = cap.get(1) {
-                p.as_str().to_string()
-            } else if let Some(env) = extract_env_key(&cap) {
-                env_var_values_path(env)
-            } else {
-                continue;
-            };
-
-            if seen.insert(key.clone()) {
-                out.push(key);
-            }
+    for arg in argv {
+        let level = arg
+            .to_str()
+            .and_then(|a| a.strip_prefix("--verbose="))
+            .and_then(|n| n.parse::<usize>().ok());
+        match level {
+            Some(n) => out.extend(std::iter::repeat_n(OsString::from("--verbose"), n)),
+            None => out.push(arg),
         }
     }
     out
 }
 
-fn extract_prompt_context(
-    input: &str,
-    caps: &regex::Captures,
-    re: &Regex,
-    key: &str,
-    extended_context: bool,
-) -> String {
-    let m = if let Some(m) = caps.get(0) {
-        m
-    } else {
-        return String::new();
-    };
-
-    let lines = line_ranges(input);
-    let line_idx = line_index_for_pos(&lines, m.start()).unwrap_or(0);
-    if !extended_context {
-        return trim_line_ending(&input[lines[line_idx].0..lines[line_idx].1]).to_string();
+fn main() {
+    if let Err(e) = run(expand_verbose_level(env::args_os())) {
+        eprintln!("error: {e:#}");
+        std::process::exit(1);
     }
+}
 
-    let (para_start, para_end) = paragraph_bounds(input, &lines, line_idx);
-    let para_text = &input[lines[para_start].0..lines[para_end].1];
-    let keys = collect_prompt_keys(para_text, re);
-    if keys.len() == 1 && keys.contains(key) {
-        return trim_surrounding_newlines(para_text).to_string();
-    }
+fn run(argv: Vec<OsString>) -> Result<()> {
+    let matches = Args::command().get_matches_from(argv);
+    let args = Args::from_arg_matches(&matches)?;
+    run_with_timeout(args, matches)
+}
 
-    if let Some((list_start, list_end)) =
-        list_entry_bounds(input, &lines, line_idx, para_start, para_end)
-    {
-        let text = &input[lines[list_start].0..lines[list_end].1];
-        return trim_surrounding_newlines(text).to_string();
-    }
+/// The --timeout error; a --pipeline stops at it even with --keep-going, because the timed-out
+/// step keeps running on its worker thread.
+#[derive(Debug)]
+struct TimedOut {
+    secs: u64,
+    phase: &'static str,
+}
 
-    trim_line_ending(&input[lines[line_idx].0..lines[line_idx].1]).to_string()
+impl std::fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "timed out after {}s while {} (--timeout)",
+            self.secs, self.phase
+        )
+    }
 }
 
-fn collect_prompt_keys(text: &str, re: &Regex) -> BTreeSet<String> {
-    let mut keys = BTreeSet::new();
-    for cap in re.captures_iter(text) {
-        if let Some(p) = cap.get(1) {
-            keys.insert(p.as_str().to_string());
-        } else if let Some(env) = extract_env_key(&cap) {
-            keys.insert(env_var_values_path(env));
+impl std::error::Error for TimedOut {}
+
+/// Runs one invocation (the command line or a --pipeline step) with its own -v level and
+/// warning count, on a worker thread if it has a --timeout.
+fn run_with_timeout(args: Args, matches: ArgMatches) -> Result<()> {
+    let timeout = Duration::from_secs(args.timeout);
+    let run_here = move || {
+        begin_run(args.verbose);
+        run_parsed(args, matches)
+    };
+    if timeout.is_zero() {
+        return run_here();
+    }
+
+    // --timeout: the run happens on a worker thread; when the deadline passes, the error is
+    // returned here and `main` exits, which ends the worker wherever it is.
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = tx.send(run_here());
+    });
+    match rx.recv_timeout(timeout) {
+        Ok(result) => result,
+        Err(mpsc::RecvTimeoutError::Timeout) if current_phase() == PHASE_WRITING => rx
+            .recv()
+            .unwrap_or_else(|_| bail!("the run ended without a result")),
+        Err(mpsc::RecvTimeoutError::Timeout) => Err(TimedOut {
+            secs: timeout.as_secs(),
+            phase: current_phase(),
         }
+        .into()),
+        Err(mpsc::RecvTimeoutError::Disconnected) => bail!("the run ended without a result"),
     }
-    keys
 }
 
-fn line_ranges(input: &str) -> Vec<(usize, usize)> {
-    let mut ranges = Vec::new();
-    let mut start = 0usize;
-    for (i, b) in input.bytes().enumerate() {
-        if b == b'\n' {
-            ranges.push((start, i + 1));
-            start = i + 1;
+/// --pipeline: runs each step as if its flags were given on the command line, in order, and
+/// prints a summary to stderr. Relative paths in steps are relative to the working directory.
+fn run_pipeline(path: &Path, keep_going: bool, quiet: bool) -> Result<()> {
+    let text = fs::read_to_string(path)
+        .with_context(|| format!("failed to read pipeline file: {}", path.display()))?;
+    let yaml: YamlValue = serde_yaml::from_str(&text)
+        .with_context(|| format!("failed to parse pipeline file: {}", path.display()))?;
+    let YamlValue::Sequence(steps) = yaml else {
+        bail!(
+            "pipeline file {} must be a YAML list of steps, got {}",
+            path.display(),
+            yaml_kind(&yaml)
+        );
+    };
+    let mut failed = Vec::new();
+    for (i, step) in steps.iter().enumerate() {
+        let name = step
+            .get("name")
+            .and_then(|n| yaml_value_to_string(n).ok())
+            .unwrap_or_else(|| format!("step {}", i + 1));
+        let result = pipeline_step_args(step).and_then(|argv| {
+            let matches = Args::command().try_get_matches_from(argv)?;
+            run_with_timeout(Args::from_arg_matches(&matches)?, matches)
+        });
+        match result {
+            Ok(()) if !quiet => eprintln!("pipeline: {name}: ok"),
+            Ok(()) => {}
+            Err(e) if keep_going && !e.is::<TimedOut>() => {
+                eprintln!("pipeline: {name}: failed: {e:#}");
+                failed.push(name);
+            }
+            Err(e) => return Err(e.context(format!("pipeline {name} failed"))),
         }
     }
-    if start < input.len() {
-        ranges.push((start, input.len()));
-    }
-    if ranges.is_empty() {
-        ranges.push((0, 0));
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} pipeline step(s) failed: {}",
+            failed.len(),
+            steps.len(),
+            failed.join(", ")
+        );
     }
-    ranges
-}
-
-fn line_index_for_pos(lines: &[(usize, usize)], pos: usize) -> Option<usize> {
-    lines.iter().position(|(s, e)| *s <= pos && pos < *e)
+    Ok(())
 }
 
-fn paragraph_bounds(input: &str, lines: &[(usize, usize)], line_idx: usize) -> (usize, usize) {
-    let mut start = line_idx;
-    while start > 0 {
-        let prev = trim_line_ending(&input[lines[start - 1].0..lines[start - 1].1]);
-        if prev.trim().is_empty() {
-            break;
+/// The command line of one --pipeline step: `key: value` becomes `--key value`, `true`
+/// a bare `--key` (`false` drops it), null a bare `--key`, and a list repeats the flag.
+fn pipeline_step_args(step: &YamlValue) -> Result<Vec<OsString>> {
+    let YamlValue::Mapping(map) = step else {
+        bail!(
+            "a pipeline step must be a mapping of flags, got {}",
+            yaml_kind(step)
+        );
+    };
+    let mut argv = vec![OsString::from("tplenv")];
+    for (key, value) in map {
+        let Some(key) = key.as_str() else {
+            bail!("pipeline step keys must be flag names");
+        };
+        if key == "name" {
+            continue;
         }
-        start -= 1;
-    }
-
-    let mut end = line_idx;
-    while end + 1 < lines.len() {
-        let next = trim_line_ending(&input[lines[end + 1].0..lines[end + 1].1]);
-        if next.trim().is_empty() {
-            break;
+        if matches!(key, "pipeline" | "keep-going") {
+            bail!("--{key} cannot be used inside a pipeline step");
+        }
+        let flag = format!("--{key}");
+        let items = match value {
+            YamlValue::Sequence(items) => items.iter().collect(),
+            other => vec![other],
+        };
+        for item in items {
+            match item {
+                YamlValue::Bool(false) => {}
+                YamlValue::Bool(true) | YamlValue::Null => argv.push(flag.clone().into()),
+                // `verbose: 2` is the level, like `--verbose=2` on the command line.
+                YamlValue::Number(n) if key == "verbose" => {
+                    let Some(level) = n.as_u64() else {
+                        bail!("verbose must be a non-negative level, got {n}");
+                    };
+                    argv.extend(std::iter::repeat_n(flag.clone().into(), level as usize));
+                }
+                other => {
+                    argv.push(flag.clone().into());
+                    argv.push(
+                        yaml_value_to_string(other)
+                            .with_context(|| format!("value of {key}"))?
+                            .into(),
+                    );
+                }
+            }
         }
-        end += 1;
     }
-    (start, end)
+    Ok(argv)
 }
 
-fn is_list_item_line(line: &str) -> bool {
-    let trimmed = line.trim_start();
-    if trimmed.starts_with("- ") {
-        return true;
+fn run_parsed(mut args: Args, matches: ArgMatches) -> Result<()> {
+    if let Some(shell_arg) = args.install_completion.as_deref() {
+        install_completion(shell_arg, args.completion_dir.as_deref(), args.quiet)?;
+        return Ok(());
+    }
+    if let Some(shell_arg) = args.print_completion.as_deref() {
+        let shell = resolve_completion_shell(shell_arg)?;
+        let mut out = io::stdout().lock();
+        out.write_all(completion_script(shell).as_bytes())?;
+        return Ok(());
+    }
+    if let Some(pipeline) = args.pipeline.as_deref() {
+        return run_pipeline(pipeline, args.keep_going, args.quiet);
     }
 
-    let mut chars = trimmed.chars().peekable();
-    let mut has_digit = false;
-    while let Some(c) = chars.peek().copied() {
-        if c.is_ascii_digit() {
-            has_digit = true;
-            chars.next();
-        } else {
-            break;
+    set_phase("discovering input files");
+    let discovery = DiscoveryOptions {
+        follow_symlinks: args.follow_symlinks,
+        verbose: args.verbose > 0,
+    };
+    let mut input_files =
+        discover_input_files(&args.file, args.file_pattern.as_deref(), &discovery)?;
+    for path in &input_files {
+        log_at(2, || format!("discovered {}", path.display()));
+    }
+    if args.inplace && input_files.iter().any(|p| is_stdin_template(p)) {
+        bail!("--inplace cannot write back to a template read from stdin (--file -)");
+    }
+    if !args.changed_files.is_empty() {
+        let changed = read_changed_files(&args.changed_files)?;
+        let matched = input_files.len();
+        input_files = filter_changed_files(input_files, &changed);
+        if !args.quiet {
+            eprintln!(
+                "--changed-files: processing {} of {matched} matched file(s), skipped {} unchanged",
+                input_files.len(),
+                matched - input_files.len()
+            );
+        }
+        if input_files.is_empty() {
+            return check_warnings(args.fail_on_warning);
         }
     }
-    if !has_digit {
-        return false;
+    let template_format = args
+        .template_format
+        .unwrap_or_else(|| detect_template_format(&input_files));
+    // JSON templates are joined into an array and skip the YAML multi-document checks.
+    let multi_yaml = input_files.len() > 1 && template_format == TemplateFormat::Yaml;
+    if multi_yaml && args.skip_non_yaml {
+        let skipped = skip_non_yaml_files(&mut input_files);
+        if !skipped.is_empty() {
+            warn(
+                args.quiet,
+                &format!("skipped {} non-YAML file(s):", skipped.len()),
+            );
+            if !args.quiet {
+                for path in &skipped {
+                    eprintln!("- {}", path.display());
+                }
+            }
+        }
+        if input_files.is_empty() {
+            bail!("no *.yaml or *.yml files left to render after --skip-non-yaml");
+        }
+    } else if multi_yaml {
+        ensure_all_yaml_files(&input_files)?;
     }
-    match chars.next() {
-        Some('.') | Some(')') => matches!(chars.next(), Some(' ')),
-        _ => false,
+    if args.list_files {
+        let mut out = io::stdout().lock();
+        for path in &input_files {
+            writeln!(out, "{}", path.display())?;
+        }
+        return check_warnings(args.fail_on_warning);
     }
-}
-
-fn leading_spaces(line: &str) -> usize {
-    line.chars().take_while(|c| *c == ' ').count()
-}
 
-fn list_entry_bounds(
-    input: &str,
-    lines: &[(usize, usize)],
-    line_idx: usize,
-    para_start: usize,
-    para_end: usize,
-) -> Option<(usize, usize)> {
-    let mut anchor = None;
-    for idx in (para_start..=line_idx).rev() {
-        let line = trim_line_ending(&input[lines[idx].0..lines[idx].1]);
-        if is_list_item_line(line) {
-            anchor = Some(idx);
-            break;
+    let base_dir = if args.relative_to_template {
+        if args.file.is_empty() {
+            bail!("--relative-to-template requires --file; use --base-dir with --file-pattern");
+        }
+        if args.file.len() > 1 {
+            bail!("--relative-to-template requires a single --file; use --base-dir instead");
+        }
+        if is_stdin_template(&input_files[0]) {
+            bail!(
+                "--relative-to-template cannot be used with --file - (stdin has no directory); use --base-dir instead"
+            );
+        }
+        Some(template_dir(&input_files[0]))
+    } else {
+        args.base_dir.clone()
+    };
+    // An explicit --base-values must exist; the default <ENV_DIR>/base.yaml is optional.
+    let base_values_required = args.base_values.is_some();
+    if let Some(name) = args.env_name.as_deref() {
+        args.values = vec![env_values_file(&args.env_dir, name)?];
+        if args.base_values.is_none() {
+            args.base_values = Some(args.env_dir.join("base.yaml"));
         }
     }
-    let anchor_idx = anchor?;
-    let anchor_line = trim_line_ending(&input[lines[anchor_idx].0..lines[anchor_idx].1]);
-    let anchor_indent = leading_spaces(anchor_line);
-
-    let mut end = para_end;
-    for idx in (anchor_idx + 1)..=para_end {
-        let line = trim_line_ending(&input[lines[idx].0..lines[idx].1]);
-        if line.trim().is_empty() {
-            end = idx.saturating_sub(1);
-            break;
+    if let Some(base) = base_dir.as_deref() {
+        for path in &mut args.values {
+            *path = resolve_against_base(path, base);
+        }
+        args.base_values = args.base_values.map(|p| resolve_against_base(&p, base));
+        args.secrets_file = args.secrets_file.map(|p| resolve_against_base(&p, base));
+        args.values_patch = args.values_patch.map(|p| resolve_against_base(&p, base));
+        args.env_file = args.env_file.map(|p| resolve_against_base(&p, base));
+        args.prompt_defaults = args.prompt_defaults.map(|p| resolve_against_base(&p, base));
+        args.prompt_history = args.prompt_history.map(|p| resolve_against_base(&p, base));
+        for path in &mut args.compare_values {
+            *path = resolve_against_base(path, base);
         }
-        if is_list_item_line(line) && leading_spaces(line) <= anchor_indent {
-            end = idx.saturating_sub(1);
-            break;
+        for path in &mut args.output {
+            *path = resolve_against_base(path, base);
         }
+        args.output_dir = args.output_dir.map(|p| resolve_against_base(&p, base));
+        args.env_export_file = args.env_export_file.map(|p| resolve_against_base(&p, base));
+    }
+    // There is no values file to read or name in messages; say where the values came from.
+    if args.values_base64.is_some() {
+        args.values = vec![PathBuf::from("--values-base64")];
+    } else if let Some(var) = args.values_env_base64.as_deref() {
+        args.values = vec![PathBuf::from(format!("--values-env-base64 {var}"))];
+    }
+    if let Some(name) = args.env_name.as_deref()
+        && !args.env_optional
+        && !args.create_values_file
+        && !args.values_file().exists()
+    {
+        bail!(
+            "values file for --env {name} not found: {} (use --env-optional to render with base values only)",
+            args.values_file().display()
+        );
     }
-    Some((anchor_idx, end))
-}
-
-fn trim_line_ending(line: &str) -> &str {
-    line.trim_end_matches(['\r', '\n'])
-}
 
-fn trim_surrounding_newlines(s: &str) -> &str {
-    s.trim_matches(['\r', '\n'])
-}
-
-fn env_var_values_path(var: &str) -> String {
-    format!("environment.{var}")
-}
-
-fn values_key_to_env_var(values_key: &str) -> String {
-    let no_prefix = values_key
-        .strip_prefix("environment.")
-        .unwrap_or(values_key);
-    no_prefix.replace('.', "_").to_uppercase()
-}
+    if args.show_config {
+        eprint!("{}", render_effective_config(&args, &matches, &input_files));
+    }
 
-fn shell_escape_single_quoted(value: &str) -> String {
-    value.replace('\'', "'\"'\"'")
-}
+    set_phase("reading templates");
+    let mut templates: Vec<(PathBuf, String)> = Vec::new();
+    let mut front_matter: Vec<(PathBuf, YamlValue)> = Vec::new();
+    for file in &input_files {
+        let bytes = if is_stdin_template(file) {
+            read_template_from(&mut io::stdin().lock(), args.max_file_size)?
+        } else {
+            ensure_file_size_within(file, args.max_file_size)?;
+            fs::read(file).with_context(|| format!("failed to read file: {}", file.display()))?
+        };
+        let mut input = decode_charset(bytes, args.input_encoding)
+            .with_context(|| format!("failed to read file: {}", file.display()))?;
+        if args.front_matter_values {
+            let (values, rest) = split_front_matter(&input)
+                .with_context(|| format!("invalid front matter in {}", file.display()))?;
+            if let Some(values) = values {
+                front_matter.push((file.clone(), values));
+                input = rest.to_string();
+            }
+        }
+        templates.push((file.clone(), input));
+    }
 
-fn render_eval_exports_with_env(
-    prompted_values: &[(String, String)],
-    resolved_env_map: &HashMap<String, String>,
-) -> String {
-    let mut export_map: HashMap<String, String> = HashMap::new();
+    // One regex to match all supported placeholders:
+    //   {{ .Values.namespace }}               -> capture group "values" (path)
+    //   {{ .Values.a/b }} (--path-sep)        -> capture group "values_slash" or "values_sep"
+    //   {{ .Files.Get "init.sh" }}            -> capture group "files_get" (relative path)
+    //   {{NAMESPACE}}                         -> capture group "env"
+    //   ${NAMESPACE}                          -> capture group "env_brace"
+    //   $ENV{NAMESPACE} (--placeholder-style) -> capture group "env_perl"
+    //   $NAMESPACE                            -> capture group "env_short"
+    //
+    // {{ ... }} placeholders may end in a filter pipeline (`| indent 4`), captured as "pipe".
+    // Values paths are dot-separated identifiers: foo.bar.baz
+    let delims = &args.delimiters;
+    let re = placeholder_regex_with_delimiters(
+        args.placeholder_style,
+        args.path_sep,
+        args.bare_values,
+        delims,
+    )?;
+    ensure_placeholder_count_within(&templates, &re, args.max_placeholders)?;
+    if args.lint {
+        run_lint(&templates, &re, delims)?;
+        return check_warnings(args.fail_on_warning);
+    }
+    // `{{ if }}` blocks are evaluated per values file below; until then both branches are
+    // scanned, so their placeholders are validated, prompted for and traced.
+    let source_templates = templates.clone();
+    for (path, input) in &mut templates {
+        *input = apply_conditionals(input, None, delims)
+            .with_context(|| format!("in {}", path.display()))?;
+    }
+    if args.scan_only {
+        let report = scan_placeholders(&templates, &re);
+        let text = match args.scan_format {
+            ScanFormat::Text => render_scan_report(&report),
+            ScanFormat::Json => format!("{}\n", yaml_to_json(&serde_yaml::to_value(&report)?)),
+        };
+        io::stdout().lock().write_all(text.as_bytes())?;
+        return check_warnings(args.fail_on_warning);
+    }
+    validate_pipelines(&templates, &re)?;
+    validate_file_references(&templates, &re)?;
+    if args.strict_placeholders {
+        ensure_no_partial_placeholders(&templates, &re, delims)?;
+    }
+    if args.strict_env_names {
+        let name_re = Regex::new(&format!("^(?:{})$", args.env_name_pattern))
+            .with_context(|| format!("invalid --env-name-pattern: {}", args.env_name_pattern))?;
+        ensure_env_names_match(&templates, &re, &name_re)?;
+    }
+    if let Some(path) = args.allowed_placeholders.as_deref() {
+        let allowlist = load_placeholder_allowlist(path, args.max_file_size)?;
+        ensure_placeholders_allowed(&templates, &re, &allowlist)?;
+    }
+    let (env_vars, values_paths) = collect_placeholders_all(&templates, &re);
+    ensure_no_meta_placeholders(&values_paths)?;
+    if args.fail_if_no_placeholders
+        && !templates
+            .iter()
+            .any(|(_, input)| placeholder_captures(&re, input).next().is_some())
+    {
+        bail!(
+            "no placeholders found in {} template(s); check --file/--file-pattern and the placeholder syntax (--placeholder-style, --path-sep)",
+            templates.len()
+        );
+    }
+    let prompt_contexts =
+        collect_prompt_contexts(&templates, &re, args.context, args.context_lines);
+    let prompt_order = collect_prompt_order(&templates, &re);
+    let annotations = collect_annotations(&templates, &re);
+    let mut trace = Trace::new(&templates, &re);
 
-    for (key, value) in prompted_values {
-        let env_name = values_key_to_env_var(key);
-        export_map.insert(env_name, value.clone());
+    if args.force && !args.create_values_file && !args.inplace {
+        bail!("--force can only be used together with --create-values-file or --inplace");
+    }
+    if args.inplace && !args.force {
+        for file in &input_files {
+            ensure_git_clean(file)?;
+        }
+    }
+    if args.eval && !args.create_values_file {
+        bail!("--eval can only be used together with --create-values-file");
+    }
+    if args.no_interactive && !args.create_values_file {
+        bail!("--no-interactive can only be used together with --create-values-file");
+    }
+    if !args.values_delete.is_empty() && !args.create_values_file {
+        bail!("--values-delete can only be used together with --create-values-file");
+    }
+    if args.prompt_description_file.is_some() && !args.create_values_file {
+        bail!("--prompt-description-file can only be used together with --create-values-file");
+    }
+    if args.dry_run_values && !args.create_values_file {
+        bail!("--dry-run-values can only be used together with --create-values-file");
+    }
+    if args.prompt_defaults.is_some() && !args.create_values_file {
+        bail!("--prompt-defaults can only be used together with --create-values-file");
+    }
+    if args.prompt_history.is_some() && !args.create_values_file {
+        bail!("--prompt-history can only be used together with --create-values-file");
+    }
+    if args.dry_run && args.create_values_file && !args.dry_run_values {
+        bail!("--dry-run with --create-values-file needs --dry-run-values, so nothing is written");
+    }
+    let output_on_stdout = args.eval
+        || (!args.inplace
+            && args.output_dir.is_none()
+            && (args.output.is_empty() || args.output.iter().any(|p| p.as_os_str() == "-")));
+    if output_on_stdout
+        && args
+            .emit_used_values
+            .as_ref()
+            .is_some_and(|p| p.as_os_str() == "-")
+    {
+        bail!(
+            "--emit-used-values - would mix with the output on stdout; write it to a file or send the output elsewhere (--output, --output-dir or --inplace)"
+        );
     }
 
-    // Always export resolved env placeholders in --eval mode, even without --force.
-    for (name, value) in resolved_env_map {
-        export_map.insert(name.clone(), value.clone());
+    let include_environment_vars_in_prompts = args.create_values_file;
+    let env_file = match args.env_file.as_deref() {
+        Some(path) => load_env_file(path)?,
+        None => HashMap::new(),
+    };
+    let ignore_os_env = args.value_file_only || args.deny_os_env_leak;
+    // Env placeholders in --set values read the env file and the OS environment, in
+    // --resolution-order.
+    let set_env = |name: &str| {
+        args.resolution_order
+            .iter()
+            .find_map(|source| match source {
+                EnvSource::EnvFile => env_file.get(name).cloned(),
+                EnvSource::OsEnv if !ignore_os_env => env::var(name).ok(),
+                _ => None,
+            })
+    };
+    let sets = args
+        .sets
+        .iter()
+        .map(|(path, value)| Ok((path.clone(), expand_set_value(path, value, &re, set_env)?)))
+        .collect::<Result<Vec<_>>>()?;
+    // --override is applied after --set, so it wins for the same key.
+    let overrides: HashMap<String, String> = set_overrides(&sets, &env_vars, &values_paths)?
+        .into_iter()
+        .chain(args.overrides.iter().cloned())
+        .collect();
+    // Keys pinned by --override are never prompted for.
+    let (prompt_env_vars, prompt_values_paths) =
+        without_overrides(&env_vars, &values_paths, &overrides);
+    let os_env_names = if args.env_ignore_case && !ignore_os_env {
+        let os_names: Vec<String> = env::vars_os()
+            .filter_map(|(name, _)| name.into_string().ok())
+            .collect();
+        env_names_ignoring_case(&env_vars, &os_names, args.quiet)
+    } else {
+        HashMap::new()
+    };
+    // Variables the env file sets are not prompted for either, but are not written back.
+    let existing_os_env_vars: BTreeSet<String> = env_vars
+        .iter()
+        .filter(|v| {
+            (!ignore_os_env && os_env_var(v, &os_env_names).is_some()) || env_file.contains_key(*v)
+        })
+        .cloned()
+        .collect();
+    let existing_os_env_values: HashMap<String, String> = if ignore_os_env {
+        HashMap::new()
+    } else {
+        env_vars
+            .iter()
+            .filter_map(|v| {
+                os_env_var(v, &os_env_names).map(|os| (v.clone(), os.to_string_lossy().to_string()))
+            })
+            .collect()
+    };
+    let needs_values_prompt = !prompt_values_paths.is_empty()
+        || (include_environment_vars_in_prompts && !prompt_env_vars.is_empty());
+    if args.create_values_file && is_url(args.values_file()) {
+        bail!(
+            "--create-values-file cannot write back to a URL values file: {}",
+            args.values_file().display()
+        );
+    }
+    set_phase("loading values");
+    let load_opts = ValuesLoadOptions {
+        max_file_size: args.max_file_size,
+        headers: &args.values_header,
+        timeout_secs: args.values_timeout,
+        quiet: args.quiet,
+    };
+    if let [a, b] = args.compare_values.as_slice() {
+        let doc_a = load_values_yaml_if_exists(a, &load_opts)?;
+        let doc_b = load_values_yaml_if_exists(b, &load_opts)?;
+        let keys: BTreeSet<String> = env_vars
+            .iter()
+            .map(|v| env_var_values_path(v))
+            .chain(values_paths.iter().cloned())
+            .collect();
+        let report = render_values_comparison(
+            &keys,
+            (a, &doc_a),
+            (b, &doc_b),
+            args.values_key_case,
+            args.compare_redact,
+        )?;
+        io::stdout().lock().write_all(report.as_bytes())?;
+        return check_warnings(args.fail_on_warning);
+    }
+    let confirm_patterns = args
+        .prompt_confirm
+        .iter()
+        .map(|g| glob_regex(g))
+        .collect::<Result<Vec<_>>>()?;
+    let multiline_patterns = args
+        .prompt_multiline
+        .iter()
+        .map(|g| glob_regex(g))
+        .collect::<Result<Vec<_>>>()?;
+    let editor_patterns = args
+        .prompt_editor
+        .iter()
+        .map(|g| glob_regex(g))
+        .collect::<Result<Vec<_>>>()?;
+    let secret_patterns = args
+        .secret_key
+        .iter()
+        .map(|g| glob_regex(g))
+        .collect::<Result<Vec<_>>>()?;
+    let optional_patterns = args
+        .optional_key
+        .iter()
+        .map(|g| glob_regex(g))
+        .collect::<Result<Vec<_>>>()?;
+    let prompt_groups = match args.prompt_description_file.as_deref() {
+        Some(path) => load_prompt_groups(path, &load_opts)?,
+        None => Vec::new(),
+    };
+    let secrets_yaml = match args.secrets_file.as_deref() {
+        Some(path) => load_values_yaml(path, &load_opts)?,
+        None => None,
+    };
+    let prompt_defaults = match args.prompt_defaults.as_deref() {
+        Some(path) => load_values_yaml(path, &load_opts)?,
+        None => None,
+    };
+    let prompt_history = match args.prompt_history.as_deref() {
+        Some(path) => Some(load_prompt_history(path)?),
+        None => None,
+    };
+    let mut prompted_values: Vec<(String, String)> = Vec::new();
+    let mut unsaved_values = None;
+    if args.create_values_file && (needs_values_prompt || !args.values_delete.is_empty()) {
+        set_phase("prompting for values");
+        let prompt_opts = PromptUpdateOptions {
+            include_environment_vars: include_environment_vars_in_prompts,
+            skip_existing_env_vars: &existing_os_env_vars,
+            existing_os_env_values: &existing_os_env_values,
+            prompt_contexts: &prompt_contexts,
+            prompt_order: &prompt_order,
+            annotations: &annotations,
+            delete_paths: &args.values_delete,
+            confirm_patterns: &confirm_patterns,
+            multiline_patterns: &multiline_patterns,
+            editor_patterns: &editor_patterns,
+            hidden_patterns: &secret_patterns,
+            prompt_groups: &prompt_groups,
+            secrets: secrets_yaml.as_ref(),
+            prompt_defaults: prompt_defaults.as_ref(),
+            history: prompt_history.as_ref(),
+            key_case: args.values_key_case,
+            path_sep: args.path_sep,
+            load: &load_opts,
+            no_interactive: args.no_interactive,
+            stdin_is_terminal: io::stdin().is_terminal(),
+            stdin_at_eof: &stdin_at_eof,
+            force: args.force,
+            skip_empty: args.skip_empty,
+            dry_run: args.dry_run_values,
+            verbose: args.verbose > 0,
+        };
+        let update = prompt_and_update_values_file(
+            args.values_file(),
+            &prompt_values_paths,
+            &prompt_env_vars,
+            &prompt_opts,
+        )?;
+        prompted_values = update.prompted;
+        unsaved_values = update.unsaved;
+        if let (Some(path), Some(history)) = (args.prompt_history.as_deref(), prompt_history) {
+            save_prompt_history(path, history, &prompted_values, &secret_patterns)?;
+        }
+        if !args.dry_run_values && !is_url(args.values_file()) {
+            let secrets: BTreeSet<String> = prompted_values
+                .iter()
+                .map(|(key, _)| key)
+                .filter(|key| secret_patterns.iter().any(|p| p.is_match(key)))
+                .cloned()
+                .collect();
+            warn_if_secrets_committable(args.values_file(), &secrets, args.quiet);
+        }
     }
+    let prompted_env_map = prompted_environment_values(&prompted_values);
 
-    let mut names: Vec<String> = export_map.keys().cloned().collect();
-    names.sort();
+    // Each group of templates resolves against one values file; without --values-by-name
+    // there is exactly one group using --values-file.
+    let values_groups = match args.values_by_name.as_deref() {
+        Some(spec) => {
+            let mapping = parse_values_by_name(spec, base_dir.as_deref())?;
+            let name_re = Regex::new(&args.values_name_regex).with_context(|| {
+                format!("invalid --values-name-regex: {}", args.values_name_regex)
+            })?;
+            group_templates_by_values(&templates, &mapping, &name_re, args.values_file())?
+        }
+        None => vec![(
+            args.values_file().to_path_buf(),
+            (0..templates.len()).collect(),
+        )],
+    };
 
-    let mut out = String::new();
-    for name in names {
-        if let Some(value) = export_map.get(&name) {
-            out.push_str(&format!(
-                "export {}='{}'\n",
-                name,
-                shell_escape_single_quoted(value)
-            ));
+    let values_default = args
+        .values_default
+        .as_deref()
+        .map(parse_values_default)
+        .transpose()?;
+    // The layers below the values file, lowest first, for --explain-value.
+    let mut lower_layers: Vec<(String, YamlValue)> = Vec::new();
+    // With --env, base.yaml and ENVIRONMENT sit below --values-default and the env file.
+    let values_default = match args.env_name.as_deref() {
+        Some(name) => {
+            let base = args.base_values.as_deref().expect("set with --env");
+            let layer = if base_values_required || base.exists() {
+                load_values_yaml(base, &load_opts)?.unwrap_or(YamlValue::Null)
+            } else {
+                YamlValue::Mapping(YamlMapping::new())
+            };
+            if !matches!(layer, YamlValue::Mapping(_)) {
+                bail!(
+                    "base values file {} must be a YAML mapping, got {}",
+                    base.display(),
+                    yaml_kind(&layer)
+                );
+            }
+            let mut environment = YamlValue::Mapping(YamlMapping::new());
+            set_yaml_path(
+                &mut environment,
+                &env_var_values_path("ENVIRONMENT"),
+                YamlValue::String(name.to_string()),
+            );
+            lower_layers.push((base.display().to_string(), layer.clone()));
+            lower_layers.push((format!("--env {name}"), environment.clone()));
+            let layer = merge_yaml(layer, environment);
+            if let Some(inline) = values_default.as_ref() {
+                lower_layers.push(("--values-default".to_string(), inline.clone()));
+            }
+            Some(match values_default {
+                Some(inline) => merge_yaml(layer, inline),
+                None => layer,
+            })
+        }
+        None => {
+            if let Some(inline) = values_default.as_ref() {
+                lower_layers.push(("--values-default".to_string(), inline.clone()));
+            }
+            values_default
         }
+    };
+    // Front matter values are the lowest layer; with several templates, later files win.
+    let mut values_default = values_default;
+    for (path, values) in front_matter.into_iter().rev() {
+        let label = format!("front matter in {}", path.display());
+        lower_layers.insert(0, (label, values.clone()));
+        values_default = Some(match values_default {
+            Some(above) => merge_yaml(values, above),
+            None => values,
+        });
     }
-    out
-}
-
-fn prompted_environment_values(prompted_values: &[(String, String)]) -> HashMap<String, String> {
-    let mut out = HashMap::new();
-    for (key, value) in prompted_values {
-        if let Some(env_name) = key.strip_prefix("environment.") {
-            out.insert(env_name.to_string(), value.clone());
+    // Earlier --values-file occurrences sit directly below the last one; later files win.
+    for path in args.lower_values_files() {
+        let layer = load_values_yaml(path, &load_opts)?.unwrap_or(YamlValue::Null);
+        if !matches!(layer, YamlValue::Mapping(_)) {
+            bail!(
+                "values file {} must be a YAML mapping, got {}",
+                path.display(),
+                yaml_kind(&layer)
+            );
         }
+        lower_layers.push((path.display().to_string(), layer.clone()));
+        values_default = Some(match values_default {
+            Some(below) => merge_yaml(below, layer),
+            None => layer,
+        });
     }
-    out
-}
-
-fn indent_multiline_value(value: &str, input: &str, match_start: usize) -> String {
-    if !value.contains('\n') {
-        return value.to_string();
+    let select = match (args.select.as_deref(), args.select_index) {
+        (Some(spec), _) => {
+            let (field, value) = parse_select(spec)?;
+            Some(ValuesSelect::Field(field, value))
+        }
+        (None, Some(index)) => Some(ValuesSelect::Index(index)),
+        (None, None) => None,
+    };
+    let values_inline = match (&args.values_base64, &args.values_env_base64) {
+        (Some(blob), _) => Some(parse_values_base64(blob, "--values-base64")?),
+        (None, Some(var)) => {
+            let blob = env::var(var).with_context(|| {
+                format!("--values-env-base64: environment variable {var} is not set")
+            })?;
+            Some(parse_values_base64(
+                &blob,
+                &format!("--values-env-base64 {var}"),
+            )?)
+        }
+        // With --dry-run-values, resolution reads the document that was not written.
+        (None, None) => unsaved_values,
+    };
+    let values_patch = match args.values_patch.as_deref() {
+        Some(path) => load_values_yaml(path, &load_opts)?,
+        None => None,
+    };
+    let resolve_opts = ResolveOptions {
+        value_file_only: args.value_file_only,
+        verbose: args.verbose > 0,
+        quiet: args.quiet,
+        conflict_policy: args.conflict_policy,
+        resolution_order: &args.resolution_order,
+        key_case: args.values_key_case,
+        strict_unknown_keys: args.strict_unknown_keys,
+        tolerant_values: args.tolerant_values,
+        always_load: args.dump_values.is_some()
+            || args.warn_unused
+            || !args.require_key.is_empty()
+            || !args.explain_value.is_empty(),
+        annotations: &annotations,
+        prompted_env_map: &prompted_env_map,
+        overrides: &overrides,
+        deny_os_env: args.deny_os_env_leak,
+        env_ignore_case: args.env_ignore_case,
+        os_env_names: &os_env_names,
+        env_file: &env_file,
+        optional_keys: &optional_patterns,
+        values_default: values_default.as_ref(),
+        values_inline: values_inline.as_ref(),
+        values_patch: values_patch.as_ref(),
+        secrets: secrets_yaml.as_ref(),
+        select: select.as_ref(),
+        load: &load_opts,
+    };
+    // The layers above the values file, lowest first, for --explain-value.
+    let mut upper_layers: Vec<(String, YamlValue)> = Vec::new();
+    if let (Some(path), Some(patch)) = (args.values_patch.as_deref(), values_patch.as_ref()) {
+        upper_layers.push((format!("--values-patch {}", path.display()), patch.clone()));
+    }
+    if let (Some(path), Some(secrets)) = (args.secrets_file.as_deref(), secrets_yaml.as_ref()) {
+        upper_layers.push((
+            format!("--secrets-file {}", path.display()),
+            secrets.clone(),
+        ));
     }
+    set_phase("resolving values");
+    let mut resolutions: Vec<ValuesGroup> = Vec::new();
+    for (values_file, indices) in values_groups {
+        let scanned: Vec<(PathBuf, String)> =
+            indices.iter().map(|&i| templates[i].clone()).collect();
+        let (scanned_env_vars, all_scanned_values_paths) = collect_placeholders_all(&scanned, &re);
+        let (scanned_env_vars, mut scanned_values_paths) =
+            without_overrides(&scanned_env_vars, &all_scanned_values_paths, &overrides);
+        let condition_paths: BTreeSet<String> = indices
+            .iter()
+            .flat_map(|&i| condition_values_paths(&source_templates[i].1, &re, delims))
+            .collect();
+        // `{{ if .Values.x }}` reads the values file even when no placeholder does.
+        scanned_values_paths.extend(condition_paths.iter().cloned());
+        let values_layer = load_values_layer(
+            &values_file,
+            &scanned_env_vars,
+            &scanned_values_paths,
+            &resolve_opts,
+        )?;
+        if (args.warn_unused || args.verbose > 0)
+            && let Some(layer) = values_layer.as_ref()
+        {
+            let mut used = all_scanned_values_paths.clone();
+            used.extend(condition_paths.iter().cloned());
+            for key in unused_values_keys(layer, &used, args.values_key_case) {
+                eprintln!("unused values file key {key} in {}", values_file.display());
+            }
+        }
+        let values_yaml = values_layer
+            .clone()
+            .map(|yaml| merge_values_layers(yaml, &resolve_opts));
+        if let (Some(layer), Some(effective)) = (values_layer, values_yaml.as_ref()) {
+            let mut layers = lower_layers.clone();
+            layers.push((values_file.display().to_string(), layer));
+            layers.extend(upper_layers.iter().cloned());
+            for key in &args.explain_value {
+                eprint!(
+                    "{}",
+                    render_value_explanation(
+                        key,
+                        &layers,
+                        effective,
+                        args.values_key_case,
+                        secrets_yaml.is_some().then(|| layers.len() - 1),
+                    )?
+                );
+            }
+        }
+        if let Some(values) = values_yaml.as_ref() {
+            let missing = missing_required_keys(
+                values,
+                &args.require_key,
+                args.values_key_case,
+                args.allow_empty_required,
+            )?;
+            if !missing.is_empty() {
+                bail!(
+                    "missing required keys in {}:\n- {}",
+                    values_file.display(),
+                    missing.join("\n- ")
+                );
+            }
+        }
 
-    let line_start = input[..match_start].rfind('\n').map(|i| i + 1).unwrap_or(0);
-    let before_match = &input[line_start..match_start];
-    let indent: String = before_match
-        .chars()
-        .take_while(|c| c.is_whitespace())
-        .collect();
-
-    let mut out = String::with_capacity(value.len() + indent.len());
-    let mut chars = value.chars().peekable();
-    while let Some(ch) = chars.next() {
-        out.push(ch);
-        if ch == '\n' && chars.peek().is_some() {
-            out.push_str(&indent);
+        // Only placeholders in the branches that are kept need to resolve.
+        let empty = YamlValue::Mapping(YamlMapping::new());
+        let values_root = values_yaml.as_ref().unwrap_or(&empty);
+        let truthy =
+            |cond: &str| condition_is_truthy(cond, values_root, &re, args.values_key_case, delims);
+        let mut group: Vec<(PathBuf, String)> = Vec::new();
+        for &i in &indices {
+            let (path, input) = &source_templates[i];
+            let output = apply_conditionals(input, Some(&truthy), delims)
+                .with_context(|| format!("in {}", path.display()))?;
+            group.push((path.clone(), output));
+        }
+        let (group_env_vars, group_values_paths) = collect_placeholders_all(&group, &re);
+        let expand_source = args.expand_values.then(|| values_yaml.clone());
+        let mut resolution = resolve_placeholders(
+            values_yaml,
+            &group_env_vars,
+            &group_values_paths,
+            &resolve_opts,
+        )?;
+        if let Some(values_yaml) = expand_source {
+            resolution = expand_nested_values(resolution, values_yaml, &re, &resolve_opts)?;
+        }
+        trace.record_resolution(
+            &resolution.env_map,
+            &resolution.values_map,
+            &resolution.sources,
+            &secret_patterns,
+            args.trace_redact,
+        );
+        trace.missing.extend(resolution.missing_keys());
+        if !resolution.sources.is_empty() {
+            log_at(2, || {
+                let mut sources: Vec<_> = resolution.sources.iter().collect();
+                sources.sort_by_key(|(key, _)| *key);
+                sources
+                    .iter()
+                    .map(|(key, source)| {
+                        format!(
+                            "resolved {} from {}",
+                            display_values_key(key),
+                            value_source_name(**source)
+                        )
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            });
         }
+        resolutions.push(ValuesGroup {
+            values_file,
+            indices,
+            templates: group,
+            resolution,
+        });
     }
-    out
-}
+    trace.record_prompts(&prompted_values, &secret_patterns, args.trace_redact);
 
-fn format_replacement_with_indent(
-    value: &str,
-    input: &str,
-    match_start: usize,
-    match_end: usize,
-) -> String {
-    if !value.contains('\n') {
-        return value.to_string();
+    if let Some(dump_path) = args.dump_values.as_ref() {
+        let dump = render_values_dump(&resolutions, secrets_yaml.as_ref())?;
+        write_output(Some(dump_path), dump.as_bytes())?;
+        if args.dump_values_only {
+            return check_warnings(args.fail_on_warning);
+        }
     }
 
-    if should_use_yaml_block_scalar(input, match_start, match_end) {
-        format_as_yaml_block_scalar(value, input, match_start)
-    } else {
-        indent_multiline_value(value, input, match_start)
+    if args.dry_run {
+        io::stdout().write_all(render_dry_run_report(&resolutions).as_bytes())?;
+        return check_warnings(args.fail_on_warning);
     }
-}
-
-fn should_use_yaml_block_scalar(input: &str, match_start: usize, match_end: usize) -> bool {
-    let line_start = input[..match_start].rfind('\n').map(|i| i + 1).unwrap_or(0);
-    let line_end = input[match_end..]
-        .find('\n')
-        .map(|i| match_end + i)
-        .unwrap_or(input.len());
-
-    let prefix = &input[line_start..match_start];
-    let suffix = &input[match_end..line_end];
-    let prefix_trimmed = prefix.trim_end();
-    let suffix_trimmed = suffix.trim();
 
-    (prefix_trimmed.ends_with(':') || prefix_trimmed.ends_with('-')) && suffix_trimmed.is_empty()
-}
-
-fn format_as_yaml_block_scalar(value: &str, input: &str, match_start: usize) -> String {
-    let line_start = input[..match_start].rfind('\n').map(|i| i + 1).unwrap_or(0);
-    let line_prefix = &input[line_start..match_start];
-    let line_indent: String = line_prefix
-        .chars()
-        .take_while(|c| c.is_whitespace())
-        .collect();
-    let content_indent = format!("{line_indent}  ");
-
-    let indicator = if has_trailing_empty_lines(value) {
-        "|+"
-    } else {
-        "|"
-    };
-    let content = indent_every_line(value, &content_indent);
-    format!("{indicator}\n{content}")
-}
-
-fn has_trailing_empty_lines(value: &str) -> bool {
-    let mut trailing_newlines = 0usize;
-    for ch in value.chars().rev() {
-        if ch == '\n' {
-            trailing_newlines += 1;
-        } else {
-            break;
+    // If anything missing, print all missing and fail
+    if resolutions.iter().any(|g| g.resolution.has_missing()) {
+        if let Some(trace_path) = args.trace_file.as_deref() {
+            write_trace(trace_path, &trace, args.trace_format)?;
+        }
+        for group in &resolutions {
+            report_missing(&group.values_file, &group.resolution, args.path_sep);
+        }
+        let required = collect_required_messages(&templates, &re)?;
+        let failures: Vec<String> = resolutions
+            .iter()
+            .flat_map(|g| g.resolution.missing_keys())
+            .filter_map(|key| {
+                required.get(&key).map(|msg| {
+                    let key = values_key_as_written(&key, args.path_sep);
+                    format!("{msg} ({})", display_values_key(&key))
+                })
+            })
+            .collect();
+        if !failures.is_empty() {
+            bail!("{}", failures.join("; "));
         }
+        bail!("not all placeholders could be resolved");
     }
-    trailing_newlines > 1
-}
 
-fn indent_every_line(value: &str, indent: &str) -> String {
-    let mut out = String::new();
-    for part in value.split_inclusive('\n') {
-        if let Some(line) = part.strip_suffix('\n') {
-            out.push_str(indent);
-            out.push_str(line);
-            out.push('\n');
-        } else {
-            out.push_str(indent);
-            out.push_str(part);
-        }
+    if let Some(path) = args.emit_used_values.as_ref() {
+        let used = render_used_values(&resolutions)?;
+        write_output(Some(path), used.as_bytes())?;
     }
-    out
-}
 
-fn resolve_env_from_values_file(
-    env_vars: &BTreeSet<String>,
-    yaml: &YamlValue,
-) -> Result<(HashMap<String, String>, Vec<String>)> {
-    let mut env_map = HashMap::new();
-    let mut missing_paths = Vec::new();
-
-    for var in env_vars {
-        let path = env_var_values_path(var);
-        match lookup_yaml_path(yaml, &path) {
-            Some(v) => {
-                env_map.insert(var.clone(), yaml_value_to_string(v)?);
+    // Render with logging (if verbose)
+    set_phase("rendering");
+    let started = std::time::Instant::now();
+    let mut rendered_outputs: Vec<(PathBuf, String)> = Vec::new();
+    for pass in 0..args.repeat.max(1) {
+        let mut rendered: Vec<Option<(PathBuf, String)>> = vec![None; templates.len()];
+        for ValuesGroup {
+            indices,
+            templates: group,
+            resolution,
+            ..
+        } in &resolutions
+        {
+            let masked = secret_keys_matching(resolution, &secret_patterns);
+            let render_opts = RenderOptions {
+                verbose: args.verbose > 0,
+                value_file_only: args.value_file_only,
+                indent: args.indent,
+                block_scalar: !args.no_block_scalar,
+                format: template_format,
+                max_file_size: args.max_file_size,
+                masked: &masked,
+                quoted: args.preserve_quotes.then_some(&resolution.string_values),
+                typed: args.typed_values.then_some(&resolution.typed_values),
+                folded_policy: args.folded_policy,
+                strict_multiline: args.strict_multiline,
+                on_warning: &|message| warn(args.quiet, message),
+                diff: args.diff && pass == 0,
+            };
+            let outputs = render_templates(
+                group,
+                &re,
+                &resolution.values_map,
+                &resolution.env_map,
+                &render_opts,
+            )?;
+            for (&i, output) in indices.iter().zip(outputs) {
+                rendered[i] = Some(output);
             }
-            None => missing_paths.push(path),
         }
+        rendered_outputs = rendered.into_iter().flatten().collect();
+    }
+    if args.time {
+        eprintln!(
+            "rendered {} file(s) {} time(s) in {:.3?}",
+            templates.len(),
+            args.repeat.max(1),
+            started.elapsed()
+        );
     }
 
-    Ok((env_map, missing_paths))
-}
-
-fn prompt_for_yaml_key(
-    path: &str,
-    default: Option<&str>,
-    context: Option<&str>,
-) -> Result<YamlValue> {
-    let mut prompt = format!("Enter value for values file key {path}");
-    if let Some(default_text) = default {
-        prompt.push_str(&format!(" [{default_text}]"));
-    }
-    prompt.push_str(": ");
-
-    let mut err = io::stderr().lock();
-    if let Some(ctx) = context {
-        err.write_all(b"\n")?;
-        err.write_all(ctx.as_bytes())?;
-        err.write_all(b"\n")?;
-    }
-    err.write_all(prompt.as_bytes())?;
-    err.flush()?;
-
-    let mut line = String::new();
-    io::stdin().read_line(&mut line)?;
-    let entered = line.trim_end_matches(['\r', '\n']);
-
-    if entered.is_empty() {
-        if let Some(v) = default {
-            return Ok(YamlValue::String(v.to_string()));
-        }
-        return Ok(YamlValue::String(String::new()));
+    if !args.expect_kind.is_empty() {
+        ensure_expected_kinds(&rendered_outputs, &args.expect_kind, args.allow_kindless)?;
     }
-
-    Ok(YamlValue::String(entered.to_string()))
-}
-
-fn set_yaml_path(root: &mut YamlValue, path: &str, value: YamlValue) {
-    let parts: Vec<&str> = path.split('.').collect();
-    if !matches!(root, YamlValue::Mapping(_)) {
-        *root = YamlValue::Mapping(YamlMapping::new());
+    if args.fail_on_duplicate_keys {
+        ensure_no_duplicate_keys(&rendered_outputs)?;
+    }
+    if args.validate {
+        ensure_rendered_yaml_parses(&rendered_outputs)?;
     }
 
-    let mut cur = root;
-    let mut value_opt = Some(value);
-
-    for (idx, part) in parts.iter().enumerate() {
-        let is_last = idx == parts.len() - 1;
-        let key = YamlValue::String((*part).to_string());
-
-        match cur {
-            YamlValue::Mapping(map) => {
-                if is_last {
-                    if let Some(v) = value_opt.take() {
-                        map.insert(key, v);
-                    }
-                    return;
-                }
-
-                let entry = map
-                    .entry(key)
-                    .or_insert_with(|| YamlValue::Mapping(YamlMapping::new()));
-                if !matches!(entry, YamlValue::Mapping(_)) {
-                    *entry = YamlValue::Mapping(YamlMapping::new());
-                }
-                cur = entry;
+    let output_opts = OutputOptions {
+        trailing_newline: if args.no_trailing_newline {
+            TrailingNewline::Strip
+        } else if args.ensure_trailing_newline {
+            TrailingNewline::Ensure
+        } else {
+            TrailingNewline::Keep
+        },
+        encoding: args.encode_output,
+        charset: args.output_encoding,
+        wrap: args.b64_wrap,
+        format: template_format,
+        doc_separator: &args.doc_separator,
+        leading_separator: args.leading_separator,
+        json_lines: args.output_jsonl,
+        json: args.output_format == OutputFormat::Json,
+    };
+    let persisted_env = if args.persist_env {
+        env_values_to_persist(&resolutions, &secret_patterns, args.persist_secrets)?
+    } else {
+        Vec::new()
+    };
+    set_phase(PHASE_WRITING);
+    let export_prefix = args.export_prefix.as_deref().unwrap_or_default();
+    let env_map: HashMap<String, String> = resolutions
+        .iter()
+        .flat_map(|g| g.resolution.env_map.clone())
+        .collect();
+    let output_secrets: BTreeSet<String> = resolutions
+        .iter()
+        .flat_map(|g| secret_keys_matching(&g.resolution, &secret_patterns))
+        .collect();
+    // With a `**` --file-pattern, --output-dir keeps the matched files' subdirectories.
+    let nested_root = args
+        .file_pattern
+        .as_deref()
+        .and_then(recursive_pattern_root);
+    if args.eval {
+        // In eval mode, stdout should stay parseable as shell exports.
+        if args.output.iter().any(|p| p.to_string_lossy() == "-") {
+            bail!("with --eval, --output - is not supported");
+        }
+        if let Some(dir) = args.output_dir.as_deref() {
+            for path in write_output_dir(
+                dir,
+                &rendered_outputs,
+                args.chunk_size,
+                nested_root.as_deref(),
+                &output_opts,
+            )? {
+                warn_if_secrets_committable(&path, &output_secrets, args.quiet);
             }
-            _ => {
-                *cur = YamlValue::Mapping(YamlMapping::new());
+        } else if !args.output.is_empty() {
+            write_outputs(&args.output, &rendered_outputs, &output_opts)?;
+            for path in output_destinations(&args.output) {
+                warn_if_secrets_committable(path, &output_secrets, args.quiet);
             }
         }
-    }
-}
-
-fn lookup_yaml_path<'a>(root: &'a YamlValue, path: &str) -> Option<&'a YamlValue> {
-    // path like "foo.bar.baz"
-    let mut cur = root;
-    for part in path.split('.') {
-        match cur {
-            YamlValue::Mapping(map) => {
-                let key = YamlValue::String(part.to_string());
-                cur = map.get(&key)?;
+        let script = render_eval_exports_with_env(&prompted_values, &env_map, export_prefix);
+        let mut out = io::stdout().lock();
+        out.write_all(script.as_bytes())?;
+    } else if args.inplace {
+        write_inplace(
+            &rendered_outputs,
+            output_opts.trailing_newline,
+            args.backup,
+            args.verbose > 0,
+        )?;
+    } else if let Some(dir) = args.output_dir.as_deref() {
+        let written = write_output_dir(
+            dir,
+            &rendered_outputs,
+            args.chunk_size,
+            nested_root.as_deref(),
+            &output_opts,
+        )?;
+        if !args.quiet {
+            let kind = if args.chunk_size.is_some() {
+                "chunk "
+            } else {
+                ""
+            };
+            eprintln!("wrote {} {kind}file(s):", written.len());
+            for path in &written {
+                eprintln!("- {}", path.display());
             }
-            _ => return None,
         }
-    }
-    Some(cur)
-}
-
-fn yaml_value_to_string(v: &YamlValue) -> Result<String> {
-    Ok(match v {
-        YamlValue::Null => "".to_string(),
-        YamlValue::Bool(b) => b.to_string(),
-        YamlValue::Number(n) => n.to_string(),
-        YamlValue::String(s) => s.clone(),
-        // For sequences/maps, serialize to YAML (trim trailing newline).
-        other => serde_yaml::to_string(other)?.trim_end().to_string(),
-    })
-}
-
-fn write_output(output: Option<&PathBuf>, bytes: &[u8]) -> Result<()> {
-    match output.map(|p| p.as_path()) {
-        None => {
-            let mut out = io::stdout().lock();
-            out.write_all(bytes)?;
+        for path in &written {
+            warn_if_secrets_committable(path, &output_secrets, args.quiet);
+        }
+    } else {
+        write_outputs(&args.output, &rendered_outputs, &output_opts)?;
+        for path in output_destinations(&args.output) {
+            warn_if_secrets_committable(path, &output_secrets, args.quiet);
         }
-        Some(p) if p.to_string_lossy() == "-" => {
-            let mut out = io::stdout().lock();
-            out.write_all(bytes)?;
+    }
+    for (values_file, values) in &persisted_env {
+        persist_env_values(values_file, values, &load_opts)?;
+        if args.verbose > 0 {
+            eprintln!(
+                "persisted {} env value(s) to {}",
+                values.len(),
+                values_file.display()
+            );
         }
-        Some(p) => {
-            fs::write(p, bytes)
-                .with_context(|| format!("failed to write output file: {}", p.display()))?;
+    }
+    if let Some(export_path) = args.env_export_file.as_deref() {
+        let script = render_eval_exports_with_env(&prompted_values, &env_map, export_prefix);
+        write_private_file(export_path, script.as_bytes())?;
+        if args.verbose > 0 {
+            let names: BTreeSet<String> = prompted_values
+                .iter()
+                .map(|(key, _)| values_key_to_env_var(key))
+                .chain(env_map.keys().cloned())
+                .collect();
+            eprintln!(
+                "wrote {} export(s) to {}",
+                names.len(),
+                export_path.display()
+            );
         }
     }
-    Ok(())
-}
 
-fn write_outputs(output: Option<&PathBuf>, rendered: &[(PathBuf, String)]) -> Result<()> {
-    if rendered.len() == 1 {
-        return write_output(output, rendered[0].1.as_bytes());
+    if let Some(trace_path) = args.trace_file.as_deref() {
+        trace.output_bytes = Some(rendered_outputs.iter().map(|(_, s)| s.len()).sum());
+        write_trace(trace_path, &trace, args.trace_format)?;
     }
-
-    let merged = render_multi_document_yaml(rendered);
-    write_output(output, merged.as_bytes())
+    check_warnings(args.fail_on_warning)
 }
 
-fn render_multi_document_yaml(rendered: &[(PathBuf, String)]) -> String {
+/// `--show-config` output: the resolved inputs and paths, then each option with its
+/// effective value and whether it came from the command line or a default.
+fn render_effective_config(args: &Args, matches: &ArgMatches, input_files: &[PathBuf]) -> String {
     let mut out = String::new();
-    for (idx, (_, content)) in rendered.iter().enumerate() {
-        if idx > 0 {
-            out.push_str("\n---\n");
-        }
-        out.push_str(content);
-        if !content.ends_with('\n') {
-            out.push('\n');
-        }
+    let inputs: Vec<String> = input_files
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect();
+    out.push_str(&format!("input files: {}\n", inputs.join(", ")));
+    let values: Vec<String> = args
+        .values
+        .iter()
+        .map(|p| p.display().to_string())
+        .collect();
+    out.push_str(&format!("values file: {}\n", values.join(", ")));
+    let output = if args.output.is_empty() {
+        "stdout".to_string()
+    } else {
+        let names: Vec<String> = output_destinations(&args.output)
+            .iter()
+            .map(|p| {
+                if p.as_os_str() == "-" {
+                    "stdout".to_string()
+                } else {
+                    p.display().to_string()
+                }
+            })
+            .collect();
+        names.join(", ")
+    };
+    out.push_str(&format!("output: {output}\n"));
+
+    // Invalid globs fail the run later; here they just match nothing.
+    let secret_patterns: Vec<Regex> = args
+        .secret_key
+        .iter()
+        .filter_map(|g| glob_regex(g).ok())
+        .collect();
+    for arg in Args::command().get_arguments().filter(|a| !a.is_hide_set()) {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        let source = match matches.value_source(id) {
+            Some(ArgValueSource::CommandLine) => "command line",
+            Some(ArgValueSource::EnvVariable) => "env",
+            Some(ArgValueSource::DefaultValue) => "default",
+            _ => continue,
+        };
+        let values: Vec<String> = matches
+            .get_raw(id)
+            .map(|raw| {
+                raw.map(|v| redact_secret_assignment(id, &v.to_string_lossy(), &secret_patterns))
+                    .collect()
+            })
+            .unwrap_or_default();
+        out.push_str(&format!("--{long}: {} ({source})\n", values.join(", ")));
     }
     out
 }
 
-fn ensure_all_yaml_files(input_files: &[PathBuf]) -> Result<()> {
-    for path in input_files {
-        if !is_yaml_file(path) {
-            bail!(
-                "all input files must be *.yaml for multi-file output, but found {}",
-                path.display()
-            );
-        }
+/// Masks the value of an `--override`/`--set` assignment whose key matches `--secret-key`.
+fn redact_secret_assignment(id: &str, raw: &str, secret_patterns: &[Regex]) -> String {
+    let keys = match id {
+        "overrides" => parse_override(raw).map(|(key, _)| vec![key]),
+        "sets" => parse_set(raw).map(|(path, _)| {
+            let env_key = env_var_values_path(&values_key_to_env_var(&path));
+            vec![path, env_key]
+        }),
+        _ => return raw.to_string(),
+    };
+    let secret = keys.is_ok_and(|keys| {
+        keys.iter()
+            .any(|key| secret_patterns.iter().any(|p| p.is_match(key)))
+    });
+    match raw.split_once('=') {
+        Some((key, _)) if secret => format!("{key}=<redacted>"),
+        _ => raw.to_string(),
     }
-    Ok(())
-}
-
-fn is_yaml_file(path: &Path) -> bool {
-    path.file_name()
-        .and_then(|n| n.to_str())
-        .map(|n| n.ends_with(".yaml"))
-        .unwrap_or(false)
 }
 
 #[derive(Copy, Clone)]
 enum CompletionShell {
     Bash,
     Zsh,
+    Fish,
+}
+
+impl CompletionShell {
+    fn generator(self) -> clap_complete::Shell {
+        match self {
+            CompletionShell::Bash => clap_complete::Shell::Bash,
+            CompletionShell::Zsh => clap_complete::Shell::Zsh,
+            CompletionShell::Fish => clap_complete::Shell::Fish,
+        }
+    }
+}
+
+/// The file name each shell looks up completions by.
+fn completion_file_name(shell: CompletionShell) -> &'static str {
+    match shell {
+        CompletionShell::Bash => "tplenv",
+        CompletionShell::Zsh => "_tplenv",
+        CompletionShell::Fish => "tplenv.fish",
+    }
 }
 
-fn install_completion(shell_arg: &str) -> Result<()> {
+fn install_completion(shell_arg: &str, completion_dir: Option<&Path>, quiet: bool) -> Result<()> {
     let shell = resolve_completion_shell(shell_arg)?;
+    if let Some(dir) = completion_dir {
+        let target = install_completion_into(shell, dir)?;
+        if !quiet {
+            eprintln!("Installed completion: {}", target.display());
+            if matches!(shell, CompletionShell::Zsh) {
+                eprintln!(
+                    "Note: {} must be on zsh's $fpath, followed by: autoload -Uz compinit && compinit",
+                    dir.display()
+                );
+            }
+        }
+        return Ok(());
+    }
     let home = home_dir()?;
 
     match shell {
@@ -1158,34 +2179,81 @@ fn install_completion(shell_arg: &str) -> Result<()> {
             let target_dir = data_home.join("bash-completion/completions");
             fs::create_dir_all(&target_dir)
                 .with_context(|| format!("failed to create {}", target_dir.display()))?;
-            let target = target_dir.join("tplenv");
-            fs::write(&target, BASH_COMPLETION)
+            let target = target_dir.join(completion_file_name(shell));
+            fs::write(&target, completion_script(shell))
                 .with_context(|| format!("failed to write {}", target.display()))?;
-            eprintln!("Installed bash completion: {}", target.display());
-            eprintln!("Open a new shell, or run: source {}", target.display());
+            if !quiet {
+                eprintln!("Installed bash completion: {}", target.display());
+                eprintln!("Open a new shell, or run: source {}", target.display());
+            }
         }
         CompletionShell::Zsh => {
             let target_dir = home.join(".zsh/completions");
             fs::create_dir_all(&target_dir)
                 .with_context(|| format!("failed to create {}", target_dir.display()))?;
-            let target = target_dir.join("_tplenv");
-            fs::write(&target, ZSH_COMPLETION)
+            let target = target_dir.join(completion_file_name(shell));
+            fs::write(&target, completion_script(shell))
                 .with_context(|| format!("failed to write {}", target.display()))?;
 
             let zshrc = home.join(".zshrc");
             ensure_line_in_file(&zshrc, "fpath=(~/.zsh/completions $fpath)")?;
             ensure_line_in_file(&zshrc, "autoload -Uz compinit && compinit")?;
 
-            eprintln!("Installed zsh completion: {}", target.display());
-            eprintln!(
-                "Open a new shell, or run: fpath=(~/.zsh/completions $fpath); autoload -Uz compinit && compinit"
-            );
+            if !quiet {
+                eprintln!("Installed zsh completion: {}", target.display());
+                eprintln!(
+                    "Open a new shell, or run: fpath=(~/.zsh/completions $fpath); autoload -Uz compinit && compinit"
+                );
+            }
+        }
+        CompletionShell::Fish => {
+            // fish autoloads completions from this directory, so no startup file is edited.
+            let config_home = env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .unwrap_or_else(|| home.join(".config"));
+            let target = install_completion_into(shell, &config_home.join("fish/completions"))?;
+            if !quiet {
+                eprintln!("Installed fish completion: {}", target.display());
+                eprintln!("Open a new shell to use it.");
+            }
         }
     }
 
     Ok(())
 }
 
+/// --completion-dir: writes the completion file into `dir` and touches nothing else.
+fn install_completion_into(shell: CompletionShell, dir: &Path) -> Result<PathBuf> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let target = dir.join(completion_file_name(shell));
+    fs::write(&target, completion_script(shell))
+        .with_context(|| format!("failed to write {}", target.display()))?;
+    Ok(target)
+}
+
+/// Generate the completion script from the live clap definition so it never drifts from the flags.
+/// Hidden flags are left out (with the groups that name them, such as the derive's `Args`
+/// group): clap_complete's bash script would offer them.
+fn completion_script(shell: CompletionShell) -> String {
+    let full = Args::command();
+    let hidden: Vec<&clap::Id> = full
+        .get_arguments()
+        .filter(|a| a.is_hide_set())
+        .map(|a| a.get_id())
+        .collect();
+    let mut cmd = clap::Command::new("tplenv")
+        .version(env!("CARGO_PKG_VERSION"))
+        .args(full.get_arguments().filter(|a| !a.is_hide_set()).cloned())
+        .groups(
+            full.get_groups()
+                .filter(|g| g.get_args().all(|id| !hidden.contains(&id)))
+                .cloned(),
+        );
+    let mut out = Vec::new();
+    clap_complete::generate(shell.generator(), &mut cmd, "tplenv", &mut out);
+    String::from_utf8(out).expect("clap_complete writes UTF-8")
+}
+
 fn resolve_completion_shell(shell_arg: &str) -> Result<CompletionShell> {
     if shell_arg == "auto" {
         let shell = env::var("SHELL").unwrap_or_default();
@@ -1196,8 +2264,9 @@ fn resolve_completion_shell(shell_arg: &str) -> Result<CompletionShell> {
         return match base {
             "bash" => Ok(CompletionShell::Bash),
             "zsh" => Ok(CompletionShell::Zsh),
+            "fish" => Ok(CompletionShell::Fish),
             _ => bail!(
-                "could not detect shell from SHELL={shell}; use --install-completion bash|zsh"
+                "could not detect shell from SHELL={shell}; use --install-completion bash|zsh|fish"
             ),
         };
     }
@@ -1205,7 +2274,8 @@ fn resolve_completion_shell(shell_arg: &str) -> Result<CompletionShell> {
     match shell_arg {
         "bash" => Ok(CompletionShell::Bash),
         "zsh" => Ok(CompletionShell::Zsh),
-        _ => bail!("unsupported shell '{shell_arg}', expected bash or zsh"),
+        "fish" => Ok(CompletionShell::Fish),
+        _ => bail!("unsupported shell '{shell_arg}', expected bash, zsh or fish"),
     }
 }
 
@@ -1241,209 +2311,355 @@ mod tests {
     use super::*;
 
     #[test]
-    fn collect_placeholders_finds_unique_env_and_values() {
-        let input = r#"
-apiVersion: v1
-metadata:
-  namespace: {{NAMESPACE}}
-  name: {{ APP_NAME }}
-  short_env: $SHORT_ENV
-  brace_env: ${BRACE_ENV}
-spec:
-  image: {{ .Values.image.repository }}:{{.Values.image.tag}}
-  replicas: {{ .Values.replicas }}
-  namespace2: {{NAMESPACE}}
-"#;
-        let re = placeholder_regex().expect("regex must compile");
-        let (env_vars, values_paths) = collect_placeholders(input, &re);
-
-        assert_eq!(
-            env_vars,
-            BTreeSet::from([
-                "APP_NAME".to_string(),
-                "BRACE_ENV".to_string(),
-                "NAMESPACE".to_string(),
-                "SHORT_ENV".to_string()
-            ])
-        );
-        assert_eq!(
-            values_paths,
-            BTreeSet::from([
-                "image.repository".to_string(),
-                "image.tag".to_string(),
-                "replicas".to_string()
-            ])
-        );
-    }
-
-    #[test]
-    fn set_yaml_path_creates_nested_mappings() {
-        let mut root = YamlValue::Mapping(YamlMapping::new());
-        set_yaml_path(
-            &mut root,
-            "service.port",
-            YamlValue::Number(serde_yaml::Number::from(8080)),
-        );
-
-        let got = lookup_yaml_path(&root, "service.port");
+    fn repeated_file_args_render_in_command_line_order() {
+        let dir = env::temp_dir().join(format!("tplenv-multi-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("temp dir");
+        let b = dir.join("b.yaml");
+        let a = dir.join("a.yaml");
+        let out = dir.join("out.yaml");
+        fs::write(&b, "b: {{ .Values.x }}\n").expect("write b");
+        fs::write(&a, "a: {{ .Values.x }}\n").expect("write a");
+        let values = dir.join("values.yaml");
+        fs::write(&values, "x: 1\n").expect("write values");
+        run([
+            OsString::from("tplenv"),
+            "--file".into(),
+            b.clone().into(),
+            "--file".into(),
+            a.clone().into(),
+            "--values".into(),
+            values.into(),
+            "--output".into(),
+            out.clone().into(),
+        ]
+        .to_vec())
+        .expect("renders");
         assert_eq!(
-            got,
-            Some(&YamlValue::Number(serde_yaml::Number::from(8080)))
+            fs::read_to_string(&out).expect("read output"),
+            "b: 1\n\n---\na: 1\n"
         );
+        fs::remove_dir_all(&dir).ok();
     }
 
     #[test]
-    fn set_yaml_path_replaces_non_mapping_intermediate_nodes() {
-        let mut root: YamlValue = serde_yaml::from_str("service: api\n").expect("valid yaml");
-        set_yaml_path(
-            &mut root,
-            "service.port",
-            YamlValue::Number(serde_yaml::Number::from(80)),
+    fn file_dash_rejects_inplace_and_relative_to_template() {
+        let err = run(["tplenv", "--file", "-", "--inplace", "--force"]
+            .into_iter()
+            .map(OsString::from)
+            .collect())
+        .expect_err("cannot write back");
+        assert!(err.to_string().starts_with("--inplace cannot write back"));
+
+        let err = run(["tplenv", "--file", "-", "--relative-to-template"]
+            .into_iter()
+            .map(OsString::from)
+            .collect())
+        .expect_err("stdin has no directory");
+        assert!(
+            err.to_string()
+                .starts_with("--relative-to-template cannot be used with --file -"),
+            "{err:#}"
         );
-
-        let got = lookup_yaml_path(&root, "service.port");
-        assert_eq!(got, Some(&YamlValue::Number(serde_yaml::Number::from(80))));
     }
 
     #[test]
-    fn yaml_value_to_string_handles_scalars_and_mappings() {
+    fn set_values_expand_env_from_env_file_and_os_env() {
+        let dir = env::temp_dir().join(format!("tplenv-set-env-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let path = |name: &str| dir.join(name).display().to_string();
+        fs::write(dir.join("app.yaml"), "image: {{ .Values.image.repo }}\n").expect("write");
+        fs::write(dir.join("values.yaml"), "image:\n  repo: app\n").expect("write");
+        fs::write(dir.join(".env"), "TPLENV_SET_REGISTRY=from-env-file\n").expect("write");
+        // SAFETY: the variable is unique to this test.
+        unsafe { env::set_var("TPLENV_SET_REGISTRY", "from-os") };
+        let render = |extra: &[&str]| {
+            let mut argv: Vec<OsString> = ["tplenv", "--file"].map(OsString::from).to_vec();
+            argv.push(path("app.yaml").into());
+            argv.extend(["--values".into(), path("values.yaml").into()]);
+            argv.extend(["--output".into(), path("out.yaml").into(), "--quiet".into()]);
+            argv.extend(["--set", "image.repo=$TPLENV_SET_REGISTRY/app"].map(OsString::from));
+            argv.extend(extra.iter().map(OsString::from));
+            run(argv).map(|()| fs::read_to_string(dir.join("out.yaml")).expect("output"))
+        };
+        assert_eq!(render(&[]).expect("renders"), "image: from-os/app\n");
+        let env_file = path(".env");
         assert_eq!(
-            yaml_value_to_string(&YamlValue::Bool(true)).expect("bool string"),
-            "true"
+            render(&["--env-file", &env_file]).expect("renders"),
+            "image: from-env-file/app\n"
         );
+        let err = render(&["--value-file-only"]).expect_err("no OS env");
         assert_eq!(
-            yaml_value_to_string(&YamlValue::String("abc".to_string())).expect("string value"),
-            "abc"
+            err.to_string(),
+            "--set image.repo: environment variable TPLENV_SET_REGISTRY is not set"
         );
-
-        let mapping: YamlValue = serde_yaml::from_str("foo: bar\n").expect("valid map yaml");
-        let rendered = yaml_value_to_string(&mapping).expect("mapping string");
-        assert!(rendered.contains("foo: bar"));
+        fs::remove_dir_all(&dir).ok();
     }
 
     #[test]
-    fn env_var_values_path_builds_expected_key() {
-        assert_eq!(env_var_values_path("NAMESPACE"), "environment.NAMESPACE");
+    fn conditions_read_the_values_file_without_other_values_placeholders() {
+        let dir = env::temp_dir().join(format!("tplenv-cond-load-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let path = |name: &str| dir.join(name).display().to_string();
+        fs::write(
+            dir.join("app.yaml"),
+            "{{ if .Values.a }}\nmode: on\n{{ else }}\nmode: off\n{{ end }}\n",
+        )
+        .expect("write");
+        fs::write(dir.join("values.yaml"), "a: true\n").expect("write");
+        let argv = [
+            "tplenv".to_string(),
+            "--file".to_string(),
+            path("app.yaml"),
+            "--values".to_string(),
+            path("values.yaml"),
+            "--output".to_string(),
+            path("out.yaml"),
+            "--quiet".to_string(),
+        ];
+        run(argv.iter().map(OsString::from).collect()).expect("renders");
+        assert_eq!(
+            fs::read_to_string(dir.join("out.yaml")).expect("output"),
+            "mode: on\n"
+        );
+        fs::remove_dir_all(&dir).ok();
     }
 
     #[test]
-    fn resolve_env_from_values_file_reads_environment_section() {
-        let yaml: YamlValue = serde_yaml::from_str(
-            r#"
-environment:
-  APP_NAME: api
-  NAMESPACE: prod
-"#,
-        )
-        .expect("valid yaml");
-        let env_vars = BTreeSet::from(["APP_NAME".to_string(), "NAMESPACE".to_string()]);
-
-        let (resolved, missing) =
-            resolve_env_from_values_file(&env_vars, &yaml).expect("env values resolve");
-
-        assert_eq!(resolved.get("APP_NAME"), Some(&"api".to_string()));
-        assert_eq!(resolved.get("NAMESPACE"), Some(&"prod".to_string()));
-        assert!(missing.is_empty());
+    fn emit_used_values_to_stdout_needs_output_elsewhere() {
+        let dir = env::temp_dir().join(format!("tplenv-emit-stdout-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        fs::write(dir.join("app.yaml"), "tag: {{ .Values.tag }}\n").expect("write");
+        fs::write(dir.join("values.yaml"), "tag: v1\nunused: x\n").expect("write");
+        let render = |extra: &[&str]| {
+            let mut argv: Vec<OsString> =
+                ["tplenv", "--quiet", "--file"].map(OsString::from).to_vec();
+            argv.push(dir.join("app.yaml").into());
+            argv.extend(["--values".into(), dir.join("values.yaml").into()]);
+            argv.extend(["--emit-used-values", "-"].map(OsString::from));
+            argv.extend(extra.iter().map(OsString::from));
+            run(argv)
+        };
+        let err = render(&[]).expect_err("both on stdout");
+        assert!(
+            err.to_string()
+                .starts_with("--emit-used-values - would mix"),
+            "{err:#}"
+        );
+        let err = render(&["--output", "-"]).expect_err("both on stdout");
+        assert!(
+            err.to_string().starts_with("--emit-used-values -"),
+            "{err:#}"
+        );
+        let out = dir.join("out.yaml").display().to_string();
+        render(&["--output", &out]).expect("output goes to a file");
+        assert_eq!(fs::read_to_string(&out).expect("output"), "tag: v1\n");
+        let _ = fs::remove_dir_all(&dir);
     }
 
     #[test]
-    fn resolve_env_from_values_file_reports_missing_keys() {
-        let yaml: YamlValue = serde_yaml::from_str(
-            r#"
-environment:
-  APP_NAME: api
-"#,
-        )
-        .expect("valid yaml");
-        let env_vars = BTreeSet::from(["APP_NAME".to_string(), "NAMESPACE".to_string()]);
-
-        let (resolved, missing) =
-            resolve_env_from_values_file(&env_vars, &yaml).expect("env values resolve");
-
-        assert_eq!(resolved.get("APP_NAME"), Some(&"api".to_string()));
-        assert!(!resolved.contains_key("NAMESPACE"));
-        assert_eq!(missing, vec!["environment.NAMESPACE".to_string()]);
+    fn env_file_conflicts_with_value_file_only() {
+        let matches = Args::command().try_get_matches_from([
+            "tplenv",
+            "--file",
+            "app.yaml",
+            "--env-file",
+            ".env",
+            "--value-file-only",
+        ]);
+        assert!(
+            matches.is_err(),
+            "--env-file conflicts with --value-file-only"
+        );
     }
 
     #[test]
-    fn file_pattern_regex_supports_num_token_and_wildcard() {
-        let re = file_pattern_regex("<NUM>-*.yaml").expect("pattern compiles");
-        assert!(re.is_match("1-demo.yaml"));
-        assert!(re.is_match("42-x.yaml"));
-        assert!(!re.is_match("demo.yaml"));
-        assert!(!re.is_match("a-demo.yaml"));
+    fn env_conflicts_with_values_file() {
+        let matches = Args::command().try_get_matches_from([
+            "tplenv",
+            "--file",
+            "app.yaml",
+            "--env",
+            "prod",
+            "--values-file",
+            "x.yaml",
+        ]);
+        assert!(matches.is_err(), "--env conflicts with --values-file");
     }
 
     #[test]
-    fn collect_prompt_paths_deduplicates_shared_keys() {
-        let values_paths = BTreeSet::from(["db.user".to_string()]);
-        let env_vars = BTreeSet::from(["APP_NAME".to_string(), "APP_NAME".to_string()]);
-        let skip = BTreeSet::new();
-
-        let all = collect_prompt_paths(&values_paths, &env_vars, true, &skip);
-
-        assert!(all.contains("db.user"));
-        assert!(all.contains("environment.APP_NAME"));
-        assert_eq!(all.len(), 2);
+    fn timeout_aborts_a_blocked_run_with_its_phase() {
+        let dir = env::temp_dir().join(format!("tplenv-timeout-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create temp dir");
+        fs::write(dir.join("t.yaml"), "a: {{ .Values.a }}\n").expect("write file");
+        // Reading a FIFO nobody writes to blocks forever.
+        let fifo = dir.join("values.yaml");
+        let status = std::process::Command::new("mkfifo")
+            .arg(&fifo)
+            .status()
+            .expect("run mkfifo");
+        assert!(status.success());
+        let argv: Vec<OsString> = ["tplenv", "--timeout", "1", "--file"]
+            .into_iter()
+            .map(OsString::from)
+            .chain([dir.join("t.yaml").into_os_string()])
+            .chain([OsString::from("--values"), fifo.into_os_string()])
+            .collect();
+        let started = std::time::Instant::now();
+        let err = run(argv).expect_err("times out");
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(
+            err.to_string(),
+            "timed out after 1s while resolving values (--timeout)"
+        );
+        fs::remove_dir_all(&dir).expect("cleanup temp dir");
     }
 
     #[test]
-    fn collect_prompt_paths_skips_existing_env_vars_without_force() {
-        let values_paths = BTreeSet::new();
-        let env_vars = BTreeSet::from(["IMAGE".to_string(), "SIGNER".to_string()]);
-        let skip = BTreeSet::from(["IMAGE".to_string()]);
+    fn repeated_values_files_merge_with_later_files_winning() {
+        let dir = env::temp_dir().join(format!("tplenv-multi-values-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let path = |name: &str| dir.join(name).display().to_string();
+        fs::write(
+            dir.join("app.yaml"),
+            "image: {{ .Values.image.repo }}:{{ .Values.image.tag }}\nhost: {{ .Values.hosts[0] }}\n",
+        )
+        .expect("write");
+        fs::write(
+            dir.join("base-values.yaml"),
+            "image:\n  repo: app\n  tag: latest\nhosts: [a, b]\n",
+        )
+        .expect("write");
+        fs::write(
+            dir.join("prod-values.yaml"),
+            "image:\n  tag: '1.2'\nhosts: [prod]\n",
+        )
+        .expect("write");
+        let argv = [
+            "tplenv".to_string(),
+            "--file".to_string(),
+            path("app.yaml"),
+            "--values".to_string(),
+            path("base-values.yaml"),
+            "--values".to_string(),
+            path("prod-values.yaml"),
+            "--output".to_string(),
+            path("out.yaml"),
+            "--quiet".to_string(),
+        ];
+        run(argv.iter().map(OsString::from).collect()).expect("renders");
+        assert_eq!(
+            fs::read_to_string(dir.join("out.yaml")).expect("output"),
+            "image: app:1.2\nhost: prod\n"
+        );
 
-        let all = collect_prompt_paths(&values_paths, &env_vars, true, &skip);
-        assert!(!all.contains("environment.IMAGE"));
-        assert!(all.contains("environment.SIGNER"));
+        let args = Args::try_parse_from(["tplenv", "--file", "app.yaml"]).expect("parses");
+        assert_eq!(args.values_file(), Path::new("Values.yaml"));
+        assert!(args.lower_values_files().is_empty());
+        fs::remove_dir_all(&dir).ok();
     }
 
     #[test]
-    fn extract_prompt_context_defaults_to_single_line() {
-        let input = "image: ${IMAGE}\n";
-        let re = placeholder_regex().expect("regex compiles");
-        let cap = re.captures(input).expect("capture exists");
-        let got = extract_prompt_context(input, &cap, &re, "environment.IMAGE", false);
-        assert_eq!(got, "image: ${IMAGE}");
-    }
+    fn pipeline_runs_steps_in_order_and_keeps_going() {
+        let step: YamlValue = serde_yaml::from_str(
+            "name: app\nfile: app.yaml\nindent: true\nquiet: false\nset: [a=1, b=2]\ndump-values:\n",
+        )
+        .expect("yaml parses");
+        let argv = pipeline_step_args(&step).expect("converts");
+        let verbose: YamlValue = serde_yaml::from_str("verbose: 2").expect("yaml parses");
+        assert_eq!(
+            pipeline_step_args(&verbose).expect("converts"),
+            ["tplenv", "--verbose", "--verbose"].map(OsString::from)
+        );
+        assert_eq!(
+            argv,
+            [
+                "tplenv",
+                "--file",
+                "app.yaml",
+                "--indent",
+                "--set",
+                "a=1",
+                "--set",
+                "b=2",
+                "--dump-values"
+            ]
+            .map(OsString::from)
+        );
 
-    #[test]
-    fn extract_prompt_context_uses_paragraph_for_single_variable() {
-        let input = "title: ${IMAGE}\nnotes: hello\n\nother: x\n";
-        let re = placeholder_regex().expect("regex compiles");
-        let cap = re.captures(input).expect("capture exists");
-        let got = extract_prompt_context(input, &cap, &re, "environment.IMAGE", true);
-        assert_eq!(got, "title: ${IMAGE}\nnotes: hello");
-    }
+        let dir = env::temp_dir().join(format!("tplenv-pipeline-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let path = |name: &str| dir.join(name).display().to_string();
+        fs::write(dir.join("values.tpl.yaml"), "tag: {{ .Values.tag }}\n").expect("write");
+        fs::write(dir.join("base.yaml"), "tag: v1\n").expect("write");
+        fs::write(dir.join("app.yaml"), "image: app:{{ .Values.tag }}\n").expect("write");
+        // The second step renders against the first step's output.
+        let pipeline = format!(
+            "- name: values\n  file: {}\n  values: {}\n  output: {}\n  quiet: true\n\
+             - file: {}\n  values: {}\n  output: {}\n  quiet: true\n",
+            path("values.tpl.yaml"),
+            path("base.yaml"),
+            path("values.yaml"),
+            path("app.yaml"),
+            path("values.yaml"),
+            path("out.yaml"),
+        );
+        fs::write(dir.join("pipeline.yaml"), &pipeline).expect("write");
+        run_pipeline(&dir.join("pipeline.yaml"), false, true).expect("pipeline runs");
+        assert_eq!(
+            fs::read_to_string(dir.join("out.yaml")).expect("output"),
+            "image: app:v1\n"
+        );
 
-    #[test]
-    fn extract_prompt_context_uses_list_entry_when_paragraph_has_multiple_variables() {
-        let input = "items:\n  - user: ${A}\n    note: x\n  - user: ${B}\n";
-        let re = placeholder_regex().expect("regex compiles");
-        let cap = re
-            .captures_iter(input)
-            .nth(1)
-            .expect("second placeholder capture");
-        let got = extract_prompt_context(input, &cap, &re, "environment.B", true);
-        assert_eq!(got, "  - user: ${B}");
+        let failing = format!(
+            "- name: broken\n  file: {}\n- name: last\n  file: {}\n  values: {}\n  output: {}\n  set: tag=v2\n  quiet: true\n",
+            path("missing.yaml"),
+            path("app.yaml"),
+            path("values.yaml"),
+            path("out.yaml"),
+        );
+        fs::write(dir.join("pipeline.yaml"), &failing).expect("write");
+        let err = run_pipeline(&dir.join("pipeline.yaml"), false, true).expect_err("aborts");
+        assert!(
+            err.to_string().contains("pipeline broken failed"),
+            "{err:#}"
+        );
+        let err = run_pipeline(&dir.join("pipeline.yaml"), true, true).expect_err("still fails");
+        assert_eq!(err.to_string(), "1 of 2 pipeline step(s) failed: broken");
+        assert_eq!(
+            fs::read_to_string(dir.join("out.yaml")).expect("output"),
+            "image: app:v2\n"
+        );
+        let _ = fs::remove_dir_all(&dir);
     }
 
     #[test]
-    fn collect_prompt_order_follows_file_occurrence() {
-        let templates = vec![(
-            PathBuf::from("a.yaml"),
-            "x: ${B}\ny: {{ .Values.alpha }}\nz: ${A}\n".to_string(),
-        )];
-        let re = placeholder_regex().expect("regex compiles");
-        let order = collect_prompt_order(&templates, &re);
+    fn pipeline_steps_count_warnings_and_verbosity_on_their_own() {
+        let dir = env::temp_dir().join(format!("tplenv-pipeline-warn-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("create dir");
+        let path = |name: &str| dir.join(name).display().to_string();
+        // A multi-line value inside a folded scalar warns (--folded-policy warn).
+        fs::write(dir.join("folded.yaml"), "notes: >\n  {{ .Values.notes }}\n").expect("write");
+        fs::write(dir.join("clean.yaml"), "tag: {{ .Values.tag }}\n").expect("write");
+        fs::write(dir.join("values.yaml"), "notes: \"a\\nb\"\ntag: v1\n").expect("write");
+        let pipeline = format!(
+            "- name: warns\n  file: {}\n  values: {}\n  indent: true\n  output: {}\n  quiet: true\n\
+             - name: clean\n  file: {}\n  values: {}\n  output: {}\n  fail-on-warning: true\n  verbose: 2\n",
+            path("folded.yaml"),
+            path("values.yaml"),
+            path("folded.out.yaml"),
+            path("clean.yaml"),
+            path("values.yaml"),
+            path("clean.out.yaml"),
+        );
+        fs::write(dir.join("pipeline.yaml"), &pipeline).expect("write");
+        run_pipeline(&dir.join("pipeline.yaml"), false, true)
+            .expect("the clean step does not see the first step's warning");
+        assert_eq!(verbosity(), 2);
         assert_eq!(
-            order,
-            vec![
-                "environment.B".to_string(),
-                "alpha".to_string(),
-                "environment.A".to_string()
-            ]
+            fs::read_to_string(dir.join("clean.out.yaml")).expect("output"),
+            "tag: v1\n"
         );
+        let _ = fs::remove_dir_all(&dir);
     }
 
     #[test]
@@ -1456,147 +2672,142 @@ environment:
             resolve_completion_shell("zsh").expect("zsh shell"),
             CompletionShell::Zsh
         ));
-        assert!(resolve_completion_shell("fish").is_err());
-    }
-
-    #[test]
-    fn is_yaml_file_only_accepts_yaml_suffix() {
-        assert!(is_yaml_file(Path::new("1-a.yaml")));
-        assert!(!is_yaml_file(Path::new("1-a.yml")));
-        assert!(!is_yaml_file(Path::new("1-a.txt")));
-    }
-
-    #[test]
-    fn render_multi_document_yaml_uses_doc_separator() {
-        let rendered = vec![
-            (PathBuf::from("1-a.yaml"), "a: 1\n".to_string()),
-            (PathBuf::from("2-b.yaml"), "b: 2\n".to_string()),
-        ];
-        let out = render_multi_document_yaml(&rendered);
-        assert_eq!(out, "a: 1\n\n---\nb: 2\n");
-    }
-
-    #[test]
-    fn extract_env_key_supports_three_env_styles() {
-        let re = placeholder_regex().expect("regex compiles");
-
-        let c1 = re
-            .captures("{{NAMESPACE}}")
-            .expect("must capture handlebars env");
-        assert_eq!(extract_env_key(&c1), Some("NAMESPACE"));
-
-        let c2 = re.captures("${APP_NAME}").expect("must capture brace env");
-        assert_eq!(extract_env_key(&c2), Some("APP_NAME"));
-
-        let c3 = re.captures("$REGION").expect("must capture short env");
-        assert_eq!(extract_env_key(&c3), Some("REGION"));
-    }
-
-    #[test]
-    fn values_key_to_env_var_handles_environment_prefix_and_dots() {
-        assert_eq!(values_key_to_env_var("environment.APP_NAME"), "APP_NAME");
-        assert_eq!(values_key_to_env_var("image.tag"), "IMAGE_TAG");
-    }
-
-    #[test]
-    fn render_eval_exports_outputs_bash_exports() {
-        let prompted = vec![
-            ("environment.APP_NAME".to_string(), "demo-app".to_string()),
-            ("image.tag".to_string(), "1.2.3".to_string()),
-        ];
-        let out = render_eval_exports_with_env(&prompted, &HashMap::new());
-        assert!(out.contains("export APP_NAME='demo-app'"));
-        assert!(out.contains("export IMAGE_TAG='1.2.3'"));
-    }
-
-    #[test]
-    fn render_eval_exports_with_env_always_includes_resolved_env_values() {
-        let prompted = vec![("image.tag".to_string(), "1.2.3".to_string())];
-        let resolved_env = HashMap::from([("IMAGE".to_string(), "repo/app:7".to_string())]);
-        let out = render_eval_exports_with_env(&prompted, &resolved_env);
-        assert!(out.contains("export IMAGE='repo/app:7'"));
-        assert!(out.contains("export IMAGE_TAG='1.2.3'"));
+        assert!(matches!(
+            resolve_completion_shell("fish").expect("fish shell"),
+            CompletionShell::Fish
+        ));
+        assert!(resolve_completion_shell("tcsh").is_err());
     }
 
     #[test]
-    fn prompted_environment_values_only_keeps_environment_entries() {
-        let prompted = vec![
-            ("environment.IMAGE".to_string(), "nginx:1.2".to_string()),
-            ("db.user".to_string(), "app".to_string()),
-        ];
-        let out = prompted_environment_values(&prompted);
-        assert_eq!(out.get("IMAGE"), Some(&"nginx:1.2".to_string()));
-        assert!(!out.contains_key("DB_USER"));
+    fn install_completion_into_writes_only_the_script() {
+        let dir = env::temp_dir().join(format!("tplenv-completion-{}", std::process::id()));
+        let bash =
+            install_completion_into(CompletionShell::Bash, &dir.join("bash")).expect("installs");
+        let zsh =
+            install_completion_into(CompletionShell::Zsh, &dir.join("zsh")).expect("installs");
+        assert_eq!(bash, dir.join("bash/tplenv"));
+        assert_eq!(zsh, dir.join("zsh/_tplenv"));
+        assert!(
+            fs::read_to_string(&zsh)
+                .expect("read file")
+                .starts_with("#compdef tplenv")
+        );
+        assert_eq!(fs::read_dir(dir.join("zsh")).expect("read dir").count(), 1);
+        fs::remove_dir_all(&dir).expect("cleanup");
     }
 
     #[test]
-    fn indent_multiline_value_uses_placeholder_line_indent() {
-        let input = "data:\n  script: |\n    {{ .Values.script }}\n";
-        let match_start = input
-            .find("{{ .Values.script }}")
-            .expect("placeholder should exist");
-        let value = "echo first\necho second";
-
-        let out = indent_multiline_value(value, input, match_start);
-        assert_eq!(out, "echo first\n    echo second");
+    fn completion_scripts_include_all_long_flags() {
+        let bash = completion_script(CompletionShell::Bash);
+        let zsh = completion_script(CompletionShell::Zsh);
+        let fish = completion_script(CompletionShell::Fish);
+        let bash_words: BTreeSet<&str> = bash
+            .split(|c: char| !(c.is_ascii_alphanumeric() || c == '-'))
+            .collect();
+        for arg in Args::command().get_arguments() {
+            if arg.is_hide_set() {
+                let flag = format!("--{}", arg.get_long().unwrap_or_default());
+                assert!(
+                    !bash_words.contains(flag.as_str()),
+                    "bash completion has {flag}"
+                );
+                continue;
+            }
+            if let Some(long) = arg.get_long() {
+                let flag = format!("--{long}");
+                assert!(bash.contains(&flag), "bash completion misses {flag}");
+                assert!(zsh.contains(&flag), "zsh completion misses {flag}");
+                assert!(
+                    fish.contains(&format!(" -l {long}")),
+                    "fish completion misses {flag}"
+                );
+            }
+        }
+        assert!(bash.contains("complete -F _tplenv "), "{bash}");
+        assert!(zsh.starts_with("#compdef tplenv"));
+        let file_line = fish
+            .lines()
+            .find(|l| l.starts_with("complete -c tplenv -s f -l file "))
+            .expect("fish completes --file");
+        assert!(file_line.ends_with(" -r -F"), "{file_line}");
+        assert!(
+            fish.contains(" -l output-jsonl -l output-json-lines -d "),
+            "{fish}"
+        );
     }
 
     #[test]
-    fn format_replacement_with_indent_uses_yaml_block_scalar_for_inline_value() {
-        let input = "data:\n  script: {{ .Values.script }}\n";
-        let token = "{{ .Values.script }}";
-        let match_start = input.find(token).expect("placeholder should exist");
-        let match_end = match_start + token.len();
-        let value = "echo first\necho second";
-
-        let out = format_replacement_with_indent(value, input, match_start, match_end);
-        assert_eq!(out, "|\n    echo first\n    echo second");
+    fn verbose_counts_repeats_and_numeric_level() {
+        let level = |argv: &[&str]| {
+            let argv = expand_verbose_level(argv.iter().map(OsString::from));
+            let matches = Args::command()
+                .try_get_matches_from(argv)
+                .expect("args parse");
+            Args::from_arg_matches(&matches)
+                .expect("args build")
+                .verbose
+        };
+        assert_eq!(level(&["tplenv", "-f", "a.yaml"]), 0);
+        assert_eq!(level(&["tplenv", "-f", "a.yaml", "-v"]), 1);
+        assert_eq!(level(&["tplenv", "-f", "a.yaml", "-vvv"]), 3);
+        assert_eq!(level(&["tplenv", "-f", "a.yaml", "--verbose=2"]), 2);
     }
 
     #[test]
-    fn format_replacement_with_indent_uses_block_scalar_keep_for_trailing_empty_lines() {
-        let input = "data:\n  script: {{ .Values.script }}\n";
-        let token = "{{ .Values.script }}";
-        let match_start = input.find(token).expect("placeholder should exist");
-        let match_end = match_start + token.len();
-        let value = "echo first\n\n";
-
-        let out = format_replacement_with_indent(value, input, match_start, match_end);
-        assert_eq!(out, "|+\n    echo first\n    \n");
+    fn doc_separator_accepts_a_leading_dash() {
+        let args = Args::try_parse_from([
+            "tplenv",
+            "--file",
+            "app.yaml",
+            "--doc-separator",
+            "--- # next",
+        ])
+        .expect("parses");
+        assert_eq!(args.doc_separator, "--- # next");
     }
 
     #[test]
-    fn indent_multiline_signer_in_yaml_list_items_stays_valid_yaml() {
-        let input = r#"name: kbs-certs
-version: "0.3.11"
-
-access_policy:
-    read:
-      - ANY
-    update:
-      - ${SIGNER}
-    create_sessions:
-      - ${SIGNER}
-"#;
-        let signer = "-----BEGIN PUBLIC KEY-----\nMIIBIjANBgkqhkiG9w0BAQEFAAOCAQ8AMIIBCgKCAQEAtestkeyline\n-----END PUBLIC KEY-----";
-        let re = placeholder_regex().expect("regex compiles");
-
-        let rendered = re.replace_all(input, |caps: &regex::Captures| {
-            if let Some(key) = extract_env_key(caps)
-                && key == "SIGNER"
-            {
-                let m = caps.get(0).expect("full match present");
-                return format_replacement_with_indent(signer, input, m.start(), m.end());
-            }
-            caps.get(0)
-                .map(|m| m.as_str().to_string())
-                .unwrap_or_default()
-        });
-
-        let rendered = rendered.to_string();
-        assert!(rendered.contains("- |\n        -----BEGIN PUBLIC KEY-----"));
-        assert_eq!(rendered.matches("- |").count(), 2);
-        let parsed: YamlValue = serde_yaml::from_str(&rendered).expect("rendered yaml is valid");
-        assert!(matches!(parsed, YamlValue::Mapping(_)));
+    fn render_effective_config_lists_options_with_source() {
+        let matches = Args::command()
+            .try_get_matches_from(["tplenv", "--file", "app.yaml", "--indent"])
+            .expect("args parse");
+        let args = Args::from_arg_matches(&matches).expect("args build");
+        let out = render_effective_config(&args, &matches, &[PathBuf::from("app.yaml")]);
+        assert!(out.contains("input files: app.yaml\n"));
+        assert!(out.contains("values file: Values.yaml\n"));
+        assert!(out.contains("output: stdout\n"));
+        assert!(out.contains("--indent: true (command line)\n"));
+        assert!(out.contains("--values-file: Values.yaml (default)\n"));
+        assert!(out.contains("--max-file-size: 50M (default)\n"));
+
+        let matches = Args::command()
+            .try_get_matches_from([
+                "tplenv",
+                "--file",
+                "app.yaml",
+                "--secret-key",
+                "environment.*PASSWORD*",
+                "--secret-key",
+                "db.*",
+                "--override",
+                "DB_PASSWORD=hunter2",
+                "--override",
+                "NAMESPACE=prod",
+                "--set",
+                "db.user=admin",
+            ])
+            .expect("args parse");
+        let args = Args::from_arg_matches(&matches).expect("args build");
+        let out = render_effective_config(&args, &matches, &[PathBuf::from("app.yaml")]);
+        assert!(
+            out.contains("--override: DB_PASSWORD=<redacted>, NAMESPACE=prod (command line)\n"),
+            "{out}"
+        );
+        assert!(
+            out.contains("--set: db.user=<redacted> (command line)\n"),
+            "{out}"
+        );
+        assert!(!out.contains("hunter2") && !out.contains("admin"), "{out}");
     }
 }
@@ -0,0 +1,111 @@
+// tests/render.rs
+use serde_yaml::Value as YamlValue;
+use std::collections::HashMap;
+use std::fs;
+use tplenv::{Options, TemplateFormat, render};
+
+fn yaml(text: &str) -> YamlValue {
+    serde_yaml::from_str(text).expect("yaml parses")
+}
+
+fn env(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+    pairs
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn render_fills_env_and_values_placeholders() {
+    let values = yaml("image:\n  repo: app\n  tag: v1\nhosts:\n  - a.example\n  - b.example\n");
+    let out = render(
+        "ns: {{NAMESPACE}}\nimage: \"{{ .Values.image.repo }}:{{ .Values.image.tag }}\"\nhost: {{ .Values.hosts[1] }}\n",
+        &env(&[("NAMESPACE", "dev")]),
+        &values,
+        &Options::default(),
+    )
+    .expect("renders");
+    assert_eq!(out, "ns: dev\nimage: \"app:v1\"\nhost: b.example\n");
+}
+
+#[test]
+fn render_prefers_the_environment_section_then_the_env_map_then_defaults() {
+    let values = yaml("environment:\n  NAMESPACE: from-values\n  REGION: eu\n");
+    let template = "{{NAMESPACE}} {{REGION}} {{ZONE}} ${PORT:-8080}\n";
+    let env = env(&[("NAMESPACE", "from-env"), ("ZONE", "a")]);
+    let out = render(template, &env, &values, &Options::default()).expect("renders");
+    assert_eq!(out, "from-values eu a 8080\n");
+
+    let opts = Options {
+        value_file_only: true,
+        ..Options::default()
+    };
+    let err = render(template, &env, &values, &opts).expect_err("ZONE is not in the values");
+    assert_eq!(err.to_string(), "no value for environment.ZONE");
+}
+
+#[test]
+fn render_applies_filters_conditionals_and_indent() {
+    let values = yaml("debug: false\ncert: |-\n  line1\n  line2\nname: App\n");
+    let template = "name: {{ .Values.name | lower }}\n{{ if .Values.debug }}\nlog: debug\n{{ end }}\ncert: |\n  {{ .Values.cert }}\n";
+    let opts = Options {
+        indent: true,
+        ..Options::default()
+    };
+    let out = render(template, &HashMap::new(), &values, &opts).expect("renders");
+    assert_eq!(out, "name: app\ncert: |\n  line1\n  line2\n");
+}
+
+#[test]
+fn render_escapes_values_for_json_templates() {
+    let values = yaml("msg: 'say \"hi\"'\n");
+    let opts = Options {
+        format: TemplateFormat::Json,
+        ..Options::default()
+    };
+    let out = render(
+        "{\"msg\": \"{{ .Values.msg }}\"}\n",
+        &HashMap::new(),
+        &values,
+        &opts,
+    )
+    .expect("renders");
+    assert_eq!(out, "{\"msg\": \"say \\\"hi\\\"\"}\n");
+}
+
+#[test]
+fn render_names_every_missing_key() {
+    let err = render(
+        "{{ .Values.a }} {{ B }} {{ .Values.c | required \"c is needed\" }}\n",
+        &HashMap::new(),
+        &yaml("{}"),
+        &Options::default(),
+    )
+    .expect_err("missing keys fail");
+    assert_eq!(
+        err.to_string(),
+        "no value for environment.B, .Values.a, c is needed (.Values.c)"
+    );
+}
+
+#[test]
+fn render_reads_files_relative_to_files_dir_only() {
+    let template = "script: {{ .Files.Get \"init.sh\" }}\n";
+    let err = render(template, &HashMap::new(), &yaml("{}"), &Options::default())
+        .expect_err("no files_dir");
+    assert_eq!(
+        err.to_string(),
+        "{{ .Files.Get \"init.sh\" }} in <template>: set Options::files_dir to read files"
+    );
+
+    let dir = std::env::temp_dir().join(format!("tplenv-lib-files-{}", std::process::id()));
+    fs::create_dir_all(&dir).expect("create dir");
+    fs::write(dir.join("init.sh"), "echo hi").expect("write file");
+    let opts = Options {
+        files_dir: Some(dir.clone()),
+        ..Options::default()
+    };
+    let out = render(template, &HashMap::new(), &yaml("{}"), &opts).expect("renders");
+    assert_eq!(out, "script: echo hi\n");
+    fs::remove_dir_all(&dir).ok();
+}